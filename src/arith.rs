@@ -0,0 +1,373 @@
+//! Integer arithmetic for `$(( ... ))`: 64-bit numbers that wrap around,
+//! C's operators with C's precedence, and names standing for the variables
+//! they name, with unset or empty ones counting as 0.
+
+/// An expression that cannot be evaluated.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ArithError {
+    #[error("syntax error in expression (error token is \"{0}\")")]
+    Syntax(String),
+    #[error("division by 0")]
+    DivisionByZero,
+    #[error("exponent less than 0")]
+    NegativeExponent,
+    /// A variable whose value refers back to itself, directly or not.
+    #[error("expression recursion level exceeded")]
+    TooDeep,
+}
+
+/// How deep variables holding expressions may refer to other variables.
+const MAX_DEPTH: usize = 32;
+
+/// Evaluates `expression`, looking variables up with `lookup`.
+pub fn evaluate(
+    expression: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<i64, ArithError> {
+    evaluate_at(expression, lookup, 0)
+}
+
+fn evaluate_at(
+    expression: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+    depth: usize,
+) -> Result<i64, ArithError> {
+    if depth > MAX_DEPTH {
+        return Err(ArithError::TooDeep);
+    }
+    let mut parser = Parser {
+        tokens: tokenize(expression)?,
+        position: 0,
+        lookup,
+        depth,
+        skipping: 0,
+    };
+    if parser.tokens.is_empty() {
+        return Ok(0);
+    }
+    let value = parser.ternary()?;
+    match parser.tokens.get(parser.position) {
+        Some(token) => Err(ArithError::Syntax(token.to_string())),
+        None => Ok(value),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Operator(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Number(number) => write!(f, "{}", number),
+            Self::Name(name) => write!(f, "{}", name),
+            Self::Operator(operator) => write!(f, "{}", operator),
+        }
+    }
+}
+
+/// Operators, longest first so `**` is not read as two `*`.
+const OPERATORS: &[&str] = &[
+    "**", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "&",
+    "^", "|", "!", "~", "?", ":", "(", ")",
+];
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, ArithError> {
+    let mut tokens = Vec::new();
+    let mut rest = expression.trim_start();
+    while !rest.is_empty() {
+        let first = rest.chars().next().unwrap_or_default();
+        let length = if first.is_ascii_digit() {
+            let length = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            let number = parse_number(&rest[..length])
+                .ok_or_else(|| ArithError::Syntax(rest.trim_end().to_string()))?;
+            tokens.push(Token::Number(number));
+            length
+        } else if first.is_ascii_alphabetic() || first == '_' {
+            let length = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..length].to_string()));
+            length
+        } else {
+            let operator = OPERATORS
+                .iter()
+                .find(|operator| rest.starts_with(**operator))
+                .ok_or_else(|| ArithError::Syntax(rest.trim_end().to_string()))?;
+            tokens.push(Token::Operator(operator));
+            operator.len()
+        };
+        rest = rest[length..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// A decimal, `0x` hexadecimal or leading-zero octal number.
+fn parse_number(text: &str) -> Option<i64> {
+    let (digits, radix) = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => (hex, 16),
+        None if text.len() > 1 && text.starts_with('0') => (&text[1..], 8),
+        None => (text, 10),
+    };
+    // Out of range numbers wrap around like the arithmetic does
+    u64::from_str_radix(digits, radix)
+        .ok()
+        .map(|number| number as i64)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    lookup: &'a dyn Fn(&str) -> Option<String>,
+    depth: usize,
+    /// Inside the branch `&&`, `||` or `?:` does not take, where division
+    /// by zero is no error since nothing is evaluated.
+    skipping: usize,
+}
+
+/// Binary operators from the loosest binding to the tightest, `**` and the
+/// unary ones aside.
+const LEVELS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+impl Parser<'_> {
+    fn peek_operator(&self) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(operator)) => Some(operator),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, operator: &str) -> Result<(), ArithError> {
+        if self.peek_operator() == Some(operator) {
+            self.position += 1;
+            return Ok(());
+        }
+        Err(self.unexpected())
+    }
+
+    /// The error for the token at the current position, or for the last
+    /// one when the expression ends too soon.
+    fn unexpected(&self) -> ArithError {
+        let start = self.position.min(self.tokens.len().saturating_sub(1));
+        let rest: Vec<String> = self.tokens[start..].iter().map(Token::to_string).collect();
+        ArithError::Syntax(rest.join(" "))
+    }
+
+    fn ternary(&mut self) -> Result<i64, ArithError> {
+        let condition = self.binary(0)?;
+        if self.peek_operator() != Some("?") {
+            return Ok(condition);
+        }
+        self.position += 1;
+        let then = self.branch(condition == 0, Self::ternary)?;
+        self.expect(":")?;
+        let otherwise = self.branch(condition != 0, Self::ternary)?;
+        Ok(if condition != 0 { then } else { otherwise })
+    }
+
+    /// Parses with `parse`, as a branch not taken when `skip` is set.
+    fn branch(
+        &mut self,
+        skip: bool,
+        parse: impl FnOnce(&mut Self) -> Result<i64, ArithError>,
+    ) -> Result<i64, ArithError> {
+        self.skipping += skip as usize;
+        let value = parse(self);
+        self.skipping -= skip as usize;
+        value
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, ArithError> {
+        let Some(operators) = LEVELS.get(level) else {
+            return self.power();
+        };
+        let mut left = self.binary(level + 1)?;
+        while let Some(operator) = self.peek_operator().filter(|op| operators.contains(op)) {
+            self.position += 1;
+            let skip = match operator {
+                "&&" => left == 0,
+                "||" => left != 0,
+                _ => false,
+            };
+            let right = self.branch(skip, |parser| parser.binary(level + 1))?;
+            left = self.apply(operator, left, right)?;
+        }
+        Ok(left)
+    }
+
+    fn apply(&self, operator: &str, left: i64, right: i64) -> Result<i64, ArithError> {
+        let divide = |divide: fn(i64, i64) -> i64| match right {
+            0 if self.skipping > 0 => Ok(0),
+            0 => Err(ArithError::DivisionByZero),
+            _ => Ok(divide(left, right)),
+        };
+        Ok(match operator {
+            "||" => (left != 0 || right != 0) as i64,
+            "&&" => (left != 0 && right != 0) as i64,
+            "|" => left | right,
+            "^" => left ^ right,
+            "&" => left & right,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            "<" => (left < right) as i64,
+            "<=" => (left <= right) as i64,
+            ">" => (left > right) as i64,
+            ">=" => (left >= right) as i64,
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" => divide(i64::wrapping_div)?,
+            "%" => divide(i64::wrapping_rem)?,
+            _ => return Err(ArithError::Syntax(operator.to_string())),
+        })
+    }
+
+    /// `**`, which binds tighter than the other binary operators and
+    /// groups from the right.
+    fn power(&mut self) -> Result<i64, ArithError> {
+        let base = self.unary()?;
+        if self.peek_operator() != Some("**") {
+            return Ok(base);
+        }
+        self.position += 1;
+        let exponent = self.power()?;
+        if exponent < 0 {
+            return if self.skipping > 0 {
+                Ok(0)
+            } else {
+                Err(ArithError::NegativeExponent)
+            };
+        }
+        Ok(base.wrapping_pow(u32::try_from(exponent).unwrap_or(u32::MAX)))
+    }
+
+    fn unary(&mut self) -> Result<i64, ArithError> {
+        match self.peek_operator() {
+            Some(operator @ ("-" | "+" | "!" | "~")) => {
+                self.position += 1;
+                let value = self.unary()?;
+                Ok(match operator {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value,
+                })
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, ArithError> {
+        let token = self.tokens.get(self.position).cloned();
+        match token {
+            Some(Token::Number(number)) => {
+                self.position += 1;
+                Ok(number)
+            }
+            Some(Token::Name(name)) => {
+                self.position += 1;
+                self.variable(&name)
+            }
+            Some(Token::Operator("(")) => {
+                self.position += 1;
+                let value = self.ternary()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    /// The value of a variable, itself evaluated as an expression unless it
+    /// is a plain number.
+    fn variable(&self, name: &str) -> Result<i64, ArithError> {
+        let value = (self.lookup)(name).unwrap_or_default();
+        let value = value.trim();
+        if let Some(number) = value.parse::<i64>().ok().or_else(|| parse_number(value)) {
+            return Ok(number);
+        }
+        evaluate_at(value, self.lookup, self.depth + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expression: &str) -> Result<i64, ArithError> {
+        evaluate(expression, &|name| match name {
+            "x" => Some("5".to_string()),
+            "expr" => Some("x * 2".to_string()),
+            "loop" => Some("loop + 1".to_string()),
+            "empty" => Some(String::new()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3"), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3"), Ok(9));
+        assert_eq!(eval("2 ** 3 ** 2"), Ok(512));
+        assert_eq!(eval("-2 ** 2"), Ok(4));
+        assert_eq!(eval("1 < 2 && 3 > 4 || 5 == 5"), Ok(1));
+        assert_eq!(eval("7 % 3 << 2 | 1"), Ok(5));
+        assert_eq!(eval("!0 + ~0"), Ok(0));
+        assert_eq!(eval("1 ? 2 : 3"), Ok(2));
+        assert_eq!(eval("0 ? 2 : 0 ? 3 : 4"), Ok(4));
+        assert_eq!(eval(""), Ok(0));
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(eval("0x1f + 010"), Ok(39));
+        assert_eq!(eval("9223372036854775807 + 1"), Ok(i64::MIN));
+        assert_eq!(eval("-9223372036854775808 / -1"), Ok(i64::MIN));
+        assert!(matches!(eval("09"), Err(ArithError::Syntax(_))));
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(eval("x + 1"), Ok(6));
+        assert_eq!(eval("expr + 1"), Ok(11));
+        assert_eq!(eval("unset + empty"), Ok(0));
+        assert_eq!(eval("loop"), Err(ArithError::TooDeep));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(eval("1 / 0"), Err(ArithError::DivisionByZero));
+        assert_eq!(eval("1 % 0"), Err(ArithError::DivisionByZero));
+        assert_eq!(eval("2 ** -1"), Err(ArithError::NegativeExponent));
+        assert_eq!(eval("1 +"), Err(ArithError::Syntax("+".to_string())));
+        assert_eq!(eval("(1"), Err(ArithError::Syntax("1".to_string())));
+        assert_eq!(eval("1 2"), Err(ArithError::Syntax("2".to_string())));
+        assert_eq!(eval("1 $ 2"), Err(ArithError::Syntax("$ 2".to_string())));
+    }
+
+    #[test]
+    fn branches_not_taken_are_not_evaluated() {
+        assert_eq!(eval("0 && 1 / 0"), Ok(0));
+        assert_eq!(eval("1 || 1 / 0"), Ok(1));
+        assert_eq!(eval("1 ? 2 : 1 / 0"), Ok(2));
+        assert_eq!(eval("0 ? 2 ** -1 : 3"), Ok(3));
+    }
+}
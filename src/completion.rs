@@ -0,0 +1,419 @@
+//! Tab completion candidates for the interactive line editor.
+
+use std::collections::BTreeMap;
+
+use crate::{jobs, paths, BuiltinCommand, ShellState};
+
+/// Candidates for the word under the cursor.
+pub struct Completion {
+    /// Byte offset in the line where the completed word starts.
+    pub start: usize,
+    /// Full replacement words, sorted and de-duplicated.
+    pub candidates: Vec<String>,
+    /// Text listed beside some candidates, such as a job's command.
+    pub hints: BTreeMap<String, String>,
+}
+
+/// A source of candidates for `complete -A`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Command,
+    Directory,
+    File,
+    /// `%N` job specs from the job table, hinted with their commands.
+    Job,
+    /// Signal names as `-NAME` options, once the word starts with `-`.
+    Signal,
+    Variable,
+}
+
+impl Action {
+    pub const NAMES: &'static [(&'static str, Action)] = &[
+        ("command", Action::Command),
+        ("directory", Action::Directory),
+        ("file", Action::File),
+        ("job", Action::Job),
+        ("signal", Action::Signal),
+        ("variable", Action::Variable),
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES
+            .iter()
+            .find(|(action, _)| *action == name)
+            .map(|(_, action)| *action)
+    }
+
+    pub fn name(self) -> &'static str {
+        Self::NAMES
+            .iter()
+            .find(|(_, action)| *action == self)
+            .map_or("", |(name, _)| name)
+    }
+}
+
+/// How the arguments of a command are completed, as set with `complete`.
+#[derive(Debug, Clone, Default)]
+pub struct CompSpec {
+    pub actions: Vec<Action>,
+    /// Words given with `-W`.
+    pub words: Vec<String>,
+}
+
+impl CompSpec {
+    /// The `complete` command that registers the spec for `name`.
+    pub fn to_command(&self, name: &str) -> String {
+        let mut command = String::from("complete");
+        for action in &self.actions {
+            command.push_str(" -A ");
+            command.push_str(action.name());
+        }
+        if !self.words.is_empty() {
+            command.push_str(" -W ");
+            command.push_str(&shell_words::quote(&self.words.join(" ")));
+        }
+        command.push(' ');
+        command.push_str(name);
+        command
+    }
+
+    /// Candidates for `word` from each action and the word list.
+    fn candidates(
+        &self,
+        state: &ShellState,
+        word: &str,
+        hints: &mut BTreeMap<String, String>,
+    ) -> Vec<String> {
+        let mut candidates: Vec<String> = self
+            .words
+            .iter()
+            .filter(|candidate| candidate.starts_with(word))
+            .cloned()
+            .collect();
+        let mark_directories = state.shopt("mark_directories");
+        for action in &self.actions {
+            match action {
+                Action::Command => candidates.extend(command_candidates(state, word)),
+                Action::Directory => candidates.extend(
+                    matching_entries(".", word)
+                        .into_iter()
+                        .filter(|(_, is_dir)| *is_dir)
+                        .map(|(candidate, _)| mark(candidate, mark_directories)),
+                ),
+                Action::File => candidates.extend(path_candidates(word, mark_directories)),
+                Action::Job => {
+                    for job in state.jobs.iter() {
+                        let spec = format!("%{}", job.id);
+                        if spec.starts_with(word) {
+                            hints.insert(spec.clone(), job.command.clone());
+                            candidates.push(spec);
+                        }
+                    }
+                }
+                Action::Signal => {
+                    if let Some(prefix) = word.strip_prefix('-') {
+                        let prefix = prefix.to_ascii_uppercase();
+                        candidates.extend(
+                            jobs::SIGNALS
+                                .iter()
+                                .filter(|(name, _)| name.starts_with(&prefix))
+                                .map(|(name, _)| format!("-{}", name)),
+                        );
+                    }
+                }
+                Action::Variable => candidates.extend(
+                    state
+                        .variables
+                        .keys()
+                        .filter(|name| name.starts_with(word))
+                        .cloned(),
+                ),
+            }
+        }
+        candidates
+    }
+}
+
+/// Completes the last word of `line`: command names for the first word,
+/// the spec registered with `complete` for arguments of its command,
+/// directories for the argument of `cd`, file paths for everything else.
+pub fn complete(state: &ShellState, line: &str) -> Completion {
+    let start = line
+        .rfind(char::is_whitespace)
+        .map(|index| index + 1)
+        .unwrap_or(0);
+    let word = &line[start..];
+    let is_command = line[..start].trim().is_empty() && !word.contains('/');
+
+    let mark_directories = state.shopt("mark_directories");
+    let spec = line[..start]
+        .split_whitespace()
+        .next()
+        .and_then(|command| state.completions.get(command));
+
+    let is_cd = line[..start].trim() == "cd";
+    let mut hints = BTreeMap::new();
+    let mut candidates = if is_command {
+        command_candidates(state, word)
+    } else if let Some(spec) = spec {
+        spec.candidates(state, word, &mut hints)
+    } else if let Some(candidates) = tilde_candidates(state, word, is_cd) {
+        candidates
+    } else if is_cd {
+        cd_candidates(state, word, mark_directories)
+    } else {
+        path_candidates(word, mark_directories)
+    };
+    crate::expand::sort_names(&mut candidates, state.shopt("nocasesort"));
+    candidates.dedup();
+    Completion {
+        start,
+        candidates,
+        hints,
+    }
+}
+
+/// Functions, builtins and `PATH` executables starting with `prefix`.
+fn command_candidates(state: &ShellState, prefix: &str) -> Vec<String> {
+    let mut candidates: Vec<String> = BuiltinCommand::NAMES
+        .iter()
+        .filter(|name| name.starts_with(prefix) && state.builtin(name).is_some())
+        .map(|name| name.to_string())
+        .collect();
+    candidates.extend(
+        state
+            .functions
+            .keys()
+            .filter(|name| name.starts_with(prefix))
+            .cloned(),
+    );
+
+    let mut path_cache = state.path_cache.borrow_mut();
+    path_cache.refresh(&crate::search_path(state));
+    candidates.extend(path_cache.names_with_prefix(prefix).cloned());
+    candidates
+}
+
+/// Completes a word starting with a `~` prefix the way expansion resolves
+/// it (`~`, `~user`, `~+`, `~-` and directory stack entries): a bare
+/// prefix naming a directory gets its `/`, and entries inside it keep the
+/// prefix as typed. `None` for other words.
+fn tilde_candidates(state: &ShellState, word: &str, cd: bool) -> Option<Vec<String>> {
+    let rest = word.strip_prefix('~')?;
+    let Some(slash) = rest.find('/') else {
+        let dir = crate::expand::tilde_prefix(state, rest)?;
+        return Some(if std::path::Path::new(&dir).is_dir() {
+            vec![format!("{}/", word)]
+        } else {
+            Vec::new()
+        });
+    };
+    let dir = crate::expand::tilde_prefix(state, &rest[..slash])?;
+    let (prefix, path) = word.split_at(1 + slash);
+    let resolved = format!("{}{}", dir, path);
+    let mark_directories = state.shopt("mark_directories");
+    let candidates = if cd {
+        cd_candidates(state, &resolved, mark_directories)
+    } else {
+        path_candidates(&resolved, mark_directories)
+    };
+    Some(
+        candidates
+            .into_iter()
+            .filter_map(|candidate| Some(format!("{}{}", prefix, candidate.strip_prefix(&dir)?)))
+            .collect(),
+    )
+}
+
+/// Lists the entries matching `word`. With `mark_directories`, directories
+/// (and symlinks to them) get a trailing `/` so completion can continue
+/// into them.
+fn path_candidates(word: &str, mark_directories: bool) -> Vec<String> {
+    matching_entries(".", word)
+        .into_iter()
+        .map(|(candidate, is_dir)| mark(candidate, is_dir && mark_directories))
+        .collect()
+}
+
+/// Directories matching `word`, relative to the current directory or, for
+/// relative words, to any `$CDPATH` entry, the way `cd` resolves them.
+fn cd_candidates(state: &ShellState, word: &str, mark_directories: bool) -> Vec<String> {
+    let mut bases = vec![".".to_string()];
+    if !paths::is_absolute(word) && !word.starts_with('.') {
+        let cdpath = state.variable("CDPATH").unwrap_or_default();
+        bases.extend(
+            paths::split_list(&cdpath)
+                .filter(|base| !base.is_empty())
+                .map(str::to_string),
+        );
+    }
+    bases
+        .iter()
+        .flat_map(|base| matching_entries(base, word))
+        .filter(|(_, is_dir)| *is_dir)
+        .map(|(candidate, _)| mark(candidate, mark_directories))
+        .collect()
+}
+
+fn mark(candidate: String, slash: bool) -> String {
+    if slash {
+        candidate + "/"
+    } else {
+        candidate
+    }
+}
+
+/// Entries of `base` matching `word`, paired with whether each is a
+/// directory once symlinks are followed. Candidates keep `word`'s own
+/// directory part and never include `base`.
+fn matching_entries(base: &str, word: &str) -> Vec<(String, bool)> {
+    let (dir_part, file_prefix) = match word.rfind('/') {
+        Some(index) => (&word[..=index], &word[index + 1..]),
+        None => ("", word),
+    };
+    let dir = std::path::Path::new(base).join(dir_part);
+    let Ok(entries) = std::fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+
+    entries
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| name.starts_with(file_prefix))
+        .filter(|name| !name.starts_with('.') || file_prefix.starts_with('.'))
+        .map(|name| {
+            let is_dir = std::fs::metadata(dir.join(&name))
+                .map(|meta| meta.is_dir())
+                .unwrap_or(false);
+            (format!("{}{}", dir_part, name), is_dir)
+        })
+        .collect()
+}
+
+/// Returns the longest prefix shared by all `candidates`.
+pub fn common_prefix(candidates: &[String]) -> String {
+    let Some(first) = candidates.first() else {
+        return String::new();
+    };
+    let mut prefix = first.as_str();
+    for candidate in &candidates[1..] {
+        let len = prefix
+            .chars()
+            .zip(candidate.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum();
+        prefix = &prefix[..len];
+    }
+    prefix.to_string()
+}
+
+/// Lays `items` out column-major, like `ls`, in as many columns as fit in
+/// `width` terminal cells with at least `gap` spaces between them. Items
+/// wider than the terminal end up one per line.
+pub fn format_columns(items: &[String], width: usize, gap: usize) -> Vec<String> {
+    if items.is_empty() {
+        return Vec::new();
+    }
+    let item_width = |item: &String| crate::prompt::display_width(item);
+    let max_width = items.iter().map(item_width).max().unwrap_or(0);
+    let column_width = max_width + gap;
+    let columns = ((width + gap) / column_width).clamp(1, items.len());
+    let rows = items.len().div_ceil(columns);
+
+    (0..rows)
+        .map(|row| {
+            let mut line = String::new();
+            for column in 0..columns {
+                let Some(item) = items.get(column * rows + row) else {
+                    break;
+                };
+                line.push_str(item);
+                line.push_str(&" ".repeat(column_width - item_width(item)));
+            }
+            line.trim_end().to_string()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn columns_fill_downwards() {
+        let names = items(&["a", "bb", "c", "dd", "e"]);
+        assert_eq!(format_columns(&names, 12, 2), ["a   c   e", "bb  dd"]);
+        assert_eq!(format_columns(&names, 80, 1), ["a  bb c  dd e"]);
+        assert_eq!(format_columns(&names, 1, 2).len(), 5);
+        assert!(format_columns(&[], 80, 2).is_empty());
+    }
+
+    #[test]
+    fn columns_measure_terminal_cells() {
+        // Six cells wide, though only three characters
+        let names = items(&["ab", "日本語", "x"]);
+        assert_eq!(format_columns(&names, 22, 2), ["ab      日本語  x"]);
+        assert_eq!(format_columns(&names, 21, 2), ["ab      x", "日本語"]);
+    }
+
+    #[test]
+    fn column_gap() {
+        let names = items(&["a", "b"]);
+        assert_eq!(format_columns(&names, 80, 0), ["ab"]);
+        assert_eq!(format_columns(&names, 80, 4), ["a    b"]);
+    }
+
+    #[test]
+    fn marked_directories() {
+        let dir = std::env::temp_dir().join(format!("completion-test.{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("subfile"), "").unwrap();
+        let _ = std::os::unix::fs::symlink(dir.join("sub"), dir.join("sublink"));
+        let word = format!("{}/sub", dir.display());
+        let candidates = |mark_directories| {
+            let mut candidates = path_candidates(&word, mark_directories);
+            candidates.sort();
+            candidates
+        };
+        assert_eq!(
+            candidates(true),
+            [
+                format!("{word}/"),
+                format!("{word}file"),
+                format!("{word}link/")
+            ]
+        );
+        assert_eq!(
+            candidates(false),
+            [word.clone(), format!("{word}file"), format!("{word}link")]
+        );
+        let mut directories = cd_candidates(&ShellState::new(), &word, true);
+        directories.sort();
+        assert_eq!(directories, [format!("{word}/"), format!("{word}link/")]);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn directory_stack_tildes() {
+        let dir = std::env::temp_dir().join(format!("completion-tilde.{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("subfile"), "").unwrap();
+        let mut state = ShellState::new();
+        state.dir_stack.push(dir.display().to_string());
+        let candidates = |line: &str| {
+            let mut candidates = complete(&state, line).candidates;
+            candidates.sort();
+            candidates
+        };
+        assert_eq!(candidates("ls ~1"), ["~1/"]);
+        assert_eq!(candidates("ls ~-0/su"), ["~-0/sub/", "~-0/subfile"]);
+        assert_eq!(candidates("cd ~+1/su"), ["~+1/sub/"]);
+        assert!(candidates("ls ~2/").is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,305 @@
+//! Parsing of compound commands that span several list items, such as
+//! `case ... esac` and `select ... done`.
+
+/// A parsed `case WORD in ... esac`.
+#[derive(Debug)]
+pub struct CaseCommand {
+    /// The raw word being matched, expanded when the command runs.
+    pub word: String,
+    pub branches: Vec<CaseBranch>,
+}
+
+/// One `pattern | pattern) commands ;;` branch.
+#[derive(Debug)]
+pub struct CaseBranch {
+    /// Raw patterns, expanded when the branch is tried.
+    pub patterns: Vec<String>,
+    pub body: String,
+    pub terminator: Terminator,
+}
+
+/// How a `case` branch ends.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Terminator {
+    /// `;;`: stop after this branch.
+    Break,
+    /// `;&`: also run the next branch, without testing its patterns.
+    FallThrough,
+    /// `;;&`: go on testing the patterns of the following branches.
+    Continue,
+}
+
+/// A parsed `select NAME [in WORDS]; do BODY; done`.
+#[derive(Debug)]
+pub struct SelectCommand {
+    pub name: String,
+    /// Raw words of the menu, expanded when the command runs. `None`
+    /// without `in`, meaning the positional parameters.
+    pub words: Option<Vec<String>>,
+    pub body: String,
+}
+
+/// Whether the reserved word `keyword` starts at byte `index` of `input`:
+/// it has to stand alone and be where a command can start, which includes
+/// right after `do`.
+pub fn keyword_at(input: &str, index: usize, keyword: &str) -> bool {
+    let rest = &input[index..];
+    let before = input[..index].trim_end_matches([' ', '\t']);
+    rest.starts_with(keyword)
+        && rest[keyword.len()..]
+            .chars()
+            .next()
+            .map_or(true, |c| c.is_whitespace() || ";&|)".contains(c))
+        && (before.is_empty()
+            || before.ends_with([';', '&', '|', '(', ')', '{', '}', '\n'])
+            || (before.len() < index
+                && before.ends_with("do")
+                && keyword_at(input, before.len() - "do".len(), "do")))
+}
+
+/// How the reserved word at byte `index` of `input`, if any, changes the
+/// nesting of compound commands: `case` and `select` open one, `esac` and
+/// `done` close one.
+pub fn nesting_change(input: &str, index: usize) -> isize {
+    let is = |keyword| keyword_at(input, index, keyword);
+    match input[index..].chars().next() {
+        Some('c') if is("case") => 1,
+        Some('s') if is("select") => 1,
+        Some('e') if is("esac") => -1,
+        Some('d') if is("done") => -1,
+        _ => 0,
+    }
+}
+
+/// Whether `input` has a `case` or `select` without its `esac` or `done`,
+/// so more lines are needed before it can run.
+pub fn is_incomplete(input: &str) -> bool {
+    let mut open = 0isize;
+    for (index, _) in crate::unquoted(input) {
+        open = (open + nesting_change(input, index)).max(0);
+    }
+    open > 0
+}
+
+/// Index of the first byte from `start` on that is neither whitespace nor
+/// part of a comment.
+fn skip_blank(input: &str, start: usize) -> usize {
+    let mut position = start;
+    loop {
+        position = input[position..]
+            .find(|c: char| !c.is_whitespace())
+            .map_or(input.len(), |offset| position + offset);
+        if !input[position..].starts_with('#') {
+            return position;
+        }
+        position = input[position..]
+            .find('\n')
+            .map_or(input.len(), |offset| position + offset);
+    }
+}
+
+/// Parses `input` as a `case` command. `None` means it is not one.
+pub fn parse_case(input: &str) -> Option<Result<CaseCommand, String>> {
+    if !keyword_at(input, 0, "case") {
+        return None;
+    }
+    Some(case_command(input))
+}
+
+fn case_command(input: &str) -> Result<CaseCommand, String> {
+    const UNEXPECTED_EOF: &str = "syntax error: unexpected end of file";
+
+    let unquoted = crate::unquoted(input);
+    let after = |start: usize| {
+        unquoted
+            .iter()
+            .copied()
+            .filter(move |(index, _)| *index >= start)
+    };
+    let skip_blank = |start: usize| skip_blank(input, start);
+
+    let word_start = skip_blank("case".len());
+    let word_end = after(word_start)
+        .find(|(_, c)| c.is_whitespace())
+        .map_or(input.len(), |(index, _)| index);
+    let word = &input[word_start..word_end];
+    if word.is_empty() {
+        return Err(UNEXPECTED_EOF.to_string());
+    }
+    let mut position = skip_blank(word_end);
+    let rest = &input[position..];
+    if !(rest.starts_with("in") && rest[2..].chars().next().map_or(true, char::is_whitespace)) {
+        return Err(format!(
+            "syntax error near `{}': expected `in'",
+            rest.split_whitespace().next().unwrap_or("")
+        ));
+    }
+    position += "in".len();
+
+    let mut branches = Vec::new();
+    loop {
+        position = skip_blank(position);
+        let rest = &input[position..];
+        if rest.is_empty() {
+            return Err(UNEXPECTED_EOF.to_string());
+        }
+        if rest.starts_with("esac") {
+            let trailing = &input[skip_blank(position + "esac".len())..];
+            if !trailing.is_empty() {
+                return Err(format!("syntax error near unexpected token `{}'", trailing));
+            }
+            break;
+        }
+        if rest.starts_with('(') {
+            position += 1;
+        }
+
+        let Some((close, _)) = after(position).find(|(_, c)| *c == ')') else {
+            return Err(UNEXPECTED_EOF.to_string());
+        };
+        let mut patterns = Vec::new();
+        let mut start = position;
+        for (index, c) in after(position).take_while(|(index, _)| *index < close) {
+            if c == '|' {
+                patterns.push(input[start..index].trim().to_string());
+                start = index + 1;
+            }
+        }
+        patterns.push(input[start..close].trim().to_string());
+        if patterns.iter().any(String::is_empty) {
+            return Err("syntax error near unexpected token `)'".to_string());
+        }
+
+        // The body runs to `;;`, `;&`, `;;&` or the `esac`, skipping over
+        // nested compound commands
+        let mut nested = 0isize;
+        let mut end = None;
+        for (index, c) in after(close + 1) {
+            let rest = &input[index..];
+            match nesting_change(input, index) {
+                -1 if nested == 0 => {
+                    if keyword_at(input, index, "esac") {
+                        end = Some((index, 0, Terminator::Break));
+                        break;
+                    }
+                    return Err("syntax error near unexpected token `done'".to_string());
+                }
+                0 => {}
+                change => {
+                    nested += change;
+                    continue;
+                }
+            }
+            match c {
+                ';' if nested == 0 => {
+                    let terminator = if rest.starts_with(";;&") {
+                        Some((3, Terminator::Continue))
+                    } else if rest.starts_with(";;") {
+                        Some((2, Terminator::Break))
+                    } else if rest.starts_with(";&") {
+                        Some((2, Terminator::FallThrough))
+                    } else {
+                        None
+                    };
+                    if let Some((len, terminator)) = terminator {
+                        end = Some((index, len, terminator));
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        let Some((body_end, len, terminator)) = end else {
+            return Err(UNEXPECTED_EOF.to_string());
+        };
+        branches.push(CaseBranch {
+            patterns,
+            body: input[close + 1..body_end].trim().to_string(),
+            terminator,
+        });
+        position = body_end + len;
+    }
+
+    Ok(CaseCommand {
+        word: word.to_string(),
+        branches,
+    })
+}
+
+/// Parses `input` as a `select` command. `None` means it is not one.
+pub fn parse_select(input: &str) -> Option<Result<SelectCommand, String>> {
+    if !keyword_at(input, 0, "select") {
+        return None;
+    }
+    Some(select_command(input))
+}
+
+fn select_command(input: &str) -> Result<SelectCommand, String> {
+    const UNEXPECTED_EOF: &str = "syntax error: unexpected end of file";
+
+    let unquoted = crate::unquoted(input);
+    let rest = input["select".len()..].trim_start();
+    let name_end = rest
+        .find(|c: char| c.is_whitespace() || c == ';')
+        .unwrap_or(rest.len());
+    let name = &rest[..name_end];
+    if name.is_empty() {
+        return Err(UNEXPECTED_EOF.to_string());
+    }
+    if !crate::is_identifier(name) {
+        return Err(format!("`{}': not a valid identifier", name));
+    }
+    let mut position = input.len() - rest.len() + name_end;
+
+    // The word list, if any, runs to the first `;` or newline
+    let after_name = input[position..].trim_start_matches([' ', '\t']);
+    let words = if after_name.starts_with("in")
+        && after_name[2..]
+            .chars()
+            .next()
+            .map_or(true, |c| c.is_whitespace() || c == ';')
+    {
+        let start = input.len() - after_name.len() + "in".len();
+        let end = unquoted
+            .iter()
+            .find(|(index, c)| *index >= start && (*c == ';' || *c == '\n'))
+            .map_or(input.len(), |(index, _)| *index);
+        position = end;
+        Some(crate::expand::split_words(&input[start..end]).map_err(|error| error.to_string())?)
+    } else {
+        None
+    };
+
+    let rest = input[position..].trim_start_matches(|c: char| c.is_whitespace() || c == ';');
+    position = input.len() - rest.len();
+    if rest.is_empty() {
+        return Err(UNEXPECTED_EOF.to_string());
+    }
+    if !keyword_at(input, position, "do") {
+        return Err(format!(
+            "syntax error near unexpected token `{}'",
+            rest.split_whitespace().next().unwrap_or("")
+        ));
+    }
+    let body_start = position + "do".len();
+
+    let mut nested = 0isize;
+    for &(index, _) in unquoted.iter().filter(|(index, _)| *index >= body_start) {
+        nested += nesting_change(input, index);
+        if nested < 0 {
+            if !keyword_at(input, index, "done") {
+                return Err("syntax error near unexpected token `esac'".to_string());
+            }
+            let trailing = &input[skip_blank(input, index + "done".len())..];
+            if !trailing.is_empty() {
+                return Err(format!("syntax error near unexpected token `{}'", trailing));
+            }
+            return Ok(SelectCommand {
+                name: name.to_string(),
+                words,
+                body: input[body_start..index].trim().to_string(),
+            });
+        }
+    }
+    Err(UNEXPECTED_EOF.to_string())
+}
@@ -0,0 +1,496 @@
+//! Minimal raw-mode line editor used when stdin is a terminal.
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::completion;
+use crate::prompt;
+use crate::ShellState;
+
+/// Spaces between columns when listing candidates, unless
+/// `COMPLETION_COLUMN_GAP` says otherwise.
+const DEFAULT_COLUMN_GAP: usize = 2;
+
+/// Spaces between columns when listing candidates: `COMPLETION_COLUMN_GAP`,
+/// if set to a number.
+fn column_gap(state: &ShellState) -> usize {
+    state
+        .variable("COMPLETION_COLUMN_GAP")
+        .and_then(|gap| gap.parse().ok())
+        .unwrap_or(DEFAULT_COLUMN_GAP)
+}
+
+/// Puts the terminal into non-canonical, no-echo mode for as long as it is
+/// alive, restoring the original settings on drop.
+struct RawMode(libc::termios);
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let original = termios;
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(original))
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.0);
+        }
+    }
+}
+
+/// Waits until `fd` has input or `deadline` passes. Returns `false` on
+/// timeout.
+pub fn wait_for_input(fd: libc::c_int, deadline: Instant) -> io::Result<bool> {
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let timeout = remaining
+            .as_micros()
+            .div_ceil(1000)
+            .min(libc::c_int::MAX as u128);
+        let mut fds = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut fds, 1, timeout as libc::c_int) } {
+            -1 => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+            0 if remaining.is_zero() => return Ok(false),
+            0 => {}
+            _ => return Ok(true),
+        }
+    }
+}
+
+/// Reads a single byte straight from `fd`, usually the terminal, bypassing
+/// std's buffer so that type-ahead stays available to child processes.
+/// Fails with `TimedOut` when nothing arrives before `deadline`.
+pub fn read_byte(fd: libc::c_int, deadline: Option<Instant>) -> io::Result<Option<u8>> {
+    if let Some(deadline) = deadline {
+        if !wait_for_input(fd, deadline)? {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out waiting for input",
+            ));
+        }
+    }
+    let mut byte = 0u8;
+    loop {
+        let read = unsafe { libc::read(fd, (&mut byte as *mut u8).cast(), 1) };
+        match read {
+            1 => return Ok(Some(byte)),
+            0 => return Ok(None),
+            _ => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+        }
+    }
+}
+
+/// Reads one line a byte at a time with `read_byte`, the newline
+/// included, leaving whatever follows it for child processes. Returns
+/// `None` at end of file before any byte.
+pub fn read_reply(fd: libc::c_int, deadline: Option<Instant>) -> io::Result<Option<String>> {
+    let mut bytes = Vec::new();
+    while let Some(byte) = read_byte(fd, deadline)? {
+        bytes.push(byte);
+        if byte == b'\n' {
+            break;
+        }
+    }
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+/// Reads one line with echo, backspace, tab completion and Up/Down
+/// history browsing. Returns `None` on Ctrl-D at an empty line. `prompt`
+/// may carry the zero-width markers of a rendered `PS1`, and so may
+/// `right_prompt`, a rendered `RPS1`. Fails with `TimedOut` when no key is
+/// pressed for `timeout`; each key starts the wait over.
+pub fn read_line(
+    state: &mut ShellState,
+    prompt: &str,
+    right_prompt: Option<&str>,
+    timeout: Option<Duration>,
+) -> io::Result<Option<String>> {
+    let _raw_mode = RawMode::enable()?;
+    let prompt = &prompt::strip_markers(prompt);
+    let right = right_prompt.map(RightPrompt::new);
+    let right = right.as_ref();
+    let mut stdout = io::stdout();
+    let mut line = String::new();
+    let mut pending = Vec::new();
+    let mut last_was_tab = false;
+    // Entry shown while browsing history, and the line typed before
+    let mut history_index: Option<usize> = None;
+    let mut draft = String::new();
+
+    write!(stdout, "{}", prompt)?;
+    refresh_right_prompt(state, right, prompt, &line)?;
+    stdout.flush()?;
+    loop {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let Some(byte) = read_key(state, prompt, right, &line, deadline)? else {
+            if line.is_empty() {
+                return Ok(None);
+            }
+            writeln!(stdout)?;
+            return Ok(Some(line));
+        };
+        let is_tab = byte == b'\t';
+        match byte {
+            b'\r' | b'\n' => {
+                writeln!(stdout)?;
+                return Ok(Some(line));
+            }
+            0x04 if line.is_empty() => return Ok(None),
+            0x03 => {
+                write!(stdout, "^C\n{}", prompt)?;
+                line.clear();
+            }
+            0x7f | 0x08 => {
+                if line.pop().is_some() {
+                    write!(stdout, "\x08 \x08")?;
+                }
+            }
+            b'\t' => complete_line(state, prompt, &mut line, last_was_tab)?,
+            0x1b => match read_escape_sequence()? {
+                Some(b'A') => {
+                    crate::load_history(state);
+                    let entries = state.history.entries();
+                    let index = match history_index {
+                        _ if entries.is_empty() => None,
+                        None => {
+                            draft = line.clone();
+                            Some(entries.len() - 1)
+                        }
+                        Some(index) => Some(index.saturating_sub(1)),
+                    };
+                    match index {
+                        Some(index) => {
+                            history_index = Some(index);
+                            replace_line(&mut line, &entries[index])?;
+                        }
+                        None => write!(stdout, "\x07")?,
+                    }
+                }
+                Some(b'B') => match history_index {
+                    Some(index) if index + 1 < state.history.entries().len() => {
+                        history_index = Some(index + 1);
+                        replace_line(&mut line, &state.history.entries()[index + 1])?;
+                    }
+                    Some(_) => {
+                        history_index = None;
+                        replace_line(&mut line, &draft)?;
+                    }
+                    None => write!(stdout, "\x07")?,
+                },
+                _ => {}
+            },
+            byte if byte < 0x20 => {}
+            byte => {
+                pending.push(byte);
+                if let Ok(text) = std::str::from_utf8(&pending) {
+                    line.push_str(text);
+                    write!(stdout, "{}", text)?;
+                    pending.clear();
+                } else if pending.len() >= 4 {
+                    pending.clear();
+                }
+            }
+        }
+        last_was_tab = is_tab;
+        refresh_right_prompt(state, right, prompt, &line)?;
+        stdout.flush()?;
+    }
+}
+
+/// A rendered `RPS1`, shown at the right edge of the input line.
+struct RightPrompt {
+    text: String,
+    width: usize,
+}
+
+impl RightPrompt {
+    fn new(rendered: &str) -> Self {
+        Self {
+            text: prompt::strip_markers(rendered),
+            width: prompt::display_width(rendered),
+        }
+    }
+}
+
+/// Draws the right prompt while the prompt and `line` leave room for it
+/// on their row, with a space between, and erases it once they do not:
+/// after text that would run into it, or that wraps. The cursor is always
+/// at the end of `line`, so erasing clears the rest of its row.
+fn refresh_right_prompt(
+    state: &ShellState,
+    right: Option<&RightPrompt>,
+    prompt: &str,
+    line: &str,
+) -> io::Result<()> {
+    let Some(right) = right else {
+        return Ok(());
+    };
+    let mut stdout = io::stdout();
+    let columns = terminal_columns(state);
+    let last_line = prompt.rsplit('\n').next().unwrap_or_default();
+    let used = prompt::display_width(last_line) + prompt::display_width(line);
+    // The last column stays empty, or the terminal would wrap
+    if used + 1 + right.width < columns {
+        let column = columns - 1 - right.width;
+        write!(stdout, "\x1b7\r\x1b[{}C{}\x1b8", column, right.text)
+    } else {
+        write!(stdout, "\x1b[K")
+    }
+}
+
+/// What ended a wait for input.
+#[derive(PartialEq)]
+enum Wakeup {
+    Input,
+    /// A signal handler asked for attention through `signals::wake`.
+    Signal,
+    Timeout,
+}
+
+/// Waits until stdin has input, a signal arrives or `deadline` passes.
+fn wait_for_event(deadline: Option<Instant>) -> io::Result<Wakeup> {
+    let mut fds = [
+        libc::pollfd {
+            fd: libc::STDIN_FILENO,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: crate::signals::wake_fd().unwrap_or(-1),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    let timeout = match deadline {
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(Wakeup::Timeout);
+            }
+            remaining
+                .as_micros()
+                .div_ceil(1000)
+                .min(libc::c_int::MAX as u128) as libc::c_int
+        }
+        None => -1,
+    };
+    match unsafe { libc::poll(fds.as_mut_ptr(), 2, timeout) } {
+        -1 => {
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+            // The handler has written to the pipe by now
+            Ok(Wakeup::Signal)
+        }
+        0 => Ok(Wakeup::Timeout),
+        _ if fds[0].revents != 0 => Ok(Wakeup::Input),
+        _ => Ok(Wakeup::Signal),
+    }
+}
+
+/// Reads the next key like `read_byte`. Signals that arrive meanwhile are
+/// handled here, between keys: the terminal size is synced, traps run and,
+/// with `set -o notify`, finished jobs are reported, their output going
+/// above the line being edited. A hangup ends the read with `Interrupted`.
+fn read_key(
+    state: &mut ShellState,
+    prompt: &str,
+    right: Option<&RightPrompt>,
+    line: &str,
+    deadline: Option<Instant>,
+) -> io::Result<Option<u8>> {
+    while wait_for_event(deadline)? == Wakeup::Signal {
+        crate::signals::drain();
+        if crate::HUNG_UP.load(std::sync::atomic::Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "hangup"));
+        }
+        if crate::WINDOW_RESIZED.swap(false, std::sync::atomic::Ordering::Relaxed) {
+            crate::sync_window_size(state);
+        }
+        if crate::signals::has_pending() {
+            clear_prompt(state, prompt, line)?;
+            crate::run_signal_traps(state);
+            write!(io::stdout(), "{}{}", prompt, line)?;
+            refresh_right_prompt(state, right, prompt, line)?;
+            io::stdout().flush()?;
+        }
+        if state.option("notify") {
+            let notices = crate::job_notices(state);
+            if !notices.is_empty() {
+                show_notices(state, prompt, line, &notices)?;
+                refresh_right_prompt(state, right, prompt, line)?;
+                io::stdout().flush()?;
+            }
+        }
+    }
+    read_byte(libc::STDIN_FILENO, deadline)
+}
+
+/// Replaces the prompt's last line and the typed text with `notices`, then
+/// draws the prompt and the text again below them.
+fn show_notices(
+    state: &ShellState,
+    prompt: &str,
+    line: &str,
+    notices: &[String],
+) -> io::Result<()> {
+    clear_prompt(state, prompt, line)?;
+    let mut stdout = io::stdout();
+    for notice in notices {
+        writeln!(stdout, "{}", notice)?;
+    }
+    write!(stdout, "{}{}", prompt, line)?;
+    stdout.flush()
+}
+
+/// Erases the prompt's last line and the typed text, leaving the cursor
+/// where the prompt started.
+fn clear_prompt(state: &ShellState, prompt: &str, line: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    let last_line = prompt.rsplit('\n').next().unwrap_or_default();
+    let width = prompt::display_width(last_line) + prompt::display_width(line);
+    let rows = width / terminal_columns(state);
+    write!(stdout, "\r")?;
+    if rows > 0 {
+        write!(stdout, "\x1b[{}A", rows)?;
+    }
+    write!(stdout, "\x1b[J")?;
+    stdout.flush()
+}
+
+/// Consumes the rest of a CSI/SS3 sequence such as an arrow key and
+/// returns its final byte.
+fn read_escape_sequence() -> io::Result<Option<u8>> {
+    match read_byte(libc::STDIN_FILENO, None)? {
+        Some(b'[') | Some(b'O') => {
+            while let Some(byte) = read_byte(libc::STDIN_FILENO, None)? {
+                if (0x40..=0x7e).contains(&byte) {
+                    return Ok(Some(byte));
+                }
+            }
+            Ok(None)
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Erases the typed text and shows `replacement` in its place.
+fn replace_line(line: &mut String, replacement: &str) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    let width = prompt::display_width(line);
+    write!(
+        stdout,
+        "{}{}{}",
+        "\x08".repeat(width),
+        " ".repeat(width),
+        "\x08".repeat(width)
+    )?;
+    write!(stdout, "{}", replacement)?;
+    *line = replacement.to_string();
+    Ok(())
+}
+
+/// Handles a Tab press: a unique candidate is inserted with a trailing
+/// space (none after a directory's `/`), several candidates are extended
+/// to their common prefix, and a second Tab with nothing to add lists them
+/// in columns.
+fn complete_line(
+    state: &ShellState,
+    prompt: &str,
+    line: &mut String,
+    list_candidates: bool,
+) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    let completion = completion::complete(state, line);
+    let word = &line[completion.start..];
+
+    let replacement = match completion.candidates.as_slice() {
+        [] => {
+            write!(stdout, "\x07")?;
+            return Ok(());
+        }
+        [candidate] if candidate.ends_with('/') => candidate.clone(),
+        [candidate] => format!("{} ", candidate),
+        candidates => completion::common_prefix(candidates),
+    };
+
+    if replacement.len() > word.len() {
+        write!(stdout, "{}", &replacement[word.len()..])?;
+        line.truncate(completion.start);
+        line.push_str(&replacement);
+    } else if list_candidates {
+        let names: Vec<String> = completion
+            .candidates
+            .iter()
+            .map(|candidate| prompt::sanitize(display_name(candidate)))
+            .collect();
+        writeln!(stdout)?;
+        let gap = column_gap(state);
+        if completion.hints.is_empty() {
+            for row in completion::format_columns(&names, terminal_columns(state), gap) {
+                writeln!(stdout, "{}", row)?;
+            }
+        } else {
+            // One candidate per line, with its hint in a second column
+            let width = names.iter().map(|name| prompt::display_width(name)).max();
+            for (name, candidate) in names.iter().zip(&completion.candidates) {
+                let hint = completion
+                    .hints
+                    .get(candidate)
+                    .map(|hint| prompt::sanitize(hint))
+                    .unwrap_or_default();
+                let padding = width.unwrap_or(0) + gap - prompt::display_width(name);
+                let row = format!("{}{}{}", name, " ".repeat(padding), hint);
+                writeln!(stdout, "{}", row.trim_end())?;
+            }
+        }
+        write!(stdout, "{}{}", prompt, line)?;
+    } else {
+        write!(stdout, "\x07")?;
+    }
+    Ok(())
+}
+
+/// The part of a path candidate shown in listings, like bash's basename
+/// display.
+fn display_name(candidate: &str) -> &str {
+    match candidate.trim_end_matches('/').rfind('/') {
+        Some(index) => &candidate[index + 1..],
+        None => candidate,
+    }
+}
+
+/// Terminal width for the editor and candidate listings, 80 columns when
+/// it cannot be read.
+pub fn terminal_columns(state: &ShellState) -> usize {
+    crate::terminal_width(state).unwrap_or(80)
+}
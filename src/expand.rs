@@ -0,0 +1,1515 @@
+//! Word splitting and expansion: words keep their quotes through parsing
+//! and are expanded (tilde, parameters, pathnames, then quote removal)
+//! right before the command runs.
+
+use crate::ShellState;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExpandError {
+    #[error("{0}: ambiguous redirect")]
+    AmbiguousRedirect(String),
+    /// A pattern as a redirection target that matches no path.
+    #[error("{0}: no match")]
+    NoMatch(String),
+    /// An unset variable expanded under `set -u`.
+    #[error("{0}: unbound variable")]
+    Unbound(String),
+    /// `${NAME?message}` with `NAME` unset.
+    #[error("{0}: {1}")]
+    Required(String, String),
+    /// A `${...}` expansion that is not one the shell knows.
+    #[error("{0}: bad substitution")]
+    BadSubstitution(String),
+    /// `${NAME=word}` with `NAME` a special or positional parameter.
+    #[error("${0}: cannot assign in this way")]
+    CannotAssign(String),
+    /// More words than `expansion_limit` allows.
+    #[error("expansion produced too many words")]
+    TooManyWords,
+    /// `$(( ... ))` with an expression that cannot be evaluated.
+    #[error("{0}: {1}")]
+    Arithmetic(String, crate::arith::ArithError),
+}
+
+impl ExpandError {
+    /// Whether the error ends a non-interactive shell, as with bash.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(
+            self,
+            ExpandError::AmbiguousRedirect(_)
+                | ExpandError::NoMatch(_)
+                | ExpandError::TooManyWords
+                | ExpandError::Arithmetic(..)
+        )
+    }
+}
+
+/// Words the expansion of one command may produce when `EXPANSION_LIMIT`
+/// is unset.
+const DEFAULT_EXPANSION_LIMIT: usize = 100_000;
+
+/// Most words the expansion of one command may produce: `EXPANSION_LIMIT`,
+/// if set to a positive number. Pattern matching stops once it has found
+/// more, rather than walk the rest of a deep tree.
+pub fn expansion_limit(state: &ShellState) -> usize {
+    state
+        .variable("EXPANSION_LIMIT")
+        .and_then(|limit| limit.parse().ok())
+        .filter(|limit| *limit > 0)
+        .unwrap_or(DEFAULT_EXPANSION_LIMIT)
+}
+
+/// A syntax error found while splitting words.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{kind}")]
+pub struct TokenizeError {
+    pub kind: TokenizeErrorKind,
+    /// Byte offset in the input of the construct at fault, such as the
+    /// quote that is never closed.
+    pub position: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum TokenizeErrorKind {
+    /// The input ended before the given closing character.
+    #[error("unexpected EOF while looking for matching `{0}'")]
+    Unterminated(char),
+    #[error("`case' inside `$(...)' is not supported")]
+    CaseInSubstitution,
+}
+
+impl TokenizeError {
+    fn new(kind: TokenizeErrorKind, position: usize) -> Self {
+        Self { kind, position }
+    }
+
+    /// The error moved `offset` bytes further, for input that was a slice
+    /// of a longer line.
+    pub fn shifted(self, offset: usize) -> Self {
+        Self::new(self.kind, self.position + offset)
+    }
+
+    /// One-based column of the error in `input`, counted in characters.
+    pub fn column(&self, input: &str) -> usize {
+        input[..self.position].chars().count() + 1
+    }
+
+    /// `input` with a caret under the error, underlined to the end of the
+    /// construct at fault: the rest of the input for an unclosed one.
+    pub fn underline(&self, input: &str) -> String {
+        let width = match self.kind {
+            TokenizeErrorKind::Unterminated(_) => input[self.position..].chars().count(),
+            TokenizeErrorKind::CaseInSubstitution => "case".len(),
+        };
+        format!(
+            "{}\n{}^{}",
+            input,
+            " ".repeat(self.column(input) - 1),
+            "~".repeat(width.saturating_sub(1))
+        )
+    }
+}
+
+/// Splits `input` into words at unquoted whitespace, keeping quotes and
+/// backslashes in place for expansion. A `#` starting a word begins a
+/// comment, and a `${...}` never ends a word. `$'...'` strings have their
+/// backslash escapes decoded right away.
+pub fn split_words(input: &str) -> Result<Vec<String>, TokenizeError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            '#' if !in_word => break,
+            '$' if chars.as_str().starts_with('\'') => {
+                // `$'...'` is decoded here and kept as a plain quoted string
+                let start = input.len() - chars.as_str().len() - 1;
+                chars.next();
+                let mut body = String::new();
+                let mut closed = false;
+                while let Some(next) = chars.next() {
+                    match next {
+                        '\'' => {
+                            closed = true;
+                            break;
+                        }
+                        '\\' => {
+                            body.push(next);
+                            if let Some(escaped) = chars.next() {
+                                body.push(escaped);
+                            }
+                        }
+                        _ => body.push(next),
+                    }
+                }
+                if !closed {
+                    let kind = TokenizeErrorKind::Unterminated('\'');
+                    return Err(TokenizeError::new(kind, start));
+                }
+                let (decoded, _) = decode_escapes(&body, Escapes::AnsiC);
+                word.push('\'');
+                word.push_str(&decoded.replace('\'', "'\\''"));
+                word.push('\'');
+                in_word = true;
+            }
+            '$' | '`' if c == '`' || chars.as_str().starts_with('(') => {
+                // `$(...)`, `$((...))` and backquotes are one piece of the
+                // word, whatever quotes and spaces they hold
+                let start = input.len() - chars.as_str().len() - 1;
+                let end = substitution_end(input, start)?;
+                word.push_str(&input[start..end]);
+                chars = input[end..].chars();
+                in_word = true;
+            }
+            '$' if chars.as_str().starts_with('{') => {
+                // `${...}` is one piece of the word, spaces included
+                word.push(c);
+                let mut depth = 0;
+                for next in chars.by_ref() {
+                    word.push(next);
+                    match next {
+                        '{' => depth += 1,
+                        '}' if depth == 1 => break,
+                        '}' => depth -= 1,
+                        _ => {}
+                    }
+                }
+                in_word = true;
+            }
+            '\\' => {
+                word.push(c);
+                if let Some(next) = chars.next() {
+                    word.push(next);
+                }
+                in_word = true;
+            }
+            '"' => {
+                let start = input.len() - chars.as_str().len() - 1;
+                let end = double_quote_end(input, start)?;
+                word.push_str(&input[start..end]);
+                chars = input[end..].chars();
+                in_word = true;
+            }
+            '\'' => {
+                let start = input.len() - chars.as_str().len() - 1;
+                word.push(c);
+                let mut closed = false;
+                for next in chars.by_ref() {
+                    word.push(next);
+                    if next == c {
+                        closed = true;
+                        break;
+                    }
+                }
+                if !closed {
+                    let kind = TokenizeErrorKind::Unterminated('\'');
+                    return Err(TokenizeError::new(kind, start));
+                }
+                in_word = true;
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// Byte index just past the command substitution, arithmetic expansion or
+/// backquoted command starting at byte `start` of `input`. Quotes and
+/// substitutions nest inside it, so neither a `"` nor a quoted `)` in
+/// `$(...)` ends it early. A `case` inside `$(...)` is rejected: its
+/// pattern `)`s could not be told apart from the closing one.
+pub fn substitution_end(input: &str, start: usize) -> Result<usize, TokenizeError> {
+    if input[start..].starts_with('`') {
+        let mut escaped = false;
+        for (offset, c) in input[start + 1..].char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '`' => return Ok(start + 1 + offset + 1),
+                _ => {}
+            }
+        }
+        let kind = TokenizeErrorKind::Unterminated('`');
+        return Err(TokenizeError::new(kind, start));
+    }
+
+    let mut depth = 0usize;
+    let mut index = start + 1;
+    let mut previous = '(';
+    while let Some(c) = input[index..].chars().next() {
+        let next = index + c.len_utf8();
+        let at_word_start = previous.is_whitespace() || ";&|(".contains(previous);
+        previous = c;
+        index = match c {
+            '\\' => next + input[next..].chars().next().map_or(0, char::len_utf8),
+            '\'' => match input[next..].find('\'') {
+                Some(offset) => next + offset + 1,
+                None => {
+                    let kind = TokenizeErrorKind::Unterminated('\'');
+                    return Err(TokenizeError::new(kind, index));
+                }
+            },
+            '"' => double_quote_end(input, index)?,
+            '`' => substitution_end(input, index)?,
+            '$' if input[next..].starts_with('(') => substitution_end(input, index)?,
+            '#' if at_word_start => input[index..]
+                .find('\n')
+                .map_or(input.len(), |offset| index + offset),
+            'c' if crate::compound::keyword_at(input, index, "case") => {
+                let kind = TokenizeErrorKind::CaseInSubstitution;
+                return Err(TokenizeError::new(kind, index));
+            }
+            '(' => {
+                depth += 1;
+                next
+            }
+            ')' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(next);
+                }
+                next
+            }
+            _ => next,
+        };
+    }
+    Err(TokenizeError::new(
+        TokenizeErrorKind::Unterminated(')'),
+        start,
+    ))
+}
+
+/// Byte index just past the double-quoted string starting at byte `start`
+/// of `input`, skipping over the substitutions it contains.
+fn double_quote_end(input: &str, start: usize) -> Result<usize, TokenizeError> {
+    let mut index = start + 1;
+    while let Some(c) = input[index..].chars().next() {
+        let next = index + c.len_utf8();
+        index = match c {
+            '"' => return Ok(next),
+            '\\' => next + input[next..].chars().next().map_or(0, char::len_utf8),
+            '`' => substitution_end(input, index)?,
+            '$' if input[next..].starts_with('(') => substitution_end(input, index)?,
+            _ => next,
+        };
+    }
+    Err(TokenizeError::new(
+        TokenizeErrorKind::Unterminated('"'),
+        start,
+    ))
+}
+
+/// Which backslash escapes `decode_escapes` understands.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Escapes {
+    /// `echo -e`: octal as `\0NNN`, and `\c` ends the output.
+    Echo,
+    /// `$'...'`: octal as `\NNN`, `\cX` control characters and escaped
+    /// quotes.
+    AnsiC,
+}
+
+/// Interprets backslash escapes such as `\n`, `\t`, `\xHH` and `\uHHHH`,
+/// as text. Bytes that do not form valid UTF-8 are kept as raw bytes; see
+/// `bytes_to_text`. A `$'...'` string ends at its first NUL, as in bash,
+/// since no argument or variable can hold one.
+pub fn decode_escapes(text: &str, escapes: Escapes) -> (String, bool) {
+    let (mut bytes, stop) = decode_escape_bytes(text, escapes);
+    if escapes == Escapes::AnsiC {
+        if let Some(nul) = bytes.iter().position(|byte| *byte == 0) {
+            bytes.truncate(nul);
+        }
+    }
+    (bytes_to_text(&bytes), stop)
+}
+
+/// First of the private-use characters standing for the bytes 0x80 to
+/// 0xFF that are not part of valid UTF-8, so that words, which are text,
+/// can carry them until `text_to_bytes` turns them back.
+const RAW_BYTE_BASE: u32 = 0x10_FF00;
+
+/// `bytes` as text, with bytes that are not valid UTF-8 kept as raw-byte
+/// characters.
+pub fn bytes_to_text(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        text.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            text.extend(char::from_u32(RAW_BYTE_BASE + u32::from(*byte)));
+        }
+    }
+    text
+}
+
+/// The bytes `text` stands for: its UTF-8, with raw-byte characters from
+/// `bytes_to_text` written as the bytes they hold.
+pub fn text_to_bytes(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        match u32::from(c).checked_sub(RAW_BYTE_BASE) {
+            Some(byte @ 0x80..=0xff) => bytes.push(byte as u8),
+            _ => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    bytes
+}
+
+/// Interprets backslash escapes such as `\n`, `\t`, `\xHH` and `\uHHHH`.
+/// Octal and `\x` escapes stand for single bytes, so the result can hold
+/// NULs and non-UTF-8 data. Unknown or incomplete sequences are kept
+/// literally, as bash does. The flag is set when an `echo` `\c` asked for
+/// the rest of the output, including the newline, to be suppressed.
+pub fn decode_escape_bytes(text: &str, escapes: Escapes) -> (Vec<u8>, bool) {
+    let mut result = Vec::new();
+    let mut chars = text.chars().peekable();
+    let push = |result: &mut Vec<u8>, c: char| {
+        result.extend(text_to_bytes(c.encode_utf8(&mut [0; 4])));
+    };
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            push(&mut result, c);
+            continue;
+        }
+        let Some(escape) = chars.next() else {
+            result.push(b'\\');
+            break;
+        };
+        match escape {
+            'a' => result.push(0x07),
+            'b' => result.push(0x08),
+            'e' | 'E' => result.push(0x1b),
+            'f' => result.push(0x0c),
+            'n' => result.push(b'\n'),
+            'r' => result.push(b'\r'),
+            't' => result.push(b'\t'),
+            'v' => result.push(0x0b),
+            '\\' => result.push(b'\\'),
+            '\'' | '"' | '?' if escapes == Escapes::AnsiC => push(&mut result, escape),
+            'c' if escapes == Escapes::Echo => return (result, true),
+            'c' => match chars.next_if(char::is_ascii) {
+                Some(control) => result.push(control.to_ascii_uppercase() as u8 & 0x1f),
+                None => result.extend_from_slice(b"\\c"),
+            },
+            '0'..='7' if escapes == Escapes::AnsiC || escape == '0' => {
+                // `echo` wants a leading 0 and up to three more digits
+                let (mut digits, max) = match escapes {
+                    Escapes::Echo => (String::new(), 3),
+                    Escapes::AnsiC => (escape.to_string(), 2),
+                };
+                digits.push_str(&read_digits(&mut chars, 8, max));
+                let value = u32::from_str_radix(&digits, 8).unwrap_or(0);
+                result.push(value as u8);
+            }
+            'x' => {
+                let digits = read_digits(&mut chars, 16, 2);
+                match u8::from_str_radix(&digits, 16) {
+                    Ok(byte) => result.push(byte),
+                    Err(_) => result.extend_from_slice(b"\\x"),
+                }
+            }
+            'u' | 'U' => {
+                let max = if escape == 'u' { 4 } else { 8 };
+                let digits = read_digits(&mut chars, 16, max);
+                match u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                {
+                    Some(decoded) => push(&mut result, decoded),
+                    None => {
+                        result.push(b'\\');
+                        push(&mut result, escape);
+                        result.extend_from_slice(digits.as_bytes());
+                    }
+                }
+            }
+            other => {
+                result.push(b'\\');
+                push(&mut result, other);
+            }
+        }
+    }
+    (result, false)
+}
+
+/// Takes up to `max` digits in `radix` from the front of `chars`.
+fn read_digits(chars: &mut std::iter::Peekable<std::str::Chars>, radix: u32, max: usize) -> String {
+    let mut digits = String::new();
+    while digits.len() < max {
+        match chars.next_if(|c| c.is_digit(radix)) {
+            Some(digit) => digits.push(digit),
+            None => break,
+        }
+    }
+    digits
+}
+
+/// A field being built. `pattern` mirrors `text` with quoted glob
+/// characters escaped, so only unquoted ones take part in matching.
+#[derive(Default)]
+struct Field {
+    text: String,
+    pattern: String,
+    has_glob: bool,
+    /// Quotes keep an otherwise empty field, as in `""`.
+    quoted: bool,
+}
+
+impl Field {
+    fn push_literal(&mut self, c: char) {
+        self.text.push(c);
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            self.pattern.push('\\');
+        }
+        self.pattern.push(c);
+    }
+
+    fn push_str_literal(&mut self, text: &str) {
+        for c in text.chars() {
+            self.push_literal(c);
+        }
+    }
+
+    fn push_glob(&mut self, c: char) {
+        self.text.push(c);
+        self.pattern.push(c);
+        if matches!(c, '*' | '?' | '[') {
+            self.has_glob = true;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.text.is_empty() && !self.quoted
+    }
+}
+
+/// Expands a raw word into zero or more fields: unquoted parameter
+/// expansions are split on whitespace and unquoted patterns are replaced
+/// by the sorted paths they match, when they match any.
+pub fn expand_word(state: &mut ShellState, word: &str) -> Result<Vec<String>, ExpandError> {
+    Ok(expand_word_globbed(state, word)?.0)
+}
+
+/// Like `expand_word`, also counting the fields that are paths matched by
+/// a pattern.
+pub fn expand_word_globbed(
+    state: &mut ShellState,
+    word: &str,
+) -> Result<(Vec<String>, usize), ExpandError> {
+    let mut words = Vec::new();
+    let mut matched = 0;
+    let limit = expansion_limit(state);
+    for field in expand(state, word, Mode::Words)? {
+        if field.is_empty() {
+            continue;
+        }
+        if field.has_glob {
+            let remaining = limit.saturating_sub(words.len());
+            let matches = glob(&field.pattern, state.shopt("nocasesort"), remaining)?;
+            if !matches.is_empty() {
+                matched += matches.len();
+                words.extend(matches);
+                continue;
+            }
+        }
+        words.push(field.text);
+    }
+    Ok((words, matched))
+}
+
+/// What a word is expanded for.
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    /// Command words: field splitting and pathname expansion.
+    Words,
+    /// Assignment values: one field, all characters literal.
+    Value,
+    /// `case` patterns: one field, unquoted glob characters kept active.
+    Pattern,
+}
+
+/// Expands `word` into fields, splitting unquoted parameter expansions
+/// only for `Mode::Words`.
+fn expand(state: &mut ShellState, word: &str, mode: Mode) -> Result<Vec<Field>, ExpandError> {
+    let mut fields: Vec<Field> = Vec::new();
+    let mut field = Field::default();
+    let mut chars = word.chars().peekable();
+
+    if let Some((len, home)) = tilde(state, word, mode) {
+        field.push_str_literal(&home);
+        chars = word[len..].chars().peekable();
+    }
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    field.push_literal(next);
+                }
+            }
+            '\'' => {
+                field.quoted = true;
+                for next in chars.by_ref() {
+                    if next == '\'' {
+                        break;
+                    }
+                    field.push_literal(next);
+                }
+            }
+            '"' => {
+                // Quotes holding only a "$@" without positional parameters
+                // leave no field behind, unlike `""`
+                let quoted = field.quoted;
+                field.quoted = true;
+                let mut empty_list = false;
+                let mut contents = false;
+                while let Some(next) = chars.next() {
+                    contents |= next != '"' && next != '$';
+                    match next {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some(escaped @ ('$' | '`' | '"' | '\\')) => field.push_literal(escaped),
+                            Some(other) => {
+                                field.push_literal('\\');
+                                field.push_literal(other);
+                            }
+                            None => field.push_literal('\\'),
+                        },
+                        '$' | '`' if next == '`' || chars.peek() == Some(&'(') => {
+                            contents = true;
+                            field.push_str_literal(&substitute(state, next, &mut chars)?);
+                        }
+                        '$' => match parameter(state, &mut chars)? {
+                            Some(values) => {
+                                empty_list |= values.is_empty();
+                                contents |= !values.is_empty();
+                                // "$@" keeps each positional parameter a
+                                // field of its own
+                                for (index, value) in values.iter().enumerate() {
+                                    if index > 0 {
+                                        fields.push(std::mem::take(&mut field));
+                                        field.quoted = true;
+                                    }
+                                    field.push_str_literal(value);
+                                }
+                            }
+                            None => {
+                                contents = true;
+                                field.push_literal('$');
+                            }
+                        },
+                        other => field.push_literal(other),
+                    }
+                }
+                if empty_list && !contents {
+                    field.quoted = quoted;
+                }
+            }
+            '$' | '`' if c == '`' || chars.peek() == Some(&'(') => {
+                let text = substitute(state, c, &mut chars)?;
+                push_unquoted(&mut field, &mut fields, mode, &text);
+            }
+            '$' => match parameter(state, &mut chars)? {
+                Some(values) => push_unquoted(&mut field, &mut fields, mode, &values.join(" ")),
+                None => field.push_literal('$'),
+            },
+            // `PATH=~/bin:~/sbin` expands both tildes
+            ':' if mode == Mode::Value => {
+                field.push_literal(c);
+                let rest: String = chars.clone().collect();
+                if let Some((len, home)) = tilde(state, &rest, mode) {
+                    field.push_str_literal(&home);
+                    for _ in rest[..len].chars() {
+                        chars.next();
+                    }
+                }
+            }
+            c if mode == Mode::Value => field.push_literal(c),
+            c => field.push_glob(c),
+        }
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+/// Adds the unquoted result of an expansion to `field`. Outside
+/// assignments its characters can match as patterns, and for command words
+/// whitespace splits it into fields.
+fn push_unquoted(field: &mut Field, fields: &mut Vec<Field>, mode: Mode, text: &str) {
+    match mode {
+        Mode::Value => field.push_str_literal(text),
+        Mode::Pattern => {
+            for c in text.chars() {
+                field.push_glob(c);
+            }
+        }
+        Mode::Words => {
+            let mut pieces = text.split(char::is_whitespace).peekable();
+            while let Some(piece) = pieces.next() {
+                for c in piece.chars() {
+                    field.push_glob(c);
+                }
+                if pieces.peek().is_some() && !field.is_empty() {
+                    fields.push(std::mem::take(field));
+                }
+            }
+        }
+    }
+}
+
+/// Takes a command substitution, arithmetic expansion or backquoted
+/// command off `chars`, after its first character `first`, and returns
+/// what it expands to: the output of the command without trailing
+/// newlines, or the value of the expression.
+fn substitute(
+    state: &mut ShellState,
+    first: char,
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> Result<String, ExpandError> {
+    let text = substitution_text(first, chars);
+    if let Some(expression) = text
+        .strip_prefix("$((")
+        .and_then(|rest| rest.strip_suffix("))"))
+    {
+        // Parameters and substitutions in the expression come first
+        let expression = expand_value(state, expression)?;
+        return crate::arith::evaluate(&expression, &|name| state.variable(name))
+            .map(|value| value.to_string())
+            .map_err(|error| ExpandError::Arithmetic(expression.trim().to_string(), error));
+    }
+    let body = match text.strip_prefix("$(") {
+        Some(body) => body.strip_suffix(')').unwrap_or(body).to_string(),
+        None => backquoted_body(&text),
+    };
+    Ok(crate::command_substitution(state, &body))
+}
+
+/// The command between backquotes, where a backslash before `$`, `` ` ``
+/// or another backslash only quotes it.
+fn backquoted_body(text: &str) -> String {
+    let inner = text.strip_prefix('`').unwrap_or(text);
+    let inner = inner.strip_suffix('`').unwrap_or(inner);
+    let mut body = String::new();
+    let mut chars = inner.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some('$' | '`' | '\\') if c == '\\' => body.extend(chars.next()),
+            _ => body.push(c),
+        }
+    }
+    body
+}
+
+/// Takes a command substitution, arithmetic expansion or backquoted
+/// command off `chars`, after its first character `first`, and returns it
+/// as written.
+fn substitution_text(first: char, chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let text: String = std::iter::once(first).chain(chars.clone()).collect();
+    let end = substitution_end(&text, 0).unwrap_or(text.len());
+    for _ in text[first.len_utf8()..end].chars() {
+        chars.next();
+    }
+    text[..end].to_string()
+}
+
+/// Expands a word that has to stay a single field, such as a redirection
+/// target. Unlike in command words, a pattern that matches nothing is an
+/// error rather than kept as written.
+pub fn expand_single(state: &mut ShellState, word: &str) -> Result<String, ExpandError> {
+    let mut fields = expand(state, word, Mode::Words)?;
+    fields.retain(|field| !field.is_empty());
+    if fields.len() != 1 {
+        return Err(ExpandError::AmbiguousRedirect(word.to_string()));
+    }
+    let field = fields.remove(0);
+    if !field.has_glob {
+        return Ok(field.text);
+    }
+    let mut matches = glob(&field.pattern, state.shopt("nocasesort"), 2)?;
+    match matches.len() {
+        0 => Err(ExpandError::NoMatch(word.to_string())),
+        1 => Ok(matches.remove(0)),
+        _ => Err(ExpandError::AmbiguousRedirect(word.to_string())),
+    }
+}
+
+/// Expands the value of an assignment: no field splitting and no pathname
+/// expansion.
+pub fn expand_value(state: &mut ShellState, word: &str) -> Result<String, ExpandError> {
+    let fields = expand(state, word, Mode::Value)?;
+    Ok(fields.into_iter().map(|field| field.text).collect())
+}
+
+/// Expands a `case` pattern for `fnmatch`: quoted parts match literally.
+pub fn expand_pattern(state: &mut ShellState, word: &str) -> Result<String, ExpandError> {
+    let fields = expand(state, word, Mode::Pattern)?;
+    Ok(fields.into_iter().map(|field| field.pattern).collect())
+}
+
+/// Length of the `~` prefix starting `text` and the directory it names,
+/// if any. The prefix ends at `/`, and in assignment values also at `:`,
+/// where another one may start.
+fn tilde(state: &ShellState, text: &str, mode: Mode) -> Option<(usize, String)> {
+    let rest = text.strip_prefix('~')?;
+    let end = rest
+        .find(|c| c == '/' || c == ':' && mode == Mode::Value)
+        .unwrap_or(rest.len());
+    Some((1 + end, tilde_prefix(state, &rest[..end])?))
+}
+
+/// Resolves the part of a `~` prefix before the first `/`: nothing for the
+/// home directory, `+` and `-` for `$PWD` and `$OLDPWD`, `N`, `+N` and
+/// `-N` for directory stack entries as `dirs -v` numbers them, or a user
+/// name. `None` leaves the prefix literal.
+pub fn tilde_prefix(state: &ShellState, user: &str) -> Option<String> {
+    match user {
+        "" => return crate::home_dir(state),
+        "+" => return state.variable("PWD"),
+        "-" => return state.variable("OLDPWD"),
+        _ => {}
+    }
+    let digits = user.strip_prefix(['+', '-']).unwrap_or(user);
+    if !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()) {
+        let stack = crate::directory_stack(state);
+        let index: usize = digits.parse().ok()?;
+        let index = if user.starts_with('-') {
+            stack.len().checked_sub(index + 1)?
+        } else {
+            index
+        };
+        return stack.get(index).cloned();
+    }
+    if !user
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+    {
+        return None;
+    }
+    let name = std::ffi::CString::new(user).ok()?;
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        return None;
+    }
+    let dir = unsafe { std::ffi::CStr::from_ptr((*entry).pw_dir) };
+    Some(dir.to_string_lossy().to_string())
+}
+
+/// Reads a parameter after `$` and returns its value, as several values
+/// for `$@`. `None` means the `$` was literal. Under `set -u`, expanding an
+/// unset parameter other than `$@` and `$*` is an error.
+fn parameter(
+    state: &mut ShellState,
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> Result<Option<Vec<String>>, ExpandError> {
+    let Some(first) = chars.peek().copied() else {
+        return Ok(None);
+    };
+    let name = match first {
+        '{' => {
+            chars.next();
+            return braced_parameter(state, chars).map(Some);
+        }
+        c if c.is_ascii_alphabetic() || c == '_' => {
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                name.push(c);
+            }
+            name
+        }
+        c if c.is_ascii_digit() || "?$#@*!".contains(c) => {
+            chars.next();
+            c.to_string()
+        }
+        _ => return Ok(None),
+    };
+    match lookup(state, &name) {
+        Some(values) => Ok(Some(values)),
+        None => unset(state, &name).map(Some),
+    }
+}
+
+/// Reads a `${...}` expansion after the `{`: `${NAME}`, `${#NAME}` for the
+/// length, `${NAME<op>word}` with `op` one of `-`, `=`, `+` or `?`,
+/// optionally preceded by `:` to treat an empty value like an unset one,
+/// `${NAME:offset[:length]}` for a substring, `#`, `##`, `%` and `%%` to
+/// remove a prefix or suffix matching a pattern, and `/`, `//`, `/#` and
+/// `/%` to replace a match. Anything else is a bad substitution.
+fn braced_parameter(
+    state: &mut ShellState,
+    chars: &mut std::iter::Peekable<std::str::Chars>,
+) -> Result<Vec<String>, ExpandError> {
+    // The body runs to the matching `}`, skipping nested `${...}`
+    let mut body = String::new();
+    let mut depth = 0;
+    let mut previous = None;
+    for c in chars.by_ref() {
+        match c {
+            '}' if depth == 0 => break,
+            '}' => depth -= 1,
+            '{' if previous == Some('$') => depth += 1,
+            _ => {}
+        }
+        body.push(c);
+        previous = Some(c);
+    }
+    let bad = || ExpandError::BadSubstitution(format!("${{{body}}}"));
+
+    if let Some(name) = body.strip_prefix('#').filter(|name| !name.is_empty()) {
+        if parameter_name_len(name) != name.len() {
+            return Err(bad());
+        }
+        return length(state, name).map(|length| vec![length.to_string()]);
+    }
+    let name_len = parameter_name_len(&body);
+    if name_len == 0 {
+        return Err(bad());
+    }
+    let (name, rest) = body.split_at(name_len);
+    let values = lookup(state, name);
+    let Some(first) = rest.chars().next() else {
+        return match values {
+            Some(values) => Ok(values),
+            None => unset(state, name),
+        };
+    };
+
+    let colon = first == ':' && rest[1..].starts_with(['-', '=', '+', '?']);
+    let rest = if colon { &rest[1..] } else { rest };
+    let (operator, word) = rest.split_at(rest.chars().next().map_or(0, char::len_utf8));
+    let values = match operator {
+        "-" | "=" | "+" | "?" => {
+            let is_set = match &values {
+                Some(values) if colon => values.iter().any(|value| !value.is_empty()),
+                Some(_) => true,
+                None => false,
+            };
+            return default_value(state, name, operator, word, is_set, values);
+        }
+        _ => match values {
+            Some(values) => values,
+            None => unset(state, name)?,
+        },
+    };
+    match operator {
+        ":" => substring(state, name, values, word),
+        "#" | "%" => {
+            let longest = word.starts_with(operator);
+            let word = if longest { &word[1..] } else { word };
+            let pattern = expand_pattern(state, word)?;
+            Ok(values
+                .iter()
+                .map(|value| remove_affix(value, &pattern, operator == "%", longest))
+                .collect())
+        }
+        "/" => {
+            let (anchor, word) = match word.chars().next() {
+                Some(anchor @ ('/' | '#' | '%')) => (Some(anchor), &word[1..]),
+                _ => (None, word),
+            };
+            let (pattern, replacement) = split_replacement(word);
+            let pattern = expand_pattern(state, pattern)?;
+            let replacement = expand_value(state, replacement)?;
+            Ok(values
+                .iter()
+                .map(|value| replace_matches(value, &pattern, &replacement, anchor))
+                .collect())
+        }
+        _ => Err(bad()),
+    }
+}
+
+/// Length of the parameter name starting `text`: a variable name with an
+/// optional `[subscript]`, a positional parameter number or one special
+/// parameter character. 0 when there is none.
+fn parameter_name_len(text: &str) -> usize {
+    let Some(first) = text.chars().next() else {
+        return 0;
+    };
+    if first.is_ascii_digit() {
+        return text
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len());
+    }
+    if "?$#@*!".contains(first) {
+        return 1;
+    }
+    if !first.is_ascii_alphabetic() && first != '_' {
+        return 0;
+    }
+    let end = text
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(text.len());
+    match text[end..]
+        .strip_prefix('[')
+        .and_then(|rest| rest.find(']'))
+    {
+        Some(close) if close > 0 => end + close + 2,
+        _ => end,
+    }
+}
+
+/// `${#NAME}`: the number of characters in the value, or of values for
+/// `@`, `*`, `NAME[@]` and `NAME[*]`.
+fn length(state: &ShellState, name: &str) -> Result<usize, ExpandError> {
+    if matches!(name, "@" | "*") || name.ends_with("[@]") || name.ends_with("[*]") {
+        return Ok(lookup(state, &name.replace('*', "@")).map_or(0, |values| values.len()));
+    }
+    match lookup(state, name) {
+        Some(values) => Ok(values.concat().chars().count()),
+        None => unset(state, name).map(|_| 0),
+    }
+}
+
+/// `${NAME-word}`, `${NAME=word}`, `${NAME+word}` and `${NAME?word}`, with
+/// `is_set` already accounting for a `:` before the operator.
+fn default_value(
+    state: &mut ShellState,
+    name: &str,
+    operator: &str,
+    word: &str,
+    is_set: bool,
+    values: Option<Vec<String>>,
+) -> Result<Vec<String>, ExpandError> {
+    match operator {
+        "+" if is_set => Ok(vec![expand_value(state, word)?]),
+        "+" => Ok(vec![String::new()]),
+        _ if is_set => Ok(values.unwrap_or_default()),
+        "-" => Ok(vec![expand_value(state, word)?]),
+        "=" => {
+            if !crate::is_identifier(name) {
+                return Err(ExpandError::CannotAssign(name.to_string()));
+            }
+            let value = expand_value(state, word)?.replace('\0', "");
+            state.set_variable(name, &value);
+            Ok(vec![value])
+        }
+        _ => {
+            let message = expand_value(state, word)?;
+            Err(ExpandError::Required(
+                name.to_string(),
+                if message.is_empty() {
+                    "parameter null or not set".to_string()
+                } else {
+                    message
+                },
+            ))
+        }
+    }
+}
+
+/// `${NAME:offset[:length]}`: characters of each value, or for `@`,
+/// `NAME[@]` and `NAME[*]` the values themselves, from `offset` on. Both
+/// are arithmetic expressions; a negative offset counts from the end, and
+/// a negative length leaves that many off the end.
+fn substring(
+    state: &mut ShellState,
+    name: &str,
+    values: Vec<String>,
+    word: &str,
+) -> Result<Vec<String>, ExpandError> {
+    let (offset, length) = match word.split_once(':') {
+        Some((offset, length)) => (offset, Some(length)),
+        None => (word, None),
+    };
+    let offset = arithmetic(state, offset)?;
+    let length = length.map(|length| arithmetic(state, length)).transpose()?;
+    let slice = |len: usize| {
+        let len = len as i64;
+        let start = if offset < 0 { len + offset } else { offset };
+        if start < 0 || start > len {
+            return None;
+        }
+        let end = match length {
+            Some(length) if length < 0 => len + length,
+            Some(length) => start.saturating_add(length).min(len),
+            None => len,
+        };
+        Some(start as usize..end.max(start) as usize)
+    };
+    let list = name == "@" || name.ends_with("[@]") || name.ends_with("[*]");
+    if list {
+        // `$@` counts `$0` as element 0, as with bash
+        let mut values = match name {
+            "@" => lookup(state, "0").unwrap_or_default(),
+            _ => Vec::new(),
+        };
+        values.extend(lookup(state, &name.replace('*', "@")).unwrap_or_default());
+        let values = slice(values.len()).map_or_else(Vec::new, |range| values[range].to_vec());
+        return Ok(if name.ends_with("[*]") {
+            vec![values.join(&list_separator(state))]
+        } else {
+            values
+        });
+    }
+    Ok(values
+        .iter()
+        .map(|value| {
+            let chars: Vec<char> = value.chars().collect();
+            slice(chars.len()).map_or_else(String::new, |range| chars[range].iter().collect())
+        })
+        .collect())
+}
+
+/// Value of an arithmetic expression in a `${...}` expansion, after its
+/// own expansions.
+fn arithmetic(state: &mut ShellState, expression: &str) -> Result<i64, ExpandError> {
+    let expression = expand_value(state, expression)?;
+    crate::arith::evaluate(&expression, &|name| state.variable(name))
+        .map_err(|error| ExpandError::Arithmetic(expression.trim().to_string(), error))
+}
+
+/// Byte offsets of the character boundaries in `text`, both ends included.
+fn boundaries(text: &str) -> Vec<usize> {
+    text.char_indices()
+        .map(|(index, _)| index)
+        .chain(std::iter::once(text.len()))
+        .collect()
+}
+
+/// `value` without the shortest or `longest` prefix, or suffix, that
+/// matches `pattern`; unchanged when none does.
+fn remove_affix(value: &str, pattern: &str, suffix: bool, longest: bool) -> String {
+    let mut bounds = boundaries(value);
+    // Try the shortest candidate first unless looking for the longest
+    if suffix != longest {
+        bounds.reverse();
+    }
+    for bound in bounds {
+        let (removed, kept) = if suffix {
+            (&value[bound..], &value[..bound])
+        } else {
+            (&value[..bound], &value[bound..])
+        };
+        if fnmatch(pattern, removed) {
+            return kept.to_string();
+        }
+    }
+    value.to_string()
+}
+
+/// Splits the word of `${NAME/pattern/replacement}` at the first `/` not
+/// escaped with a backslash. Without one, the replacement is empty.
+fn split_replacement(word: &str) -> (&str, &str) {
+    let mut escaped = false;
+    for (index, c) in word.char_indices() {
+        match c {
+            '/' if !escaped => return (&word[..index], &word[index + 1..]),
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    (word, "")
+}
+
+/// Replaces the longest match of `pattern` in `value`: the first one, or
+/// with `anchor` `/` every one, `#` one at the start and `%` one at the
+/// end. An empty pattern matches nothing.
+fn replace_matches(value: &str, pattern: &str, replacement: &str, anchor: Option<char>) -> String {
+    if pattern.is_empty() {
+        return value.to_string();
+    }
+    let bounds = boundaries(value);
+    let mut result = String::new();
+    let mut start = 0;
+    let mut replaced = false;
+    while start < value.len() {
+        let searching = match anchor {
+            Some('/') => true,
+            Some('#') => start == 0,
+            _ => !replaced,
+        };
+        let end = bounds
+            .iter()
+            .rev()
+            .copied()
+            .take_while(|&end| searching && end > start)
+            .filter(|&end| anchor != Some('%') || end == value.len())
+            .find(|&end| fnmatch(pattern, &value[start..end]));
+        match end {
+            Some(end) => {
+                result.push_str(replacement);
+                start = end;
+                replaced = true;
+            }
+            None => {
+                let c = value[start..].chars().next().unwrap_or_default();
+                result.push(c);
+                start += c.len_utf8();
+            }
+        }
+    }
+    result
+}
+
+/// Values of a parameter, or `None` when it is unset. `$@` and `$*` are
+/// always set, if possibly empty. `NAME[index]` picks an array element,
+/// `NAME[@]` and `NAME[*]` all of them; a plain variable is a one-element
+/// array.
+fn lookup(state: &ShellState, name: &str) -> Option<Vec<String>> {
+    if let Some((base, subscript)) = name.strip_suffix(']').and_then(|name| name.split_once('[')) {
+        let values = state
+            .array(base)
+            .or_else(|| state.variable(base).map(|value| vec![value]))?;
+        return match subscript {
+            "@" => Some(values),
+            "*" => Some(vec![values.join(&list_separator(state))]),
+            index => {
+                let value = values.get(index.parse::<usize>().ok()?)?;
+                Some(vec![value.clone()])
+            }
+        };
+    }
+    match name {
+        "@" => Some(state.positional.clone()),
+        "*" => Some(vec![state.positional.join(&list_separator(state))]),
+        _ => state.variable(name).map(|value| vec![value]),
+    }
+}
+
+/// What `$*` puts between values: the first character of `IFS`, a space
+/// when it is unset and nothing when it is empty.
+fn list_separator(state: &ShellState) -> String {
+    match state.variable("IFS") {
+        Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+        None => " ".to_string(),
+    }
+}
+
+/// Expansion of an unset parameter: empty, or an error under `set -u`.
+fn unset(state: &ShellState, name: &str) -> Result<Vec<String>, ExpandError> {
+    if state.option("nounset") {
+        return Err(ExpandError::Unbound(name.to_string()));
+    }
+    Ok(vec![String::new()])
+}
+
+/// Paths matching `pattern`, sorted with `sort_names`. Hidden entries only
+/// match components that start with a literal `.`. Fails once more than
+/// `limit` paths match.
+pub fn glob(pattern: &str, nocase: bool, limit: usize) -> Result<Vec<String>, ExpandError> {
+    let (prefix, rest) = match pattern.strip_prefix('/') {
+        Some(rest) => ("/", rest),
+        None => ("", pattern),
+    };
+    let components: Vec<&str> = rest.split('/').collect();
+    let mut matches = Vec::new();
+    glob_into(prefix, &components, &mut matches, limit);
+    if matches.len() > limit {
+        return Err(ExpandError::TooManyWords);
+    }
+    sort_names(&mut matches, nocase);
+    Ok(matches)
+}
+
+/// Sorts names the same way whatever the locale: by their UTF-8 bytes or,
+/// with `nocase` (`shopt nocasesort`), by their lowercase form, with ties
+/// still broken by bytes.
+pub fn sort_names(names: &mut [String], nocase: bool) {
+    if nocase {
+        names.sort_by_cached_key(|name| (name.to_lowercase(), name.clone()));
+    } else {
+        names.sort();
+    }
+}
+
+/// Matches `components` below the directory `prefix`, which is empty for
+/// the current directory and otherwise ends with `/`. Gives up once there
+/// are more than `limit` matches.
+fn glob_into(prefix: &str, components: &[&str], matches: &mut Vec<String>, limit: usize) {
+    if matches.len() > limit {
+        return;
+    }
+    let Some((component, rest)) = components.split_first() else {
+        matches.push(prefix.to_string());
+        return;
+    };
+    if component.is_empty() {
+        // Repeated or trailing slashes
+        if rest.is_empty() {
+            matches.push(prefix.to_string());
+        } else {
+            glob_into(prefix, rest, matches, limit);
+        }
+        return;
+    }
+
+    let names = if has_glob(component) {
+        let dir = if prefix.is_empty() { "." } else { prefix };
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        entries
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| !name.starts_with('.') || component.starts_with('.'))
+            .filter(|name| fnmatch(component, name))
+            .collect()
+    } else {
+        vec![unescape(component)]
+    };
+    for name in names {
+        if matches.len() > limit {
+            return;
+        }
+        let path = format!("{}{}", prefix, name);
+        if rest.is_empty() {
+            if std::fs::symlink_metadata(&path).is_ok() {
+                matches.push(path);
+            }
+        } else if std::path::Path::new(&path).is_dir() {
+            glob_into(&format!("{}/", path), rest, matches, limit);
+        }
+    }
+}
+
+fn has_glob(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '*' | '?' | '[' => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn unescape(pattern: &str) -> String {
+    let mut result = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(next) = chars.next() {
+                result.push(next);
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+/// Matches `text` against a shell pattern with `*`, `?`, `[...]` and
+/// backslash escapes.
+pub fn fnmatch(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_from(&pattern, &text)
+}
+
+fn match_from(pattern: &[char], text: &[char]) -> bool {
+    let Some((&first, rest)) = pattern.split_first() else {
+        return text.is_empty();
+    };
+    match first {
+        '*' => (0..=text.len()).any(|skip| match_from(rest, &text[skip..])),
+        '?' => !text.is_empty() && match_from(rest, &text[1..]),
+        '[' => match (text.first(), bracket(rest)) {
+            (Some(c), Some((matches, len))) => matches(*c) && match_from(&rest[len..], &text[1..]),
+            (Some(c), None) => *c == '[' && match_from(rest, &text[1..]),
+            (None, _) => false,
+        },
+        '\\' if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && match_from(&rest[1..], &text[1..])
+        }
+        c => text.first() == Some(&c) && match_from(rest, &text[1..]),
+    }
+}
+
+/// Parses a bracket expression after its `[`, returning a matcher and how
+/// many pattern characters it used, including the closing `]`.
+#[allow(clippy::type_complexity)]
+fn bracket(pattern: &[char]) -> Option<(Box<dyn Fn(char) -> bool>, usize)> {
+    let mut index = 0;
+    let negate = matches!(pattern.first(), Some('!' | '^'));
+    if negate {
+        index += 1;
+    }
+    let mut ranges = Vec::new();
+    let start = index;
+    while index < pattern.len() {
+        let c = pattern[index];
+        if c == ']' && index > start {
+            let matcher = move |c: char| {
+                ranges.iter().any(|(low, high)| (*low..=*high).contains(&c)) != negate
+            };
+            return Some((Box::new(matcher), index + 1));
+        }
+        let low = if c == '\\' && index + 1 < pattern.len() {
+            index += 1;
+            pattern[index]
+        } else {
+            c
+        };
+        if pattern.get(index + 1) == Some(&'-') && pattern.get(index + 2).is_some_and(|c| *c != ']')
+        {
+            ranges.push((low, pattern[index + 2]));
+            index += 3;
+        } else {
+            ranges.push((low, low));
+            index += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_shared_by_echo_and_ansi_c() {
+        for escapes in [Escapes::Echo, Escapes::AnsiC] {
+            let decode = |text| decode_escape_bytes(text, escapes).0;
+            assert_eq!(decode(r"a\tb\n\\"), b"a\tb\n\\");
+            assert_eq!(decode(r"\x41\x4"), b"A\x04");
+            assert_eq!(decode(r"\xzz"), b"\\xzz");
+            assert_eq!(decode(r"é\U0001F980"), "é🦀".as_bytes());
+            assert_eq!(decode(r"\q"), b"\\q");
+            assert_eq!(decode(r"\xff"), b"\xff");
+        }
+    }
+
+    #[test]
+    fn echo_escapes() {
+        assert_eq!(
+            decode_escapes(r"\0101\101", Escapes::Echo),
+            ("A\\101".into(), false)
+        );
+        assert_eq!(
+            decode_escapes(r"one\ctwo", Escapes::Echo),
+            ("one".into(), true)
+        );
+        assert_eq!(decode_escapes(r"\'", Escapes::Echo).0, r"\'");
+    }
+
+    #[test]
+    fn ansi_c_escapes() {
+        assert_eq!(decode_escapes(r"\101\0x", Escapes::AnsiC).0, "A");
+        assert_eq!(decode_escapes(r"a\x00b", Escapes::AnsiC).0, "a");
+        let (raw, _) = decode_escapes(r"\xff\xc3\xa9", Escapes::AnsiC);
+        assert_eq!(text_to_bytes(&raw), b"\xff\xc3\xa9");
+        assert_eq!(raw.chars().nth(1), Some('é'));
+        assert_eq!(decode_escapes(r"\ca\c[", Escapes::AnsiC).0, "\u{1}\u{1b}");
+        assert_eq!(decode_escapes(r#"\'\"\?"#, Escapes::AnsiC).0, r#"'"?"#);
+    }
+
+    #[test]
+    fn parameter_names() {
+        for (text, len) in [
+            ("name#x", 4),
+            ("a_1[2]%", 6),
+            ("a[]", 1),
+            ("12x", 2),
+            ("@:1", 1),
+            ("-x", 0),
+            ("", 0),
+        ] {
+            assert_eq!(parameter_name_len(text), len, "{text}");
+        }
+    }
+
+    #[test]
+    fn prefix_and_suffix_removal() {
+        let path = "/usr/lib/a.tar.gz";
+        assert_eq!(remove_affix(path, "*/", false, false), "usr/lib/a.tar.gz");
+        assert_eq!(remove_affix(path, "*/", false, true), "a.tar.gz");
+        assert_eq!(remove_affix(path, ".*", true, false), "/usr/lib/a.tar");
+        assert_eq!(remove_affix(path, ".*", true, true), "/usr/lib/a");
+        assert_eq!(remove_affix(path, "x*", false, true), path);
+        assert_eq!(remove_affix("héé", "é", true, false), "hé");
+    }
+
+    #[test]
+    fn pattern_replacement() {
+        assert_eq!(replace_matches("banana", "an", "AN", None), "bANana");
+        assert_eq!(replace_matches("banana", "an", "AN", Some('/')), "bANANa");
+        assert_eq!(replace_matches("banana", "b*n", "", None), "a");
+        assert_eq!(replace_matches("banana", "a", "_", Some('#')), "banana");
+        assert_eq!(replace_matches("banana", "a", "_", Some('%')), "banan_");
+        assert_eq!(replace_matches("banana", "", "_", Some('/')), "banana");
+        assert_eq!(split_replacement(r"a\/b/c/d"), (r"a\/b", "c/d"));
+        assert_eq!(split_replacement("a"), ("a", ""));
+    }
+
+    #[test]
+    fn ansi_c_strings_are_decoded_when_split() {
+        assert_eq!(split_words(r"echo $'a\tb'").unwrap(), ["echo", "'a\tb'"]);
+        assert_eq!(
+            split_words(r"echo $'it\'s'").unwrap(),
+            ["echo", r"'it'\''s'"]
+        );
+        assert!(split_words(r"echo $'open").is_err());
+    }
+
+    /// Distinct file names from a fixed-seed xorshift generator, mixing
+    /// case, digits, punctuation and multibyte characters. None starts
+    /// with `.`, which `*` would skip.
+    fn random_names(seed: &mut u64, count: usize) -> Vec<String> {
+        const CHARS: &[char] = &[
+            'a', 'B', 'b', 'Z', 'z', '0', '9', '_', '-', '.', ' ', '~', 'é', 'É', 'ß', 'Ω', 'ω',
+            '日', '本', '🦀', '[', '*',
+        ];
+        let mut next = || {
+            *seed ^= *seed << 13;
+            *seed ^= *seed >> 7;
+            *seed ^= *seed << 17;
+            *seed as usize
+        };
+        let mut names = std::collections::BTreeSet::new();
+        while names.len() < count {
+            let len = 1 + next() % 6;
+            let name: String = (0..len).map(|_| CHARS[next() % CHARS.len()]).collect();
+            if !name.starts_with('.') {
+                names.insert(name);
+            }
+        }
+        names.into_iter().collect()
+    }
+
+    #[test]
+    fn glob_and_completion_order_is_bytewise() {
+        let dir = std::env::temp_dir().join(format!("expand-order.{}", std::process::id()));
+        let prefix = format!("{}/", dir.display());
+        let mut state = crate::ShellState::new();
+        let mut seed = 0x9e37_79b9_7f4a_7c15;
+        for round in 0..40 {
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            let names = random_names(&mut seed, 1 + round % 15);
+            for name in &names {
+                std::fs::write(dir.join(name), "").unwrap();
+            }
+            let mut paths: Vec<String> = names.iter().map(|name| prefix.clone() + name).collect();
+            // `LC_ALL=C sort`: by UTF-8 bytes
+            paths.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+            let pattern = format!("{}*", prefix);
+            assert_eq!(glob(&pattern, false, usize::MAX).unwrap(), paths);
+            state.shopts.insert("nocasesort", false);
+            let line = format!("ls {}", prefix);
+            assert_eq!(crate::completion::complete(&state, &line).candidates, paths);
+
+            // Case folded, then by bytes among names equal but for case
+            let key = |path: &String| (path.to_lowercase(), path.as_bytes().to_vec());
+            paths.sort_by_key(key);
+            assert_eq!(glob(&pattern, true, usize::MAX).unwrap(), paths);
+            state.shopts.insert("nocasesort", true);
+            assert_eq!(crate::completion::complete(&state, &line).candidates, paths);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,141 @@
+//! Helpers for the files the shell itself writes.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+/// Distinguishes temp files of concurrent writes from the same process.
+static TEMP_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// Replaces `path` with `bytes` without ever exposing a partial file: the
+/// data goes to a temp file in the same directory, is synced, and is then
+/// renamed over `path`, keeping the permissions `path` already had. On
+/// error the original file is left untouched.
+pub fn atomic_write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+    let temp = dir.join(format!(
+        ".{}.{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let permissions = std::fs::metadata(path).ok().map(|meta| meta.permissions());
+
+    let result = (|| {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp)?;
+        file.write_all(bytes)?;
+        if let Some(permissions) = &permissions {
+            file.set_permissions(permissions.clone())?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temp, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Adds `bytes` to the end of `path`, creating it readable only by the
+/// user when missing. Writers that also go through here wait for each
+/// other on an exclusive lock, and a write that fails partway is cut back
+/// off, so readers never see half of an addition. Only regular files are
+/// synced and cut back; a device such as `/dev/null` just takes the bytes.
+pub fn append(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)?;
+    // Released when the file is closed
+    while unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == -1 {
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        return file.write_all(bytes);
+    }
+    let result = file.write_all(bytes).and_then(|()| file.sync_data());
+    if result.is_err() {
+        let _ = file.set_len(meta.len());
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("files-test.{}.{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn append_creates_then_adds() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = scratch("append");
+        append(&path, b"one\n").unwrap();
+        append(&path, b"two\n").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"one\ntwo\n");
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn concurrent_appends_stay_whole() {
+        let path = scratch("concurrent");
+        let threads: Vec<_> = (0..8)
+            .map(|index| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let line = format!("{}\n", index.to_string().repeat(10_000));
+                    for _ in 0..10 {
+                        append(&path, line.as_bytes()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 80);
+        for line in lines {
+            assert_eq!(line.len(), 10_000);
+            assert!(line.chars().all(|c| c == line.chars().next().unwrap()));
+        }
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn atomic_write_replaces() {
+        let path = scratch("atomic");
+        atomic_write(&path, b"old").unwrap();
+        atomic_write(&path, b"new").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        let _ = std::fs::remove_file(path);
+    }
+}
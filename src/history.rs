@@ -0,0 +1,573 @@
+//! Command history shared by the `history` builtin and the main loop.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Entries kept from the history file when `HISTSIZE` is unset.
+const DEFAULT_HISTSIZE: usize = 500;
+
+/// Longest line taken from a history file. Longer ones are most likely not
+/// commands at all, and reading the tail of a file stops after this many
+/// bytes per entry wanted.
+const MAX_ENTRY_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<String>,
+    /// History file not read yet; see `defer_load`.
+    pending_file: Option<String>,
+    /// Leading entries that came from the history file, which `append`
+    /// leaves out.
+    saved: usize,
+}
+
+impl History {
+    /// Remembers `path` to be read on first use instead of at startup.
+    pub fn defer_load(&mut self, path: String) {
+        self.pending_file = Some(path);
+    }
+
+    /// Reads the deferred history file, if any, in front of the entries
+    /// recorded since startup. Only the last `histsize` lines are read,
+    /// the value of `HISTSIZE`.
+    pub fn load_pending(&mut self, histsize: Option<&str>) {
+        let Some(path) = self.pending_file.take() else {
+            return;
+        };
+        let size = histsize
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(DEFAULT_HISTSIZE);
+        // A missing history file just means an empty history
+        let Ok(loaded) = read_tail(&path, size) else {
+            return;
+        };
+        loaded.warn(&path);
+        let recent = std::mem::take(&mut self.entries);
+        for line in &loaded.lines {
+            self.add(line);
+        }
+        self.saved += self.entries.len();
+        self.entries.extend(recent);
+    }
+
+    /// Records a command line, ignoring blank ones.
+    pub fn add(&mut self, line: &str) {
+        let line = line.trim_end_matches(['\n', '\r']);
+        if line.trim().is_empty() {
+            return;
+        }
+        self.entries.push(line.to_string());
+    }
+
+    /// Records a command line read at the prompt, unless it matches one of
+    /// the `:`-separated patterns in `ignore`, the value of `HISTIGNORE`. A
+    /// pattern matches the whole line, and `&` stands for the previous
+    /// entry.
+    pub fn record(&mut self, line: &str, ignore: &str) {
+        let line = line.trim_end_matches(['\n', '\r']);
+        let ignored = ignore
+            .split(':')
+            .filter(|pattern| !pattern.is_empty())
+            .any(|pattern| match pattern {
+                "&" => self.entries.last().is_some_and(|last| last == line),
+                pattern => crate::expand::fnmatch(pattern, line),
+            });
+        if !ignored {
+            self.add(line);
+        }
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// Number the next recorded command will get, as `history` shows it.
+    pub fn next_number(&self) -> usize {
+        self.entries.len() + 1
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.pending_file = None;
+        self.saved = 0;
+    }
+
+    /// Deletes the entry with the 1-based number shown by `history`.
+    /// Returns `false` when there is no such entry.
+    pub fn delete(&mut self, number: usize) -> bool {
+        if number == 0 || number > self.entries.len() {
+            return false;
+        }
+        self.entries.remove(number - 1);
+        if number <= self.saved {
+            self.saved -= 1;
+        }
+        true
+    }
+
+    /// Replaces `path` with the current history, one entry per line.
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let mut contents = String::new();
+        for entry in &self.entries {
+            contents.push_str(entry);
+            contents.push('\n');
+        }
+        crate::files::atomic_write(std::path::Path::new(path), contents.as_bytes())
+    }
+
+    /// Adds the entries recorded since the history file was read to the
+    /// end of `path`, without reading it first.
+    pub fn append(&mut self, path: &str) -> io::Result<()> {
+        let mut contents = String::new();
+        for entry in &self.entries[self.saved..] {
+            contents.push_str(entry);
+            contents.push('\n');
+        }
+        crate::files::append(std::path::Path::new(path), contents.as_bytes())?;
+        self.saved = self.entries.len();
+        Ok(())
+    }
+
+    /// Appends the lines of `path` to the history, leaving out corrupt
+    /// ones like `load_pending`.
+    pub fn read(&mut self, path: &str) -> io::Result<()> {
+        let loaded = Loaded::parse(&std::fs::read(path)?, false);
+        loaded.warn(path);
+        for line in &loaded.lines {
+            self.add(line);
+        }
+        Ok(())
+    }
+
+    /// Expands the `!` references in a line read at the prompt: `!!` for the
+    /// previous command, `!N` and `!-N` by number, and `!prefix` for the
+    /// latest one starting with `prefix`. A reference may go on with `:`
+    /// and a word designator, `N`, `N-M`, `N*`, `^`, `$` or `*`, to take
+    /// only some of its words; `!$`, `!^` and `!*` are short for `!!:$`,
+    /// `!!:^` and `!!:*`. Modifiers such as `:h` or `:s/old/new/` may
+    /// follow; see `apply_modifier`. Single quotes, a backslash, and a `!` followed by
+    /// a blank, `=` or `(` keep it literal. A line starting with `^` is a
+    /// quick substitution; see `substitute`. Returns `None` when the line
+    /// has no references, and the message to print when one fails.
+    pub fn expand(&self, line: &str) -> Result<Option<String>, String> {
+        if let Some(spec) = line.strip_prefix('^') {
+            return self.substitute(spec).map(Some);
+        }
+        let mut result = String::new();
+        let mut expanded = false;
+        let mut in_single = false;
+        let mut in_double = false;
+        let mut i = 0;
+        while let Some(c) = line[i..].chars().next() {
+            let rest = &line[i + c.len_utf8()..];
+            match c {
+                '\'' if !in_double => in_single = !in_single,
+                '"' if !in_single => in_double = !in_double,
+                '\\' if !in_single => {
+                    let escaped = rest.chars().next().map_or(0, char::len_utf8);
+                    result.push_str(&line[i..i + 1 + escaped]);
+                    i += 1 + escaped;
+                    continue;
+                }
+                // `$!` and `${!name}` are parameters, not references
+                '!' if !in_single
+                    && starts_reference(rest, in_double)
+                    && !result.ends_with('$')
+                    && !result.ends_with("${") =>
+                {
+                    let (text, len) = self.reference(&line[i..])?;
+                    result.push_str(&text);
+                    i += len;
+                    expanded = true;
+                    continue;
+                }
+                _ => {}
+            }
+            result.push(c);
+            i += c.len_utf8();
+        }
+        Ok(expanded.then_some(result))
+    }
+
+    /// `^old^new^rest`: the previous command with the first `old` replaced
+    /// by `new`, followed by `rest`. Both the last caret and `rest` may be
+    /// left out, as may `^new` to just delete `old`.
+    fn substitute(&self, spec: &str) -> Result<String, String> {
+        let spec = spec.trim_end_matches(['\n', '\r']);
+        let entry = self
+            .entries
+            .last()
+            .ok_or_else(|| "!!: event not found".to_string())?;
+        let mut parts = spec.splitn(3, '^');
+        let old = parts.next().unwrap_or_default();
+        let new = parts.next().unwrap_or_default();
+        let rest = parts.next().unwrap_or_default();
+        if old.is_empty() || !entry.contains(old) {
+            return Err(format!("^{}: substitution failed", spec));
+        }
+        Ok(format!("{}{}", entry.replacen(old, new, 1), rest))
+    }
+
+    /// Expansion of the reference starting with the `!` that begins `text`,
+    /// and its length in bytes.
+    fn reference(&self, text: &str) -> Result<(String, usize), String> {
+        let body = &text[1..];
+        let (entry, event_len) = match body.chars().next() {
+            Some('!') => (self.entries.last(), 1),
+            // `!$`, `!^`, `!*` and `!:N` refer to the previous command
+            Some('$' | '^' | '*' | ':') => (self.entries.last(), 0),
+            Some('-' | '0'..='9') => {
+                let digits = body[1..]
+                    .find(|c: char| !c.is_ascii_digit())
+                    .map_or(body.len(), |end| end + 1);
+                let number = body[..digits].parse::<isize>().unwrap_or(0);
+                let index = match number {
+                    number if number < 0 => self.entries.len().checked_sub(number.unsigned_abs()),
+                    number => (number as usize).checked_sub(1),
+                };
+                (index.and_then(|index| self.entries.get(index)), digits)
+            }
+            _ => {
+                let len = body
+                    .find(|c: char| c.is_whitespace() || ":;&|<>()'\"".contains(c))
+                    .unwrap_or(body.len());
+                let prefix = &body[..len];
+                let entry = self
+                    .entries
+                    .iter()
+                    .rev()
+                    .find(|entry| entry.starts_with(prefix));
+                (entry, len)
+            }
+        };
+        let event = &text[..1 + event_len];
+        let entry = entry.ok_or_else(|| format!("{}: event not found", event))?;
+
+        let designator = &body[event_len..];
+        let (spec, spec_len) = match designator.strip_prefix(':') {
+            Some(spec) if spec.starts_with(|c: char| "^$*-".contains(c) || c.is_ascii_digit()) => {
+                let len = designator_len(spec);
+                (&spec[..len], 1 + len)
+            }
+            // The colon may be left out before `^`, `$` and `*`
+            _ if designator.starts_with(['^', '$', '*']) => (&designator[..1], 1),
+            _ => ("", 0),
+        };
+        let mut len = 1 + event_len + spec_len;
+        let mut selected = if spec.is_empty() {
+            entry.clone()
+        } else {
+            let words = crate::expand::split_words(entry)
+                .unwrap_or_else(|_| entry.split_whitespace().map(str::to_string).collect());
+            select_words(&words, spec)
+                .ok_or_else(|| format!("{}: bad word specifier", &text[..len]))?
+        };
+        while let Some(modifier) = text[len..].strip_prefix(':') {
+            let Some((modified, modifier_len)) = apply_modifier(&selected, modifier)? else {
+                break;
+            };
+            selected = modified;
+            len += 1 + modifier_len;
+        }
+        Ok((selected, len))
+    }
+}
+
+/// Applies the modifier at the start of `modifier` to `text`, returning
+/// the result and the modifier's length, or `None` when it is not one:
+/// `h` and `t` keep the head and tail of a path, `r` and `e` drop and
+/// keep its `.suffix`, `q` quotes, and `s/old/new/` replaces the first
+/// `old` (every one with `gs`), `&` in `new` standing for `old`.
+fn apply_modifier(text: &str, modifier: &str) -> Result<Option<(String, usize)>, String> {
+    let suffix = text.rfind('.').filter(|dot| !text[*dot..].contains('/'));
+    let modified = match modifier.chars().next() {
+        Some('h') => text
+            .rsplit_once('/')
+            .map_or(text, |(head, _)| head)
+            .to_string(),
+        Some('t') => text
+            .rsplit_once('/')
+            .map_or(text, |(_, tail)| tail)
+            .to_string(),
+        Some('r') => suffix.map_or(text, |dot| &text[..dot]).to_string(),
+        Some('e') => suffix.map_or("", |dot| &text[dot..]).to_string(),
+        Some('q') => format!("'{}'", text.replace('\'', "'\\''")),
+        Some('s' | 'g') => {
+            let global = modifier.starts_with('g');
+            let body = if global { &modifier[1..] } else { modifier };
+            let Some(body) = body.strip_prefix('s') else {
+                return Ok(None);
+            };
+            let Some(delimiter) = body.chars().next() else {
+                return Err(format!(":{}: bad word specifier", modifier));
+            };
+            let body = &body[delimiter.len_utf8()..];
+            let (old, rest) = body
+                .split_once(delimiter)
+                .ok_or_else(|| format!(":{}: bad word specifier", modifier))?;
+            // The closing delimiter may be left out at the end of the line
+            let (new, end) = match rest.split_once(delimiter) {
+                Some((new, after)) => (new, modifier.len() - after.len()),
+                None => (rest, modifier.len()),
+            };
+            let new = new.replace('&', old);
+            if old.is_empty() || !text.contains(old) {
+                return Err(format!(":{}: substitution failed", &modifier[..end]));
+            }
+            let replaced = if global {
+                text.replace(old, &new)
+            } else {
+                text.replacen(old, &new, 1)
+            };
+            return Ok(Some((replaced, end)));
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some((modified, 1)))
+}
+
+/// Whether a `!` followed by `rest` starts a history reference.
+fn starts_reference(rest: &str, in_double: bool) -> bool {
+    match rest.chars().next() {
+        None => false,
+        Some(c) if c.is_whitespace() || c == '=' || c == '(' => false,
+        // `"...!"` ends with a literal `!`
+        Some('"') => !in_double,
+        Some(_) => true,
+    }
+}
+
+/// Length of the word designator at the start of `spec`.
+fn designator_len(spec: &str) -> usize {
+    if spec.starts_with(['^', '$', '*']) {
+        return 1;
+    }
+    let range_end = |text: &str| -> usize {
+        match text.chars().next() {
+            Some('$') => 1,
+            _ => text
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(text.len()),
+        }
+    };
+    let first = spec
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(spec.len());
+    match spec[first..].chars().next() {
+        Some('*') if first > 0 => first + 1,
+        Some('-') => first + 1 + range_end(&spec[first + 1..]),
+        _ => first,
+    }
+}
+
+/// The words of an entry picked by a word designator, joined by spaces, or
+/// `None` when they are out of range. Word 0 is the command name.
+fn select_words(words: &[String], spec: &str) -> Option<String> {
+    let last = words.len().checked_sub(1)?;
+    let bound = |text: &str| -> Option<usize> {
+        match text {
+            "$" => Some(last),
+            text => text.parse().ok(),
+        }
+    };
+    let (first, end) = match spec {
+        "^" => (1, 1),
+        "$" => (last, last),
+        // All the arguments, possibly none
+        "*" => return Some(words[1..].join(" ")),
+        spec => match spec.split_once('-') {
+            // `N-` stops before the last word
+            Some((first, end)) => (
+                if first.is_empty() { 0 } else { bound(first)? },
+                if end.is_empty() {
+                    last.checked_sub(1)?
+                } else {
+                    bound(end)?
+                },
+            ),
+            None => match spec.strip_suffix('*') {
+                Some(first) => (bound(first)?, last),
+                None => (bound(spec)?, bound(spec)?),
+            },
+        },
+    };
+    if first > end || end > last {
+        return None;
+    }
+    Some(words[first..=end].join(" "))
+}
+
+/// Lines of a history file, with what was wrong with the others.
+#[derive(Debug, Default)]
+struct Loaded {
+    lines: Vec<String>,
+    /// Lines left out: too long, or holding NULs, so not text.
+    skipped: usize,
+    /// Lines kept with invalid UTF-8 replaced.
+    repaired: usize,
+}
+
+impl Loaded {
+    /// Splits `bytes` into lines, dropping the first one when it may be
+    /// the end of a line cut off by `read_tail`.
+    fn parse(bytes: &[u8], partial_start: bool) -> Self {
+        let mut loaded = Self::default();
+        let mut lines = bytes.split(|byte| *byte == b'\n');
+        if partial_start {
+            lines.next();
+        }
+        for line in lines {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.len() > MAX_ENTRY_LEN || line.contains(&0) {
+                loaded.skipped += 1;
+                continue;
+            }
+            let text = match std::str::from_utf8(line) {
+                Ok(text) => text.to_string(),
+                Err(_) => {
+                    loaded.repaired += 1;
+                    String::from_utf8_lossy(line).into_owned()
+                }
+            };
+            loaded.lines.push(text);
+        }
+        // Like `str::lines`, a final newline does not start an empty line
+        if bytes.ends_with(b"\n") {
+            loaded.lines.pop();
+        }
+        loaded
+    }
+
+    /// Prints one warning for everything wrong with the file, if anything.
+    fn warn(&self, path: &str) {
+        if self.skipped > 0 || self.repaired > 0 {
+            eprintln!(
+                "history: {}: skipped {} corrupt entries, repaired {} with invalid UTF-8",
+                path, self.skipped, self.repaired
+            );
+        }
+    }
+}
+
+/// Reads the last `max_lines` lines of `path`, scanning backwards from the
+/// end so a long file is not read in full. Reading stops after
+/// `MAX_ENTRY_LEN` bytes per line wanted, so a file of overlong lines or
+/// binary data without newlines costs no more than that.
+fn read_tail(path: &str, max_lines: usize) -> io::Result<Loaded> {
+    const CHUNK: u64 = 8192;
+
+    let mut file = std::fs::File::open(path)?;
+    let len = file.seek(SeekFrom::End(0))?;
+    let budget = max_lines.saturating_add(1).saturating_mul(MAX_ENTRY_LEN);
+    let mut start = len;
+    let mut buffer = Vec::new();
+    let mut newlines = 0;
+    // One extra newline: the first line in the buffer may be partial
+    while start > 0 && newlines <= max_lines && buffer.len() < budget {
+        let read_len = CHUNK.min(start);
+        start -= read_len;
+        file.seek(SeekFrom::Start(start))?;
+        let mut chunk = vec![0; read_len as usize];
+        file.read_exact(&mut chunk)?;
+        newlines += chunk.iter().filter(|byte| **byte == b'\n').count();
+        chunk.extend(buffer);
+        buffer = chunk;
+    }
+
+    let mut loaded = Loaded::parse(&buffer, start > 0);
+    let skip = loaded.lines.len().saturating_sub(max_lines);
+    loaded.lines.drain(..skip);
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str, contents: &[u8]) -> String {
+        let path =
+            std::env::temp_dir().join(format!("history-test.{}.{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn corrupt_lines_are_skipped_or_repaired() {
+        let mut bytes = b"echo one\r\nbad\0binary\necho caf\xe9\n".to_vec();
+        bytes.extend(vec![b'x'; MAX_ENTRY_LEN + 1]);
+        bytes.extend(b"\necho two\n");
+        let loaded = Loaded::parse(&bytes, false);
+        assert_eq!(loaded.lines, ["echo one", "echo caf\u{fffd}", "echo two"]);
+        assert_eq!((loaded.skipped, loaded.repaired), (2, 1));
+
+        let loaded = Loaded::parse(b"tail of a cut line\nls\npwd", true);
+        assert_eq!(loaded.lines, ["ls", "pwd"]);
+    }
+
+    #[test]
+    fn only_the_tail_of_a_long_file_is_read() {
+        let contents: String = (0..100_000).map(|n| format!("echo {}\n", n)).collect();
+        let path = scratch("long", contents.as_bytes());
+        let loaded = read_tail(&path, 3).unwrap();
+        assert_eq!(loaded.lines, ["echo 99997", "echo 99998", "echo 99999"]);
+        let _ = std::fs::remove_file(path);
+
+        // Binary data without newlines is read no further than the budget
+        let path = scratch("binary", &vec![0xff; 4 * MAX_ENTRY_LEN]);
+        let loaded = read_tail(&path, 1).unwrap();
+        assert!(loaded.lines.is_empty());
+        assert_eq!(loaded.skipped, 0);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn histignore_patterns() {
+        // `HISTIGNORE`, the entry before, the line and whether it is kept
+        let table = [
+            ("", None, "ls", true),
+            ("ls", None, "ls", false),
+            // Patterns match the whole line
+            ("ls", None, "ls -l", true),
+            ("ls*", None, "ls -l", false),
+            ("l?", None, "ls", false),
+            ("[bf]g", None, "fg", false),
+            ("[bf]g", None, "jobs", true),
+            (" *", None, " secret", false),
+            (" *", None, "echo a b", true),
+            // Colon-separated lists, empty entries skipped
+            ("ls:pwd:exit", None, "pwd", false),
+            ("ls:pwd:exit", None, "cd", true),
+            ("::pwd:", None, "pwd", false),
+            ("::", None, "pwd", true),
+            // `&` is the previous entry
+            ("&", Some("ls"), "ls", false),
+            ("&", Some("ls"), "ls -l", true),
+            ("&", None, "ls", true),
+            ("pwd:&", Some("cd /"), "cd /", false),
+            ("pwd:&", Some("cd /"), "pwd", false),
+            ("pwd:&", Some("cd /"), "cd", true),
+            // Quoted pattern characters match themselves
+            ("echo \\*", None, "echo *", false),
+            ("echo \\*", None, "echo a", true),
+        ];
+        for (ignore, previous, line, kept) in table {
+            let mut history = History::default();
+            if let Some(previous) = previous {
+                history.add(previous);
+            }
+            history.record(line, ignore);
+            let recorded = history.entries().len() > usize::from(previous.is_some());
+            assert_eq!(recorded, kept, "{:?} {:?} {:?}", ignore, previous, line);
+        }
+    }
+
+    #[test]
+    fn loading_a_corrupt_file_keeps_recorded_entries() {
+        let path = scratch("load", b"\xff\xfe\0\0garbage\nls -l\n");
+        let mut history = History::default();
+        history.defer_load(path.clone());
+        history.add("echo typed");
+        history.load_pending(None);
+        assert_eq!(history.entries(), ["ls -l", "echo typed"]);
+        let _ = std::fs::remove_file(path);
+    }
+}
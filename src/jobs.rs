@@ -0,0 +1,485 @@
+//! Job table for background and stopped pipelines. A job owns every
+//! process of its pipeline and shares one process group, so signals sent to
+//! the job reach all members together.
+
+use std::fmt;
+
+/// State of a single pipeline member.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    Stopped,
+    /// Exited, holding the shell status (`128 + signal` when killed).
+    Done(i32),
+}
+
+/// Aggregated state of a whole job.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobState {
+    Running,
+    Stopped,
+    Done(i32),
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Running => write!(f, "Running"),
+            Self::Stopped => write!(f, "Stopped"),
+            Self::Done(0) => write!(f, "Done"),
+            Self::Done(status) if *status > 128 => {
+                let name = unsafe { std::ffi::CStr::from_ptr(libc::strsignal(status - 128)) };
+                write!(f, "{}", name.to_string_lossy())
+            }
+            Self::Done(status) => write!(f, "Exit {}", status),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Job {
+    pub id: usize,
+    pub pgid: libc::pid_t,
+    pub pids: Vec<libc::pid_t>,
+    pub states: Vec<ProcessState>,
+    pub command: String,
+}
+
+impl Job {
+    /// Running until every member exits and stopped as soon as any member
+    /// stops. A finished job reports the last member's status, or with
+    /// `pipefail` the last nonzero one.
+    pub fn state(&self, pipefail: bool) -> JobState {
+        if self.states.contains(&ProcessState::Stopped) {
+            return JobState::Stopped;
+        }
+        if self.states.contains(&ProcessState::Running) {
+            return JobState::Running;
+        }
+        let mut statuses = self.states.iter().rev().map(|state| match state {
+            ProcessState::Done(status) => *status,
+            _ => 0,
+        });
+        let status = if pipefail {
+            statuses.find(|status| *status != 0).unwrap_or(0)
+        } else {
+            statuses.next().unwrap_or(0)
+        };
+        JobState::Done(status)
+    }
+
+    /// Pid of the last member that was started, the one `$!` names.
+    pub fn last_pid(&self) -> Option<libc::pid_t> {
+        self.pids.iter().rev().find(|pid| **pid != 0).copied()
+    }
+
+    /// Sends `signal` to the job's process group.
+    pub fn signal(&self, signal: libc::c_int) -> std::io::Result<()> {
+        if unsafe { libc::kill(-self.pgid, signal) } == -1 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Marks stopped members as running again after a `SIGCONT`.
+    pub fn resume(&mut self) {
+        for state in &mut self.states {
+            if *state == ProcessState::Stopped {
+                *state = ProcessState::Running;
+            }
+        }
+    }
+
+    fn update(&mut self, pid: libc::pid_t, raw_status: libc::c_int) {
+        let Some(index) = self.pids.iter().position(|member| *member == pid) else {
+            return;
+        };
+        self.states[index] = process_state(raw_status);
+    }
+
+    /// Blocks until every member has exited or any member stops.
+    pub fn wait(&mut self, pipefail: bool) -> JobState {
+        while self.state(pipefail) == JobState::Running {
+            let mut raw_status = 0;
+            let pid = unsafe { libc::waitpid(-self.pgid, &mut raw_status, libc::WUNTRACED) };
+            if pid == -1 {
+                if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                // Nothing left to wait for, e.g. reaped elsewhere
+                for state in &mut self.states {
+                    if *state == ProcessState::Running {
+                        *state = ProcessState::Done(0);
+                    }
+                }
+                break;
+            }
+            self.update(pid, raw_status);
+        }
+        self.state(pipefail)
+    }
+}
+
+/// Converts a raw `waitpid` status into a member state.
+pub(crate) fn process_state(raw_status: libc::c_int) -> ProcessState {
+    if libc::WIFSTOPPED(raw_status) {
+        ProcessState::Stopped
+    } else if libc::WIFCONTINUED(raw_status) {
+        ProcessState::Running
+    } else if libc::WIFSIGNALED(raw_status) {
+        ProcessState::Done(128 + libc::WTERMSIG(raw_status))
+    } else {
+        ProcessState::Done(libc::WEXITSTATUS(raw_status))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct JobTable {
+    jobs: Vec<Job>,
+    /// Statuses of finished jobs already reported but not yet waited for,
+    /// by the pid of their last member.
+    finished: std::collections::BTreeMap<libc::pid_t, i32>,
+}
+
+impl JobTable {
+    /// Adds `job` under the next free job number and returns that number.
+    pub fn insert(&mut self, mut job: Job) -> usize {
+        job.id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        let id = job.id;
+        self.jobs.push(job);
+        id
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Job> {
+        let index = self.jobs.iter().position(|job| job.id == id)?;
+        Some(self.jobs.remove(index))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter()
+    }
+
+    /// Number of jobs in the table.
+    pub fn count(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// The job `%+` refers to: the most recently added.
+    pub fn current(&self) -> Option<usize> {
+        self.jobs.last().map(|job| job.id)
+    }
+
+    /// The job `%-` refers to: the one before the current job.
+    pub fn previous(&self) -> Option<usize> {
+        let len = self.jobs.len();
+        (len >= 2).then(|| self.jobs[len - 2].id)
+    }
+
+    /// The `+`/`-` marker shown next to a job in listings.
+    pub fn marker(&self, id: usize) -> char {
+        if Some(id) == self.current() {
+            '+'
+        } else if Some(id) == self.previous() {
+            '-'
+        } else {
+            ' '
+        }
+    }
+
+    /// Resolves a job spec: `%n`, `%%`, `%+`, `%-` or `%prefix`. A missing
+    /// spec means the current job.
+    pub fn resolve(&self, spec: Option<&str>) -> Option<usize> {
+        let Some(spec) = spec else {
+            return self.current();
+        };
+        let spec = spec.strip_prefix('%')?;
+        match spec {
+            "" | "%" | "+" => self.current(),
+            "-" => self.previous(),
+            _ => match spec.parse::<usize>() {
+                Ok(id) => self.jobs.iter().find(|job| job.id == id).map(|job| job.id),
+                Err(_) => self
+                    .jobs
+                    .iter()
+                    .rev()
+                    .find(|job| job.command.starts_with(spec))
+                    .map(|job| job.id),
+            },
+        }
+    }
+
+    /// Collects status changes of background members without blocking.
+    pub fn reap(&mut self) {
+        // One process group at a time, so children of other shells in the
+        // same process are left to them
+        for job in self.jobs.iter_mut().filter(|job| job.pgid > 0) {
+            loop {
+                let mut raw_status = 0;
+                let pid = unsafe {
+                    libc::waitpid(
+                        -job.pgid,
+                        &mut raw_status,
+                        libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED,
+                    )
+                };
+                if pid <= 0 {
+                    break;
+                }
+                job.update(pid, raw_status);
+            }
+        }
+    }
+
+    /// Blocks until any job finishes, or one is already done, and removes
+    /// it from the table. `None` when there are no jobs to wait for.
+    pub fn wait_any(&mut self, pipefail: bool) -> Option<Job> {
+        loop {
+            let done = self
+                .jobs
+                .iter()
+                .position(|job| matches!(job.state(pipefail), JobState::Done(_)));
+            if let Some(index) = done {
+                return Some(self.jobs.remove(index));
+            }
+            if self.jobs.is_empty() {
+                return None;
+            }
+            let mut raw_status = 0;
+            let pid = unsafe { libc::waitpid(-1, &mut raw_status, libc::WUNTRACED) };
+            if pid == -1 {
+                if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return None;
+            }
+            for job in &mut self.jobs {
+                job.update(pid, raw_status);
+            }
+        }
+    }
+
+    /// Takes the status kept for a finished job whose last member was
+    /// `pid`, so it can be waited for once.
+    pub fn take_status(&mut self, pid: libc::pid_t) -> Option<i32> {
+        self.finished.remove(&pid)
+    }
+
+    /// Forgets the statuses kept for finished jobs.
+    pub fn clear_statuses(&mut self) {
+        self.finished.clear();
+    }
+
+    /// Removes finished jobs, returning their listing lines. Their statuses
+    /// are kept for `take_status`.
+    pub fn take_finished(&mut self, pipefail: bool) -> Vec<String> {
+        let mut notices = Vec::new();
+        let mut index = 0;
+        while index < self.jobs.len() {
+            let job = &self.jobs[index];
+            if let JobState::Done(status) = job.state(pipefail) {
+                notices.push(self.format(job, pipefail));
+                if let Some(pid) = job.last_pid() {
+                    self.finished.insert(pid, status);
+                }
+                self.jobs.remove(index);
+            } else {
+                index += 1;
+            }
+        }
+        notices
+    }
+
+    /// Formats a job like `jobs` does: `[1]+  Running    sleep 5 | cat &`.
+    pub fn format(&self, job: &Job, pipefail: bool) -> String {
+        self.format_with(job, pipefail, "  ")
+    }
+
+    /// Formats a job like `jobs -l` does, with its process group id:
+    /// `[1]+ 4242 Running    sleep 5 | cat &`.
+    pub fn format_long(&self, job: &Job, pipefail: bool) -> String {
+        self.format_with(job, pipefail, &format!(" {} ", job.pgid))
+    }
+
+    /// A job as a JSON line, for the machine-readable mode of `jobs`.
+    pub fn format_json(&self, job: &Job, pipefail: bool) -> String {
+        let object = crate::json::Object::new()
+            .number("id", job.id as i64)
+            .string("marker", self.marker(job.id).to_string().trim())
+            .number("pgid", job.pgid.into());
+        let object = match job.state(pipefail) {
+            JobState::Running => object.string("state", "running"),
+            JobState::Stopped => object.string("state", "stopped"),
+            JobState::Done(status) => object
+                .string("state", "done")
+                .number("status", status.into()),
+        };
+        object.string("command", &job.command).finish()
+    }
+
+    fn format_with(&self, job: &Job, pipefail: bool, separator: &str) -> String {
+        let state = job.state(pipefail);
+        let suffix = if state == JobState::Running { " &" } else { "" };
+        format!(
+            "[{}]{}{}{:<24}{}{}",
+            job.id,
+            self.marker(job.id),
+            separator,
+            state.to_string(),
+            crate::prompt::sanitize(&job.command),
+            suffix
+        )
+    }
+}
+
+/// Signal names accepted by `kill`, without the `SIG` prefix.
+pub const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("WINCH", libc::SIGWINCH),
+];
+
+/// Parses a signal given by name (with or without `SIG`) or number.
+pub fn parse_signal(spec: &str) -> Option<libc::c_int> {
+    if let Ok(number) = spec.parse::<libc::c_int>() {
+        return (0..=64).contains(&number).then_some(number);
+    }
+    let name = spec.to_ascii_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNALS
+        .iter()
+        .find(|(signal, _)| *signal == name)
+        .map(|(_, number)| *number)
+}
+
+/// Gives the terminal to `pgid` when the shell is interactive.
+pub fn give_terminal(pgid: libc::pid_t) {
+    unsafe {
+        libc::tcsetpgrp(libc::STDIN_FILENO, pgid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(command: &str) -> Job {
+        Job {
+            id: 0,
+            pgid: 0,
+            pids: Vec::new(),
+            states: Vec::new(),
+            command: command.to_string(),
+        }
+    }
+
+    #[test]
+    fn job_specs() {
+        let mut table = JobTable::default();
+        for command in ["sleep 10", "vim notes", "sleep 20"] {
+            table.insert(job(command));
+        }
+        assert_eq!(table.resolve(None), Some(3));
+        for spec in ["%%", "%+", "%"] {
+            assert_eq!(table.resolve(Some(spec)), Some(3), "{}", spec);
+        }
+        assert_eq!(table.resolve(Some("%-")), Some(2));
+        assert_eq!(table.resolve(Some("%1")), Some(1));
+        assert_eq!(table.resolve(Some("%4")), None);
+        assert_eq!(table.resolve(Some("%vim")), Some(2));
+        assert_eq!(table.resolve(Some("%sleep")), Some(3));
+        assert_eq!(table.resolve(Some("%emacs")), None);
+        assert_eq!(table.resolve(Some("1")), None);
+        assert_eq!([1, 2, 3].map(|id| table.marker(id)), [' ', '-', '+']);
+
+        // Numbers are not reused while a later job is still around
+        table.remove(2);
+        assert_eq!(table.resolve(Some("%-")), Some(1));
+        assert_eq!(table.resolve(Some("%2")), None);
+        assert_eq!(table.insert(job("make")), 4);
+    }
+
+    #[test]
+    fn job_listing() {
+        use ProcessState::{Done, Running, Stopped};
+
+        let mut table = JobTable::default();
+        let members: [(&str, &[ProcessState]); 5] = [
+            ("sleep 5 | cat", &[Running, Running]),
+            ("vim notes", &[Stopped]),
+            ("false | true", &[Done(1), Done(0)]),
+            ("sleep 9", &[Done(128 + libc::SIGKILL)]),
+            ("printf '\x1b[2J'", &[Done(3)]),
+        ];
+        for (index, (command, states)) in members.into_iter().enumerate() {
+            let id = table.insert(job(command));
+            let job = table.get_mut(id).unwrap();
+            job.pgid = 4200 + index as libc::pid_t;
+            job.states = states.to_vec();
+        }
+        let lines = |format: fn(&JobTable, &Job, bool) -> String, pipefail| {
+            table
+                .iter()
+                .map(|job| format(&table, job, pipefail))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            lines(JobTable::format, false),
+            [
+                "[1]   Running                 sleep 5 | cat &",
+                "[2]   Stopped                 vim notes",
+                "[3]   Done                    false | true",
+                "[4]-  Killed                  sleep 9",
+                "[5]+  Exit 3                  printf '^[[2J'",
+            ]
+        );
+        // With pipefail a job fails with its last failing member
+        assert_eq!(
+            lines(JobTable::format, true)[2],
+            "[3]   Exit 1                  false | true"
+        );
+        assert_eq!(
+            lines(JobTable::format_long, false)[..2],
+            [
+                "[1]  4200 Running                 sleep 5 | cat &",
+                "[2]  4201 Stopped                 vim notes",
+            ]
+        );
+        assert_eq!(
+            lines(JobTable::format_json, false)[3..],
+            [
+                r#"{"id":4,"marker":"-","pgid":4203,"state":"done","status":137,"command":"sleep 9"}"#,
+                r#"{"id":5,"marker":"+","pgid":4204,"state":"done","status":3,"command":"printf '\u001b[2J'"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn signal_names() {
+        assert_eq!(parse_signal("TERM"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("sigkill"), Some(libc::SIGKILL));
+        assert_eq!(parse_signal("SIGhup"), Some(libc::SIGHUP));
+        assert_eq!(parse_signal("9"), Some(9));
+        assert_eq!(parse_signal("0"), Some(0));
+        assert_eq!(parse_signal("65"), None);
+        assert_eq!(parse_signal("-1"), None);
+        assert_eq!(parse_signal("SIG"), None);
+        assert_eq!(parse_signal("BOGUS"), None);
+    }
+}
@@ -0,0 +1,101 @@
+//! JSON lines written instead of text by `type`, `jobs` and `history` when
+//! the shell is started with `MYSHELL_JSON=1`, for programs wrapping it.
+//! Only flat objects of strings, numbers and booleans are needed, so they
+//! are written by hand.
+
+/// Environment variable that turns the mode on when the shell starts.
+pub const VARIABLE: &str = "MYSHELL_JSON";
+
+/// A JSON object, built one field at a time.
+#[derive(Debug, Default)]
+pub struct Object {
+    fields: Vec<String>,
+}
+
+impl Object {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn string(mut self, key: &str, value: &str) -> Self {
+        self.fields.push(format!("{}:{}", quote(key), quote(value)));
+        self
+    }
+
+    pub fn number(mut self, key: &str, value: i64) -> Self {
+        self.fields.push(format!("{}:{}", quote(key), value));
+        self
+    }
+
+    pub fn boolean(mut self, key: &str, value: bool) -> Self {
+        self.fields.push(format!("{}:{}", quote(key), value));
+        self
+    }
+
+    /// The object on one line.
+    pub fn finish(self) -> String {
+        format!("{{{}}}", self.fields.join(","))
+    }
+}
+
+/// `text` as a JSON string, with quotes, backslashes and control
+/// characters escaped.
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> String {
+        Object::new().string("k", value).finish()
+    }
+
+    #[test]
+    fn quotes_and_backslashes() {
+        assert_eq!(string(r#"say "hi""#), r#"{"k":"say \"hi\""}"#);
+        assert_eq!(string(r"C:\dir\"), r#"{"k":"C:\\dir\\"}"#);
+        assert_eq!(Object::new().string("a\"b", "").finish(), r#"{"a\"b":""}"#);
+    }
+
+    #[test]
+    fn control_characters() {
+        assert_eq!(string("a\nb\rc\td"), r#"{"k":"a\nb\rc\td"}"#);
+        assert_eq!(
+            string("\0\u{1b}[1m\u{7f}"),
+            r#"{"k":"\u0000\u001b[1m\u007f"}"#
+        );
+        assert_eq!(string("\u{85}"), r#"{"k":"\u0085"}"#);
+    }
+
+    #[test]
+    fn characters_outside_the_bmp_pass_through() {
+        assert_eq!(string("🦀 é 漢"), "{\"k\":\"🦀 é 漢\"}");
+    }
+
+    #[test]
+    fn objects() {
+        let line = Object::new()
+            .string("name", "x")
+            .number("id", -3)
+            .boolean("done", true)
+            .finish();
+        assert_eq!(line, r#"{"name":"x","id":-3,"done":true}"#);
+        assert_eq!(Object::new().finish(), "{}");
+    }
+}
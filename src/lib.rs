@@ -0,0 +1,7320 @@
+//! A small POSIX-style shell. The binary just calls `run`; other programs
+//! can drive the shell through `session::ShellSession`.
+
+#[cfg(not(unix))]
+compile_error!("the shell needs a Unix platform: it forks, sets up the terminal with termios and checks file modes");
+
+mod arith;
+mod completion;
+mod compound;
+mod editor;
+mod expand;
+mod files;
+mod history;
+mod jobs;
+mod json;
+mod limits;
+mod pathcache;
+mod paths;
+mod prompt;
+pub mod session;
+mod signals;
+
+#[allow(unused_imports)]
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+enum Command {
+    Function(Function),
+    BuiltinCommand(BuiltinCommand),
+    ExecutableCommand(ExecutableCommand),
+}
+
+enum BuiltinCommand {
+    Exit,
+    Logout,
+    Echo,
+    Type,
+    Pwd,
+    Cd,
+    Trap,
+    Shopt,
+    Set,
+    Jobs,
+    Fg,
+    Bg,
+    Kill,
+    History,
+    Local,
+    Export,
+    Unset,
+    Return,
+    Source,
+    Caller,
+    Sleep,
+    Break,
+    Wait,
+    Wc,
+    Basename,
+    Dirname,
+    Realpath,
+    Pushd,
+    Popd,
+    Dirs,
+    Repeat,
+    Enable,
+    Watch,
+    Getopts,
+    Complete,
+    Ulimit,
+    Clear,
+    Read,
+    Alias,
+    Unalias,
+}
+
+/// A piece of builtin output, kept as bytes rather than text.
+#[derive(Clone, Debug)]
+struct OutputLine {
+    bytes: Vec<u8>,
+    is_err: bool,
+    newline: bool,
+}
+
+#[derive(Debug)]
+struct Output(Vec<OutputLine>);
+
+impl Output {
+    fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    fn add(&mut self, line: &str, is_err: bool) {
+        self.0.push(OutputLine {
+            bytes: expand::text_to_bytes(line),
+            is_err,
+            newline: true,
+        });
+    }
+
+    /// Adds bytes as they are, without a newline, as for `echo -n`. NULs
+    /// and non-UTF-8 data go through unchanged.
+    fn add_bytes(&mut self, bytes: &[u8], is_err: bool) {
+        self.0.push(OutputLine {
+            bytes: bytes.to_vec(),
+            is_err,
+            newline: false,
+        });
+    }
+
+    fn get(&self) -> &[OutputLine] {
+        &self.0
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Where the shell's own output goes: fds 1 and 2, unless a `ShellSession`
+/// gave it files of its own, so embedding never touches the process's
+/// descriptors, or a `{ ... }` group is redirected. Commands the shell
+/// starts get the same files as their stdout and stderr before their own
+/// redirections, and the group's input as their stdin.
+#[derive(Debug, Default)]
+struct Sinks {
+    stdin: Option<std::fs::File>,
+    stdout: Option<std::fs::File>,
+    stderr: Option<std::fs::File>,
+}
+
+impl Sinks {
+    fn stdout(&self) -> Box<dyn Write + Send> {
+        match self.stdout.as_ref().and_then(|file| file.try_clone().ok()) {
+            Some(file) => Box::new(file),
+            None => Box::new(io::stdout()),
+        }
+    }
+
+    fn stderr(&self) -> Box<dyn Write + Send> {
+        match self.stderr.as_ref().and_then(|file| file.try_clone().ok()) {
+            Some(file) => Box::new(file),
+            None => Box::new(io::stderr()),
+        }
+    }
+
+    /// Points a command's stdin, stdout and stderr at the sinks, if any.
+    fn attach(&self, process: &mut std::process::Command) {
+        if let Some(file) = self.stdin.as_ref().and_then(|file| file.try_clone().ok()) {
+            process.stdin(file);
+        }
+        if let Some(file) = self.stdout.as_ref().and_then(|file| file.try_clone().ok()) {
+            process.stdout(file);
+        }
+        if let Some(file) = self.stderr.as_ref().and_then(|file| file.try_clone().ok()) {
+            process.stderr(file);
+        }
+    }
+}
+
+/// `print!` and `println!` to the shell's stdout, and `eprint!` and
+/// `eprintln!` to its stderr, wherever `state.sinks` sends them. Write
+/// errors are ignored.
+macro_rules! print_out {
+    ($state:expr, $($arg:tt)*) => {{
+        let _ = write!($state.sinks.stdout(), $($arg)*);
+    }};
+}
+
+macro_rules! println_out {
+    ($state:expr) => {{
+        let _ = writeln!($state.sinks.stdout());
+    }};
+    ($state:expr, $($arg:tt)*) => {{
+        let _ = writeln!($state.sinks.stdout(), $($arg)*);
+    }};
+}
+
+macro_rules! print_err {
+    ($state:expr, $($arg:tt)*) => {{
+        let _ = write!($state.sinks.stderr(), $($arg)*);
+    }};
+}
+
+macro_rules! println_err {
+    ($state:expr) => {{
+        let _ = writeln!($state.sinks.stderr());
+    }};
+    ($state:expr, $($arg:tt)*) => {{
+        let _ = writeln!($state.sinks.stderr(), $($arg)*);
+    }};
+}
+
+/// Pseudo-signals accepted by the `trap` builtin besides the real signals
+/// of `jobs::SIGNALS`.
+const TRAP_SIGNALS: &[&str] = &["DEBUG", "ERR", "EXIT"];
+
+/// Options managed by the `shopt` builtin, with their default values.
+const SHOPT_OPTIONS: &[(&str, bool)] = &[
+    ("autocd", false),
+    ("cd_correct", false),
+    ("cd_file_parent", false),
+    ("checksamefile", false),
+    ("checkwinsize", true),
+    ("confirm_device_clobber", false),
+    ("confirm_glob_removal", false),
+    ("expand_aliases", false),
+    ("huponexit", false),
+    ("login_shell", false),
+    ("mark_directories", true),
+    ("nocasesort", false),
+    ("verbose_path_errors", false),
+];
+
+/// `shopt` options that report how the shell was started and cannot be
+/// set.
+const READONLY_SHOPTS: &[&str] = &["login_shell"];
+
+/// Options managed by `set -o`, with their default values.
+const SET_OPTIONS: &[(&str, bool)] = &[
+    ("errexit", false),
+    ("histignorefailure", false),
+    ("mkdirredirect", false),
+    ("noexec", false),
+    ("notify", false),
+    ("nounset", false),
+    ("parsedebug", false),
+    ("pipefail", false),
+    ("posix", false),
+    ("xtrace", false),
+];
+
+/// `set -o` options left out of `set -o`/`set +o` listings.
+const HIDDEN_SET_OPTIONS: &[&str] = &["parsedebug"];
+
+/// Single-letter `set` flags and the options they stand for.
+const SET_FLAGS: &[(char, &str)] = &[
+    ('b', "notify"),
+    ('e', "errexit"),
+    ('n', "noexec"),
+    ('u', "nounset"),
+    ('x', "xtrace"),
+];
+
+/// Set by the SIGWINCH handler, cleared once `LINES`/`COLUMNS` are synced.
+static WINDOW_RESIZED: AtomicBool = AtomicBool::new(false);
+
+/// Set by the SIGINT handler installed while `sleep` runs.
+static SLEEP_INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Default)]
+struct ShellState {
+    last_status: i32,
+    traps: std::collections::BTreeMap<String, String>,
+    in_trap: bool,
+    subshell_depth: u32,
+    shopts: std::collections::BTreeMap<&'static str, bool>,
+    options: std::collections::BTreeMap<&'static str, bool>,
+    interactive: bool,
+    jobs: jobs::JobTable,
+    history: history::History,
+    functions: std::collections::BTreeMap<String, Function>,
+    frames: Vec<Frame>,
+    /// Script given on the command line, `None` when reading stdin.
+    script: Option<String>,
+    /// Line of the script or sourced file being run, 0 when reading
+    /// commands interactively.
+    line: usize,
+    /// `$1`, `$2`, ... of the running script or function.
+    positional: Vec<String>,
+    /// Process id of the last background job, for `$!`.
+    last_background: Option<libc::pid_t>,
+    /// Filled on first use by completion or `type -a`.
+    path_cache: std::cell::RefCell<pathcache::PathCache>,
+    /// Set by `return` until the running function body has unwound.
+    returning: bool,
+    /// Loops being run, innermost last; `break` can leave at most this many.
+    loops: usize,
+    /// Loops `break` still has to leave.
+    breaking: usize,
+    /// Coprocesses started by `coproc` that have not been reaped yet.
+    coprocs: Vec<Coproc>,
+    /// File redirected with `<` for the running builtin; see `take_input`.
+    builtin_input: Option<std::fs::File>,
+    /// Whether the running builtin's stdout, redirections included, is a
+    /// terminal.
+    builtin_stdout_terminal: bool,
+    /// Directories saved by `pushd`, below the current one.
+    dir_stack: Vec<String>,
+    /// Command lines read at the prompt, for `\#`.
+    commands_read: usize,
+    /// Builtins turned off with `enable -n`.
+    disabled_builtins: std::collections::BTreeSet<String>,
+    /// Where `getopts` is inside a group of options such as `-abc`: the
+    /// `OPTIND` it left, and the offset of the next letter in that
+    /// argument. Setting `OPTIND` to anything else starts over.
+    getopts_position: (usize, usize),
+    /// Argument completion for commands, set with `complete`.
+    completions: std::collections::BTreeMap<String, completion::CompSpec>,
+    /// Process group that had the terminal before an interactive shell
+    /// took it, given back by `shutdown`.
+    terminal_owner: Option<libc::pid_t>,
+    /// Limits `ulimit ... -- command` puts on the processes the command
+    /// starts, applied in each child before it runs the program.
+    command_limits: Vec<limits::Setting>,
+    /// Whether `type`, `jobs` and `history` write JSON lines; see
+    /// `json::VARIABLE`.
+    json_output: bool,
+    /// Set while a negated pipeline runs: failures of the commands inside
+    /// it, down to function bodies and subshells, neither end the shell
+    /// under `set -e` nor trigger the `ERR` trap.
+    errexit_suppressed: bool,
+    /// Where the shell's own output goes; see `Sinks`.
+    sinks: Sinks,
+    /// Run by a `ShellSession`: leaving the shell stops running commands
+    /// and records the status in `exited` instead of ending the process.
+    embedded: bool,
+    /// Status the shell was left with, once an embedded shell is left.
+    exited: Option<i32>,
+    /// Status of the last command substitution in the words being
+    /// expanded, which a line of only assignments returns.
+    substitution_status: Option<i32>,
+    /// Shell variables, starting with the environment the shell was given.
+    /// Only exported ones reach the commands it runs.
+    variables: std::collections::BTreeMap<String, Variable>,
+    /// Aliases defined with `alias`; see `expand_aliases`.
+    aliases: std::collections::BTreeMap<String, String>,
+}
+
+/// A shell variable, and whether `export` marked it for the environment
+/// of commands.
+#[derive(Debug, Clone)]
+struct Variable {
+    value: String,
+    exported: bool,
+}
+
+/// A function defined with `name() { ... }`.
+#[derive(Debug, Clone)]
+struct Function {
+    body: String,
+    /// File and line of the definition, where the commands of the body
+    /// are reported to be.
+    source: String,
+    line: usize,
+}
+
+/// A process started by `coproc`, with the shell's ends of the pipes to it.
+#[derive(Debug)]
+struct Coproc {
+    name: String,
+    pid: libc::pid_t,
+    /// Reads the coprocess's output, then writes its input: the values of
+    /// the `NAME` array. Both close when the coprocess is dropped.
+    fds: [std::os::fd::OwnedFd; 2],
+}
+
+impl Coproc {
+    fn raw_fds(&self) -> [libc::c_int; 2] {
+        use std::os::fd::AsRawFd;
+
+        [self.fds[0].as_raw_fd(), self.fds[1].as_raw_fd()]
+    }
+}
+
+/// Deepest nesting of function calls and sourced files.
+const MAX_CALL_DEPTH: usize = 256;
+
+/// A function call or sourced file in progress, with what it has to
+/// restore on return.
+#[derive(Debug, Default)]
+struct Frame {
+    /// Function name, or `source` for a sourced file.
+    function: String,
+    /// File whose commands the frame is running.
+    source: String,
+    /// Line of the caller the frame was entered from.
+    line: usize,
+    /// Previous values of variables made local, `None` when unset.
+    locals: Vec<(String, Option<Variable>)>,
+    /// Option flags saved by `local -`.
+    options: Option<std::collections::BTreeMap<&'static str, bool>>,
+    /// Caller's positional parameters, when the frame replaced them.
+    positional: Option<Vec<String>>,
+}
+
+impl ShellState {
+    fn new() -> Self {
+        use std::os::unix::ffi::OsStrExt;
+
+        let variables = std::env::vars_os()
+            .map(|(name, value)| {
+                let variable = Variable {
+                    value: expand::bytes_to_text(value.as_bytes()),
+                    exported: true,
+                };
+                (expand::bytes_to_text(name.as_bytes()), variable)
+            })
+            .collect();
+        let mut state = Self {
+            shopts: SHOPT_OPTIONS.iter().copied().collect(),
+            options: SET_OPTIONS.iter().copied().collect(),
+            variables,
+            ..Self::default()
+        };
+        // Like bash, start from the physical directory when the inherited
+        // `$PWD` does not name the working directory
+        if logical_pwd(&state).is_none() {
+            if let Ok(dir) = std::env::current_dir() {
+                state.set_variable("PWD", &dir.display().to_string());
+            }
+        }
+        let mut output = Output::new();
+        for args in DEFAULT_COMPLETIONS {
+            complete_fn(args, &mut state, &mut output);
+        }
+        state
+    }
+
+    fn shopt(&self, name: &str) -> bool {
+        self.shopts.get(name).copied().unwrap_or(false)
+    }
+
+    /// The builtin called `name`, unless `enable -n` turned it off.
+    fn builtin(&self, name: &str) -> Option<BuiltinCommand> {
+        if self.disabled_builtins.contains(name) {
+            return None;
+        }
+        BuiltinCommand::from_str(name)
+    }
+
+    fn option(&self, name: &str) -> bool {
+        self.options.get(name).copied().unwrap_or(false)
+    }
+
+    /// File the innermost frame is running, or the script at top level.
+    fn current_source(&self) -> String {
+        match self.frames.last() {
+            Some(frame) => frame.source.clone(),
+            None => self.script.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Variables computed from the call stack rather than stored:
+    /// `CURRENT_FUNC` (`main` at top level), `CURRENT_SOURCE` and
+    /// `CALL_DEPTH`, plus `NAME_PID` for each coprocess.
+    fn dynamic_variables(&self) -> Vec<(String, String)> {
+        let function = self
+            .frames
+            .last()
+            .map_or("main", |frame| frame.function.as_str());
+        let mut variables = vec![
+            ("CALL_DEPTH".to_string(), self.frames.len().to_string()),
+            ("CURRENT_FUNC".to_string(), function.to_string()),
+            ("CURRENT_SOURCE".to_string(), self.current_source()),
+        ];
+        variables.extend(
+            self.coprocs
+                .iter()
+                .map(|coproc| (format!("{}_PID", coproc.name), coproc.pid.to_string())),
+        );
+        variables
+    }
+
+    /// Arrays computed from shell state: `FUNCNAME`, the running function
+    /// followed by its callers down to `main`, unset outside any function
+    /// or sourced file, and the file descriptors of each coprocess.
+    fn array(&self, name: &str) -> Option<Vec<String>> {
+        match name {
+            "FUNCNAME" if !self.frames.is_empty() => Some(
+                self.frames
+                    .iter()
+                    .rev()
+                    .map(|frame| frame.function.clone())
+                    .chain(std::iter::once("main".to_string()))
+                    .collect(),
+            ),
+            _ => self
+                .coprocs
+                .iter()
+                .find(|coproc| coproc.name == name)
+                .map(|coproc| coproc.raw_fds().iter().map(|fd| fd.to_string()).collect()),
+        }
+    }
+
+    /// Looks a variable up: special parameters, then the ones computed from
+    /// the call stack, then shell variables. An array stands for its first
+    /// element.
+    fn variable(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => return Some(self.last_status.to_string()),
+            "$" => return Some(std::process::id().to_string()),
+            "#" => return Some(self.positional.len().to_string()),
+            "!" => return self.last_background.map(|pid| pid.to_string()),
+            "0" => {
+                return self.script.clone().or_else(|| std::env::args().next());
+            }
+            _ => {}
+        }
+        if let Ok(index) = name.parse::<usize>() {
+            return self.positional.get(index.checked_sub(1)?).cloned();
+        }
+        if let Some(values) = self.array(name) {
+            return values.into_iter().next();
+        }
+        self.dynamic_variables()
+            .into_iter()
+            .find(|(dynamic, _)| dynamic == name)
+            .map(|(_, value)| value)
+            .or_else(|| Some(self.variables.get(name)?.value.clone()))
+    }
+
+    /// Sets a shell variable, keeping its export flag; a new variable stays
+    /// out of the environment of commands. A value cannot hold NULs, so they
+    /// are dropped.
+    fn set_variable(&mut self, name: &str, value: &str) {
+        let exported = self.variables.get(name).is_some_and(|old| old.exported);
+        let variable = Variable {
+            value: value.replace('\0', ""),
+            exported,
+        };
+        self.restore_variable(name, Some(variable));
+    }
+
+    fn unset_variable(&mut self, name: &str) {
+        self.restore_variable(name, None);
+    }
+
+    /// Marks a variable for the environment of commands, after setting it
+    /// to `value` if given. Exporting an unset name without a value sets it
+    /// to the empty string.
+    fn export_variable(&mut self, name: &str, value: Option<&str>) {
+        let value = match value {
+            Some(value) => value.replace('\0', ""),
+            None => self
+                .variables
+                .get(name)
+                .map(|old| old.value.clone())
+                .unwrap_or_default(),
+        };
+        let variable = Variable {
+            value,
+            exported: true,
+        };
+        self.restore_variable(name, Some(variable));
+    }
+
+    /// Puts back a variable as `saved_variable` returned it, unsetting it
+    /// for `None`. The C library reads the time zone from the process
+    /// environment, so an exported `TZ` is the one variable mirrored there.
+    fn restore_variable(&mut self, name: &str, variable: Option<Variable>) {
+        if name == "TZ" {
+            match &variable {
+                Some(tz) if tz.exported => std::env::set_var(name, &tz.value),
+                _ => std::env::remove_var(name),
+            }
+        }
+        match variable {
+            Some(variable) => self.variables.insert(name.to_string(), variable),
+            None => self.variables.remove(name),
+        };
+    }
+
+    /// A variable as it is now, for `restore_variable`.
+    fn saved_variable(&self, name: &str) -> Option<Variable> {
+        self.variables.get(name).cloned()
+    }
+
+    /// Exported variables: the environment of the commands the shell runs.
+    fn environment(&self) -> impl Iterator<Item = (&str, std::ffi::OsString)> {
+        use std::os::unix::ffi::OsStringExt;
+
+        self.variables
+            .iter()
+            .filter(|(_, variable)| variable.exported)
+            .map(|(name, variable)| {
+                let value = std::ffi::OsString::from_vec(expand::text_to_bytes(&variable.value));
+                (name.as_str(), value)
+            })
+    }
+
+    /// Standard input of the running builtin: the file it redirected with
+    /// `<`, otherwise the shell's own stdin.
+    fn take_input(&mut self) -> Box<dyn io::Read> {
+        let sink = || self.sinks.stdin.as_ref()?.try_clone().ok();
+        match self.builtin_input.take().or_else(sink) {
+            Some(file) => Box::new(file),
+            None => Box::new(io::stdin()),
+        }
+    }
+
+    /// Pushes a call frame unless that would exceed `MAX_CALL_DEPTH`.
+    fn push_frame(&mut self, function: &str, source: String) -> bool {
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            println_err!(
+                self,
+                "{}: maximum nesting level exceeded ({})",
+                function,
+                MAX_CALL_DEPTH
+            );
+            return false;
+        }
+        self.frames.push(Frame {
+            function: function.to_string(),
+            source,
+            line: self.line,
+            ..Frame::default()
+        });
+        true
+    }
+}
+
+impl BuiltinCommand {
+    const NAMES: &'static [&'static str] = &[
+        ".", "alias", "basename", "bg", "break", "caller", "cd", "clear", "complete", "dirname",
+        "dirs", "echo", "enable", "exit", "export", "fg", "getopts", "history", "jobs", "kill",
+        "local", "logout", "popd", "pushd", "pwd", "read", "realpath", "repeat", "return", "set",
+        "shopt", "sleep", "source", "trap", "type", "ulimit", "unalias", "unset", "wait", "watch",
+        "wc",
+    ];
+
+    fn from_str(command: &str) -> Option<Self> {
+        match command {
+            "exit" => Some(Self::Exit),
+            "logout" => Some(Self::Logout),
+            "echo" => Some(Self::Echo),
+            "type" => Some(Self::Type),
+            "pwd" => Some(Self::Pwd),
+            "cd" => Some(Self::Cd),
+            "trap" => Some(Self::Trap),
+            "shopt" => Some(Self::Shopt),
+            "set" => Some(Self::Set),
+            "jobs" => Some(Self::Jobs),
+            "fg" => Some(Self::Fg),
+            "bg" => Some(Self::Bg),
+            "kill" => Some(Self::Kill),
+            "history" => Some(Self::History),
+            "local" => Some(Self::Local),
+            "export" => Some(Self::Export),
+            "unset" => Some(Self::Unset),
+            "return" => Some(Self::Return),
+            "source" | "." => Some(Self::Source),
+            "caller" => Some(Self::Caller),
+            "sleep" => Some(Self::Sleep),
+            "break" => Some(Self::Break),
+            "wait" => Some(Self::Wait),
+            "wc" => Some(Self::Wc),
+            "basename" => Some(Self::Basename),
+            "dirname" => Some(Self::Dirname),
+            "realpath" => Some(Self::Realpath),
+            "pushd" => Some(Self::Pushd),
+            "popd" => Some(Self::Popd),
+            "dirs" => Some(Self::Dirs),
+            "repeat" => Some(Self::Repeat),
+            "enable" => Some(Self::Enable),
+            "watch" => Some(Self::Watch),
+            "getopts" => Some(Self::Getopts),
+            "read" => Some(Self::Read),
+            "alias" => Some(Self::Alias),
+            "unalias" => Some(Self::Unalias),
+            "complete" => Some(Self::Complete),
+            "ulimit" => Some(Self::Ulimit),
+            "clear" => Some(Self::Clear),
+            _ => None,
+        }
+    }
+
+    fn to_impl(&self) -> fn(&[&str], &mut ShellState, &mut Output) -> i32 {
+        match self {
+            Self::Exit => exit_fn,
+            Self::Logout => logout_fn,
+            Self::Echo => echo_fn,
+            Self::Type => type_fn,
+            Self::Pwd => pwd_fn,
+            Self::Cd => cd_fn,
+            Self::Trap => trap_fn,
+            Self::Shopt => shopt_fn,
+            Self::Set => set_fn,
+            Self::Jobs => jobs_fn,
+            Self::Fg => fg_fn,
+            Self::Bg => bg_fn,
+            Self::Kill => kill_fn,
+            Self::History => history_fn,
+            Self::Local => local_fn,
+            Self::Export => export_fn,
+            Self::Unset => unset_fn,
+            Self::Return => return_fn,
+            Self::Source => source_fn,
+            Self::Caller => caller_fn,
+            Self::Sleep => sleep_fn,
+            Self::Break => break_fn,
+            Self::Wait => wait_fn,
+            Self::Wc => wc_fn,
+            Self::Basename => basename_fn,
+            Self::Dirname => dirname_fn,
+            Self::Realpath => realpath_fn,
+            Self::Pushd => pushd_fn,
+            Self::Popd => popd_fn,
+            Self::Dirs => dirs_fn,
+            Self::Repeat => repeat_fn,
+            Self::Enable => enable_fn,
+            Self::Watch => watch_fn,
+            Self::Getopts => getopts_fn,
+            Self::Read => read_fn,
+            Self::Alias => alias_fn,
+            Self::Unalias => unalias_fn,
+            Self::Complete => complete_fn,
+            Self::Ulimit => ulimit_fn,
+            Self::Clear => clear_fn,
+        }
+    }
+
+    /// POSIX special builtins: errors in them abort a non-interactive
+    /// shell running in posix mode. Of the others this shell has no
+    /// `exec`, `eval`, `readonly`, `shift`, `times` or `:`.
+    fn is_special(&self) -> bool {
+        matches!(
+            self,
+            Self::Exit
+                | Self::Trap
+                | Self::Set
+                | Self::Export
+                | Self::Unset
+                | Self::Return
+                | Self::Source
+                | Self::Break
+        )
+    }
+
+    /// Builtins that only read shell state and finish without waiting on
+    /// anything but their input. In a pipeline they run in the shell
+    /// itself; the others run in a forked child, like a subshell, so what
+    /// they change stays there.
+    fn is_pure(&self) -> bool {
+        matches!(
+            self,
+            Self::Echo
+                | Self::Type
+                | Self::Pwd
+                | Self::Caller
+                | Self::Wc
+                | Self::Basename
+                | Self::Dirname
+                | Self::Realpath
+        )
+    }
+}
+
+struct ExecutableCommand {
+    path: String,
+}
+
+fn exit_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    leave("exit", args, state, output)
+}
+
+fn logout_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    if !state.shopt("login_shell") {
+        output.add("logout: not login shell: use 'exit'", true);
+        return 1;
+    }
+    leave("logout", args, state, output)
+}
+
+/// Shared by `exit` and `logout`: ends the shell with the given status,
+/// or the last one.
+fn leave(name: &str, args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    if args.len() > 1 {
+        output.add(&format!("{}: too many arguments", name), true);
+        return 1;
+    }
+    let exit_code = if !args.is_empty() {
+        args[0].parse::<i32>().unwrap_or(0)
+    } else {
+        state.last_status
+    };
+    // Inside a subshell this only ends the forked child, whose status
+    // becomes the status of the `( ... )` group
+    exit_shell(state, exit_code);
+    exit_code
+}
+
+/// Leaves the shell from inside a command: `exit`, `set -e` and fatal
+/// errors. A shell embedded in a `ShellSession` runs the `EXIT` trap,
+/// records the status in `exited` and runs no more commands, leaving the
+/// process to the program that embeds it; any other shell `shutdown`s.
+fn exit_shell(state: &mut ShellState, status: i32) {
+    if !state.embedded {
+        shutdown(state, status);
+    }
+    state.last_status = status;
+    run_trap(state, "EXIT");
+    state.traps.remove("EXIT");
+    state.exited = Some(status);
+}
+
+/// Ends the shell with `status`, taking the same steps in the same order
+/// on every way out: the `EXIT` trap runs first, then the commands read
+/// this session are appended to `HISTFILE`, then jobs are sent SIGHUP
+/// when a login shell with `huponexit` set or a hung-up shell exits, and
+/// last the terminal goes back to the process group that had it. Only the
+/// trap runs in subshells. A hangup is re-raised at the end, so whoever
+/// waits for the shell sees it killed by SIGHUP.
+fn shutdown(state: &mut ShellState, status: i32) -> ! {
+    let _ = io::stdout().flush();
+    // `$?` in the trap is the status the shell exits with
+    state.last_status = status;
+    run_trap(state, "EXIT");
+    state.traps.remove("EXIT");
+    let hung_up = HUNG_UP.load(Ordering::Relaxed);
+    if state.interactive && state.subshell_depth == 0 {
+        if let Some(path) = state.variable("HISTFILE") {
+            if let Err(e) = state.history.append(&path) {
+                println_err!(
+                    state,
+                    "history: {}: {}",
+                    prompt::sanitize(&path),
+                    error_text(&e)
+                );
+            }
+        }
+        if hung_up || state.shopt("login_shell") && state.shopt("huponexit") {
+            for job in state.jobs.iter() {
+                let _ = job.signal(libc::SIGHUP);
+                let _ = job.signal(libc::SIGCONT);
+            }
+        }
+        if let Some(owner) = state.terminal_owner {
+            jobs::give_terminal(owner);
+        }
+    }
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    if hung_up {
+        unsafe {
+            libc::signal(libc::SIGHUP, libc::SIG_DFL);
+            libc::raise(libc::SIGHUP);
+        }
+    }
+    std::process::exit(status);
+}
+
+fn echo_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let mut newline = true;
+    let mut escapes = false;
+    let mut words = args;
+    // Flags are only recognised as a leading run of `-n`/`-e`/`-E` words;
+    // posix mode only knows `-n`
+    let allowed = if state.option("posix") { "n" } else { "neE" };
+    while let Some(flags) = words.first().and_then(|word| word.strip_prefix('-')) {
+        if flags.is_empty() || !flags.chars().all(|flag| allowed.contains(flag)) {
+            break;
+        }
+        for flag in flags.chars() {
+            match flag {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        words = &words[1..];
+    }
+
+    let text = words.join(" ");
+    let (mut bytes, stop) = if escapes {
+        expand::decode_escape_bytes(&text, expand::Escapes::Echo)
+    } else {
+        (expand::text_to_bytes(&text), false)
+    };
+    newline &= !stop;
+    if newline {
+        bytes.push(b'\n');
+    }
+    output.add_bytes(&bytes, false);
+    0
+}
+
+fn type_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    if args.first() == Some(&"-a") {
+        return type_all(&args[1..], state, output);
+    }
+    if args.is_empty() {
+        output.add("type: missing argument", true);
+        return 1;
+    }
+    if args.len() > 1 {
+        output.add("type: too many arguments", true);
+        return 1;
+    }
+    if let Some(value) = state.aliases.get(args[0]).filter(|_| !state.json_output) {
+        output.add(&format!("{} is aliased to `{}'", args[0], value), false);
+        return 0;
+    }
+    let command = resolve_command(state, args[0]);
+    if state.json_output {
+        return type_json(state, args[0], command, output);
+    }
+    match command {
+        Some(Command::Function(function)) => {
+            output.add(&format!("{} is a function", args[0]), false);
+            output.add(&format!("{} () {{ {} }}", args[0], function.body), false);
+            0
+        }
+        Some(Command::BuiltinCommand(builtin)) => {
+            if builtin.is_special() && state.option("posix") {
+                output.add(&format!("{} is a special shell builtin", args[0]), false);
+            } else {
+                output.add(&format!("{} is a shell builtin", args[0]), false);
+            }
+            0
+        }
+        Some(Command::ExecutableCommand(executable)) => {
+            output.add(&format!("{} is {}", args[0], executable.path), false);
+            0
+        }
+        None => {
+            output.add(&format!("{}: not found", prompt::sanitize(args[0])), true);
+            for line in path_diagnostics(state, args[0]) {
+                output.add(&line, true);
+            }
+            1
+        }
+    }
+}
+
+/// `type` in the machine-readable mode: `{"name":"echo","kind":"builtin"}`.
+/// A name that is not found is reported as usual.
+fn type_json(state: &ShellState, name: &str, command: Option<Command>, output: &mut Output) -> i32 {
+    let object = json::Object::new().string("name", name);
+    let object = match command {
+        Some(Command::Function(function)) => object
+            .string("kind", "function")
+            .string("body", &function.body),
+        Some(Command::BuiltinCommand(builtin)) => object
+            .string("kind", "builtin")
+            .boolean("special", builtin.is_special() && state.option("posix")),
+        Some(Command::ExecutableCommand(executable)) => object
+            .string("kind", "file")
+            .string("path", &executable.path),
+        None => {
+            output.add(&format!("{}: not found", prompt::sanitize(name)), true);
+            return 1;
+        }
+    };
+    output.add(&object.finish(), false);
+    0
+}
+
+/// `type -a`: every function, builtin and `PATH` entry providing each name.
+fn type_all(names: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let mut status = 0;
+    let mut path_cache = state.path_cache.borrow_mut();
+    path_cache.refresh(&search_path(state));
+    for name in names {
+        let mut found = false;
+        let object = || json::Object::new().string("name", name);
+        if state.functions.contains_key(*name) {
+            let line = match state.json_output {
+                true => object().string("kind", "function").finish(),
+                false => format!("{} is a function", name),
+            };
+            output.add(&line, false);
+            found = true;
+        }
+        if state.builtin(name).is_some() {
+            let line = match state.json_output {
+                true => object().string("kind", "builtin").finish(),
+                false => format!("{} is a shell builtin", name),
+            };
+            output.add(&line, false);
+            found = true;
+        }
+        for path in path_cache.paths(name) {
+            let line = match state.json_output {
+                true => object()
+                    .string("kind", "file")
+                    .string("path", path)
+                    .finish(),
+                false => format!("{} is {}", name, path),
+            };
+            output.add(&line, false);
+            found = true;
+        }
+        if !found {
+            output.add(&format!("{}: not found", prompt::sanitize(name)), true);
+            for line in path_diagnostics(state, name) {
+                output.add(&line, true);
+            }
+            status = 1;
+        }
+    }
+    status
+}
+
+fn pwd_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let mut physical = false;
+    for arg in args {
+        match *arg {
+            "-P" => physical = true,
+            "-L" => physical = false,
+            _ => {
+                output.add("pwd: too many arguments", true);
+                return 1;
+            }
+        }
+    }
+    if !physical {
+        if let Some(logical) = logical_pwd(state) {
+            output.add(&logical, false);
+            return 0;
+        }
+    }
+    let current_dir = std::env::current_dir().and_then(std::fs::canonicalize);
+    if current_dir.is_err() {
+        output.add("pwd: unable to get current directory", true);
+        return 1;
+    }
+    output.add(&current_dir.unwrap().display().to_string(), false);
+    0
+}
+
+/// Returns `$PWD` if it is an absolute path naming the current directory.
+fn logical_pwd(state: &ShellState) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    let pwd = state.variable("PWD")?;
+    if !paths::is_absolute(&pwd) {
+        return None;
+    }
+    let pwd_meta = std::fs::metadata(&pwd).ok()?;
+    let cwd_meta = std::fs::metadata(".").ok()?;
+    if pwd_meta.dev() == cwd_meta.dev() && pwd_meta.ino() == cwd_meta.ino() {
+        Some(pwd)
+    } else {
+        None
+    }
+}
+
+/// The logical `$PWD` when it is still valid, otherwise the physical
+/// working directory.
+fn working_directory(state: &ShellState) -> Option<String> {
+    logical_pwd(state).or_else(|| {
+        std::env::current_dir()
+            .ok()
+            .map(|dir| dir.display().to_string())
+    })
+}
+
+/// Lexically resolves `.` and `..` components of `path` against `base`
+/// without following symlinks.
+fn normalize_logical_path(base: &str, path: &str) -> String {
+    paths::normalize(base, path)
+}
+
+/// The directory stack as `dirs` shows it: the working directory, then
+/// the directories saved by `pushd`.
+fn directory_stack(state: &ShellState) -> Vec<String> {
+    std::iter::once(working_directory(state).unwrap_or_default())
+        .chain(state.dir_stack.iter().cloned())
+        .collect()
+}
+
+/// Prints the directory stack on one line, with the home directory as `~`.
+fn print_directory_stack(state: &ShellState, output: &mut Output) {
+    let home = home_dir(state);
+    let entries: Vec<String> = directory_stack(state)
+        .into_iter()
+        .map(|dir| tilde_abbreviate(&dir, home.as_deref()))
+        .collect();
+    output.add(&entries.join(" "), false);
+}
+
+fn tilde_abbreviate(dir: &str, home: Option<&str>) -> String {
+    match home.and_then(|home| dir.strip_prefix(home)) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{}", rest),
+        _ => dir.to_string(),
+    }
+}
+
+/// Parses a `+N` or `-N` stack position into an index from the left of a
+/// stack of `len` entries. `Err` for arguments that are not positions,
+/// `Ok(None)` for positions out of range.
+fn stack_index(arg: &str, len: usize) -> Result<Option<usize>, ()> {
+    let (from_left, digits) = match arg.split_at(arg.len().min(1)) {
+        ("+", digits) => (true, digits),
+        ("-", digits) => (false, digits),
+        _ => return Err(()),
+    };
+    let n = digits.parse::<usize>().map_err(|_| ())?;
+    if n >= len {
+        return Ok(None);
+    }
+    Ok(Some(if from_left { n } else { len - 1 - n }))
+}
+
+/// Makes `stack` the directory stack, changing to its first entry unless
+/// that is already the working directory. The stack is left alone when
+/// the directory cannot be entered.
+fn set_directory_stack(
+    state: &mut ShellState,
+    name: &str,
+    mut stack: Vec<String>,
+    change: bool,
+    output: &mut Output,
+) -> i32 {
+    if change {
+        let target = stack[0].clone();
+        if let Err(e) = change_dir(state, &target, false, output) {
+            output.add(
+                &format!("{}: {}: {}", name, prompt::sanitize(&target), e),
+                true,
+            );
+            return 1;
+        }
+        stack[0] = working_directory(state).unwrap_or(target);
+    }
+    state.dir_stack = stack.split_off(1);
+    print_directory_stack(state, output);
+    0
+}
+
+/// `pushd [-n] [DIR | +N | -N]` saves the working directory and changes
+/// to `DIR`, rotates entry `N` of the stack to the top, or without
+/// arguments swaps the top two entries. `-n` only changes the stack.
+fn pushd_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let no_change = args.first() == Some(&"-n");
+    let args = if no_change { &args[1..] } else { args };
+    let mut stack = directory_stack(state);
+    match args {
+        [] => {
+            if stack.len() < 2 {
+                output.add("pushd: no other directory", true);
+                return 1;
+            }
+            stack.swap(0, 1);
+            set_directory_stack(state, "pushd", stack, !no_change, output)
+        }
+        [arg] => match stack_index(arg, stack.len()) {
+            Ok(Some(index)) => {
+                if no_change {
+                    // Only the saved entries turn; the top stays put
+                    let rest = &mut stack[1..];
+                    rest.rotate_left(index.saturating_sub(1) % rest.len().max(1));
+                } else {
+                    stack.rotate_left(index);
+                }
+                set_directory_stack(state, "pushd", stack, !no_change && index != 0, output)
+            }
+            Ok(None) => {
+                output.add(
+                    &format!(
+                        "pushd: {}: directory stack index out of range",
+                        prompt::sanitize(arg)
+                    ),
+                    true,
+                );
+                1
+            }
+            Err(()) if arg.starts_with('-') && arg.len() > 1 => {
+                output.add(
+                    &format!("pushd: {}: invalid option", prompt::sanitize(arg)),
+                    true,
+                );
+                2
+            }
+            Err(()) => {
+                if no_change {
+                    stack.insert(1, arg.to_string());
+                } else {
+                    stack.insert(0, arg.to_string());
+                }
+                set_directory_stack(state, "pushd", stack, !no_change, output)
+            }
+        },
+        _ => {
+            output.add("pushd: too many arguments", true);
+            1
+        }
+    }
+}
+
+/// `popd [-n] [+N | -N]` removes the top of the directory stack and
+/// changes to the new top, or removes entry `N`. `-n` removes the entry
+/// below the top instead and never changes directory.
+fn popd_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let no_change = args.first() == Some(&"-n");
+    let args = if no_change { &args[1..] } else { args };
+    let mut stack = directory_stack(state);
+    if stack.len() < 2 {
+        output.add("popd: directory stack empty", true);
+        return 1;
+    }
+    let index = match args {
+        [] => usize::from(no_change),
+        [arg] => match stack_index(arg, stack.len()) {
+            Ok(Some(0)) if no_change => 1,
+            Ok(Some(index)) => index,
+            Ok(None) => {
+                output.add(
+                    &format!(
+                        "popd: {}: directory stack index out of range",
+                        prompt::sanitize(arg)
+                    ),
+                    true,
+                );
+                return 1;
+            }
+            Err(()) => {
+                output.add(
+                    &format!("popd: {}: invalid argument", prompt::sanitize(arg)),
+                    true,
+                );
+                return 2;
+            }
+        },
+        _ => {
+            output.add("popd: too many arguments", true);
+            return 1;
+        }
+    };
+    stack.remove(index);
+    set_directory_stack(state, "popd", stack, index == 0, output)
+}
+
+/// `dirs [-c] [-l] [-p] [-v]` prints the directory stack: on one line, one
+/// per line with `-p`, numbered with `-v`, without `~` with `-l`. `-c`
+/// clears it.
+fn dirs_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let (mut long, mut per_line, mut numbered) = (false, false, false);
+    for arg in args {
+        let Some(flags) = arg.strip_prefix('-').filter(|flags| !flags.is_empty()) else {
+            output.add(
+                &format!("dirs: {}: invalid argument", prompt::sanitize(arg)),
+                true,
+            );
+            return 2;
+        };
+        for flag in flags.chars() {
+            match flag {
+                'c' => {
+                    state.dir_stack.clear();
+                    return 0;
+                }
+                'l' => long = true,
+                'p' => per_line = true,
+                'v' => (per_line, numbered) = (true, true),
+                _ => {
+                    output.add(
+                        &format!(
+                            "dirs: -{}: invalid option",
+                            prompt::sanitize(&flag.to_string())
+                        ),
+                        true,
+                    );
+                    return 2;
+                }
+            }
+        }
+    }
+    let home = if long { None } else { home_dir(state) };
+    let entries: Vec<String> = directory_stack(state)
+        .into_iter()
+        .map(|dir| tilde_abbreviate(&dir, home.as_deref()))
+        .collect();
+    if !per_line {
+        output.add(&entries.join(" "), false);
+    } else {
+        for (index, entry) in entries.iter().enumerate() {
+            if numbered {
+                output.add(&format!("{:>2}  {}", index, entry), false);
+            } else {
+                output.add(entry, false);
+            }
+        }
+    }
+    0
+}
+
+/// `realpath [-e|-E|-m] [-L|-P|-s] [-q] [-z] PATH ...` prints each path
+/// absolute, with symlinks, `.` and `..` resolved, as GNU `realpath`
+/// does. By default, `-E`, all but the last component must exist; `-e`
+/// wants all of them and `-m` none. `-s` keeps symlinks and `-L` resolves
+/// `..` before them. `-q` leaves out errors and `-z` ends each path with
+/// a NUL rather than a newline.
+fn realpath_fn(args: &[&str], _state: &mut ShellState, output: &mut Output) -> i32 {
+    let mut existence = Existence::AllButLast;
+    let mut symlinks = Symlinks::Physical;
+    let mut quiet = false;
+    let mut zero = false;
+    let mut paths = Vec::new();
+    let mut options = true;
+    for arg in args {
+        let letters = match *arg {
+            "--" if options => {
+                options = false;
+                continue;
+            }
+            "--canonicalize-existing" => "e",
+            "--canonicalize" => "E",
+            "--canonicalize-missing" => "m",
+            "--logical" => "L",
+            "--physical" => "P",
+            "--strip" | "--no-symlinks" => "s",
+            "--quiet" => "q",
+            "--zero" => "z",
+            flag if options && flag.starts_with("--") => {
+                let flag = prompt::sanitize(flag);
+                output.add(&format!("realpath: unrecognized option '{}'", flag), true);
+                return 1;
+            }
+            flag if options && flag.starts_with('-') && flag.len() > 1 => &flag[1..],
+            path => {
+                paths.push(path);
+                continue;
+            }
+        };
+        for letter in letters.chars() {
+            match letter {
+                'e' => existence = Existence::All,
+                'E' => existence = Existence::AllButLast,
+                'm' => existence = Existence::None,
+                'L' => symlinks = Symlinks::Logical,
+                'P' => symlinks = Symlinks::Physical,
+                's' => symlinks = Symlinks::Kept,
+                'q' => quiet = true,
+                'z' => zero = true,
+                _ => {
+                    let letter = prompt::sanitize(&letter.to_string());
+                    output.add(&format!("realpath: invalid option -- '{}'", letter), true);
+                    return 1;
+                }
+            }
+        }
+    }
+    if paths.is_empty() {
+        output.add("realpath: missing operand", true);
+        return 1;
+    }
+
+    let mut status = 0;
+    for path in paths {
+        match resolve_path(path, existence, symlinks) {
+            Ok(resolved) if zero => {
+                use std::os::unix::ffi::OsStringExt;
+
+                let mut bytes = resolved.into_os_string().into_vec();
+                bytes.push(0);
+                output.add_bytes(&bytes, false);
+            }
+            Ok(resolved) => output.add(&resolved.display().to_string(), false),
+            Err(e) => {
+                if !quiet {
+                    let path = prompt::sanitize(path);
+                    output.add(&format!("realpath: {}: {}", path, error_text(&e)), true);
+                }
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+/// How much of a path `resolve_path` needs to exist.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Existence {
+    All,
+    AllButLast,
+    None,
+}
+
+/// How `resolve_path` treats symlinks: followed as they come, followed
+/// after resolving `..` lexically, or kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Symlinks {
+    Physical,
+    Logical,
+    Kept,
+}
+
+/// Makes `path` absolute against the working directory and resolves it
+/// component by component, like GNU `realpath`. Past the first missing
+/// component, when `existence` allows one, the rest is added lexically.
+fn resolve_path(
+    path: &str,
+    existence: Existence,
+    symlinks: Symlinks,
+) -> io::Result<std::path::PathBuf> {
+    use std::path::PathBuf;
+
+    // Components still to resolve, the next one last: names, `..` and `/`
+    fn components(path: &std::path::Path) -> impl Iterator<Item = std::ffi::OsString> + '_ {
+        path.components().rev().map(|c| c.as_os_str().to_owned())
+    }
+
+    if path.is_empty() {
+        return Err(io::Error::from_raw_os_error(libc::ENOENT));
+    }
+    let cwd = std::env::current_dir()?;
+    let path = match symlinks {
+        Symlinks::Logical => PathBuf::from(paths::normalize(&cwd.to_string_lossy(), path)),
+        _ => cwd.join(path),
+    };
+    let mut pending: Vec<_> = components(&path).collect();
+    let mut resolved = PathBuf::from("/");
+    let mut missing = false;
+    let mut links = 0;
+    while let Some(name) = pending.pop() {
+        if name == "/" {
+            resolved = PathBuf::from("/");
+            continue;
+        } else if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        resolved.push(&name);
+        if missing {
+            continue;
+        }
+        let last = pending.is_empty();
+        match std::fs::symlink_metadata(&resolved) {
+            Ok(meta) if meta.file_type().is_symlink() && symlinks != Symlinks::Kept => {
+                links += 1;
+                if links > 40 {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                }
+                let target = std::fs::read_link(&resolved)?;
+                resolved.pop();
+                pending.extend(components(&target));
+            }
+            Ok(_) if last => {}
+            Ok(_) => match std::fs::metadata(&resolved) {
+                Ok(meta) if meta.is_dir() => {}
+                _ if existence == Existence::None => missing = true,
+                _ => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+            },
+            Err(_) if existence == Existence::None => missing = true,
+            Err(e)
+                if existence == Existence::AllButLast
+                    && e.kind() == io::ErrorKind::NotFound
+                    && (last || symlinks == Symlinks::Kept) =>
+            {
+                missing = true;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(resolved)
+}
+
+/// What went wrong with a file, as commands print it: the text of an OS
+/// error without the "(os error N)" Rust adds.
+fn error_text(error: &io::Error) -> String {
+    let text = error.to_string();
+    match error.raw_os_error() {
+        Some(code) => text
+            .strip_suffix(&format!(" (os error {})", code))
+            .unwrap_or(&text)
+            .to_string(),
+        None => text,
+    }
+}
+
+/// Changes the working directory and keeps `PWD`/`OLDPWD` in sync. By
+/// default `..` is resolved against the logical `$PWD`, so it leaves a
+/// symlinked directory the way it was entered; when that path no longer
+/// resolves, or with `physical`, symlinks are followed instead. So are
+/// they for `..` once `$PWD` itself no longer leads to the working
+/// directory, say because a symlink on it was removed.
+fn change_dir(
+    state: &mut ShellState,
+    target: &str,
+    physical: bool,
+    output: &mut Output,
+) -> io::Result<()> {
+    let old_pwd = working_directory(state);
+    let climbs = !paths::is_absolute(target) && target.split('/').any(|part| part == "..");
+    let stale_pwd = match state.variable("PWD") {
+        Some(pwd) if !physical && climbs && logical_pwd(state).is_none() => Some(pwd),
+        _ => None,
+    };
+    let logical = match &old_pwd {
+        Some(base) if !physical && stale_pwd.is_none() => {
+            Some(normalize_logical_path(base, target))
+        }
+        _ => None,
+    };
+    let new_pwd = match logical {
+        Some(logical) if std::env::set_current_dir(&logical).is_ok() => logical,
+        logical => {
+            std::env::set_current_dir(target)?;
+            if let Some(path) = logical.or(stale_pwd) {
+                output.add(
+                    &format!(
+                        "cd: warning: {}: no such directory, using the physical path",
+                        prompt::sanitize(&path)
+                    ),
+                    true,
+                );
+            }
+            std::env::current_dir()?.display().to_string()
+        }
+    };
+    if let Some(old_pwd) = old_pwd {
+        state.set_variable("OLDPWD", &old_pwd);
+    }
+    state.set_variable("PWD", &new_pwd);
+    Ok(())
+}
+
+/// Looks a relative directory up under the non-empty entries of `$CDPATH`.
+/// Paths starting with `/`, `.` or `..` are never searched.
+fn search_cdpath(state: &ShellState, target: &str) -> Option<String> {
+    if paths::is_absolute(target) || target.starts_with('.') {
+        return None;
+    }
+    let cdpath = state.variable("CDPATH")?;
+    let found = paths::split_list(&cdpath)
+        .filter(|base| !base.is_empty())
+        .map(|base| paths::join(base, target))
+        .find(|candidate| std::path::Path::new(candidate).is_dir());
+    found
+}
+
+fn cd_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let mut physical = false;
+    let mut args = args;
+    while let Some((first, rest)) = args.split_first() {
+        match *first {
+            "-L" => physical = false,
+            "-P" => physical = true,
+            "--" => {
+                args = rest;
+                break;
+            }
+            _ => break,
+        }
+        args = rest;
+    }
+    if args.is_empty() {
+        // If no args provided, change to HOME directory
+        if let Some(home) = home_dir(state) {
+            if change_dir(state, &home, physical, output).is_err() {
+                output.add(
+                    &format!("cd: {}: No such file or directory", prompt::sanitize(&home)),
+                    true,
+                );
+                return 1;
+            }
+            return 0;
+        }
+        output.add("cd: unable to get home directory", true);
+        return 1;
+    }
+    if args.len() > 1 {
+        output.add("cd: too many arguments", true);
+        return 1;
+    }
+    let new_dir = if args[0] == "~" {
+        home_dir(state)
+    } else {
+        Some(args[0].to_string())
+    };
+    let Some(new_dir) = new_dir else {
+        output.add("cd: unable to get home directory", true);
+        return 1;
+    };
+    if let Some(found) = search_cdpath(state, &new_dir) {
+        if change_dir(state, &found, physical, output).is_ok() {
+            // Like bash, show where a `CDPATH` lookup led
+            if let Some(pwd) = state.variable("PWD") {
+                output.add(&pwd, false);
+            }
+            return 0;
+        }
+    }
+    match change_dir(state, &new_dir, physical, output) {
+        Ok(()) => 0,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) => {
+            // With `cd_file_parent`, a file leads to its directory
+            let parent = std::path::Path::new(&new_dir)
+                .parent()
+                .map(|parent| parent.display().to_string())
+                .filter(|parent| !parent.is_empty())
+                .unwrap_or_else(|| ".".to_string());
+            let is_file = std::path::Path::new(&new_dir).is_file();
+            if state.shopt("cd_file_parent")
+                && is_file
+                && change_dir(state, &parent, physical, output).is_ok()
+            {
+                output.add(
+                    &format!(
+                        "cd: {}: not a directory, using {}",
+                        prompt::sanitize(&new_dir),
+                        prompt::sanitize(&parent)
+                    ),
+                    true,
+                );
+                return 0;
+            }
+            output.add(
+                &format!("cd: {}: Not a directory", prompt::sanitize(&new_dir)),
+                true,
+            );
+            1
+        }
+        Err(_) => {
+            // With `cd_correct`, offer a sibling one edit away
+            if state.shopt("cd_correct") && state.interactive {
+                if let Some(corrected) = correct_directory(&new_dir) {
+                    let question = format!(
+                        "cd: no such dir '{}'. Did you mean '{}'? [y/N] ",
+                        new_dir, corrected
+                    );
+                    if confirm(state, &question)
+                        && change_dir(state, &corrected, physical, output).is_ok()
+                    {
+                        return 0;
+                    }
+                }
+            }
+            output.add(
+                &format!(
+                    "cd: {}: No such file or directory",
+                    prompt::sanitize(&new_dir)
+                ),
+                true,
+            );
+            1
+        }
+    }
+}
+
+/// A directory next to the missing `path` whose name is at most one edit
+/// away from its last component, preferring the closest and then the
+/// first by name.
+fn correct_directory(path: &str) -> Option<String> {
+    let path = std::path::Path::new(path);
+    let name = path.file_name()?.to_str()?;
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty());
+    let entries = std::fs::read_dir(parent.unwrap_or(std::path::Path::new("."))).ok()?;
+    let mut candidates: Vec<(usize, String)> = entries
+        .flatten()
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .map(|candidate| (edit_distance(name, &candidate), candidate))
+        .filter(|(distance, _)| *distance <= 1)
+        .collect();
+    candidates.sort();
+    let (_, candidate) = candidates.into_iter().next()?;
+    Some(match parent {
+        Some(parent) => parent.join(candidate).display().to_string(),
+        None => candidate,
+    })
+}
+
+/// Levenshtein distance between `a` and `b`, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+fn trap_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    if args.is_empty() || args[0] == "-p" {
+        let wanted: Vec<_> = args.iter().skip(1).map(|arg| trap_name(arg)).collect();
+        for (signal, handler) in &state.traps {
+            if !wanted.is_empty() && !wanted.contains(&Some(signal.clone())) {
+                continue;
+            }
+            output.add(
+                &format!("trap -- {} {}", shell_words::quote(handler), signal),
+                false,
+            );
+        }
+        return 0;
+    }
+    // A lone operand or `-` resets the trap, an empty string ignores it
+    let (action, signals) = if args.len() == 1 {
+        ("-", args)
+    } else {
+        (args[0], &args[1..])
+    };
+    let mut status = 0;
+    for signal in signals {
+        let Some(name) = trap_name(signal) else {
+            output.add(
+                &format!(
+                    "trap: {}: invalid signal specification",
+                    prompt::sanitize(signal)
+                ),
+                true,
+            );
+            status = 1;
+            continue;
+        };
+        if action == "-" {
+            state.traps.remove(&name);
+        } else {
+            state.traps.insert(name.clone(), action.to_string());
+        }
+        update_disposition(state, &name);
+    }
+    status
+}
+
+/// The name `trap` keeps a signal under: a pseudo-signal as it is, with
+/// number 0 the traditional name for `EXIT`, and a real signal, given by
+/// name or number, as its `SIG` name.
+fn trap_name(spec: &str) -> Option<String> {
+    if spec == "0" {
+        return Some("EXIT".to_string());
+    }
+    let upper = spec.to_ascii_uppercase();
+    if TRAP_SIGNALS.contains(&upper.as_str()) {
+        return Some(upper);
+    }
+    let number = jobs::parse_signal(spec)?;
+    jobs::SIGNALS
+        .iter()
+        .find(|(_, signal)| *signal == number)
+        .map(|(name, _)| format!("SIG{}", name))
+}
+
+/// Sets what the real signal behind trap `name` does to match its trap:
+/// caught for a handler, ignored for an empty one, and without a trap
+/// whatever the shell does on its own.
+fn update_disposition(state: &ShellState, name: &str) {
+    let Some(signal) = name.strip_prefix("SIG").and_then(jobs::parse_signal) else {
+        return;
+    };
+    match state.traps.get(name).map(String::as_str) {
+        Some("") => signals::ignore(signal),
+        Some(_) => signals::catch(signal),
+        None => match signal {
+            libc::SIGWINCH => signals::install(signal, handle_sigwinch),
+            libc::SIGCHLD if state.interactive => signals::install(signal, handle_sigchld),
+            libc::SIGHUP if state.interactive => signals::install(signal, handle_sighup),
+            libc::SIGTSTP | libc::SIGTTIN | libc::SIGTTOU if state.interactive => {
+                signals::ignore(signal)
+            }
+            _ => signals::set_default(signal),
+        },
+    }
+}
+
+fn shopt_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let mut set = None;
+    let mut quiet = false;
+    let mut names = Vec::new();
+    for arg in args {
+        match *arg {
+            "-s" => set = Some(true),
+            "-u" => set = Some(false),
+            "-q" => quiet = true,
+            name => names.push(name),
+        }
+    }
+    for name in &names {
+        if !state.shopts.contains_key(name) {
+            output.add(
+                &format!(
+                    "shopt: {}: invalid shell option name",
+                    prompt::sanitize(name)
+                ),
+                true,
+            );
+            return 1;
+        }
+    }
+
+    if let (Some(value), false) = (set, names.is_empty()) {
+        if let Some(name) = names.iter().find(|name| READONLY_SHOPTS.contains(name)) {
+            output.add(
+                &format!("shopt: {}: cannot be changed", prompt::sanitize(name)),
+                true,
+            );
+            return 1;
+        }
+        for name in names {
+            if let Some(enabled) = state.shopts.get_mut(name) {
+                *enabled = value;
+            }
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for (name, enabled) in &state.shopts {
+        if !names.is_empty() && !names.contains(name) {
+            continue;
+        }
+        if set.is_some_and(|value| value != *enabled) {
+            continue;
+        }
+        if !enabled && !names.is_empty() {
+            status = 1;
+        }
+        if !quiet {
+            let value = if *enabled { "on" } else { "off" };
+            output.add(&format!("{:<15}\t{}", name, value), false);
+        }
+    }
+    status
+}
+
+fn set_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    if args.is_empty() {
+        list_variables(state, output);
+        return 0;
+    }
+
+    let mut i = 0;
+    while i < args.len() {
+        let enable = match args[i] {
+            "-o" => true,
+            "+o" => false,
+            arg => {
+                let (enable, flags) = match arg.split_at(1) {
+                    ("-", flags) if !flags.is_empty() => (true, flags),
+                    ("+", flags) if !flags.is_empty() => (false, flags),
+                    _ => {
+                        output.add(
+                            &format!("set: {}: invalid option", prompt::sanitize(arg)),
+                            true,
+                        );
+                        return 2;
+                    }
+                };
+                for flag in flags.chars() {
+                    let Some((_, name)) = SET_FLAGS.iter().find(|(short, _)| *short == flag) else {
+                        output.add(
+                            &format!(
+                                "set: -{}: invalid option",
+                                prompt::sanitize(&flag.to_string())
+                            ),
+                            true,
+                        );
+                        return 2;
+                    };
+                    state.options.insert(name, enable);
+                }
+                i += 1;
+                continue;
+            }
+        };
+        let Some(name) = args.get(i + 1) else {
+            for (name, enabled) in &state.options {
+                if HIDDEN_SET_OPTIONS.contains(name) {
+                    continue;
+                }
+                if enable {
+                    let value = if *enabled { "on" } else { "off" };
+                    output.add(&format!("{:<15}\t{}", name, value), false);
+                } else {
+                    let flag = if *enabled { '-' } else { '+' };
+                    output.add(&format!("set {}o {}", flag, name), false);
+                }
+            }
+            return 0;
+        };
+        match state.options.get_mut(*name) {
+            Some(value) => *value = enable,
+            None => {
+                output.add(
+                    &format!("set: {}: invalid option name", prompt::sanitize(name)),
+                    true,
+                );
+                return 1;
+            }
+        }
+        i += 2;
+    }
+    0
+}
+
+/// Prints every variable sorted by name, quoted so the listing can be read
+/// back as shell input. Values computed from the call stack are left out;
+/// coprocess arrays are shown as `NAME=([0]="fd" [1]="fd")`.
+fn list_variables(state: &ShellState, output: &mut Output) {
+    let mut variables: Vec<(String, String)> = state
+        .variables
+        .iter()
+        .map(|(name, variable)| {
+            let value = shell_words::quote(&variable.value).into_owned();
+            (name.clone(), value)
+        })
+        .collect();
+    for coproc in &state.coprocs {
+        variables.push((format!("{}_PID", coproc.name), coproc.pid.to_string()));
+        variables.push((
+            coproc.name.clone(),
+            format!(
+                "([0]=\"{}\" [1]=\"{}\")",
+                coproc.raw_fds()[0],
+                coproc.raw_fds()[1]
+            ),
+        ));
+    }
+    variables.sort();
+    for (name, value) in variables {
+        output.add(&format!("{}={}", name, value), false);
+    }
+}
+
+/// `jobs [-lrs] [jobspec ...]` lists the given jobs, or all of them: `-l`
+/// adds process group ids, `-r` keeps only running jobs and `-s` only
+/// stopped ones.
+fn jobs_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let mut long = false;
+    let mut running = false;
+    let mut stopped = false;
+    let mut args = args;
+    while let Some((first, rest)) = args.split_first() {
+        if *first == "--" {
+            args = rest;
+            break;
+        }
+        let Some(flags) = first.strip_prefix('-').filter(|flags| !flags.is_empty()) else {
+            break;
+        };
+        for flag in flags.chars() {
+            match flag {
+                'l' => long = true,
+                'r' => running = true,
+                's' => stopped = true,
+                _ => {
+                    output.add(
+                        &format!(
+                            "jobs: -{}: invalid option",
+                            prompt::sanitize(&flag.to_string())
+                        ),
+                        true,
+                    );
+                    output.add("jobs: usage: jobs [-lrs] [jobspec ...]", true);
+                    return 2;
+                }
+            }
+        }
+        args = rest;
+    }
+
+    let pipefail = state.option("pipefail");
+    state.jobs.reap();
+    release_coprocs(state);
+    let mut status = 0;
+    let ids: Vec<usize> = if args.is_empty() {
+        state.jobs.iter().map(|job| job.id).collect()
+    } else {
+        let mut ids = Vec::new();
+        for spec in args {
+            match state.jobs.resolve(Some(spec)) {
+                Some(id) => ids.push(id),
+                None => {
+                    output.add(
+                        &format!("jobs: {}: no such job", prompt::sanitize(spec)),
+                        true,
+                    );
+                    status = 1;
+                }
+            }
+        }
+        ids
+    };
+    for job in state.jobs.iter().filter(|job| ids.contains(&job.id)) {
+        let shown = match job.state(pipefail) {
+            jobs::JobState::Running => running || !stopped,
+            jobs::JobState::Stopped => stopped || !running,
+            jobs::JobState::Done(_) => !running && !stopped,
+        };
+        if !shown {
+            continue;
+        }
+        let line = if state.json_output {
+            state.jobs.format_json(job, pipefail)
+        } else if long {
+            state.jobs.format_long(job, pipefail)
+        } else {
+            state.jobs.format(job, pipefail)
+        };
+        output.add(&line, false);
+    }
+    // Finished jobs are reported once, here, instead of before the prompt
+    state.jobs.take_finished(pipefail);
+    status
+}
+
+fn fg_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let spec = args.first().copied();
+    let Some(job) = state
+        .jobs
+        .resolve(spec)
+        .and_then(|id| state.jobs.remove(id))
+    else {
+        output.add(
+            &format!(
+                "fg: {}: no such job",
+                prompt::sanitize(spec.unwrap_or("current"))
+            ),
+            true,
+        );
+        return 1;
+    };
+    println_out!(state, "{}", prompt::sanitize(&job.command));
+    let _ = io::stdout().flush();
+    wait_foreground(state, job, true)
+}
+
+fn bg_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let spec = args.first().copied();
+    let Some(id) = state.jobs.resolve(spec) else {
+        output.add(
+            &format!(
+                "bg: {}: no such job",
+                prompt::sanitize(spec.unwrap_or("current"))
+            ),
+            true,
+        );
+        return 1;
+    };
+    let marker = state.jobs.marker(id);
+    let pipefail = state.option("pipefail");
+    let Some(job) = state.jobs.get_mut(id) else {
+        return 1;
+    };
+    if job.state(pipefail) != jobs::JobState::Stopped {
+        output.add(&format!("bg: job {} already in background", id), true);
+        return 0;
+    }
+    if let Err(e) = job.signal(libc::SIGCONT) {
+        output.add(&format!("bg: {}", e), true);
+        return 1;
+    }
+    job.resume();
+    output.add(
+        &format!("[{}]{} {} &", id, marker, prompt::sanitize(&job.command)),
+        false,
+    );
+    0
+}
+
+/// `wait [-n] [-p VAR] [ID ...]` waits for the jobs given as `%job` or
+/// pid and returns the status of the last, `128 + signal` for a killed
+/// one. Without IDs it waits for every job and returns 0; with `-n` for
+/// whichever job finishes first. `-p` stores the pid or ID waited for.
+fn wait_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let mut any = false;
+    let mut variable = None;
+    let mut ids = args;
+    while let Some(flag) = ids
+        .first()
+        .filter(|arg| arg.starts_with('-') && arg.len() > 1)
+    {
+        match *flag {
+            "--" => {
+                ids = &ids[1..];
+                break;
+            }
+            "-n" => any = true,
+            "-p" => {
+                let Some(name) = ids.get(1) else {
+                    output.add("wait: -p: option requires an argument", true);
+                    return 2;
+                };
+                if !is_identifier(name) {
+                    output.add(
+                        &format!("wait: `{}': not a valid identifier", prompt::sanitize(name)),
+                        true,
+                    );
+                    return 2;
+                }
+                variable = Some(*name);
+                ids = &ids[1..];
+            }
+            _ => {
+                output.add(
+                    &format!("wait: {}: invalid option", prompt::sanitize(flag)),
+                    true,
+                );
+                return 2;
+            }
+        }
+        ids = &ids[1..];
+    }
+
+    let pipefail = state.option("pipefail");
+    let status = if any {
+        match state.jobs.wait_any(pipefail) {
+            Some(job) => {
+                if let (Some(name), Some(pid)) = (variable, job.last_pid()) {
+                    state.set_variable(name, &pid.to_string());
+                }
+                match job.state(pipefail) {
+                    jobs::JobState::Done(status) => status,
+                    _ => 0,
+                }
+            }
+            None => 127,
+        }
+    } else if ids.is_empty() {
+        while state.jobs.wait_any(pipefail).is_some() {}
+        state.jobs.clear_statuses();
+        0
+    } else {
+        let mut status = 0;
+        for id in ids {
+            status = wait_for(state, id, output);
+            if let Some(name) = variable {
+                state.set_variable(name, id);
+            }
+        }
+        status
+    };
+    release_coprocs(state);
+    status
+}
+
+/// Waits for one `%job` or pid, falling back to the status kept for a job
+/// that was already reported as done.
+fn wait_for(state: &mut ShellState, id: &str, output: &mut Output) -> i32 {
+    let pipefail = state.option("pipefail");
+    let job_id = if id.starts_with('%') {
+        let Some(job_id) = state.jobs.resolve(Some(id)) else {
+            output.add(
+                &format!("wait: {}: no such job", prompt::sanitize(id)),
+                true,
+            );
+            return 127;
+        };
+        job_id
+    } else {
+        let Ok(pid) = id.parse::<libc::pid_t>() else {
+            output.add(
+                &format!(
+                    "wait: `{}': not a pid or valid job spec",
+                    prompt::sanitize(id)
+                ),
+                true,
+            );
+            return 2;
+        };
+        let job_id = state
+            .jobs
+            .iter()
+            .find(|job| job.pids.contains(&pid))
+            .map(|job| job.id);
+        match job_id {
+            Some(job_id) => job_id,
+            None => {
+                if let Some(status) = state.jobs.take_status(pid) {
+                    return status;
+                }
+                output.add(
+                    &format!("wait: pid {} is not a child of this shell", pid),
+                    true,
+                );
+                return 127;
+            }
+        }
+    };
+    let Some(mut job) = state.jobs.remove(job_id) else {
+        return 127;
+    };
+    match job.wait(pipefail) {
+        jobs::JobState::Done(status) => status,
+        _ => {
+            state.jobs.insert(job);
+            128 + libc::SIGTSTP
+        }
+    }
+}
+
+/// `basename NAME [SUFFIX]` prints the last component of `NAME`, without
+/// `SUFFIX` unless that is all there is.
+fn basename_fn(args: &[&str], _state: &mut ShellState, output: &mut Output) -> i32 {
+    let (name, suffix) = match args {
+        [] => {
+            output.add("basename: missing operand", true);
+            return 1;
+        }
+        [name] => (*name, None),
+        [name, suffix] => (*name, Some(*suffix)),
+        [_, _, extra, ..] => {
+            output.add(
+                &format!("basename: extra operand '{}'", prompt::sanitize(extra)),
+                true,
+            );
+            return 1;
+        }
+    };
+    let base = basename(name);
+    let base = match suffix.and_then(|suffix| base.strip_suffix(suffix)) {
+        Some(stripped) if !stripped.is_empty() => stripped,
+        _ => base,
+    };
+    output.add(base, false);
+    0
+}
+
+/// `dirname NAME ...` prints each `NAME` without its last component, `.`
+/// when there is nothing left.
+fn dirname_fn(args: &[&str], _state: &mut ShellState, output: &mut Output) -> i32 {
+    if args.is_empty() {
+        output.add("dirname: missing operand", true);
+        return 1;
+    }
+    for name in args {
+        output.add(dirname(name), false);
+    }
+    0
+}
+
+/// Last component of `path`, ignoring trailing slashes: `/` for the root
+/// and empty for an empty path, as coreutils does.
+fn basename(path: &str) -> &str {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        return if path.is_empty() { "" } else { "/" };
+    }
+    trimmed.rsplit('/').next().unwrap_or(trimmed)
+}
+
+/// `path` without its last component and the slashes before it.
+fn dirname(path: &str) -> &str {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        return if path.is_empty() { "." } else { "/" };
+    }
+    match trimmed.rfind('/') {
+        Some(index) => match trimmed[..index].trim_end_matches('/') {
+            "" => "/",
+            parent => parent,
+        },
+        None => ".",
+    }
+}
+
+/// Line, word and byte counts of one input of `wc`.
+#[derive(Default, Clone, Copy)]
+struct Counts {
+    lines: usize,
+    words: usize,
+    bytes: usize,
+}
+
+impl Counts {
+    fn of(mut input: impl io::Read) -> io::Result<Self> {
+        let mut counts = Self::default();
+        let mut in_word = false;
+        let mut buffer = [0; 8192];
+        loop {
+            let len = match input.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            counts.bytes += len;
+            for byte in &buffer[..len] {
+                if *byte == b'\n' {
+                    counts.lines += 1;
+                }
+                let is_space = byte.is_ascii_whitespace() || *byte == 0x0b;
+                if !is_space && !in_word {
+                    counts.words += 1;
+                }
+                in_word = !is_space;
+            }
+        }
+        Ok(counts)
+    }
+}
+
+/// `wc [-lwc] [FILE ...]` counts lines, words and bytes of each file, or
+/// of its standard input without files or for `-`. Several files get a
+/// `total` line.
+fn wc_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let (mut lines, mut words, mut bytes) = (false, false, false);
+    let mut files = Vec::new();
+    let mut options_done = false;
+    for arg in args {
+        match *arg {
+            "--" if !options_done => options_done = true,
+            "--lines" if !options_done => lines = true,
+            "--words" if !options_done => words = true,
+            "--bytes" if !options_done => bytes = true,
+            flags if !options_done && flags.len() > 1 && flags.starts_with('-') => {
+                for flag in flags[1..].chars() {
+                    match flag {
+                        'l' => lines = true,
+                        'w' => words = true,
+                        'c' => bytes = true,
+                        _ => {
+                            output.add(
+                                &format!(
+                                    "wc: invalid option -- '{}'",
+                                    prompt::sanitize(&flag.to_string())
+                                ),
+                                true,
+                            );
+                            return 1;
+                        }
+                    }
+                }
+            }
+            file => files.push(file),
+        }
+    }
+    if !(lines || words || bytes) {
+        (lines, words, bytes) = (true, true, true);
+    }
+
+    let reads_stdin = files.is_empty() || files.contains(&"-");
+    let mut status = 0;
+    let mut results: Vec<(Counts, Option<&str>)> = Vec::new();
+    if files.is_empty() {
+        match Counts::of(state.take_input()) {
+            Ok(counts) => results.push((counts, None)),
+            Err(e) => {
+                output.add(&format!("wc: -: {}", e), true);
+                return 1;
+            }
+        }
+    }
+    for file in &files {
+        let counts = if *file == "-" {
+            Counts::of(state.take_input())
+        } else {
+            std::fs::File::open(file).and_then(Counts::of)
+        };
+        match counts {
+            Ok(counts) => results.push((counts, Some(file))),
+            Err(e) => {
+                output.add(&format!("wc: {}: {}", prompt::sanitize(file), e), true);
+                status = 1;
+            }
+        }
+    }
+    if results.len() > 1 {
+        let total = results
+            .iter()
+            .fold(Counts::default(), |total, (counts, _)| Counts {
+                lines: total.lines + counts.lines,
+                words: total.words + counts.words,
+                bytes: total.bytes + counts.bytes,
+            });
+        results.push((total, Some("total")));
+    }
+
+    // Like coreutils: a single count stands alone, stdin gets the default
+    // width, and files line up on their largest count
+    let shown = [lines, words, bytes].iter().filter(|shown| **shown).count();
+    let width = if shown == 1 && results.len() == 1 {
+        1
+    } else if reads_stdin {
+        7
+    } else {
+        results
+            .iter()
+            .map(|(counts, _)| counts.lines.max(counts.words).max(counts.bytes))
+            .max()
+            .unwrap_or(0)
+            .to_string()
+            .len()
+    };
+    for (counts, name) in &results {
+        let mut columns = Vec::new();
+        for (shown, count) in [
+            (lines, counts.lines),
+            (words, counts.words),
+            (bytes, counts.bytes),
+        ] {
+            if shown {
+                columns.push(format!("{:>width$}", count, width = width));
+            }
+        }
+        if let Some(name) = name {
+            columns.push(name.to_string());
+        }
+        output.add(&columns.join(" "), false);
+    }
+    status
+}
+
+/// Sleeps for the sum of the intervals given, each a possibly fractional
+/// number with an optional `s`, `m`, `h` or `d` suffix. Ctrl-C ends it
+/// with status 130; other signals do not extend it.
+fn sleep_fn(args: &[&str], _state: &mut ShellState, output: &mut Output) -> i32 {
+    if args.is_empty() {
+        output.add("sleep: missing operand", true);
+        return 1;
+    }
+    let total = match sleep_total(args) {
+        Ok(total) => total,
+        Err(arg) => {
+            output.add(
+                &format!("sleep: invalid time interval '{}'", prompt::sanitize(arg)),
+                true,
+            );
+            return 1;
+        }
+    };
+
+    if sleep_interruptibly(total) {
+        output.add("", false);
+        return 128 + libc::SIGINT;
+    }
+    0
+}
+
+/// The sum of `sleep`'s intervals, or the first one that is not a valid
+/// interval. Too long a sum, `infinity` included, is as long as possible.
+fn sleep_total<'a>(args: &[&'a str]) -> Result<std::time::Duration, &'a str> {
+    let mut total = std::time::Duration::ZERO;
+    for arg in args {
+        let (number, unit) = match arg.strip_suffix(['s', 'm', 'h', 'd']) {
+            Some(number) => (number, &arg[number.len()..]),
+            None => (*arg, "s"),
+        };
+        let scale = match unit {
+            "m" => 60.0,
+            "h" => 3600.0,
+            "d" => 86400.0,
+            _ => 1.0,
+        };
+        match number.parse::<f64>() {
+            Ok(seconds) if seconds >= 0.0 => {
+                total = total.saturating_add(seconds_to_duration(seconds * scale));
+            }
+            _ => return Err(arg),
+        }
+    }
+    Ok(total)
+}
+
+/// `seconds`, which must not be negative or NaN, as a duration, saturated
+/// at the longest one.
+fn seconds_to_duration(seconds: f64) -> std::time::Duration {
+    std::time::Duration::try_from_secs_f64(seconds).unwrap_or(std::time::Duration::MAX)
+}
+
+/// When a sleep of `duration` starting now ends, or `None` when that is
+/// past the end of the clock's range and the sleep never ends.
+fn sleep_deadline(duration: std::time::Duration) -> Option<std::time::Instant> {
+    std::time::Instant::now().checked_add(duration)
+}
+
+/// Longest single nap of `sleep_interruptibly`, well within `time_t`.
+const LONGEST_NAP: std::time::Duration = std::time::Duration::from_secs(86400);
+
+/// Sleeps for `duration` or until SIGINT arrives, returning whether it was
+/// interrupted.
+fn sleep_interruptibly(duration: std::time::Duration) -> bool {
+    let deadline = sleep_deadline(duration);
+    SLEEP_INTERRUPTED.store(false, Ordering::Relaxed);
+    let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+    unsafe {
+        // No SA_RESTART, so the signal cuts nanosleep short
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction =
+            handle_sleep_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, &mut previous);
+    }
+    let mut interrupted = false;
+    loop {
+        let remaining = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(std::time::Instant::now()) {
+                Some(remaining) => remaining,
+                None => break,
+            },
+            None => std::time::Duration::MAX,
+        };
+        if SLEEP_INTERRUPTED.load(Ordering::Relaxed) {
+            interrupted = true;
+            break;
+        }
+        if remaining.is_zero() {
+            break;
+        }
+        let remaining = remaining.min(LONGEST_NAP);
+        let request = libc::timespec {
+            tv_sec: remaining.as_secs() as libc::time_t,
+            tv_nsec: remaining.subsec_nanos() as libc::c_long,
+        };
+        // Interrupted by SIGCHLD or SIGWINCH: sleep for what is left
+        unsafe { libc::nanosleep(&request, std::ptr::null_mut()) };
+    }
+    unsafe { libc::sigaction(libc::SIGINT, &previous, std::ptr::null_mut()) };
+    interrupted
+}
+
+/// Erases the screen and moves the cursor to its top left corner.
+const CLEAR_SCREEN: &str = "\x1b[2J\x1b[H";
+
+/// Seconds between runs of `watch` without `-n`.
+const DEFAULT_WATCH_INTERVAL: f64 = 2.0;
+
+/// `watch [-n SECONDS] command...` clears the screen and runs the command
+/// every `SECONDS` seconds, under a header with the interval, the command
+/// and the time, until interrupted with Ctrl-C.
+fn watch_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let mut interval = DEFAULT_WATCH_INTERVAL;
+    let mut args = args;
+    if let Some(option) = args.first().and_then(|arg| arg.strip_prefix("-n")) {
+        let (value, rest) = if option.is_empty() {
+            (args.get(1).copied(), args.get(2..).unwrap_or(&[]))
+        } else {
+            (Some(option), &args[1..])
+        };
+        match value.map(str::parse::<f64>) {
+            Some(Ok(seconds)) if seconds > 0.0 => interval = seconds,
+            _ => {
+                output.add(
+                    &format!(
+                        "watch: invalid interval '{}'",
+                        prompt::sanitize(value.unwrap_or(""))
+                    ),
+                    true,
+                );
+                return 1;
+            }
+        }
+        args = rest;
+    }
+    if args.is_empty() {
+        output.add("watch: usage: watch [-n seconds] command [args ...]", true);
+        return 2;
+    }
+
+    // The words are already expanded, so they are quoted to run as is
+    let line = shell_words::join(args);
+    let header = format!("Every {:.1}s: {}", interval, line);
+    loop {
+        let now = prompt::format_time(std::time::SystemTime::now(), "%a %b %e %H:%M:%S %Y");
+        // Off a terminal the time just follows the header
+        let columns = terminal_width(state).unwrap_or(0);
+        let padding = columns.saturating_sub(header.chars().count() + now.chars().count());
+        print_out!(
+            state,
+            "{}{}{}{}\n\n",
+            CLEAR_SCREEN,
+            header,
+            " ".repeat(padding.max(2)),
+            now
+        );
+        let _ = io::stdout().flush();
+        let status = execute_line(state, &line);
+        if status == 128 + libc::SIGINT || state.returning || state.exited.is_some() {
+            break;
+        }
+        if sleep_interruptibly(seconds_to_duration(interval)) {
+            println_out!(state);
+            break;
+        }
+    }
+    0
+}
+
+/// `clear` clears the terminal without terminfo. Written anywhere but a
+/// terminal, it prints nothing.
+fn clear_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    if !args.is_empty() {
+        output.add("clear: usage: clear", true);
+        return 2;
+    }
+    if state.builtin_stdout_terminal {
+        output.add_bytes(CLEAR_SCREEN.as_bytes(), false);
+    }
+    0
+}
+
+fn kill_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let usage = "kill: usage: kill [-s sigspec | -n signum | -sigspec] pid | jobspec ... or kill -l [sigspec]";
+    let mut signal = libc::SIGTERM;
+    let mut targets = args;
+    match args.first().copied() {
+        None => {
+            output.add(usage, true);
+            return 2;
+        }
+        Some("-l") => {
+            for (name, number) in jobs::SIGNALS {
+                output.add(&format!("{:>2}) SIG{}", number, name), false);
+            }
+            return 0;
+        }
+        Some("-s") | Some("-n") => {
+            let Some(spec) = args.get(1) else {
+                output.add(usage, true);
+                return 2;
+            };
+            let Some(number) = jobs::parse_signal(spec) else {
+                output.add(
+                    &format!(
+                        "kill: {}: invalid signal specification",
+                        prompt::sanitize(spec)
+                    ),
+                    true,
+                );
+                return 1;
+            };
+            signal = number;
+            targets = &args[2..];
+        }
+        Some(arg) if arg.starts_with('-') && arg.len() > 1 => {
+            let Some(number) = jobs::parse_signal(&arg[1..]) else {
+                output.add(
+                    &format!(
+                        "kill: {}: invalid signal specification",
+                        prompt::sanitize(&arg[1..])
+                    ),
+                    true,
+                );
+                return 1;
+            };
+            signal = number;
+            targets = &args[1..];
+        }
+        Some(_) => {}
+    }
+    if targets.is_empty() {
+        output.add(usage, true);
+        return 2;
+    }
+
+    let mut status = 0;
+    for target in targets {
+        if target.starts_with('%') {
+            let Some(job) = state
+                .jobs
+                .resolve(Some(target))
+                .and_then(|id| state.jobs.get_mut(id))
+            else {
+                output.add(
+                    &format!("kill: {}: no such job", prompt::sanitize(target)),
+                    true,
+                );
+                status = 1;
+                continue;
+            };
+            if let Err(e) = job.signal(signal).or_else(|e| probe_result(signal, e)) {
+                output.add(
+                    &format!("kill: {}: {}", prompt::sanitize(target), error_text(&e)),
+                    true,
+                );
+                status = 1;
+            } else if signal == libc::SIGCONT {
+                job.resume();
+            }
+            continue;
+        }
+        let Ok(pid) = target.parse::<libc::pid_t>() else {
+            output.add(
+                &format!(
+                    "kill: {}: arguments must be process or job IDs",
+                    prompt::sanitize(target)
+                ),
+                true,
+            );
+            status = 1;
+            continue;
+        };
+        if unsafe { libc::kill(pid, signal) } == -1 {
+            if let Err(e) = probe_result(signal, io::Error::last_os_error()) {
+                output.add(&format!("kill: ({}) - {}", pid, error_text(&e)), true);
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+/// Signal 0 only probes whether a process exists. Not being allowed to
+/// signal it still means it does, so that error is not a failure.
+fn probe_result(signal: libc::c_int, error: io::Error) -> io::Result<()> {
+    if signal == 0 && error.raw_os_error() == Some(libc::EPERM) {
+        Ok(())
+    } else {
+        Err(error)
+    }
+}
+
+/// Reads the history file deferred at startup, unless that is done.
+fn load_history(state: &mut ShellState) {
+    let histsize = state.variable("HISTSIZE");
+    state.history.load_pending(histsize.as_deref());
+}
+
+fn history_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    load_history(state);
+    let file_arg = |index: usize, option: &str| -> Result<String, String> {
+        match args.get(index) {
+            Some(path) => Ok(path.to_string()),
+            None => state
+                .variable("HISTFILE")
+                .ok_or_else(|| format!("history: {}: HISTFILE not set", option)),
+        }
+    };
+    match args.first().copied() {
+        Some("-c") => {
+            state.history.clear();
+            0
+        }
+        Some("-d") => {
+            let Some(offset) = args.get(1) else {
+                output.add("history: -d: option requires an argument", true);
+                return 2;
+            };
+            let deleted = offset
+                .parse::<usize>()
+                .is_ok_and(|number| state.history.delete(number));
+            if !deleted {
+                output.add(
+                    &format!(
+                        "history: {}: history position out of range",
+                        prompt::sanitize(offset)
+                    ),
+                    true,
+                );
+                return 1;
+            }
+            0
+        }
+        Some(option @ ("-w" | "-r")) => {
+            let path = match file_arg(1, option) {
+                Ok(path) => path,
+                Err(message) => {
+                    output.add(&message, true);
+                    return 1;
+                }
+            };
+            let result = if option == "-w" {
+                state.history.write(&path)
+            } else {
+                state.history.read(&path)
+            };
+            if let Err(e) = result {
+                output.add(
+                    &format!("history: {}: {}", prompt::sanitize(&path), e),
+                    true,
+                );
+                return 1;
+            }
+            0
+        }
+        Some(arg) if arg.starts_with('-') => {
+            output.add(
+                &format!("history: {}: invalid option", prompt::sanitize(arg)),
+                true,
+            );
+            2
+        }
+        count => {
+            let entries = state.history.entries();
+            let skip = match count.map(str::parse::<usize>) {
+                Some(Ok(count)) => entries.len().saturating_sub(count),
+                Some(Err(_)) => {
+                    output.add(
+                        &format!(
+                            "history: {}: numeric argument required",
+                            prompt::sanitize(args[0])
+                        ),
+                        true,
+                    );
+                    return 1;
+                }
+                None => 0,
+            };
+            for (index, entry) in entries.iter().enumerate().skip(skip) {
+                let line = if state.json_output {
+                    json::Object::new()
+                        .number("number", index as i64 + 1)
+                        .string("command", entry)
+                        .finish()
+                } else {
+                    format!("{:>5}  {}", index + 1, entry)
+                };
+                output.add(&line, false);
+            }
+            0
+        }
+    }
+}
+
+fn local_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    if state
+        .frames
+        .last()
+        .map_or(true, |frame| frame.function == "source")
+    {
+        output.add("local: can only be used in a function", true);
+        return 1;
+    }
+    let mut status = 0;
+    for arg in args {
+        let saved = state.saved_variable(arg.split_once('=').map_or(arg, |(name, _)| name));
+        let options = state.options.clone();
+        let Some(frame) = state.frames.last_mut() else {
+            break;
+        };
+        if *arg == "-" {
+            frame.options.get_or_insert(options);
+            continue;
+        }
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (*arg, None),
+        };
+        if !is_identifier(name) {
+            output.add(
+                &format!("local: `{}': not a valid identifier", prompt::sanitize(arg)),
+                true,
+            );
+            status = 1;
+            continue;
+        }
+        if !frame.locals.iter().any(|(local, _)| local == name) {
+            frame.locals.push((name.to_string(), saved));
+        }
+        match value {
+            Some(value) => state.set_variable(name, value),
+            None => state.unset_variable(name),
+        }
+    }
+    status
+}
+
+fn export_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let mut remove = false;
+    let mut names = args;
+    while let Some(option) = names.first().filter(|arg| arg.starts_with('-')) {
+        match *option {
+            "--" => {
+                names = &names[1..];
+                break;
+            }
+            "-n" => remove = true,
+            "-p" => {}
+            _ => {
+                output.add(
+                    &format!("export: {}: invalid option", prompt::sanitize(option)),
+                    true,
+                );
+                output.add("export: usage: export [-n] [-p] [name[=value] ...]", true);
+                return 2;
+            }
+        }
+        names = &names[1..];
+    }
+    if names.is_empty() {
+        for (name, variable) in &state.variables {
+            if variable.exported {
+                let value = shell_words::quote(&variable.value);
+                output.add(&format!("export {}={}", name, value), false);
+            }
+        }
+        return 0;
+    }
+    let mut status = 0;
+    for arg in names {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (*arg, None),
+        };
+        if !is_identifier(name) {
+            output.add(
+                &format!(
+                    "export: `{}': not a valid identifier",
+                    prompt::sanitize(arg)
+                ),
+                true,
+            );
+            status = 1;
+        } else if remove {
+            if let Some(value) = value {
+                state.set_variable(name, value);
+            }
+            if let Some(mut variable) = state.saved_variable(name) {
+                variable.exported = false;
+                state.restore_variable(name, Some(variable));
+            }
+        } else {
+            state.export_variable(name, value);
+        }
+    }
+    status
+}
+
+fn unset_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let mut functions = false;
+    let mut names = args;
+    while let Some(option) = names.first().filter(|arg| arg.starts_with('-')) {
+        match *option {
+            "--" => {
+                names = &names[1..];
+                break;
+            }
+            "-f" => functions = true,
+            "-v" => functions = false,
+            _ => {
+                output.add(
+                    &format!("unset: {}: invalid option", prompt::sanitize(option)),
+                    true,
+                );
+                output.add("unset: usage: unset [-f] [-v] [name ...]", true);
+                return 2;
+            }
+        }
+        names = &names[1..];
+    }
+    let mut status = 0;
+    for name in names {
+        if functions {
+            state.functions.remove(*name);
+        } else if is_identifier(name) {
+            state.unset_variable(name);
+        } else {
+            output.add(
+                &format!(
+                    "unset: `{}': not a valid identifier",
+                    prompt::sanitize(name)
+                ),
+                true,
+            );
+            status = 1;
+        }
+    }
+    status
+}
+
+fn return_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    if state.frames.is_empty() {
+        output.add(
+            "return: can only `return' from a function or sourced script",
+            true,
+        );
+        return 1;
+    }
+    let status = match args.first() {
+        Some(arg) => match arg.parse::<i32>() {
+            Ok(status) => status & 0xff,
+            Err(_) => {
+                output.add(
+                    &format!(
+                        "return: {}: numeric argument required",
+                        prompt::sanitize(arg)
+                    ),
+                    true,
+                );
+                2
+            }
+        },
+        None => state.last_status,
+    };
+    state.returning = true;
+    status
+}
+
+/// `break [N]` leaves the innermost `N` loops, all of them when there are
+/// fewer.
+fn break_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let count = match args.first() {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(count) if count > 0 => count,
+            Ok(_) => {
+                output.add(
+                    &format!("break: {}: loop count out of range", prompt::sanitize(arg)),
+                    true,
+                );
+                return 1;
+            }
+            Err(_) => {
+                output.add(
+                    &format!(
+                        "break: {}: numeric argument required",
+                        prompt::sanitize(arg)
+                    ),
+                    true,
+                );
+                return 2;
+            }
+        },
+        None => 1,
+    };
+    if state.loops == 0 {
+        output.add(
+            "break: only meaningful in a `select' or `repeat' loop",
+            true,
+        );
+        return 0;
+    }
+    state.breaking = count.min(state.loops);
+    0
+}
+
+/// `enable NAME...` turns builtins back on and `enable -n NAME...` turns
+/// them off, so the name runs the `PATH` command instead. Without names,
+/// prints the enabled builtins, the disabled ones with `-n` or all of
+/// them with `-a`.
+/// `ulimit [-SHa] [-cdfnstuv [limit]] [-- command [args ...]]`: shows
+/// limits, or changes them for the shell and everything it starts. After
+/// `--` the new limits apply only to the programs the command starts, as
+/// soft limits unless `-H` is given; builtins and functions it runs are
+/// not limited themselves.
+fn ulimit_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    const USAGE: &str = "ulimit: usage: ulimit [-SHa] [-cdfnstuv [limit]] [-- command [args ...]]";
+    let mut soft = false;
+    let mut hard = false;
+    let mut all = false;
+    let mut queries = Vec::new();
+    let mut values = Vec::new();
+    let mut command = None;
+    let mut words = args.iter();
+    while let Some(word) = words.next() {
+        if *word == "--" {
+            command = Some(words.as_slice());
+            break;
+        }
+        let Some(flags) = word.strip_prefix('-').filter(|flags| !flags.is_empty()) else {
+            // A bare value is for the file size limit
+            values.extend(limits::find('f').map(|limit| (limit, *word)));
+            continue;
+        };
+        let mut last = None;
+        for flag in flags.chars() {
+            match flag {
+                'S' => soft = true,
+                'H' => hard = true,
+                'a' => all = true,
+                flag => match limits::find(flag) {
+                    Some(limit) => {
+                        queries.extend(last.replace(limit));
+                    }
+                    None => {
+                        output.add(
+                            &format!(
+                                "ulimit: -{}: invalid option",
+                                prompt::sanitize(&flag.to_string())
+                            ),
+                            true,
+                        );
+                        output.add(USAGE, true);
+                        return 2;
+                    }
+                },
+            }
+        }
+        // The last limit of a group takes the value that follows, if any
+        let value = words
+            .as_slice()
+            .first()
+            .filter(|value| !value.starts_with('-'));
+        match (last, value) {
+            (Some(limit), Some(value)) => {
+                values.push((limit, *value));
+                words.next();
+            }
+            (Some(limit), None) => queries.push(limit),
+            _ => {}
+        }
+    }
+
+    // Without -S or -H both limits change, except for one command: there
+    // the soft limit alone lets it be told with SIGXCPU and the like
+    let per_command = command.is_some_and(|words| !words.is_empty());
+    let both = !soft && !hard && !per_command;
+    let soft = soft || !hard;
+    let mut settings = Vec::new();
+    for (limit, value) in values {
+        match limit.setting(value, soft, hard || both) {
+            Some(setting) => settings.push(setting),
+            None => {
+                output.add(
+                    &format!("ulimit: {}: invalid number", prompt::sanitize(value)),
+                    true,
+                );
+                return 1;
+            }
+        }
+    }
+
+    if let Some(words) = command.filter(|words| !words.is_empty()) {
+        let outer = state.command_limits.len();
+        state.command_limits.extend(settings);
+        // The words are already expanded, so they are quoted to run as is
+        let status = execute_line(state, &shell_words::join(words));
+        state.command_limits.truncate(outer);
+        return status;
+    }
+
+    let mut status = 0;
+    for setting in &settings {
+        if let Err(e) = setting.apply() {
+            output.add(
+                &format!("ulimit: {}: cannot modify limit: {}", setting.limit.name, e),
+                true,
+            );
+            status = 1;
+        }
+    }
+    if all {
+        queries = limits::LIMITS.iter().collect();
+    } else if queries.is_empty() && settings.is_empty() {
+        queries.extend(limits::find('f'));
+    }
+    let long = queries.len() > 1;
+    for limit in queries {
+        match limit.show(hard) {
+            Ok(value) if long => {
+                let label = format!("({}, -{})", limit.unit, limit.flag);
+                output.add(
+                    &format!("{:<20} {:<16} {}", limit.name, label, value),
+                    false,
+                );
+            }
+            Ok(value) => output.add(&value, false),
+            Err(e) => {
+                output.add(&format!("ulimit: {}: {}", limit.name, e), true);
+                status = 1;
+            }
+        }
+    }
+    status
+}
+
+fn enable_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let mut disable = false;
+    let mut all = false;
+    let mut names = Vec::new();
+    for arg in args {
+        match *arg {
+            "-n" => disable = true,
+            "-a" => all = true,
+            option if option.starts_with('-') && option.len() > 1 => {
+                output.add(
+                    &format!("enable: {}: invalid option", prompt::sanitize(option)),
+                    true,
+                );
+                output.add("enable: usage: enable [-a] [-n] [name ...]", true);
+                return 2;
+            }
+            name => names.push(name),
+        }
+    }
+
+    if names.is_empty() {
+        for name in BuiltinCommand::NAMES {
+            let disabled = state.disabled_builtins.contains(*name);
+            if all || disabled == disable {
+                let flag = if disabled { "-n " } else { "" };
+                output.add(&format!("enable {}{}", flag, name), false);
+            }
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for name in names {
+        if BuiltinCommand::from_str(name).is_none() {
+            output.add(
+                &format!("enable: {}: not a shell builtin", prompt::sanitize(name)),
+                true,
+            );
+            status = 1;
+        } else if disable {
+            state.disabled_builtins.insert(name.to_string());
+        } else {
+            state.disabled_builtins.remove(name);
+        }
+    }
+    status
+}
+
+/// `complete` arguments run at startup to complete the shell's own
+/// builtins.
+const DEFAULT_COMPLETIONS: &[&[&str]] = &[
+    &["-A", "signal", "-A", "job", "kill"],
+    &["-A", "job", "fg", "bg", "wait"],
+    &["-A", "variable", "unset", "export"],
+];
+
+/// `complete [-pr] [-A action] [-W wordlist] [name ...]` sets how the
+/// arguments of each `name` are completed. With `-p` or nothing to set,
+/// prints the specs as `complete` commands; `-r` removes them.
+fn complete_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    const USAGE: &str = "complete: usage: complete [-pr] [-A action] [-W wordlist] [name ...]";
+    let mut print = false;
+    let mut remove = false;
+    let mut spec = completion::CompSpec::default();
+    let mut names = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "-p" => print = true,
+            "-r" => remove = true,
+            "-A" => {
+                let Some(name) = args.next() else {
+                    output.add("complete: -A: option requires an argument", true);
+                    output.add(USAGE, true);
+                    return 2;
+                };
+                match completion::Action::from_name(name) {
+                    Some(action) => spec.actions.push(action),
+                    None => {
+                        output.add(
+                            &format!("complete: {}: invalid action name", prompt::sanitize(name)),
+                            true,
+                        );
+                        return 1;
+                    }
+                }
+            }
+            "-W" => {
+                let Some(words) = args.next() else {
+                    output.add("complete: -W: option requires an argument", true);
+                    output.add(USAGE, true);
+                    return 2;
+                };
+                spec.words
+                    .extend(words.split_whitespace().map(str::to_string));
+            }
+            option if option.starts_with('-') && option.len() > 1 => {
+                output.add(
+                    &format!("complete: {}: invalid option", prompt::sanitize(option)),
+                    true,
+                );
+                output.add(USAGE, true);
+                return 2;
+            }
+            name => names.push(name.to_string()),
+        }
+    }
+
+    if remove {
+        if names.is_empty() {
+            state.completions.clear();
+        }
+        let mut status = 0;
+        for name in names {
+            if state.completions.remove(&name).is_none() {
+                output.add(
+                    &format!(
+                        "complete: {}: no completion specification",
+                        prompt::sanitize(&name)
+                    ),
+                    true,
+                );
+                status = 1;
+            }
+        }
+        return status;
+    }
+
+    if print || names.is_empty() {
+        if names.is_empty() {
+            names = state.completions.keys().cloned().collect();
+        }
+        let mut status = 0;
+        for name in names {
+            match state.completions.get(&name) {
+                Some(spec) => output.add(&spec.to_command(&name), false),
+                None => {
+                    output.add(
+                        &format!(
+                            "complete: {}: no completion specification",
+                            prompt::sanitize(&name)
+                        ),
+                        true,
+                    );
+                    status = 1;
+                }
+            }
+        }
+        return status;
+    }
+
+    for name in names {
+        state.completions.insert(name, spec.clone());
+    }
+    0
+}
+
+/// `read [-r] [-p PROMPT] [-t SECONDS] [NAME...]` reads a line of input
+/// and splits it over the `NAME`s, the last taking the rest of the line;
+/// without names the whole line goes to `REPLY`. Unless `-r` is given, a
+/// backslash quotes the next character and one before the newline joins
+/// the next line on. Waiting is bounded by `-t`, or else by `TMOUT`, and
+/// running out of time returns 142, as with bash. `-t 0` reads nothing
+/// and only tells whether input is waiting.
+fn read_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    use std::os::unix::io::AsRawFd;
+
+    let mut raw = false;
+    let mut prompt = None;
+    let mut timeout = tmout(state);
+    let mut args = args;
+    loop {
+        match args {
+            ["-r", rest @ ..] => {
+                raw = true;
+                args = rest;
+            }
+            ["-p", text, rest @ ..] => {
+                prompt = Some(*text);
+                args = rest;
+            }
+            ["-t", seconds, rest @ ..] => {
+                match seconds.parse::<f64>() {
+                    Ok(seconds) if seconds >= 0.0 => timeout = Some(seconds_to_duration(seconds)),
+                    _ => {
+                        output.add(
+                            &format!(
+                                "read: {}: invalid timeout specification",
+                                prompt::sanitize(seconds)
+                            ),
+                            true,
+                        );
+                        return 1;
+                    }
+                }
+                args = rest;
+            }
+            ["--", rest @ ..] => {
+                args = rest;
+                break;
+            }
+            [option, ..] if option.starts_with('-') && option.len() > 1 => {
+                output.add(
+                    &format!("read: {}: invalid option", prompt::sanitize(option)),
+                    true,
+                );
+                output.add(
+                    "read: usage: read [-r] [-p prompt] [-t timeout] [name ...]",
+                    true,
+                );
+                return 2;
+            }
+            _ => break,
+        }
+    }
+    if let Some(name) = args.iter().find(|name| !is_identifier(name)) {
+        output.add(
+            &format!("read: `{}': not a valid identifier", prompt::sanitize(name)),
+            true,
+        );
+        return 1;
+    }
+
+    // The file stays open while its descriptor is read
+    let input = state
+        .builtin_input
+        .take()
+        .or_else(|| state.sinks.stdin.as_ref()?.try_clone().ok());
+    let fd = input
+        .as_ref()
+        .map_or(libc::STDIN_FILENO, |file| file.as_raw_fd());
+    if timeout == Some(std::time::Duration::ZERO) {
+        let ready = editor::wait_for_input(fd, std::time::Instant::now());
+        return if matches!(ready, Ok(true)) { 0 } else { 1 };
+    }
+    if let Some(prompt) = prompt {
+        if unsafe { libc::isatty(fd) } == 1 {
+            print_err!(state, "{}", prompt);
+        }
+    }
+    let deadline = timeout.and_then(sleep_deadline);
+    let mut line = String::new();
+    let status = loop {
+        match editor::read_reply(fd, deadline) {
+            Ok(Some(mut part)) => {
+                // As in bash, NULs are dropped: no variable can hold one
+                part.retain(|c| c != '\0');
+                let complete = part.ends_with('\n');
+                let part = part.strip_suffix('\n').unwrap_or(&part);
+                match part.strip_suffix('\\') {
+                    Some(joined) if complete && !raw && !is_escaped(joined) => {
+                        line.push_str(joined);
+                    }
+                    _ => {
+                        line.push_str(part);
+                        break if complete { 0 } else { 1 };
+                    }
+                }
+            }
+            Ok(None) => break 1,
+            Err(error) if error.kind() == io::ErrorKind::TimedOut => return 128 + libc::SIGALRM,
+            Err(error) => {
+                output.add(&format!("read: {}", error), true);
+                return 1;
+            }
+        }
+    };
+
+    let chars = reply_chars(&line, raw);
+    if args.is_empty() {
+        state.set_variable("REPLY", &chars.iter().map(|(c, _)| c).collect::<String>());
+        return status;
+    }
+    let ifs = state.variable("IFS").unwrap_or_else(|| " \t\n".to_string());
+    for (name, value) in args.iter().zip(split_reply(&chars, &ifs, args.len())) {
+        state.set_variable(name, &value);
+    }
+    status
+}
+
+/// Whether `text` ends with an odd number of backslashes, so that one
+/// after it is quoted.
+fn is_escaped(text: &str) -> bool {
+    let backslashes = text.len() - text.trim_end_matches('\\').len();
+    backslashes % 2 == 1
+}
+
+/// The characters of a line `read` took, each with whether a backslash
+/// quoted it. With `raw`, backslashes are kept as they are.
+fn reply_chars(line: &str, raw: bool) -> Vec<(char, bool)> {
+    let mut chars = Vec::new();
+    let mut rest = line.chars();
+    while let Some(c) = rest.next() {
+        match c {
+            '\\' if !raw => chars.extend(rest.next().map(|next| (next, true))),
+            c => chars.push((c, false)),
+        }
+    }
+    chars
+}
+
+/// Splits a line `read` took into `count` fields at unquoted `ifs`
+/// characters. Runs of `IFS` whitespace count as one separator and are
+/// dropped at both ends, the last field keeps the rest of the line, and
+/// missing fields are empty.
+fn split_reply(chars: &[(char, bool)], ifs: &str, count: usize) -> Vec<String> {
+    let separator = |index: usize| {
+        chars
+            .get(index)
+            .is_some_and(|&(c, quoted)| !quoted && ifs.contains(c))
+    };
+    let space = |index: usize| separator(index) && chars[index].0.is_whitespace();
+    let text =
+        |range: std::ops::Range<usize>| chars[range].iter().map(|(c, _)| c).collect::<String>();
+
+    let mut fields = Vec::new();
+    let mut index = 0;
+    while space(index) {
+        index += 1;
+    }
+    while fields.len() + 1 < count && index < chars.len() {
+        let start = index;
+        while index < chars.len() && !separator(index) {
+            index += 1;
+        }
+        fields.push(text(start..index));
+        while space(index) {
+            index += 1;
+        }
+        if separator(index) {
+            index += 1;
+            while space(index) {
+                index += 1;
+            }
+        }
+    }
+    let mut end = chars.len();
+    while end > index && space(end - 1) {
+        end -= 1;
+    }
+    fields.push(text(index..end));
+    fields.resize(count, String::new());
+    fields
+}
+
+/// `getopts OPTSTRING NAME [ARG...]` parses the next option of the
+/// arguments, or of the positional parameters, into `NAME` and `OPTARG`,
+/// advancing `OPTIND`. Returns 1 once the options are over. With a
+/// leading `:` in `OPTSTRING`, errors are reported only through `NAME`
+/// and `OPTARG`; `OPTERR=0` just silences the messages.
+fn getopts_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let [optstring, name, rest @ ..] = args else {
+        output.add("getopts: usage: getopts optstring name [arg ...]", true);
+        return 2;
+    };
+    if !is_identifier(name) {
+        output.add(
+            &format!(
+                "getopts: `{}': not a valid identifier",
+                prompt::sanitize(name)
+            ),
+            true,
+        );
+        return 1;
+    }
+    let words: Vec<String> = if rest.is_empty() {
+        state.positional.clone()
+    } else {
+        rest.iter().map(|arg| arg.to_string()).collect()
+    };
+    let (silent, optstring) = match optstring.strip_prefix(':') {
+        Some(optstring) => (true, optstring),
+        None => (false, *optstring),
+    };
+    let report = !silent && state.variable("OPTERR").map_or(true, |value| value != "0");
+    let program = state.variable("0").unwrap_or_default();
+
+    let mut optind = state
+        .variable("OPTIND")
+        .and_then(|value| value.parse::<usize>().ok())
+        .filter(|optind| *optind > 0)
+        .unwrap_or(1);
+    let mut offset = match state.getopts_position {
+        (position, offset) if position == optind => offset,
+        _ => 0,
+    };
+    let finish = |state: &mut ShellState, optind: usize, offset: usize| {
+        state.set_variable("OPTIND", &optind.to_string());
+        state.getopts_position = (optind, offset);
+    };
+    let end = |state: &mut ShellState, optind: usize| {
+        state.set_variable(name, "?");
+        state.unset_variable("OPTARG");
+        finish(state, optind, 0);
+        1
+    };
+
+    let word = words.get(optind - 1).map(String::as_str).unwrap_or("");
+    if offset == 0 {
+        if word == "--" {
+            return end(state, optind + 1);
+        }
+        if !word.starts_with('-') || word == "-" {
+            return end(state, optind);
+        }
+        offset = 1;
+    }
+    let Some(letter) = word[offset..].chars().next() else {
+        return end(state, optind);
+    };
+    offset += letter.len_utf8();
+    if offset >= word.len() {
+        optind += 1;
+        offset = 0;
+    }
+
+    let spec = optstring
+        .char_indices()
+        .find(|(_, c)| *c == letter && letter != ':');
+    let Some((index, _)) = spec else {
+        if report {
+            output.add(
+                &format!(
+                    "{}: illegal option -- {}",
+                    prompt::sanitize(&program),
+                    prompt::sanitize(&letter.to_string())
+                ),
+                true,
+            );
+        }
+        state.set_variable(name, "?");
+        if silent {
+            state.set_variable("OPTARG", &letter.to_string());
+        } else {
+            state.unset_variable("OPTARG");
+        }
+        finish(state, optind, offset);
+        return 0;
+    };
+
+    if !optstring[index + letter.len_utf8()..].starts_with(':') {
+        state.unset_variable("OPTARG");
+        state.set_variable(name, &letter.to_string());
+        finish(state, optind, offset);
+        return 0;
+    }
+    // The argument is the rest of this word, or else the next one
+    let argument = if offset > 0 {
+        let argument = word[offset..].to_string();
+        optind += 1;
+        offset = 0;
+        Some(argument)
+    } else {
+        let argument = words.get(optind - 1).cloned();
+        if argument.is_some() {
+            optind += 1;
+        }
+        argument
+    };
+    match argument {
+        Some(argument) => {
+            state.set_variable("OPTARG", &argument);
+            state.set_variable(name, &letter.to_string());
+        }
+        None if silent => {
+            state.set_variable("OPTARG", &letter.to_string());
+            state.set_variable(name, ":");
+        }
+        None => {
+            if report {
+                output.add(
+                    &format!(
+                        "{}: option requires an argument -- {}",
+                        prompt::sanitize(&program),
+                        prompt::sanitize(&letter.to_string())
+                    ),
+                    true,
+                );
+            }
+            state.unset_variable("OPTARG");
+            state.set_variable(name, "?");
+        }
+    }
+    finish(state, optind, offset);
+    0
+}
+
+/// `repeat N command...` runs the command `N` times, as a loop `break`
+/// can leave, and returns the status of the last run.
+fn repeat_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let Some((count, command)) = args.split_first() else {
+        output.add("repeat: usage: repeat count command [args ...]", true);
+        return 2;
+    };
+    let Ok(count) = count.parse::<usize>() else {
+        output.add(
+            &format!(
+                "repeat: {}: numeric argument required",
+                prompt::sanitize(count)
+            ),
+            true,
+        );
+        return 2;
+    };
+    // The words are already expanded, so they are quoted to run as is
+    let line = shell_words::join(command);
+    let mut status = 0;
+    state.loops += 1;
+    for _ in 0..count {
+        status = execute_line(state, &line);
+        if state.breaking > 0 {
+            state.breaking -= 1;
+            break;
+        }
+        if state.returning || state.exited.is_some() {
+            break;
+        }
+    }
+    state.loops -= 1;
+    status
+}
+
+/// Whether a raw word is a `NAME=value` assignment.
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=')
+        .is_some_and(|(name, _)| is_identifier(name))
+}
+
+pub(crate) fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Runs a function body in a new call frame, at the line it was defined
+/// on. Whether the body finishes, `return`s or stops on an error, locals
+/// and `local -` options are put back before the caller continues.
+fn call_function(state: &mut ShellState, name: &str, function: &Function, args: &[String]) -> i32 {
+    if !state.push_frame(name, function.source.clone()) {
+        return 1;
+    }
+    set_positional(state, args.to_vec());
+    let call_line = std::mem::replace(&mut state.line, function.line);
+    let status = execute_line(state, &function.body);
+    state.line = call_line;
+    pop_frame(state);
+    status
+}
+
+/// Function called, when defined, in place of reporting an unknown command.
+const NOT_FOUND_HANDLER: &str = "command_not_found_handle";
+
+/// Hands an unknown command and its arguments to `NOT_FOUND_HANDLER`,
+/// whose status becomes the command's. Without a handler, or for a command
+/// the handler itself could not find, reports it and returns 127.
+fn command_not_found(state: &mut ShellState, command: &str, args: &[String]) -> i32 {
+    let in_handler = state
+        .frames
+        .iter()
+        .any(|frame| frame.function == NOT_FOUND_HANDLER);
+    match state.functions.get(NOT_FOUND_HANDLER).cloned() {
+        Some(function) if !in_handler => {
+            let args: Vec<String> = std::iter::once(command.to_string())
+                .chain(args.iter().cloned())
+                .collect();
+            call_function(state, NOT_FOUND_HANDLER, &function, &args)
+        }
+        _ => {
+            println_err!(state, "{}: command not found", prompt::sanitize(command));
+            for line in path_diagnostics(state, command) {
+                println_err!(state, "{}", line);
+            }
+            127
+        }
+    }
+}
+
+/// With `shopt -s verbose_path_errors`, one line for each `PATH` directory
+/// saying why it does not provide `command`. Relative directories are
+/// marked, since they depend on the working directory.
+fn path_diagnostics(state: &ShellState, command: &str) -> Vec<String> {
+    if !state.shopt("verbose_path_errors") || command.contains('/') {
+        return Vec::new();
+    }
+    pathcache::probe_path(state, command)
+        .into_iter()
+        .map(|probe| {
+            let relative = if std::path::Path::new(&probe.dir).is_absolute() {
+                ""
+            } else {
+                " (relative)"
+            };
+            format!("  {}{}: {}", probe.dir, relative, probe.probe)
+        })
+        .collect()
+}
+
+/// Replaces the positional parameters for the innermost frame, which
+/// restores the caller's when it is left.
+fn set_positional(state: &mut ShellState, positional: Vec<String>) {
+    let previous = std::mem::replace(&mut state.positional, positional);
+    if let Some(frame) = state.frames.last_mut() {
+        frame.positional.get_or_insert(previous);
+    }
+}
+
+/// Leaves the innermost frame, restoring what it saved.
+fn pop_frame(state: &mut ShellState) {
+    state.returning = false;
+    let frame = state.frames.pop().unwrap_or_default();
+    if let Some(positional) = frame.positional {
+        state.positional = positional;
+    }
+    for (name, saved) in frame.locals.into_iter().rev() {
+        state.restore_variable(&name, saved);
+    }
+    if let Some(options) = frame.options {
+        state.options = options;
+    }
+}
+
+fn source_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let Some(path) = args.first() else {
+        output.add("source: filename argument required", true);
+        return 2;
+    };
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            output.add(&format!("source: {}: {}", prompt::sanitize(path), e), true);
+            return 1;
+        }
+    };
+    if !state.push_frame("source", path.to_string()) {
+        return 1;
+    }
+    if args.len() > 1 {
+        set_positional(state, args[1..].iter().map(|arg| arg.to_string()).collect());
+    }
+    let status = execute_script(state, &contents, false);
+    pop_frame(state);
+    status
+}
+
+/// `caller` prints the line and file the running function or sourced
+/// file was called from; `caller N` prints the line, function and file of
+/// the call `N` frames further up.
+fn caller_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let depth = match args.first() {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(depth) => Some(depth),
+            Err(_) => {
+                output.add(
+                    &format!("caller: {}: invalid number", prompt::sanitize(arg)),
+                    true,
+                );
+                return 2;
+            }
+        },
+        None => None,
+    };
+    let Some(index) = state.frames.len().checked_sub(depth.unwrap_or(0) + 1) else {
+        return 1;
+    };
+    let line = state.frames[index].line;
+    let (function, source) = match index.checked_sub(1) {
+        Some(outer) => {
+            let frame = &state.frames[outer];
+            (frame.function.clone(), frame.source.clone())
+        }
+        // Without a script, as with `-c`, the file is `$0`
+        None => ("main".to_string(), state.variable("0").unwrap_or_default()),
+    };
+    match depth {
+        Some(_) => output.add(&format!("{} {} {}", line, function, source), false),
+        None => output.add(&format!("{} {}", line, source), false),
+    }
+    0
+}
+
+/// Runs the lines of a script until they run out or `return` is used.
+/// Those of the shell's own script or `-c` command, `top_level`, are
+/// audited like commands typed at the prompt; see `execute_audited`.
+fn execute_script(state: &mut ShellState, contents: &str, top_level: bool) -> i32 {
+    let outer_line = state.line;
+    let mut status = 0;
+    let mut lines = contents.lines().enumerate();
+    while let Some((index, line)) = lines.next() {
+        state.line = index + 1;
+        // A `case` runs once its `esac` has been read, and a list once it
+        // no longer ends in `&&` or `||`
+        let mut input = line.to_string();
+        while compound::is_incomplete(&input) || ends_with_connector(&input) {
+            let Some((_, line)) = lines.next() else {
+                break;
+            };
+            input.push('\n');
+            input.push_str(line);
+        }
+        status = if top_level {
+            execute_audited(state, &input)
+        } else {
+            execute_line(state, &input)
+        };
+        if state.returning || state.exited.is_some() {
+            break;
+        }
+    }
+    state.line = outer_line;
+    status
+}
+
+/// Parses `name() { body; }` or `function name { body; }`, returning the
+/// name and the body between the braces.
+fn function_definition(input: &str) -> Option<(&str, &str)> {
+    let (name, rest) = match input.strip_prefix("function ") {
+        Some(rest) => {
+            let rest = rest.trim_start();
+            let end = rest.find(|c: char| c.is_whitespace() || c == '(' || c == '{')?;
+            let (name, rest) = rest.split_at(end);
+            (name, rest.trim_start().strip_prefix("()").unwrap_or(rest))
+        }
+        None => {
+            let (name, rest) = input.split_once('(')?;
+            (name.trim_end(), rest.trim_start().strip_prefix(')')?)
+        }
+    };
+    if !is_identifier(name) {
+        return None;
+    }
+    let body = rest.trim().strip_prefix('{')?.strip_suffix('}')?;
+    Some((name, body.trim()))
+}
+
+/// Runs the traps of the signals caught since the last call. Never inside
+/// another trap: signals that arrive while one runs stay pending until it
+/// is done, and one caught several times in between runs once.
+fn run_signal_traps(state: &mut ShellState) {
+    while !state.in_trap && state.exited.is_none() {
+        let pending = signals::take_pending();
+        if pending.is_empty() {
+            break;
+        }
+        for number in pending {
+            if let Some((name, _)) = jobs::SIGNALS.iter().find(|(_, signal)| *signal == number) {
+                run_trap(state, &format!("SIG{}", name));
+            }
+        }
+    }
+}
+
+/// Runs the handler registered for `signal`, if any. Handlers never trigger
+/// traps themselves and leave `last_status` untouched.
+fn run_trap(state: &mut ShellState, signal: &str) {
+    if state.in_trap {
+        return;
+    }
+    let handler = match state.traps.get(signal) {
+        Some(handler) if !handler.is_empty() => handler.clone(),
+        _ => return,
+    };
+    let last_status = state.last_status;
+    state.in_trap = true;
+    execute_line(state, &handler);
+    state.in_trap = false;
+    state.last_status = last_status;
+}
+
+/// Looks `command` up as a function first, then as a builtin or in PATH.
+/// In posix mode special builtins take precedence over functions.
+fn resolve_command(state: &ShellState, command: &str) -> Option<Command> {
+    let special = state
+        .builtin(command)
+        .is_some_and(|builtin| builtin.is_special());
+    if !(special && state.option("posix")) {
+        if let Some(function) = state.functions.get(command) {
+            return Some(Command::Function(function.clone()));
+        }
+    }
+    search_command(state, command)
+}
+
+fn search_command(state: &ShellState, command: &str) -> Option<Command> {
+    // First check if it's a builtin command
+    if let Some(builtin) = state.builtin(command) {
+        return Some(Command::BuiltinCommand(builtin));
+    }
+
+    // Then check if it's an executable in PATH
+    if let Some(path) = pathcache::find_executable(state, command) {
+        return Some(Command::ExecutableCommand(ExecutableCommand { path }));
+    }
+
+    None
+}
+
+/// `$PATH`, or `paths::DEFAULT_PATH` when it is unset.
+fn search_path(state: &ShellState) -> String {
+    state
+        .variable("PATH")
+        .unwrap_or_else(|| paths::DEFAULT_PATH.to_string())
+}
+
+/// `$HOME`.
+fn home_dir(state: &ShellState) -> Option<String> {
+    state.variable("HOME")
+}
+
+#[derive(Debug, Clone)]
+struct TokenizerResult {
+    /// Leading `NAME=value` words; the command is empty when there are
+    /// only assignments.
+    assignments: Vec<String>,
+    command: String,
+    args: Vec<String>,
+    redirect_stdin: Option<String>,
+    redirect_stdout: Option<String>,
+    append_stdout: bool,
+    redirect_stderr: Option<String>,
+    append_stderr: bool,
+    /// `[N]>&WORD` and `[N]<&WORD` as written, turned into the
+    /// `duplicate_*` fields once `WORD` is expanded.
+    duplicate_words: Vec<DuplicateWord>,
+    /// `<&N` or `<&-`, in place of `redirect_stdin`.
+    duplicate_stdin: Option<Duplicate>,
+    /// `>&N` or `>&-`, in place of `redirect_stdout`.
+    duplicate_stdout: Option<Duplicate>,
+    /// `2>&N` or `2>&-` other than `2>&1`, in place of `redirect_stderr`.
+    duplicate_stderr: Option<Duplicate>,
+    /// Duplications of descriptors above 2, such as `3<&0`, which only
+    /// external commands get.
+    duplicate_fds: Vec<(libc::c_int, Duplicate)>,
+    /// Words that pathname expansion turned into matching paths, counted
+    /// once the stage is expanded.
+    glob_matches: usize,
+    /// Followed by `|&`, or redirected with `>& file`: stderr goes
+    /// wherever stdout does, after the stage's own redirections.
+    pipe_stderr: bool,
+    /// A group of commands in place of the command.
+    group: Option<Group>,
+}
+
+/// A list of commands run as one stage.
+#[derive(Debug, Clone, PartialEq)]
+enum Group {
+    /// `( list )`, run in a forked copy of the shell.
+    Subshell(String),
+    /// `{ list; }`, run in the shell itself, except in a pipeline or the
+    /// background, which need a process of their own.
+    Braces(String),
+}
+
+impl Group {
+    fn body(&self) -> &str {
+        match self {
+            Self::Subshell(body) | Self::Braces(body) => body,
+        }
+    }
+}
+
+/// A `[N]>&WORD` or `[N]<&WORD` redirection before `WORD` is expanded.
+#[derive(Debug, Clone, PartialEq)]
+struct DuplicateWord {
+    /// The descriptor written in front, `None` for a bare `>&` or `<&`.
+    fd: Option<libc::c_int>,
+    output: bool,
+    word: String,
+}
+
+impl DuplicateWord {
+    /// Splits a word starting with an optional descriptor number and `>&`
+    /// or `<&` into that number, the direction and the rest of the word.
+    fn operator(word: &str) -> Option<(Option<libc::c_int>, bool, &str)> {
+        let digits = word.len() - word.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let rest = &word[digits..];
+        let output = rest.starts_with(">&");
+        if !output && !rest.starts_with("<&") {
+            return None;
+        }
+        let fd = match digits {
+            0 => None,
+            _ => Some(word[..digits].parse().ok()?),
+        };
+        Some((fd, output, &rest[2..]))
+    }
+
+    /// The descriptor it changes.
+    fn target(&self) -> libc::c_int {
+        self.fd.unwrap_or(if self.output { 1 } else { 0 })
+    }
+
+    /// Applies the redirection to `stage` now that its word expanded to
+    /// `operand`: a descriptor number or `-` duplicates or closes, and
+    /// for a bare `>&` anything else is a file for stdout and stderr, as
+    /// in csh.
+    fn resolve(
+        self,
+        stage: &mut TokenizerResult,
+        operand: String,
+    ) -> Result<(), expand::ExpandError> {
+        let Some(duplicate) = Duplicate::parse(&operand) else {
+            if self.fd.is_none() && self.output {
+                stage.redirect_stdout = Some(operand);
+                stage.duplicate_stdout = None;
+                stage.append_stdout = false;
+                stage.pipe_stderr = true;
+                return Ok(());
+            }
+            return Err(expand::ExpandError::AmbiguousRedirect(self.word));
+        };
+        match self.target() {
+            0 => {
+                stage.duplicate_stdin = Some(duplicate);
+                stage.redirect_stdin = None;
+            }
+            1 => {
+                stage.duplicate_stdout = Some(duplicate);
+                stage.redirect_stdout = None;
+            }
+            // Stderr follows stdout wherever the stage's own redirections
+            // send it, as after `|&`
+            2 if duplicate == Duplicate::Fd(1) => {
+                stage.pipe_stderr = true;
+                stage.redirect_stderr = None;
+                stage.duplicate_stderr = None;
+            }
+            2 => {
+                stage.duplicate_stderr = Some(duplicate);
+                stage.redirect_stderr = None;
+                stage.pipe_stderr = false;
+            }
+            fd => {
+                stage.duplicate_fds.retain(|(other, _)| *other != fd);
+                stage.duplicate_fds.push((fd, duplicate));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What `>&WORD` or `<&WORD` makes of a stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Duplicate {
+    /// A copy of the shell's descriptor.
+    Fd(libc::c_int),
+    /// `-`: the stream is closed.
+    Close,
+}
+
+impl Duplicate {
+    /// A descriptor number or `-`; `None` for any other word.
+    fn parse(word: &str) -> Option<Self> {
+        if word == "-" {
+            return Some(Self::Close);
+        }
+        if word.is_empty() || !word.bytes().all(|byte| byte.is_ascii_digit()) {
+            return None;
+        }
+        word.parse().ok().map(Self::Fd)
+    }
+
+    /// Opens the stream for a command, `output` for `>&`. A closed stream
+    /// is stood in for by `/dev/null` opened the other way round, so using
+    /// it fails with EBADF as a closed descriptor would. Fds 1 and 2 are
+    /// the shell's sinks when it has them.
+    fn open(self, output: bool, sinks: &Sinks) -> Result<std::fs::File, String> {
+        use std::os::unix::io::FromRawFd;
+
+        let sink = match self {
+            Self::Fd(1) => sinks.stdout.as_ref(),
+            Self::Fd(2) => sinks.stderr.as_ref(),
+            _ => None,
+        };
+        if let Some(file) = sink {
+            return file.try_clone().map_err(|e| e.to_string());
+        }
+        match self {
+            Self::Fd(fd) => {
+                let copy = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+                if copy == -1 {
+                    return Err(format!("{}: Bad file descriptor", fd));
+                }
+                Ok(unsafe { std::fs::File::from_raw_fd(copy) })
+            }
+            Self::Close => std::fs::OpenOptions::new()
+                .read(output)
+                .write(!output)
+                .open("/dev/null")
+                .map_err(|e| format!("/dev/null: {}", e)),
+        }
+    }
+}
+
+/// A command line of one or more `|`-connected stages, run in the
+/// background when it ends with `&`.
+#[derive(Debug)]
+struct Pipeline {
+    stages: Vec<TokenizerResult>,
+    background: bool,
+    /// `!` in front: the status is inverted.
+    negate: bool,
+    /// `time` in front, with `-p` asking for the POSIX format.
+    timed: Option<TimeFormat>,
+}
+
+/// How `time` reports the time a pipeline took.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TimeFormat {
+    /// `real\t0m0.001s`, as bash prints it.
+    Default,
+    /// `real 0.00`, for `time -p`.
+    Posix,
+}
+
+fn handle_tokens(tokens: Vec<String>) -> Result<TokenizerResult, String> {
+    let mut result = TokenizerResult {
+        assignments: Vec::new(),
+        command: String::new(),
+        args: Vec::new(),
+        redirect_stdin: None,
+        redirect_stdout: None,
+        append_stdout: false,
+        redirect_stderr: None,
+        append_stderr: false,
+        duplicate_words: Vec::new(),
+        duplicate_stdin: None,
+        duplicate_stdout: None,
+        duplicate_stderr: None,
+        duplicate_fds: Vec::new(),
+        glob_matches: 0,
+        pipe_stderr: false,
+        group: None,
+    };
+
+    let mut i = 0;
+    while i < tokens.len() {
+        if let Some((fd, output, attached)) = DuplicateWord::operator(&tokens[i]) {
+            // The operand can follow in the same word or the next one
+            let operand = if !attached.is_empty() {
+                attached
+            } else if i + 1 < tokens.len() {
+                i += 1;
+                &tokens[i]
+            } else {
+                return Err(format!(
+                    "syntax error: missing operand after `{}'",
+                    tokens[i]
+                ));
+            };
+            let duplicate = DuplicateWord {
+                fd,
+                output,
+                word: operand.to_string(),
+            };
+            result
+                .duplicate_words
+                .retain(|other| other.target() != duplicate.target());
+            result.duplicate_words.push(duplicate);
+            i += 1;
+            continue;
+        }
+        match tokens[i].as_str() {
+            "<" | "0<" => {
+                if i + 1 >= tokens.len() {
+                    return Err(
+                        "syntax error: missing file name after redirection operator".to_string()
+                    );
+                }
+                result.redirect_stdin = Some(tokens[i + 1].to_string());
+                result.duplicate_stdin = None;
+                result
+                    .duplicate_words
+                    .retain(|duplicate| duplicate.target() != 0);
+                i += 2;
+            }
+            ">" | "1>" => {
+                if i + 1 >= tokens.len() {
+                    return Err(
+                        "syntax error: missing file name after redirection operator".to_string()
+                    );
+                }
+                result.redirect_stdout = Some(tokens[i + 1].to_string());
+                result.duplicate_stdout = None;
+                result
+                    .duplicate_words
+                    .retain(|duplicate| duplicate.target() != 1);
+                i += 2;
+            }
+            "2>" => {
+                if i + 1 >= tokens.len() {
+                    return Err(
+                        "syntax error: missing file name after redirection operator".to_string()
+                    );
+                }
+                result.redirect_stderr = Some(tokens[i + 1].to_string());
+                result
+                    .duplicate_words
+                    .retain(|duplicate| duplicate.target() != 2);
+                i += 2;
+            }
+            ">>" | "1>>" => {
+                if i + 1 >= tokens.len() {
+                    return Err(
+                        "syntax error: missing file name after redirection operator".to_string()
+                    );
+                }
+                result.redirect_stdout = Some(tokens[i + 1].to_string());
+                result.duplicate_stdout = None;
+                result
+                    .duplicate_words
+                    .retain(|duplicate| duplicate.target() != 1);
+                result.append_stdout = true;
+                i += 2;
+            }
+            "2>>" => {
+                if i + 1 >= tokens.len() {
+                    return Err(
+                        "syntax error: missing file name after redirection operator".to_string()
+                    );
+                }
+                result.redirect_stderr = Some(tokens[i + 1].to_string());
+                result
+                    .duplicate_words
+                    .retain(|duplicate| duplicate.target() != 2);
+                result.append_stderr = true;
+                i += 2;
+            }
+            word if result.command.is_empty() && is_assignment(word) => {
+                result.assignments.push(word.to_string());
+                i += 1;
+            }
+            word if result.command.is_empty() => {
+                result.command = word.to_string();
+                i += 1;
+            }
+            arg => {
+                result.args.push(arg.to_string());
+                i += 1;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+/// Parses and runs a single input line, returning its exit status.
+fn execute_line(state: &mut ShellState, input: &str) -> i32 {
+    let mut status = state.last_status;
+    for command in split_list(input) {
+        status = execute_list_item(state, command);
+        run_signal_traps(state);
+        if state.returning || state.breaking > 0 || state.exited.is_some() {
+            break;
+        }
+    }
+    status
+}
+
+/// Splits a command line at `;`, `&` and newlines outside quotes, braces,
+/// parentheses and compound commands such as `case`. An item ended by `&`
+/// keeps it, to run in the background. A newline after `&&` or `||`
+/// continues the list item.
+fn split_list(input: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    for (index, c) in top_level(input) {
+        let continued = c == '\n' && ends_with_connector(&input[start..index]);
+        if c == '&' && is_background_operator(input, index) {
+            items.push(&input[start..=index]);
+            start = index + 1;
+        } else if matches!(c, ';' | '\n') && !continued {
+            items.push(&input[start..index]);
+            start = index + 1;
+        }
+    }
+    items.push(&input[start..]);
+    items.retain(|item| !item.trim().is_empty());
+    items
+}
+
+/// Whether the `&` at byte `index` of `input` sends the command before it
+/// to the background, rather than being part of `&&`, `|&`, `>&`, `<&` or
+/// `&>`.
+fn is_background_operator(input: &str, index: usize) -> bool {
+    let before = input[..index].chars().next_back();
+    let after = input[index + 1..].chars().next();
+    !matches!(before, Some('&' | '|' | '>' | '<')) && !matches!(after, Some('&' | '>'))
+}
+
+/// Whether `input` ends in `&&` or `||`, so its list goes on in the next
+/// line.
+fn ends_with_connector(input: &str) -> bool {
+    let chars = top_level(input);
+    let mut last = chars.iter().rev().filter(|(_, c)| !c.is_whitespace());
+    match (last.next(), last.next()) {
+        (Some(&(index, c)), Some(&(before, first))) => {
+            matches!(c, '&' | '|') && first == c && before + 1 == index
+        }
+        _ => false,
+    }
+}
+
+/// Splits `input` at each of `separators` outside quotes, braces,
+/// parentheses and compound commands.
+fn split_top_level<'a>(input: &'a str, separators: &[char]) -> Vec<&'a str> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    for (index, c) in top_level(input) {
+        if separators.contains(&c) {
+            items.push(&input[start..index]);
+            start = index + c.len_utf8();
+        }
+    }
+    items.push(&input[start..]);
+    items
+}
+
+/// The characters of `input` that can separate its commands: unquoted and
+/// outside braces, parentheses and compound commands.
+fn top_level(input: &str) -> Vec<(usize, char)> {
+    let mut chars = Vec::new();
+    let mut depth = 0usize;
+    let mut blocks = 0isize;
+    for (index, c) in unquoted(input) {
+        let change = compound::nesting_change(input, index);
+        match c {
+            _ if change != 0 => blocks = (blocks + change).max(0),
+            // Inside `case`, `)` ends patterns rather than groups
+            _ if blocks > 0 => {}
+            '{' | '(' => depth += 1,
+            '}' | ')' => depth = depth.saturating_sub(1),
+            c if depth == 0 => chars.push((index, c)),
+            _ => {}
+        }
+    }
+    chars
+}
+
+/// How a pipeline of an and-or list is joined to the one before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Connector {
+    /// `&&`: runs after a success
+    And,
+    /// `||`: runs after a failure
+    Or,
+}
+
+impl Connector {
+    fn token(self) -> &'static str {
+        match self {
+            Self::And => "&&",
+            Self::Or => "||",
+        }
+    }
+}
+
+/// Splits a list item at `&&` and `||` outside quotes and groups into its
+/// pipelines, each after the first with the operator before it.
+fn split_and_or(input: &str) -> Vec<(Option<Connector>, &str)> {
+    let mut items = Vec::new();
+    let mut connector = None;
+    let mut start = 0;
+    let chars = top_level(input);
+    let mut pairs = chars.iter().zip(chars.iter().skip(1));
+    while let Some((&(index, c), &(next_index, next))) = pairs.next() {
+        let operator = match (c, next) {
+            ('&', '&') => Connector::And,
+            ('|', '|') => Connector::Or,
+            _ => continue,
+        };
+        if next_index != index + 1 {
+            continue;
+        }
+        items.push((connector, &input[start..index]));
+        connector = Some(operator);
+        start = next_index + 1;
+        pairs.next();
+    }
+    items.push((connector, &input[start..]));
+    items
+}
+
+/// Splits a `( list )` or `{ list; }` stage into the group and what
+/// follows its end, the redirections. `None` when `segment` starts with
+/// neither, and an error when the group is never closed.
+fn parse_group(segment: &str) -> Option<Result<(Group, &str), String>> {
+    let start = segment.len() - segment.trim_start().len();
+    let braces = is_reserved_brace(segment, start);
+    if !braces && !segment[start..].starts_with('(') {
+        return None;
+    }
+    let (open, close) = if braces { ('{', '}') } else { ('(', ')') };
+    let mut depth = 0usize;
+    let mut blocks = 0isize;
+    for (index, c) in unquoted(segment) {
+        let change = compound::nesting_change(segment, index);
+        match c {
+            _ if change != 0 => blocks = (blocks + change).max(0),
+            // Inside `case`, `)` ends patterns rather than groups
+            _ if blocks > 0 => {}
+            // Braces only count as reserved words, not in `{a,b}` or `${x}`
+            _ if braces && !is_reserved_brace(segment, index) => {}
+            c if c == open => depth += 1,
+            c if c == close && depth == 1 => {
+                let body = segment[start + 1..index].trim();
+                let group = if braces {
+                    Group::Braces(body.to_string())
+                } else {
+                    Group::Subshell(body.to_string())
+                };
+                return Some(Ok((group, &segment[index + 1..])));
+            }
+            c if c == close => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    Some(Err(format!(
+        "syntax error: unexpected end of file, expecting `{}'",
+        close
+    )))
+}
+
+/// Whether the brace at byte `index` of `input` is the reserved word `{`
+/// or `}`: a word of its own, and for `}` the first word of a command.
+fn is_reserved_brace(input: &str, index: usize) -> bool {
+    let brace = input[index..].chars().next();
+    if !matches!(brace, Some('{' | '}')) {
+        return false;
+    }
+    let before = &input[..index];
+    let after = &input[index + 1..];
+    let ends_word =
+        |next: Option<char>| next.map_or(true, |c| c.is_whitespace() || ";|&<>)".contains(c));
+    match brace {
+        Some('{') => {
+            before
+                .chars()
+                .next_back()
+                .map_or(true, |c| c.is_whitespace() || ";|&(".contains(c))
+                && after.starts_with(char::is_whitespace)
+        }
+        Some('}') => {
+            before.trim_end().ends_with([';', '\n', '&', '{']) && ends_word(after.chars().next())
+        }
+        _ => false,
+    }
+}
+
+/// Byte offsets and characters of `input` that are neither quoted nor
+/// escaped, leaving out the quotes and backslashes themselves. Comments,
+/// from a `#` starting a word to the end of the line, are left out too, as
+/// is everything inside a `$(...)` or backquoted command.
+fn unquoted(input: &str) -> Vec<(usize, char)> {
+    let mut chars = Vec::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut dollar = false;
+    let mut comment = false;
+    let mut previous = None;
+    let mut substitution_end = 0;
+    for (index, c) in input.char_indices() {
+        if comment {
+            if c == '\n' {
+                comment = false;
+                chars.push((index, c));
+            }
+            continue;
+        }
+        let at_word_start = previous.map_or(true, |p: char| p.is_whitespace() || ";&|".contains(p));
+        previous = Some(c);
+        if index < substitution_end {
+            continue;
+        }
+        if escaped {
+            escaped = false;
+            dollar = false;
+            continue;
+        }
+        // Nothing inside `$(...)` or backquotes is part of this command
+        if matches!(quote, None | Some('"'))
+            && (c == '`' || (c == '$' && input[index + 1..].starts_with('(')))
+        {
+            substitution_end = expand::substitution_end(input, index).unwrap_or(input.len());
+            if quote.is_none() {
+                chars.push((index, c));
+            }
+            dollar = false;
+            continue;
+        }
+        // `$'...'` is tracked as quote `$`: it ends at `'` but, unlike
+        // plain single quotes, allows backslash escapes
+        let after_dollar = std::mem::replace(&mut dollar, c == '$' && quote.is_none());
+        match (c, quote) {
+            ('\\', Some('"' | '$') | None) => escaped = true,
+            ('#', None) if at_word_start => comment = true,
+            ('\'', None) if after_dollar => quote = Some('$'),
+            ('\'' | '"', None) => quote = Some(c),
+            ('\'', Some('$')) => quote = None,
+            (_, Some('$')) => {}
+            (c, Some(open)) if c == open => quote = None,
+            (c, None) => chars.push((index, c)),
+            _ => {}
+        }
+    }
+    chars
+}
+
+/// Runs one command of a list: a compound command, a function definition
+/// or a pipeline, whose stages may be groups.
+fn execute_list_item(state: &mut ShellState, input: &str) -> i32 {
+    let pipelines = split_and_or(input);
+    if pipelines.len() > 1 {
+        // `&` sends the whole list to the background
+        let body = input.trim_end();
+        if let Some(list) = body.strip_suffix('&') {
+            if is_background_operator(body, list.len()) {
+                return execute_list_item(state, &format!("{{ {}; }} &", list));
+            }
+        }
+        return execute_and_or(state, &pipelines);
+    }
+    let expanded = (state.shopt("expand_aliases") && !state.aliases.is_empty())
+        .then(|| expand_aliases(state, input, &mut Vec::new()));
+    let input = expanded.as_deref().unwrap_or(input).trim();
+    // `set -n` only parses, and is ignored by interactive shells
+    if state.option("noexec") && !state.interactive {
+        check_syntax(state, input);
+        return state.last_status;
+    }
+    let mut negated = false;
+    let status = if let Some(case) = compound::parse_case(input) {
+        match case {
+            Ok(case) => execute_case(state, case),
+            Err(message) => {
+                syntax_error(state, &message);
+                return state.last_status;
+            }
+        }
+    } else if let Some(select) = compound::parse_select(input) {
+        match select {
+            Ok(select) => execute_select(state, select),
+            Err(message) => {
+                syntax_error(state, &message);
+                return state.last_status;
+            }
+        }
+    } else if let Some((name, body)) = coproc_definition(input) {
+        start_coproc(state, name, body, input)
+    } else if let Some((name, body)) = function_definition(input) {
+        let function = Function {
+            body: body.to_string(),
+            source: state.current_source(),
+            line: state.line,
+        };
+        state.functions.insert(name.to_string(), function);
+        0
+    } else {
+        match parse_line(state, input) {
+            Some(pipeline) => {
+                // `!` makes the failure the point of the command
+                negated = pipeline.negate;
+                if state.option("parsedebug") {
+                    println_err!(state, "{:#?}", pipeline);
+                }
+                if !state.in_trap {
+                    state.set_variable("BASH_COMMAND", input);
+                }
+                run_trap(state, "DEBUG");
+                execute_pipeline(state, pipeline, input)
+            }
+            None => return state.last_status,
+        }
+    };
+
+    state.last_status = status;
+    if status != 0 && !negated && !state.errexit_suppressed && state.exited.is_none() {
+        run_trap(state, "ERR");
+        if state.option("errexit") && !state.in_trap {
+            exit_shell(state, status);
+        }
+    }
+    status
+}
+
+/// Runs an and-or list: each pipeline after the first runs only when the
+/// status so far is a success, after `&&`, or a failure, after `||`. Only
+/// the last pipeline can trigger `set -e` and the `ERR` trap.
+fn execute_and_or(state: &mut ShellState, pipelines: &[(Option<Connector>, &str)]) -> i32 {
+    for (index, (_, pipeline)) in pipelines.iter().enumerate() {
+        if pipeline.trim().is_empty() {
+            let message = match pipelines.get(index + 1) {
+                Some((Some(next), _)) => {
+                    format!("syntax error near unexpected token `{}'", next.token())
+                }
+                _ => "syntax error: unexpected end of file".to_string(),
+            };
+            syntax_error(state, &message);
+            return state.last_status;
+        }
+    }
+    let mut status = 0;
+    for (index, &(connector, pipeline)) in pipelines.iter().enumerate() {
+        let runs = match connector {
+            None => true,
+            Some(Connector::And) => status == 0,
+            Some(Connector::Or) => status != 0,
+        };
+        if !runs {
+            continue;
+        }
+        let suppressed = state.errexit_suppressed;
+        state.errexit_suppressed |= index + 1 < pipelines.len();
+        status = execute_list_item(state, pipeline);
+        state.errexit_suppressed = suppressed;
+        if state.returning || state.breaking > 0 || state.exited.is_some() {
+            break;
+        }
+    }
+    status
+}
+
+/// Replaces the first word of `text` with the alias it names, unless that
+/// alias is already being expanded, in `active`. The alias value's own
+/// first word is expanded in turn, and when the value ends in a blank, so
+/// is the word after it. Quoted words never name aliases.
+fn expand_aliases(state: &ShellState, text: &str, active: &mut Vec<String>) -> String {
+    let word_start = text.len() - text.trim_start().len();
+    let word_len = text[word_start..]
+        .find(|c: char| c.is_whitespace() || "|&;()<>".contains(c))
+        .unwrap_or(text.len() - word_start);
+    let (blank, rest) = text.split_at(word_start);
+    let (word, after) = rest.split_at(word_len);
+    let Some(value) = state
+        .aliases
+        .get(word)
+        .filter(|_| !active.iter().any(|name| name == word))
+    else {
+        return text.to_string();
+    };
+    active.push(word.to_string());
+    let value = expand_aliases(state, value, active);
+    active.pop();
+    let after = if value.ends_with([' ', '\t']) {
+        expand_aliases(state, after, active)
+    } else {
+        after.to_string()
+    };
+    format!("{blank}{value}{after}")
+}
+
+/// Whether `alias` accepts `name`: no blanks, quotes, `/`, `$`, `=` or
+/// characters that end a word.
+fn is_alias_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name
+            .chars()
+            .any(|c| c.is_whitespace() || "/$`=\\'\"|&;()<>".contains(c))
+}
+
+/// `alias [-p] [NAME[=VALUE] ...]` defines each `NAME=VALUE` and prints
+/// each other `NAME` as an `alias` command that would define it again;
+/// without names it prints every alias.
+fn alias_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    let args = args.strip_prefix(&["-p"]).unwrap_or(args);
+    let definition =
+        |name: &str, value: &str| format!("alias {}='{}'", name, value.replace('\'', r"'\''"));
+    if args.is_empty() {
+        for (name, value) in &state.aliases {
+            output.add(&definition(name, value), false);
+        }
+        return 0;
+    }
+    let mut status = 0;
+    for arg in args {
+        match arg.split_once('=') {
+            Some((name, value)) if is_alias_name(name) => {
+                state.aliases.insert(name.to_string(), value.to_string());
+            }
+            Some((name, _)) => {
+                let name = prompt::sanitize(name);
+                output.add(&format!("alias: `{}': invalid alias name", name), true);
+                status = 1;
+            }
+            None => match state.aliases.get(*arg) {
+                Some(value) => output.add(&definition(arg, value), false),
+                None => {
+                    output.add(
+                        &format!("alias: {}: not found", prompt::sanitize(arg)),
+                        true,
+                    );
+                    status = 1;
+                }
+            },
+        }
+    }
+    status
+}
+
+/// `unalias [-a] [NAME ...]` removes the named aliases, or all of them.
+fn unalias_fn(args: &[&str], state: &mut ShellState, output: &mut Output) -> i32 {
+    if args.first() == Some(&"-a") {
+        state.aliases.clear();
+        return 0;
+    }
+    if args.is_empty() {
+        output.add("unalias: usage: unalias [-a] name [name ...]", true);
+        return 2;
+    }
+    let mut status = 0;
+    for name in args {
+        if state.aliases.remove(*name).is_none() {
+            output.add(
+                &format!("unalias: {}: not found", prompt::sanitize(name)),
+                true,
+            );
+            status = 1;
+        }
+    }
+    status
+}
+
+/// Parses a list item and any subshell or function body inside it without
+/// running anything, reporting syntax errors like `execute_list_item`.
+fn check_syntax(state: &mut ShellState, input: &str) {
+    let pipelines = split_and_or(input);
+    if pipelines.len() > 1 {
+        for (_, pipeline) in pipelines {
+            check_syntax(state, pipeline);
+        }
+        return;
+    }
+    let input = input.trim();
+    match compound::parse_case(input) {
+        Some(Ok(case)) => {
+            for branch in &case.branches {
+                for item in split_list(&branch.body) {
+                    check_syntax(state, item);
+                }
+            }
+            return;
+        }
+        Some(Err(message)) => return syntax_error(state, &message),
+        None => {}
+    }
+    match compound::parse_select(input) {
+        Some(Ok(select)) => {
+            for item in split_list(&select.body) {
+                check_syntax(state, item);
+            }
+            return;
+        }
+        Some(Err(message)) => return syntax_error(state, &message),
+        None => {}
+    }
+    let body = coproc_definition(input)
+        .map(|(_, body)| body)
+        .or_else(|| function_definition(input).map(|(_, body)| body));
+    if let Some(body) = body {
+        for item in split_list(body) {
+            check_syntax(state, item);
+        }
+        return;
+    }
+    let Some(pipeline) = parse_line(state, input) else {
+        return;
+    };
+    for stage in &pipeline.stages {
+        for item in split_list(stage.group.as_ref().map_or("", Group::body)) {
+            check_syntax(state, item);
+        }
+    }
+}
+
+/// Reports a syntax error, with the file and line when running a script,
+/// and sets the status to 2.
+fn syntax_error(state: &mut ShellState, message: &str) {
+    println_err!(
+        state,
+        "{}{}",
+        error_location(state),
+        prompt::sanitize(message)
+    );
+    state.last_status = 2;
+}
+
+/// Reports a syntax error found splitting `line` into words by its
+/// column, and at the prompt shows the line with the spot underlined.
+fn tokenize_error(state: &mut ShellState, line: &str, error: expand::TokenizeError) {
+    let message = format!("syntax error near column {}: {}", error.column(line), error);
+    syntax_error(state, &message);
+    if state.interactive && state.line == 0 {
+        println_err!(state, "{}", error.underline(line));
+    }
+}
+
+/// `file: line N: ` while running a script or sourced file, to put in
+/// front of error messages; empty for interactive commands.
+fn error_location(state: &ShellState) -> String {
+    if state.line == 0 {
+        return String::new();
+    }
+    let source = state.current_source();
+    let source = if source.is_empty() { "-c" } else { &source };
+    format!("{}: line {}: ", source, state.line)
+}
+
+/// Runs the body of the first branch with a pattern matching the word,
+/// then continues as its terminator says.
+fn execute_case(state: &mut ShellState, case: compound::CaseCommand) -> i32 {
+    let word = match expand::expand_value(state, &case.word) {
+        Ok(word) => word,
+        Err(error) => return expansion_error(state, error),
+    };
+    let mut status = 0;
+    let mut fall_through = false;
+    for branch in &case.branches {
+        if !fall_through {
+            let mut matched = false;
+            for pattern in &branch.patterns {
+                match expand::expand_pattern(state, pattern) {
+                    Ok(pattern) if expand::fnmatch(&pattern, &word) => {
+                        matched = true;
+                        break;
+                    }
+                    Ok(_) => {}
+                    Err(error) => return expansion_error(state, error),
+                }
+            }
+            if !matched {
+                continue;
+            }
+        }
+        status = if branch.body.is_empty() {
+            0
+        } else {
+            execute_line(state, &branch.body)
+        };
+        if state.returning || state.breaking > 0 || state.exited.is_some() {
+            break;
+        }
+        match branch.terminator {
+            compound::Terminator::Break => break,
+            compound::Terminator::FallThrough => fall_through = true,
+            compound::Terminator::Continue => fall_through = false,
+        }
+    }
+    status
+}
+
+/// Shows the words as a numbered menu on stderr and reads choices with
+/// the `$PS3` prompt until end of input or `break`. Each choice sets the
+/// variable to the chosen word, or to nothing for an invalid one, and
+/// `REPLY` to the line read, then runs the body. An empty line shows the
+/// menu again.
+fn execute_select(state: &mut ShellState, select: compound::SelectCommand) -> i32 {
+    let items = match &select.words {
+        Some(words) => {
+            let mut items = Vec::new();
+            for word in words {
+                match expand::expand_word(state, word) {
+                    Ok(fields) => items.extend(fields),
+                    Err(error) => return expansion_error(state, error),
+                }
+            }
+            items
+        }
+        None => state.positional.clone(),
+    };
+    if items.is_empty() {
+        return 0;
+    }
+
+    let width = items.len().to_string().len();
+    let mut status = 0;
+    let mut show_menu = true;
+    state.loops += 1;
+    loop {
+        if show_menu {
+            for (number, item) in items.iter().enumerate() {
+                println_err!(state, "{:>width$}) {}", number + 1, item, width = width);
+            }
+        }
+        print_err!(
+            state,
+            "{}",
+            state.variable("PS3").unwrap_or_else(|| "#? ".to_string())
+        );
+        let _ = io::stderr().flush();
+        let Ok(Some(line)) = editor::read_reply(libc::STDIN_FILENO, None) else {
+            println_err!(state);
+            break;
+        };
+        let reply = line.trim_end_matches(['\n', '\r']);
+        show_menu = reply.trim().is_empty();
+        if show_menu {
+            continue;
+        }
+        let choice = reply
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|number| items.get(number.checked_sub(1)?));
+        state.set_variable("REPLY", reply);
+        state.set_variable(&select.name, choice.map_or("", String::as_str));
+        status = execute_line(state, &select.body);
+        if state.breaking > 0 {
+            state.breaking -= 1;
+            break;
+        }
+        if state.returning || state.exited.is_some() {
+            break;
+        }
+    }
+    state.loops -= 1;
+    status
+}
+
+/// Reports a failed expansion, with the file and line in scripts. Fatal
+/// ones, such as unbound variables under `set -u`, end a non-interactive
+/// shell; interactively only the command is abandoned.
+fn expansion_error(state: &mut ShellState, error: expand::ExpandError) -> i32 {
+    println_err!(
+        state,
+        "{}{}",
+        error_location(state),
+        prompt::sanitize(&error.to_string())
+    );
+    if error.is_fatal() && !state.interactive {
+        exit_shell(state, 1);
+    }
+    1
+}
+
+/// Turns the state of a forked child into a subshell's, with no jobs or
+/// traps of its own yet. Leaving it ends the child even when the shell it
+/// was forked from is embedded.
+fn enter_subshell(state: &mut ShellState) {
+    state.subshell_depth += 1;
+    // Ignored signals stay ignored, caught ones go back to their defaults
+    let traps = std::mem::take(&mut state.traps);
+    for (name, handler) in traps {
+        if !handler.is_empty() {
+            update_disposition(state, &name);
+        }
+    }
+    signals::take_pending();
+    state.jobs = jobs::JobTable::default();
+    state.embedded = false;
+    state.set_variable("BASH_SUBSHELL", &state.subshell_depth.to_string());
+}
+
+/// Name of the coprocess array when `coproc` is not given one.
+const DEFAULT_COPROC: &str = "COPROC";
+
+/// Parses `coproc [NAME] { body; }` or `coproc command`, returning the
+/// array name and the commands to run. A name is only taken before a
+/// `{ ... }` or `( ... )` body, so `coproc cat` runs `cat`.
+fn coproc_definition(input: &str) -> Option<(&str, &str)> {
+    if !compound::keyword_at(input, 0, "coproc") {
+        return None;
+    }
+    let rest = input["coproc".len()..].trim();
+    let (name, body) = match rest.split_once(char::is_whitespace) {
+        Some((name, body)) if is_identifier(name) && body.trim_start().starts_with(['{', '(']) => {
+            (name, body.trim())
+        }
+        _ => (DEFAULT_COPROC, rest),
+    };
+    let body = match body.strip_prefix('{') {
+        Some(inner) => inner.strip_suffix('}')?.trim(),
+        None => body,
+    };
+    (!body.is_empty()).then_some((name, body))
+}
+
+/// Forks a copy of the shell running `body` with its stdin and stdout on
+/// pipes, in the background like `command &`. The shell keeps the other
+/// ends as the `name` array until the job is reaped.
+fn start_coproc(state: &mut ShellState, name: &str, body: &str, input: &str) -> i32 {
+    use std::os::unix::io::AsRawFd;
+
+    if let Some(index) = state.coprocs.iter().position(|coproc| coproc.name == name) {
+        let coproc = state.coprocs.remove(index);
+        println_err!(
+            state,
+            "warning: coproc [{}:{}] still exists",
+            coproc.pid,
+            coproc.name
+        );
+    }
+    // Both ends are closed when dropped, on every path out of here
+    let pipes = pipe().and_then(|to_coproc| Ok((to_coproc, pipe()?)));
+    let ((to_read, to_write), (from_read, from_write)) = match pipes {
+        Ok(pipes) => pipes,
+        Err(e) => {
+            println_err!(state, "coproc: {}", e);
+            return 1;
+        }
+    };
+
+    match fork_shell() {
+        -1 => {
+            println_err!(state, "fork: {}", io::Error::last_os_error());
+            1
+        }
+        0 => {
+            unsafe {
+                libc::setpgid(0, 0);
+                libc::dup2(to_read.as_raw_fd(), libc::STDIN_FILENO);
+                libc::dup2(from_write.as_raw_fd(), libc::STDOUT_FILENO);
+            }
+            drop((to_read, to_write, from_read, from_write));
+            unsafe {
+                for signal in [libc::SIGINT, libc::SIGQUIT, libc::SIGTSTP] {
+                    libc::signal(signal, libc::SIG_DFL);
+                }
+            }
+            state.coprocs.clear();
+            state.interactive = false;
+            state.sinks.stdin = None;
+            state.sinks.stdout = None;
+            enter_subshell(state);
+            let status = execute_line(state, body);
+            exit_child(status);
+        }
+        pid => {
+            unsafe { libc::setpgid(pid, pid) };
+            drop((to_read, from_write));
+            // Kept until the coprocess is released. They stay close-on-exec,
+            // so commands only get them through explicit redirections
+            state.coprocs.push(Coproc {
+                name: name.to_string(),
+                pid,
+                fds: [from_read.into(), to_write.into()],
+            });
+            state.last_background = Some(pid);
+            let id = state.jobs.insert(jobs::Job {
+                id: 0,
+                pgid: pid,
+                pids: vec![pid],
+                states: vec![jobs::ProcessState::Running],
+                command: input.to_string(),
+            });
+            if state.interactive {
+                println_out!(state, "[{}] {}", id, pid);
+            }
+            0
+        }
+    }
+}
+
+/// Closes the pipes of coprocesses whose job has finished and forgets
+/// their `NAME` arrays.
+fn release_coprocs(state: &mut ShellState) {
+    let pipefail = state.option("pipefail");
+    let jobs = &state.jobs;
+    state.coprocs.retain(|coproc| {
+        jobs.iter().any(|job| {
+            job.pids.contains(&coproc.pid)
+                && !matches!(job.state(pipefail), jobs::JobState::Done(_))
+        })
+    });
+}
+
+/// Splits `input` at every `separator` outside quotes.
+fn split_unquoted(input: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (index, c) in unquoted(input) {
+        if c == separator {
+            parts.push(&input[start..index]);
+            start = index + c.len_utf8();
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+/// Tokenizes `input` into a pipeline. Returns `None` for blank lines and,
+/// after reporting the error and recording status 2, for syntax errors.
+fn parse_line(state: &mut ShellState, input: &str) -> Option<Pipeline> {
+    let line = input;
+    let mut input = input;
+    let mut background = false;
+    if let [.., body, last] = split_unquoted(input, '&').as_slice() {
+        if last.trim().is_empty() && !body.trim().is_empty() {
+            input = &input[..input.len() - last.len() - 1];
+            background = true;
+        } else if input.trim() == "&" {
+            syntax_error(state, "syntax error near unexpected token `&'");
+            return None;
+        }
+    }
+
+    let body_len = input.len();
+    // `!` and `time` can come in either order, before the first stage
+    let mut negate = false;
+    let mut timed = None;
+    loop {
+        let rest = input.trim_start();
+        if rest.starts_with('!') && rest[1..].starts_with(char::is_whitespace) {
+            negate = !negate;
+            input = &rest[1..];
+        } else if compound::keyword_at(rest, 0, "time") {
+            let rest = rest["time".len()..].trim_start();
+            match rest.strip_prefix("-p") {
+                Some(after) if after.is_empty() || after.starts_with(char::is_whitespace) => {
+                    timed = Some(TimeFormat::Posix);
+                    input = after;
+                }
+                _ => {
+                    timed = Some(TimeFormat::Default);
+                    input = rest;
+                }
+            }
+        } else {
+            break;
+        }
+    }
+
+    let segments = split_top_level(input, &['|']);
+    let mut stages: Vec<TokenizerResult> = Vec::new();
+    // Where the segment starts in `line`
+    let mut offset = body_len - input.len();
+    for (index, segment) in segments.iter().enumerate() {
+        let segment_offset = offset;
+        offset += segment.len() + 1;
+        // `|&` pipes stderr too: the `&` starts the next segment
+        let segment = match segment.strip_prefix('&') {
+            Some(rest) if index > 0 => {
+                if let Some(previous) = stages.last_mut() {
+                    previous.pipe_stderr = true;
+                }
+                rest
+            }
+            _ => segment,
+        };
+        // A group is followed by nothing but redirections
+        let (group, words) = match parse_group(segment) {
+            Some(Ok((group, rest))) => (Some(group), rest),
+            Some(Err(message)) => {
+                syntax_error(state, &message);
+                return None;
+            }
+            None => (None, segment),
+        };
+        let tokens = match expand::split_words(words) {
+            Ok(tokens) => tokens,
+            Err(error) => {
+                let shift = segment_offset + segments[index].len() - words.len();
+                tokenize_error(state, line, error.shifted(shift));
+                return None;
+            }
+        };
+        if tokens.is_empty() && group.is_none() {
+            if segments.len() > 1 {
+                syntax_error(state, "syntax error near unexpected token `|'");
+            }
+            return None;
+        }
+
+        let mut tokenized = match handle_tokens(tokens) {
+            Ok(tokenized) => tokenized,
+            Err(message) => {
+                syntax_error(state, &message);
+                return None;
+            }
+        };
+        if let Some(group) = group {
+            let unexpected = tokenized.assignments.first().unwrap_or(&tokenized.command);
+            if group.body().is_empty() || !unexpected.is_empty() {
+                let token = match &group {
+                    _ if !unexpected.is_empty() => unexpected,
+                    Group::Subshell(_) => ")",
+                    Group::Braces(_) => "}",
+                };
+                let message = format!("syntax error near unexpected token `{}'", token);
+                syntax_error(state, &message);
+                return None;
+            }
+            tokenized.group = Some(group);
+        }
+        stages.push(tokenized);
+    }
+    Some(Pipeline {
+        stages,
+        background,
+        negate,
+        timed,
+    })
+}
+
+/// Runs a pipeline and settles its status: `time` reports how long it
+/// took without changing the status, and `!` inverts it after that.
+fn execute_pipeline(state: &mut ShellState, pipeline: Pipeline, input: &str) -> i32 {
+    let started = pipeline.timed.map(|format| (format, Times::now()));
+    let suppressed = state.errexit_suppressed;
+    state.errexit_suppressed |= pipeline.negate;
+    let status = run_stages(state, pipeline.stages, pipeline.background, input);
+    state.errexit_suppressed = suppressed;
+    if let Some((format, started)) = started {
+        report_time(state, format, &started, &Times::now());
+    }
+    if pipeline.negate {
+        (status == 0) as i32
+    } else {
+        status
+    }
+}
+
+/// Wall clock and CPU time used so far, by the shell and the children it
+/// has waited for.
+struct Times {
+    real: std::time::Instant,
+    user: std::time::Duration,
+    sys: std::time::Duration,
+}
+
+impl Times {
+    fn now() -> Self {
+        let mut user = std::time::Duration::ZERO;
+        let mut sys = std::time::Duration::ZERO;
+        for who in [libc::RUSAGE_SELF, libc::RUSAGE_CHILDREN] {
+            let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+            if unsafe { libc::getrusage(who, &mut usage) } == 0 {
+                user += timeval_duration(usage.ru_utime);
+                sys += timeval_duration(usage.ru_stime);
+            }
+        }
+        Self {
+            real: std::time::Instant::now(),
+            user,
+            sys,
+        }
+    }
+}
+
+fn timeval_duration(time: libc::timeval) -> std::time::Duration {
+    std::time::Duration::from_secs(time.tv_sec as u64)
+        + std::time::Duration::from_micros(time.tv_usec as u64)
+}
+
+/// Prints the time between `start` and `end` to stderr for `time`.
+fn report_time(state: &ShellState, format: TimeFormat, start: &Times, end: &Times) {
+    let times = [
+        ("real", end.real.duration_since(start.real)),
+        ("user", end.user.saturating_sub(start.user)),
+        ("sys", end.sys.saturating_sub(start.sys)),
+    ];
+    if format == TimeFormat::Default {
+        println_err!(state);
+    }
+    for (name, time) in times {
+        let seconds = time.as_secs_f64();
+        match format {
+            TimeFormat::Default => {
+                let minutes = (seconds / 60.0).floor();
+                println_err!(
+                    state,
+                    "{}\t{}m{:.3}s",
+                    name,
+                    minutes,
+                    seconds - minutes * 60.0
+                );
+            }
+            TimeFormat::Posix => println_err!(state, "{} {:.2}", name, seconds),
+        }
+    }
+}
+
+/// Runs a simple foreground command or brace group in the shell itself,
+/// anything else, subshell groups included, as a job.
+fn run_stages(
+    state: &mut ShellState,
+    pipeline: Vec<TokenizerResult>,
+    background: bool,
+    input: &str,
+) -> i32 {
+    let mut stages = Vec::new();
+    state.substitution_status = None;
+    for stage in pipeline {
+        match expand_stage(state, stage) {
+            Ok(stage) => stages.push(stage),
+            Err(error) => return expansion_error(state, error),
+        }
+    }
+    if state.option("xtrace") {
+        // The commands inside a group are traced as they run
+        for stage in stages.iter().filter(|stage| stage.group.is_none()) {
+            let words = stage
+                .assignments
+                .iter()
+                .chain(std::iter::once(&stage.command))
+                .chain(&stage.args)
+                .filter(|word| !word.is_empty());
+            println_err!(state, "+ {}", shell_words::join(words));
+        }
+    }
+    if state.shopt("confirm_glob_removal") && state.interactive {
+        for stage in &stages {
+            if is_mass_removal(state, stage)
+                && !confirm(
+                    state,
+                    &format!("remove {} files? [y/N] ", stage.glob_matches),
+                )
+            {
+                return 1;
+            }
+        }
+    }
+    if let (1, false, Some(Group::Braces(body))) = (stages.len(), background, &stages[0].group) {
+        return run_brace_group(state, &stages[0], body);
+    }
+    if stages.len() == 1 && !background && stages[0].group.is_none() {
+        let stage = stages.remove(0);
+        if stage.command.is_empty() {
+            let status = assign_only(state, stage);
+            return state
+                .substitution_status
+                .filter(|_| status == 0)
+                .unwrap_or(status);
+        }
+        return execute_command(state, stage, input.trim());
+    }
+    let command = input.trim().trim_end_matches('&').trim_end();
+    run_job(state, &stages, command, background)
+}
+
+/// Expands the words of a parsed stage right before it runs. Assignment
+/// values are not split, and redirection targets must stay one word.
+fn expand_stage(
+    state: &mut ShellState,
+    stage: TokenizerResult,
+) -> Result<TokenizerResult, expand::ExpandError> {
+    let mut words = Vec::new();
+    let mut glob_matches = 0;
+    for word in std::iter::once(&stage.command)
+        .chain(&stage.args)
+        .filter(|word| !word.is_empty())
+    {
+        let (fields, matched) = expand::expand_word_globbed(state, word)?;
+        words.extend(fields);
+        glob_matches += matched;
+        if words.len() > expand::expansion_limit(state) {
+            return Err(expand::ExpandError::TooManyWords);
+        }
+    }
+    let mut words = words.into_iter();
+    let mut assignments = Vec::new();
+    for assignment in &stage.assignments {
+        let (name, value) = split_assignment(assignment);
+        assignments.push(format!("{}={}", name, expand::expand_value(state, value)?));
+    }
+    let mut target = |path: Option<String>| {
+        path.map(|path| expand::expand_single(state, &path))
+            .transpose()
+    };
+    let mut expanded = TokenizerResult {
+        assignments,
+        command: words.next().unwrap_or_default(),
+        args: words.collect(),
+        redirect_stdin: target(stage.redirect_stdin)?,
+        redirect_stdout: target(stage.redirect_stdout)?,
+        append_stdout: stage.append_stdout,
+        redirect_stderr: target(stage.redirect_stderr)?,
+        append_stderr: stage.append_stderr,
+        duplicate_words: Vec::new(),
+        duplicate_stdin: stage.duplicate_stdin,
+        duplicate_stdout: stage.duplicate_stdout,
+        duplicate_stderr: stage.duplicate_stderr,
+        duplicate_fds: stage.duplicate_fds,
+        glob_matches,
+        pipe_stderr: stage.pipe_stderr,
+        group: stage.group,
+    };
+    for duplicate in stage.duplicate_words {
+        let operand = expand::expand_single(state, &duplicate.word)?;
+        duplicate.resolve(&mut expanded, operand)?;
+    }
+    Ok(expanded)
+}
+
+fn split_assignment(assignment: &str) -> (&str, &str) {
+    assignment.split_once('=').unwrap_or((assignment, ""))
+}
+
+/// Sets each `NAME=value`, returning the previous values for
+/// `restore_assignments`. Assignments in front of a command are exported
+/// to what it runs; on their own they only set shell variables.
+fn apply_assignments(
+    state: &mut ShellState,
+    assignments: &[String],
+    export: bool,
+) -> Vec<(String, Option<Variable>)> {
+    assignments
+        .iter()
+        .map(|assignment| {
+            let (name, value) = split_assignment(assignment);
+            let previous = state.saved_variable(name);
+            if export {
+                state.export_variable(name, Some(value));
+            } else {
+                state.set_variable(name, value);
+            }
+            (name.to_string(), previous)
+        })
+        .collect()
+}
+
+fn restore_assignments(state: &mut ShellState, saved: Vec<(String, Option<Variable>)>) {
+    for (name, variable) in saved.into_iter().rev() {
+        state.restore_variable(&name, variable);
+    }
+}
+
+/// Runs a stage without a command: its assignments persist and its
+/// redirections only create or truncate their files.
+fn assign_only(state: &mut ShellState, stage: TokenizerResult) -> i32 {
+    apply_assignments(state, &stage.assignments, false);
+    let redirects = [
+        (&stage.redirect_stdout, stage.append_stdout),
+        (&stage.redirect_stderr, stage.append_stderr),
+    ];
+    for (path, append) in redirects {
+        if let Some(path) = path {
+            if let Err(e) = open_redirect(state, path, append) {
+                println_err!(state, "{}: {}", prompt::sanitize(path), e);
+                return 1;
+            }
+        }
+    }
+    let duplicates = [
+        (stage.duplicate_stdin, false),
+        (stage.duplicate_stdout, true),
+        (stage.duplicate_stderr, true),
+    ];
+    for (duplicate, output) in duplicates {
+        if let Some(Err(message)) = duplicate.map(|duplicate| duplicate.open(output, &state.sinks))
+        {
+            println_err!(state, "{}", message);
+            return 1;
+        }
+    }
+    0
+}
+
+/// Whether a stage's input redirection names the same file as its output
+/// redirection, which truncating the output would destroy before it is
+/// read.
+fn reads_own_output(stage: &TokenizerResult) -> bool {
+    let (Some(input), Some(output)) = (&stage.redirect_stdin, &stage.redirect_stdout) else {
+        return false;
+    };
+    match (std::fs::canonicalize(input), std::fs::canonicalize(output)) {
+        (Ok(input), Ok(output)) => input == output,
+        _ => false,
+    }
+}
+
+/// Longest redirection target accepted, and longest component in it.
+const PATH_MAX: usize = libc::PATH_MAX as usize;
+const NAME_MAX: usize = 255;
+
+/// Rejects redirection targets no file can have: empty, holding a NUL, or
+/// longer than the system allows.
+fn check_redirect_path(path: &str) -> io::Result<()> {
+    let too_long = path.len() >= PATH_MAX || path.split('/').any(|name| name.len() > NAME_MAX);
+    if path.is_empty() || path.contains('\0') || too_long {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid file name",
+        ));
+    }
+    Ok(())
+}
+
+/// Opens the target of an input redirection.
+fn open_input_redirect(path: &str) -> io::Result<std::fs::File> {
+    check_redirect_path(path)?;
+    std::fs::File::open(path)
+}
+
+/// Opens the target of an output redirection. With
+/// `confirm_device_clobber`, writing to a device asks first; see
+/// `should_confirm`. With `set -o mkdirredirect`, missing parent
+/// directories are created.
+fn open_redirect(state: &ShellState, path: &str, append: bool) -> io::Result<std::fs::File> {
+    check_redirect_path(path)?;
+    if state.shopt("confirm_device_clobber") {
+        let needs_confirmation = std::fs::metadata(path)
+            .map(|meta| should_confirm(&meta, path, &own_terminals()))
+            .unwrap_or(false);
+        if needs_confirmation && !confirm_device_write(state, path) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "refusing to write to a device",
+            ));
+        }
+    }
+    if state.option("mkdirredirect") {
+        if let Some(parent) = std::path::Path::new(path).parent() {
+            if !parent.as_os_str().is_empty() && !parent.exists() {
+                std::fs::create_dir_all(parent)?;
+            }
+        }
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true);
+    if append {
+        options.append(true);
+    } else {
+        options.truncate(true);
+    }
+    options.open(path)
+}
+
+/// Device numbers of the terminals on the shell's own stdin, stdout and
+/// stderr.
+fn own_terminals() -> Vec<u64> {
+    [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO]
+        .into_iter()
+        .filter_map(|fd| unsafe {
+            let mut stat: libc::stat = std::mem::zeroed();
+            (libc::isatty(fd) == 1 && libc::fstat(fd, &mut stat) == 0)
+                .then_some(stat.st_rdev as u64)
+        })
+        .collect()
+}
+
+/// Character devices it is always fine to write to.
+const SAFE_DEVICES: &[&str] = &["/dev/null", "/dev/stdout", "/dev/stderr", "/dev/tty"];
+
+/// Whether writing to `path`, described by `meta`, has to be confirmed
+/// under `confirm_device_clobber`: it is a character device other than the
+/// harmless ones and the terminals in `owned_ttys`.
+fn should_confirm(meta: &std::fs::Metadata, path: &str, owned_ttys: &[u64]) -> bool {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    meta.file_type().is_char_device()
+        && !owned_ttys.contains(&meta.rdev())
+        && !SAFE_DEVICES.contains(&path)
+}
+
+/// Asks whether to write to the device `path`. Non-interactive shells
+/// cannot ask and refuse.
+fn confirm_device_write(state: &ShellState, path: &str) -> bool {
+    state.interactive && confirm(state, &format!("really write to {}? (y/n) ", path))
+}
+
+/// Shows `question` on stderr and reads the answer from stdin the way
+/// the line editor does, leaving any type-ahead after it unread. Only a
+/// yes counts; so does nothing when the question cannot be shown.
+fn confirm(state: &ShellState, question: &str) -> bool {
+    print_err!(state, "{}", question);
+    if io::stderr().flush().is_err() {
+        return false;
+    }
+    matches!(
+        editor::read_reply(libc::STDIN_FILENO, None),
+        Ok(Some(answer)) if matches!(answer.trim(), "y" | "Y" | "yes")
+    )
+}
+
+/// Commands `confirm_glob_removal` watches when `CONFIRM_GLOB_COMMANDS`,
+/// a colon-separated list, is unset.
+const DEFAULT_CONFIRM_GLOB_COMMANDS: &str = "rm";
+
+/// Paths from patterns it takes for `confirm_glob_removal` to ask, when
+/// `CONFIRM_GLOB_THRESHOLD` is unset.
+const DEFAULT_CONFIRM_GLOB_THRESHOLD: usize = 10;
+
+/// Whether `stage` runs a watched command on at least the threshold of
+/// paths from pathname expansion.
+fn is_mass_removal(state: &ShellState, stage: &TokenizerResult) -> bool {
+    let commands = state
+        .variable("CONFIRM_GLOB_COMMANDS")
+        .unwrap_or_else(|| DEFAULT_CONFIRM_GLOB_COMMANDS.to_string());
+    let threshold = state
+        .variable("CONFIRM_GLOB_THRESHOLD")
+        .and_then(|threshold| threshold.parse().ok())
+        .unwrap_or(DEFAULT_CONFIRM_GLOB_THRESHOLD);
+    let name = stage.command.rsplit('/').next().unwrap_or_default();
+    stage.glob_matches >= threshold.max(1) && commands.split(':').any(|command| command == name)
+}
+
+/// Spawns every stage of a pipeline into one new process group and either
+/// waits for it or, with `background`, records it in the job table.
+fn run_job(
+    state: &mut ShellState,
+    stages: &[TokenizerResult],
+    command: &str,
+    background: bool,
+) -> i32 {
+    use std::os::unix::ffi::OsStringExt;
+    use std::os::unix::process::CommandExt;
+
+    // Whatever the shell printed has to come out before the children's
+    // output
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    let interactive = state.interactive;
+    let mut job = jobs::Job {
+        id: 0,
+        pgid: 0,
+        pids: Vec::new(),
+        states: Vec::new(),
+        command: command.to_string(),
+    };
+    let mut previous_stdout: Option<std::fs::File> = None;
+    for (index, stage) in stages.iter().enumerate() {
+        let is_last = index + 1 == stages.len();
+        if stage.command.is_empty() && stage.group.is_none() {
+            job.pids.push(0);
+            job.states.push(jobs::ProcessState::Done(0));
+            continue;
+        }
+        let take_terminal = interactive && !background && index == 0;
+        let builtin = match resolve_command(state, &stage.command) {
+            Some(Command::BuiltinCommand(builtin)) if stage.group.is_none() => Some(builtin),
+            _ => None,
+        };
+        if builtin.is_some() || stage.group.is_some() {
+            let stdin = match previous_stdout.take() {
+                None if index > 0 => std::fs::File::open("/dev/null").ok(),
+                stdin => stdin,
+            };
+            let stage = BuiltinStage {
+                builtin,
+                stage,
+                stdin,
+                is_last,
+                take_terminal,
+            };
+            previous_stdout = start_builtin_stage(state, stage, &mut job);
+            continue;
+        }
+        // Commands are looked up in the shell's `PATH`, which need not be
+        // exported, and see only exported variables
+        let program = if stage.command.contains('/') {
+            None
+        } else {
+            pathcache::find_executable(state, &stage.command)
+        };
+        let mut process = std::process::Command::new(program.as_deref().unwrap_or(&stage.command));
+        process
+            .arg0(&stage.command)
+            .args(
+                stage
+                    .args
+                    .iter()
+                    .map(|arg| std::ffi::OsString::from_vec(expand::text_to_bytes(arg))),
+            )
+            .env_clear()
+            .envs(state.environment())
+            .envs(
+                stage
+                    .assignments
+                    .iter()
+                    .map(|assignment| split_assignment(assignment)),
+            )
+            .process_group(job.pgid);
+        state.sinks.attach(&mut process);
+        match previous_stdout.take() {
+            Some(stdout) => process.stdin(stdout),
+            None if index > 0 => process.stdin(std::process::Stdio::null()),
+            None => &mut process,
+        };
+        let mut redirect_failed = false;
+        let mut pipe_reader = None;
+        if !is_last && stage.pipe_stderr {
+            match pipe_both() {
+                Ok((reader, stdout, stderr)) => {
+                    process.stdout(stdout).stderr(stderr);
+                    pipe_reader = Some(reader);
+                }
+                Err(e) => {
+                    println_err!(state, "pipe error: {}", e);
+                    redirect_failed = true;
+                }
+            }
+        } else if !is_last {
+            process.stdout(std::process::Stdio::piped());
+        }
+
+        let redirects = [
+            (&stage.redirect_stdout, stage.append_stdout, true),
+            (&stage.redirect_stderr, stage.append_stderr, false),
+        ];
+        if state.shopt("checksamefile") && reads_own_output(stage) {
+            println_err!(state, "warning: reading and writing the same file");
+        }
+        if let Some(path) = &stage.redirect_stdin {
+            match open_input_redirect(path) {
+                Ok(file) => {
+                    process.stdin(file);
+                }
+                Err(e) => {
+                    println_err!(state, "{}: {}", prompt::sanitize(path), e);
+                    redirect_failed = true;
+                }
+            }
+        }
+        if let Some(duplicate) = stage.duplicate_stdin {
+            match duplicate.open(false, &state.sinks) {
+                Ok(file) => {
+                    process.stdin(file);
+                }
+                Err(message) => {
+                    println_err!(state, "{}", message);
+                    redirect_failed = true;
+                }
+            }
+        }
+        if let Some(duplicate) = stage.duplicate_stdout {
+            match duplicate.open(true, &state.sinks) {
+                Ok(file) => {
+                    if stage.pipe_stderr {
+                        if let Ok(copy) = file.try_clone() {
+                            process.stderr(copy);
+                        }
+                    }
+                    process.stdout(file);
+                }
+                Err(message) => {
+                    println_err!(state, "{}", message);
+                    redirect_failed = true;
+                }
+            }
+        }
+        // `2>&1` on the last stage, whose stdout is the shell's
+        if is_last
+            && stage.pipe_stderr
+            && stage.redirect_stdout.is_none()
+            && stage.duplicate_stdout.is_none()
+        {
+            if let Ok(file) = Duplicate::Fd(1).open(true, &state.sinks) {
+                process.stderr(file);
+            }
+        }
+        for (path, append, is_stdout) in redirects {
+            let Some(path) = path else {
+                continue;
+            };
+            match open_redirect(state, path, append) {
+                Ok(file) if is_stdout => {
+                    if stage.pipe_stderr {
+                        if let Ok(copy) = file.try_clone() {
+                            process.stderr(copy);
+                        }
+                    }
+                    process.stdout(file);
+                }
+                // `|&` redirects stderr after this
+                Ok(_) if stage.pipe_stderr => {}
+                Ok(file) => {
+                    process.stderr(file);
+                }
+                Err(e) => {
+                    println_err!(
+                        state,
+                        "Error opening file {}: {}",
+                        prompt::sanitize(path),
+                        e
+                    );
+                    redirect_failed = true;
+                }
+            }
+        }
+        if let Some(duplicate) = stage.duplicate_stderr {
+            match duplicate.open(true, &state.sinks) {
+                Ok(file) => {
+                    process.stderr(file);
+                }
+                Err(message) => {
+                    println_err!(state, "{}", message);
+                    redirect_failed = true;
+                }
+            }
+        }
+        for (_, duplicate) in &stage.duplicate_fds {
+            if let Duplicate::Fd(fd) = duplicate {
+                if *fd > 2 && unsafe { libc::fcntl(*fd, libc::F_GETFD) } == -1 {
+                    println_err!(state, "{}: Bad file descriptor", fd);
+                    redirect_failed = true;
+                }
+            }
+        }
+        if redirect_failed {
+            job.pids.push(0);
+            job.states.push(jobs::ProcessState::Done(1));
+            continue;
+        }
+
+        let limits = state.command_limits.clone();
+        let duplicate_fds = stage.duplicate_fds.clone();
+        unsafe {
+            process.pre_exec(move || {
+                if take_terminal {
+                    libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+                }
+                for setting in &limits {
+                    setting.apply()?;
+                }
+                // Fds 0 to 2 are the command's own by now
+                for (fd, duplicate) in &duplicate_fds {
+                    match duplicate {
+                        // A descriptor kept as itself only loses close-on-exec
+                        Duplicate::Fd(source) if source == fd => libc::fcntl(*fd, libc::F_SETFD, 0),
+                        Duplicate::Fd(source) => libc::dup2(*source, *fd),
+                        Duplicate::Close => libc::close(*fd),
+                    };
+                }
+                for signal in [
+                    libc::SIGINT,
+                    libc::SIGQUIT,
+                    libc::SIGTSTP,
+                    libc::SIGTTIN,
+                    libc::SIGTTOU,
+                ] {
+                    libc::signal(signal, libc::SIG_DFL);
+                }
+                Ok(())
+            });
+        }
+        match process.spawn() {
+            Ok(mut child) => {
+                let pid = child.id() as libc::pid_t;
+                if job.pgid == 0 {
+                    job.pgid = pid;
+                }
+                previous_stdout = pipe_reader.take().or_else(|| {
+                    let stdout = child.stdout.take()?;
+                    Some(std::fs::File::from(std::os::fd::OwnedFd::from(stdout)))
+                });
+                job.pids.push(pid);
+                job.states.push(jobs::ProcessState::Running);
+            }
+            // Such as a limit from `ulimit ... --` the system refused
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                println_err!(state, "{}: {}", prompt::sanitize(&stage.command), e);
+                job.pids.push(0);
+                job.states.push(jobs::ProcessState::Done(126));
+            }
+            Err(_) => {
+                println_err!(
+                    state,
+                    "{}: command not found",
+                    prompt::sanitize(&stage.command)
+                );
+                for line in path_diagnostics(state, &stage.command) {
+                    println_err!(state, "{}", line);
+                }
+                job.pids.push(0);
+                job.states.push(jobs::ProcessState::Done(127));
+            }
+        }
+    }
+
+    if background && job.pgid != 0 {
+        let last_pid = job.last_pid();
+        state.last_background = last_pid;
+        let id = state.jobs.insert(job);
+        if interactive {
+            println_out!(state, "[{}] {}", id, last_pid.unwrap_or(0));
+        }
+        return 0;
+    }
+    wait_foreground(state, job, false)
+}
+
+/// A builtin or a group in a pipeline run by `run_job`.
+struct BuiltinStage<'a> {
+    /// `None` for a group.
+    builtin: Option<BuiltinCommand>,
+    stage: &'a TokenizerResult,
+    /// Output of the previous stage, if any.
+    stdin: Option<std::fs::File>,
+    is_last: bool,
+    /// Whether the stage gets the terminal, as the first of an interactive
+    /// foreground job.
+    take_terminal: bool,
+}
+
+/// Starts a builtin stage of a pipeline, adding it to `job`, and returns
+/// the read end of its output for the next stage. Pure builtins run right
+/// away in the shell, and a forked child feeds what they printed into the
+/// pipe so the next stage can start; the others and groups run in a
+/// forked child.
+fn start_builtin_stage(
+    state: &mut ShellState,
+    stage: BuiltinStage,
+    job: &mut jobs::Job,
+) -> Option<std::fs::File> {
+    let pipe = if stage.is_last {
+        None
+    } else {
+        match pipe_both() {
+            Ok(pipe) => Some(pipe),
+            Err(e) => {
+                println_err!(state, "pipe error: {}", e);
+                job.pids.push(0);
+                job.states.push(jobs::ProcessState::Done(1));
+                return None;
+            }
+        }
+    };
+    if stage.builtin.as_ref().is_some_and(BuiltinCommand::is_pure) {
+        run_pure_stage(state, stage, pipe, job)
+    } else {
+        fork_builtin_stage(state, stage, pipe, job)
+    }
+}
+
+fn run_pure_stage(
+    state: &mut ShellState,
+    stage: BuiltinStage,
+    pipe: Option<(std::fs::File, std::fs::File, std::fs::File)>,
+    job: &mut jobs::Job,
+) -> Option<std::fs::File> {
+    let tokenized = stage.stage;
+    let Some(builtin) = &stage.builtin else {
+        return None;
+    };
+    let (out_file, err_file) = match open_builtin_redirects(state, tokenized) {
+        Ok(files) => files,
+        Err(status) => {
+            job.pids.push(0);
+            job.states.push(jobs::ProcessState::Done(status));
+            return None;
+        }
+    };
+    if state.builtin_input.is_none() {
+        state.builtin_input = stage.stdin;
+    }
+    let (mut status, output) = run_builtin(state, builtin, tokenized);
+
+    let Some((reader, writer, writer_copy)) = pipe else {
+        let mut out = io::BufWriter::new(match out_file {
+            Some(file) => Box::new(file) as Box<dyn Write>,
+            None => state.sinks.stdout(),
+        });
+        let mut err = io::BufWriter::new(match err_file {
+            Some(file) => Box::new(file) as Box<dyn Write>,
+            None => state.sinks.stderr(),
+        });
+        if let Err(e) = write_output(&output, &mut out, &mut err) {
+            println_err!(
+                state,
+                "{}: write error: {}",
+                prompt::sanitize(&tokenized.command),
+                e
+            );
+            status = 1;
+        }
+        job.pids.push(0);
+        job.states.push(jobs::ProcessState::Done(status));
+        return None;
+    };
+
+    // `|&` sends stderr wherever stdout goes
+    let out = out_file.unwrap_or(writer);
+    let err = match (tokenized.pipe_stderr, err_file) {
+        (true, _) => Some(out.try_clone().unwrap_or(writer_copy)),
+        (false, file) => file,
+    };
+    // A child writes the output, so the shell can go on to start the stages
+    // reading it; once they are gone, writing fails with EPIPE and the
+    // child ends. Unlike a thread, it leaves nothing behind that a later
+    // fork could copy halfway through, such as a held lock.
+    match fork_shell() {
+        -1 => {
+            println_err!(state, "fork: {}", io::Error::last_os_error());
+            job.pids.push(0);
+            job.states.push(jobs::ProcessState::Done(1));
+        }
+        0 => {
+            unsafe {
+                libc::setpgid(0, job.pgid);
+                for signal in [libc::SIGINT, libc::SIGQUIT, libc::SIGTSTP] {
+                    libc::signal(signal, libc::SIG_DFL);
+                }
+            }
+            drop(reader);
+            let mut out = io::BufWriter::new(out);
+            let mut err = io::BufWriter::new(match err {
+                Some(file) => Box::new(file) as Box<dyn Write>,
+                None => state.sinks.stderr(),
+            });
+            let _ = write_output(&output, &mut out, &mut err);
+            drop((out, err));
+            exit_child(status);
+        }
+        pid => {
+            if job.pgid == 0 {
+                job.pgid = pid;
+            }
+            unsafe { libc::setpgid(pid, job.pgid) };
+            job.pids.push(pid);
+            job.states.push(jobs::ProcessState::Running);
+        }
+    }
+    Some(reader)
+}
+
+fn fork_builtin_stage(
+    state: &mut ShellState,
+    stage: BuiltinStage,
+    pipe: Option<(std::fs::File, std::fs::File, std::fs::File)>,
+    job: &mut jobs::Job,
+) -> Option<std::fs::File> {
+    use std::os::unix::io::AsRawFd;
+
+    match fork_shell() {
+        -1 => {
+            println_err!(state, "fork: {}", io::Error::last_os_error());
+            job.pids.push(0);
+            job.states.push(jobs::ProcessState::Done(1));
+            None
+        }
+        0 => {
+            unsafe {
+                libc::setpgid(0, job.pgid);
+                if stage.take_terminal {
+                    libc::tcsetpgrp(libc::STDIN_FILENO, libc::getpgrp());
+                }
+                // Also SIGPIPE, as `Command` does for the external stages
+                for signal in [
+                    libc::SIGINT,
+                    libc::SIGQUIT,
+                    libc::SIGTSTP,
+                    libc::SIGTTIN,
+                    libc::SIGTTOU,
+                    libc::SIGPIPE,
+                ] {
+                    libc::signal(signal, libc::SIG_DFL);
+                }
+                if let Some(stdin) = &stage.stdin {
+                    libc::dup2(stdin.as_raw_fd(), libc::STDIN_FILENO);
+                }
+                if let Some((_, writer, _)) = &pipe {
+                    libc::dup2(writer.as_raw_fd(), libc::STDOUT_FILENO);
+                    if stage.stage.pipe_stderr {
+                        libc::dup2(writer.as_raw_fd(), libc::STDERR_FILENO);
+                    }
+                }
+            }
+            // Input and output go through the pipes now on fds 0, 1 and 2,
+            // not the shell's sinks
+            if stage.stdin.is_some() {
+                state.sinks.stdin = None;
+            }
+            if pipe.is_some() {
+                state.sinks.stdout = None;
+                if stage.stage.pipe_stderr {
+                    state.sinks.stderr = None;
+                }
+            }
+            drop(pipe);
+            drop(stage.stdin);
+            enter_subshell(state);
+            let status = match &stage.stage.group {
+                Some(group) => run_forked_group(state, stage.stage, group.body()),
+                None => execute_command(state, stage.stage.clone(), &stage.stage.command),
+            };
+            exit_child(status);
+        }
+        pid => {
+            if job.pgid == 0 {
+                job.pgid = pid;
+            }
+            unsafe { libc::setpgid(pid, job.pgid) };
+            job.pids.push(pid);
+            job.states.push(jobs::ProcessState::Running);
+            pipe.map(|(reader, _, _)| reader)
+        }
+    }
+}
+
+/// Runs the list of a group in the forked child. The group's redirections
+/// move the child's own descriptors, so every command in the list
+/// inherits them.
+fn run_forked_group(state: &mut ShellState, stage: &TokenizerResult, body: &str) -> i32 {
+    use std::os::unix::io::AsRawFd;
+
+    let (out_file, err_file) = match open_builtin_redirects(state, stage) {
+        Ok(files) => files,
+        Err(status) => return status,
+    };
+    if let Some(file) = &out_file {
+        unsafe { libc::dup2(file.as_raw_fd(), libc::STDOUT_FILENO) };
+        state.sinks.stdout = None;
+    }
+    if let Some(file) = &err_file {
+        unsafe { libc::dup2(file.as_raw_fd(), libc::STDERR_FILENO) };
+        state.sinks.stderr = None;
+    }
+    if let Some(file) = state.builtin_input.take() {
+        unsafe { libc::dup2(file.as_raw_fd(), libc::STDIN_FILENO) };
+        state.sinks.stdin = None;
+    }
+    drop((out_file, err_file));
+    execute_line(state, body)
+}
+
+/// Runs the list of a `{ ... }` group in the shell itself. Its
+/// redirections stand in for the sinks while the list runs, so builtins
+/// and the commands it starts share them, in the order they write.
+fn run_brace_group(state: &mut ShellState, stage: &TokenizerResult, body: &str) -> i32 {
+    let (stdout, stderr) = match open_builtin_redirects(state, stage) {
+        Ok(files) => files,
+        Err(status) => return status,
+    };
+    let stdin = state.builtin_input.take();
+    let keep = |redirected: Option<std::fs::File>, current: &Option<std::fs::File>| {
+        redirected.or_else(|| current.as_ref()?.try_clone().ok())
+    };
+    let sinks = Sinks {
+        stdin: keep(stdin, &state.sinks.stdin),
+        stdout: keep(stdout, &state.sinks.stdout),
+        stderr: keep(stderr, &state.sinks.stderr),
+    };
+    let saved = std::mem::replace(&mut state.sinks, sinks);
+    let status = execute_line(state, body);
+    state.sinks = saved;
+    status
+}
+
+/// Runs `body` in a forked subshell for `$(...)` and returns what it
+/// wrote to stdout, without trailing newlines. `$?` becomes the status the
+/// subshell exited with.
+pub(crate) fn command_substitution(state: &mut ShellState, body: &str) -> String {
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+
+    let (mut reader, writer) = match pipe() {
+        Ok(ends) => ends,
+        Err(error) => {
+            println_err!(state, "pipe: {}", error);
+            state.last_status = 1;
+            return String::new();
+        }
+    };
+    let pid = match fork_shell() {
+        -1 => {
+            println_err!(state, "fork: {}", io::Error::last_os_error());
+            state.last_status = 1;
+            return String::new();
+        }
+        0 => {
+            unsafe {
+                for signal in [libc::SIGINT, libc::SIGQUIT, libc::SIGPIPE] {
+                    libc::signal(signal, libc::SIG_DFL);
+                }
+                libc::dup2(writer.as_raw_fd(), libc::STDOUT_FILENO);
+            }
+            state.sinks.stdout = None;
+            drop((reader, writer));
+            enter_subshell(state);
+            // As in bash, `set -e` is not inherited; only the status of
+            // the whole substitution counts, as that of an assignment
+            state.options.insert("errexit", false);
+            let status = execute_line(state, body);
+            exit_child(status);
+        }
+        pid => pid,
+    };
+    drop(writer);
+    let mut output = Vec::new();
+    if let Err(error) = reader.read_to_end(&mut output) {
+        println_err!(state, "command substitution: {}", error);
+    }
+    let mut raw_status = 0;
+    while unsafe { libc::waitpid(pid, &mut raw_status, 0) } == -1 {
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            break;
+        }
+    }
+    if let jobs::ProcessState::Done(status) = jobs::process_state(raw_status) {
+        state.last_status = status;
+        state.substitution_status = Some(status);
+    }
+    // No word or variable can hold a NUL, so bash drops them with a warning
+    let len = output.len();
+    output.retain(|byte| *byte != 0);
+    if output.len() < len {
+        println_err!(
+            state,
+            "warning: command substitution: ignored null byte in input"
+        );
+    }
+    let mut output = expand::bytes_to_text(&output);
+    output.truncate(output.trim_end_matches('\n').len());
+    output
+}
+
+/// Forks a copy of the shell that goes on running commands itself. What
+/// the shell buffered is flushed first, so the child does not write it
+/// again.
+fn fork_shell() -> libc::pid_t {
+    // Output that cannot be flushed is lost either way
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    unsafe { libc::fork() }
+}
+
+/// Ends a child from `fork_shell` with `status` once its buffered output
+/// is written. Output it could not write makes a success a failure.
+fn exit_child(status: i32) -> ! {
+    match io::stdout().flush() {
+        Err(_) if status == 0 => std::process::exit(1),
+        _ => std::process::exit(status),
+    }
+}
+
+/// A pipe a stage writes into, stderr included after `|&`: its read end,
+/// and two copies of its write end for stdout and stderr.
+fn pipe_both() -> io::Result<(std::fs::File, std::fs::File, std::fs::File)> {
+    let (reader, writer) = pipe()?;
+    let copy = writer.try_clone()?;
+    Ok((reader, writer, copy))
+}
+
+/// A pipe's read and write ends. They are closed on exec, so only the
+/// processes they are handed to keep them open, and when dropped.
+fn pipe() -> io::Result<(std::fs::File, std::fs::File)> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owned at once, so both close on every way out
+    let (reader, writer) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    for fd in [&reader, &writer] {
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((reader.into(), writer.into()))
+}
+
+/// Set to `1` to report descriptors each top-level command leaves open.
+const FD_AUDIT_VARIABLE: &str = "MYSHELL_FD_AUDIT";
+
+/// Runs a command line read at the top level. With `MYSHELL_FD_AUDIT=1`,
+/// reports the descriptors it leaves open.
+fn execute_audited(state: &mut ShellState, input: &str) -> i32 {
+    let audit = state
+        .variable(FD_AUDIT_VARIABLE)
+        .is_some_and(|value| value == "1");
+    let before = if audit { open_fds() } else { None };
+    let status = execute_line(state, input);
+    if let Some(before) = before {
+        report_fd_leaks(state, &before);
+    }
+    status
+}
+
+/// Descriptors open in the shell, or `None` without `/proc/self/fd`.
+fn open_fds() -> Option<std::collections::BTreeSet<libc::c_int>> {
+    let fds: Vec<libc::c_int> = std::fs::read_dir("/proc/self/fd")
+        .ok()?
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .collect();
+    // Leaves out the one the listing itself used, closed by now
+    Some(
+        fds.into_iter()
+            .filter(|fd| unsafe { libc::fcntl(*fd, libc::F_GETFD) } != -1)
+            .collect(),
+    )
+}
+
+/// Reports descriptors open now that were not in `before`, other than the
+/// pipes of coprocesses, which outlive the command that started them.
+fn report_fd_leaks(state: &ShellState, before: &std::collections::BTreeSet<libc::c_int>) {
+    let Some(after) = open_fds() else {
+        return;
+    };
+    for fd in after.difference(before) {
+        if state
+            .coprocs
+            .iter()
+            .any(|coproc| coproc.raw_fds().contains(fd))
+        {
+            continue;
+        }
+        let target = std::fs::read_link(format!("/proc/self/fd/{}", fd))
+            .map(|target| target.display().to_string())
+            .unwrap_or_default();
+        println_err!(state, "fd audit: {} left open: {}", fd, target);
+    }
+}
+
+/// Waits for `job` in the foreground, handing it the terminal meanwhile. A
+/// stopped job goes (back) into the job table. With `resume` the job is
+/// sent `SIGCONT` first, as for `fg`.
+fn wait_foreground(state: &mut ShellState, mut job: jobs::Job, resume: bool) -> i32 {
+    let pipefail = state.option("pipefail");
+    if state.interactive && job.pgid != 0 {
+        jobs::give_terminal(job.pgid);
+    }
+    if resume {
+        if let Err(e) = job.signal(libc::SIGCONT) {
+            println_err!(state, "fg: {}", e);
+        }
+        job.resume();
+    }
+    let job_state = job.wait(pipefail);
+    if state.interactive {
+        jobs::give_terminal(unsafe { libc::getpgrp() });
+    }
+    match job_state {
+        jobs::JobState::Done(status) => {
+            // Like bash, move past the `^C` echoed by the terminal
+            if state.interactive && status == 128 + libc::SIGINT {
+                println_out!(state);
+            }
+            match status - 128 {
+                libc::SIGXCPU => println_err!(state, "CPU time limit exceeded"),
+                libc::SIGXFSZ => println_err!(state, "File size limit exceeded"),
+                _ => {}
+            }
+            status
+        }
+        _ => {
+            let id = state.jobs.insert(job);
+            let notice = state
+                .jobs
+                .iter()
+                .find(|job| job.id == id)
+                .map(|job| state.jobs.format(job, pipefail));
+            println_out!(state);
+            if let Some(notice) = notice {
+                println_out!(state, "{}", notice);
+            }
+            128 + libc::SIGTSTP
+        }
+    }
+}
+
+/// Runs a single foreground command: builtins in the shell itself,
+/// executables as a one-stage job.
+fn execute_command(state: &mut ShellState, tokenized: TokenizerResult, input: &str) -> i32 {
+    let tokenized = autocd(state, tokenized);
+    let builtin = match resolve_command(state, &tokenized.command) {
+        Some(Command::Function(function)) => {
+            let saved = apply_assignments(state, &tokenized.assignments, true);
+            let status = call_function(state, &tokenized.command, &function, &tokenized.args);
+            restore_assignments(state, saved);
+            return status;
+        }
+        Some(Command::BuiltinCommand(builtin)) => builtin,
+        Some(Command::ExecutableCommand(_)) => {
+            return run_job(state, std::slice::from_ref(&tokenized), input, false);
+        }
+        None => return command_not_found(state, &tokenized.command, &tokenized.args),
+    };
+    let (out_file, err_file) = match open_builtin_redirects(state, &tokenized) {
+        Ok(files) => files,
+        Err(status) => return status,
+    };
+
+    state.builtin_stdout_terminal = out_file.is_none() && stdout_is_terminal();
+
+    // Buffered writers for the file handles, flushed by `write_output`
+    // before the command returns
+    let mut out_writer: io::BufWriter<Box<dyn Write>> = io::BufWriter::new(match out_file {
+        Some(file) => Box::new(file),
+        None => state.sinks.stdout(),
+    });
+
+    let mut err_writer: io::BufWriter<Box<dyn Write>> = io::BufWriter::new(match err_file {
+        Some(file) => Box::new(file),
+        None => state.sinks.stderr(),
+    });
+
+    let (mut status, mut output) = run_builtin(state, &builtin, &tokenized);
+    if let Err(e) = write_output(&output, &mut out_writer, &mut err_writer) {
+        println_err!(
+            state,
+            "{}: write error: {}",
+            prompt::sanitize(&tokenized.command),
+            e
+        );
+        status = 1;
+    }
+    if status != 0 && builtin.is_special() && state.option("posix") && !state.interactive {
+        exit_shell(state, status);
+    }
+
+    output.clear();
+    status
+}
+
+/// With `shopt -s autocd`, turns a lone directory name that is not a
+/// command into `cd` with it as the argument.
+fn autocd(state: &ShellState, tokenized: TokenizerResult) -> TokenizerResult {
+    if !state.shopt("autocd")
+        || !tokenized.args.is_empty()
+        || resolve_command(state, &tokenized.command).is_some()
+        || !std::path::Path::new(&tokenized.command).is_dir()
+    {
+        return tokenized;
+    }
+    TokenizerResult {
+        args: vec![tokenized.command.clone()],
+        command: "cd".to_string(),
+        ..tokenized
+    }
+}
+
+/// Opens a builtin's redirections: the files its stdout and stderr go to,
+/// and its `<` file as `state.builtin_input`. Failures are reported and
+/// come back as the status to return.
+fn open_builtin_redirects(
+    state: &mut ShellState,
+    tokenized: &TokenizerResult,
+) -> Result<(Option<std::fs::File>, Option<std::fs::File>), i32> {
+    let mut out_file = None;
+    if let Some(path) = &tokenized.redirect_stdout {
+        match open_redirect(state, path, tokenized.append_stdout) {
+            Ok(file) => out_file = Some(file),
+            Err(e) => {
+                println_err!(
+                    state,
+                    "Error opening output file {}: {}",
+                    prompt::sanitize(path),
+                    e
+                );
+                return Err(1);
+            }
+        }
+    }
+
+    let mut err_file = None;
+    if let Some(path) = &tokenized.redirect_stderr {
+        match open_redirect(state, path, tokenized.append_stderr) {
+            Ok(file) => err_file = Some(file),
+            Err(e) => {
+                println_err!(
+                    state,
+                    "Error opening error file {}: {}",
+                    prompt::sanitize(path),
+                    e
+                );
+                return Err(1);
+            }
+        }
+    }
+
+    if let Some(path) = &tokenized.redirect_stdin {
+        match open_input_redirect(path) {
+            Ok(file) => state.builtin_input = Some(file),
+            Err(e) => {
+                println_err!(state, "{}: {}", prompt::sanitize(path), e);
+                return Err(1);
+            }
+        }
+    }
+
+    let duplicates = [
+        (tokenized.duplicate_stdin, 0),
+        (tokenized.duplicate_stdout, 1),
+        (tokenized.duplicate_stderr, 2),
+    ];
+    for (duplicate, fd) in duplicates {
+        let Some(duplicate) = duplicate else {
+            continue;
+        };
+        match duplicate.open(fd != 0, &state.sinks) {
+            Ok(file) if fd == 1 => out_file = Some(file),
+            Ok(file) if fd == 2 => err_file = Some(file),
+            Ok(file) => state.builtin_input = Some(file),
+            Err(message) => {
+                println_err!(state, "{}", message);
+                return Err(1);
+            }
+        }
+    }
+
+    // After `>& file` or `2>&1` stderr goes wherever stdout does
+    if tokenized.pipe_stderr {
+        err_file = match &out_file {
+            Some(file) => file.try_clone().ok(),
+            None => Duplicate::Fd(1).open(true, &state.sinks).ok(),
+        };
+    }
+    Ok((out_file, err_file))
+}
+
+/// Runs a builtin with the stage's assignments in place and returns its
+/// status and what it printed.
+fn run_builtin(
+    state: &mut ShellState,
+    builtin: &BuiltinCommand,
+    tokenized: &TokenizerResult,
+) -> (i32, Output) {
+    let args: Vec<&str> = tokenized.args.iter().map(String::as_str).collect();
+    let mut output = Output::new();
+
+    // Assignments before a builtin only last for it, except before special
+    // builtins in posix mode
+    let persist = builtin.is_special() && state.option("posix");
+    let saved = apply_assignments(state, &tokenized.assignments, !persist);
+    let status = builtin.to_impl()(&args, state, &mut output);
+    state.builtin_input = None;
+    state.builtin_stdout_terminal = false;
+    if !persist {
+        restore_assignments(state, saved);
+    }
+    (status, output)
+}
+
+/// Writes a builtin's output to its buffered stdout and stderr and flushes
+/// both. Switching from one to the other flushes the first, so lines keep
+/// their order when both end up in the same place.
+fn write_output(output: &Output, out: &mut impl Write, err: &mut impl Write) -> io::Result<()> {
+    let mut last_is_err = None;
+    for line in output.get() {
+        if last_is_err == Some(!line.is_err) {
+            if line.is_err {
+                out.flush()?;
+            } else {
+                err.flush()?;
+            }
+        }
+        last_is_err = Some(line.is_err);
+        let writer: &mut dyn Write = if line.is_err { err } else { out };
+        writer.write_all(&line.bytes)?;
+        if line.newline {
+            writer.write_all(b"\n")?;
+        }
+    }
+    out.flush()?;
+    err.flush()
+}
+
+extern "C" fn handle_sigwinch(_signal: libc::c_int) {
+    WINDOW_RESIZED.store(true, Ordering::Relaxed);
+    signals::wake();
+}
+
+/// Set by the SIGHUP handler of an interactive shell, which then shuts
+/// down between commands or keys.
+static HUNG_UP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sighup(_signal: libc::c_int) {
+    HUNG_UP.store(true, Ordering::Relaxed);
+    signals::wake();
+}
+
+/// Wakes the line editor so it can report jobs that finished; reaping is
+/// left to `job_notices`.
+extern "C" fn handle_sigchld(_signal: libc::c_int) {
+    signals::wake();
+}
+
+extern "C" fn handle_sleep_interrupt(_signal: libc::c_int) {
+    SLEEP_INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+/// Returns the terminal size as `(lines, columns)` when stdout is a terminal.
+fn terminal_size() -> Option<(u16, u16)> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == -1
+        || size.ws_col == 0
+    {
+        return None;
+    }
+    Some((size.ws_row, size.ws_col))
+}
+
+fn stdout_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+/// Width to format output to, for the line editor and builtins that lay
+/// text out in columns: `$COLUMNS`, kept current on SIGWINCH by
+/// `sync_window_size`, or the width the terminal reports. `None` when
+/// stdout is not a terminal, where output should stay in one unwrapped
+/// column.
+fn terminal_width(state: &ShellState) -> Option<usize> {
+    if !stdout_is_terminal() {
+        return None;
+    }
+    state
+        .variable("COLUMNS")
+        .and_then(|columns| columns.parse().ok())
+        .filter(|&columns| columns > 0)
+        .or_else(|| terminal_size().map(|(_, columns)| columns as usize))
+}
+
+/// Exports the terminal size as `LINES` and `COLUMNS` when `checkwinsize`
+/// is on. Without a terminal the variables are left untouched.
+fn sync_window_size(state: &mut ShellState) {
+    if !state.shopt("checkwinsize") {
+        return;
+    }
+    if let Some((lines, columns)) = terminal_size() {
+        state.set_variable("LINES", &lines.to_string());
+        state.set_variable("COLUMNS", &columns.to_string());
+    }
+}
+
+/// Reads the next command line, through the line editor when stdin is a
+/// terminal. Returns `None` at end of input.
+fn read_input(state: &mut ShellState) -> io::Result<Option<String>> {
+    let info = prompt::PromptInfo {
+        jobs: state.jobs.count(),
+        history_number: state.history.next_number(),
+        command_number: state.commands_read + 1,
+        now: std::time::SystemTime::now(),
+        local_now: Default::default(),
+        user: state.variable("USER").unwrap_or_default(),
+        pwd: state.variable("PWD").unwrap_or_else(|| {
+            std::env::current_dir()
+                .map(|dir| dir.display().to_string())
+                .unwrap_or_default()
+        }),
+        home: home_dir(state),
+    };
+    let ps1 = state.variable("PS1");
+    let prompt = prompt::render(ps1.as_deref(), editor::terminal_columns(state), &info);
+    let right_prompt = prompt::render_right(state.variable("RPS1").as_deref(), &info);
+    let timeout = input_timeout(state);
+    let Some(mut input) = read_line_with_prompt(state, &prompt, right_prompt.as_deref(), timeout)?
+    else {
+        return Ok(None);
+    };
+    // Keep reading with `PS2` until a `case` or an and-or list is complete
+    while compound::is_incomplete(&input) || ends_with_connector(&input) {
+        let prompt = state.variable("PS2").unwrap_or_else(|| "> ".to_string());
+        let Some(line) = read_line_with_prompt(state, &prompt, None, timeout)? else {
+            break;
+        };
+        if !input.ends_with('\n') {
+            input.push('\n');
+        }
+        input.push_str(&line);
+    }
+    Ok(Some(input))
+}
+
+/// How long an interactive shell waits for a key before giving up; see
+/// `tmout`.
+fn input_timeout(state: &ShellState) -> Option<std::time::Duration> {
+    if !state.interactive {
+        return None;
+    }
+    tmout(state)
+}
+
+/// `TMOUT` seconds, if it is set to a positive number.
+fn tmout(state: &ShellState) -> Option<std::time::Duration> {
+    let seconds = state.variable("TMOUT")?.parse::<u64>().ok()?;
+    if seconds == 0 {
+        return None;
+    }
+    Some(std::time::Duration::from_secs(seconds))
+}
+
+/// Reads one line after showing `prompt`, and `right_prompt` at the right
+/// edge when the line editor is used, failing with `TimedOut` once no key
+/// has come for `timeout`.
+fn read_line_with_prompt(
+    state: &mut ShellState,
+    prompt: &str,
+    right_prompt: Option<&str>,
+    timeout: Option<std::time::Duration>,
+) -> io::Result<Option<String>> {
+    // `-i` can make the shell interactive without a terminal to edit on
+    if state.interactive && unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+        return editor::read_line(state, prompt, right_prompt, timeout);
+    }
+    print_out!(state, "{}", prompt::strip_markers(prompt));
+    io::stdout().flush()?;
+    if state.interactive {
+        // Unbuffered, so a `TMOUT` wait sees every byte not read yet
+        let deadline = timeout.map(|timeout| std::time::Instant::now() + timeout);
+        return editor::read_reply(libc::STDIN_FILENO, deadline);
+    }
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(input))
+}
+
+/// Collects the jobs that finished, releasing their coprocesses, and
+/// returns their notices wrapped to the terminal width.
+fn job_notices(state: &mut ShellState) -> Vec<String> {
+    state.jobs.reap();
+    release_coprocs(state);
+    state
+        .jobs
+        .take_finished(state.option("pipefail"))
+        .iter()
+        .flat_map(|notice| prompt::wrap(notice, editor::terminal_columns(state)))
+        .collect()
+}
+
+/// Summary printed by `--help`.
+const USAGE: &str = "\
+Usage: codecrafters-shell [option ...] [script [arg ...]]
+       codecrafters-shell [option ...] -c command [name [arg ...]]
+       codecrafters-shell [option ...] -s [arg ...]
+
+Options:
+  -c command   run command instead of reading from a script or stdin
+  -i, --interactive
+               run interactively even when stdin is not a terminal
+  -l, --login  act as a login shell
+  -n           check the syntax of the commands without running them
+  -s           read commands from stdin, taking operands as $1, $2, ...
+  --noprofile  do not read ~/.codecrafters_shell_profile as a login shell
+  --norc       do not read ~/.codecrafters_shellrc when interactive
+  --rcfile file
+               read file instead of ~/.codecrafters_shellrc
+  --help       print this summary and exit
+  --version    print the shell version and exit
+";
+
+/// Command-line arguments of the shell itself.
+#[derive(Debug, Default)]
+struct Invocation {
+    /// Command string given with `-c`.
+    command: Option<String>,
+    /// Script file to run instead of reading stdin.
+    script: Option<String>,
+    /// `$0` for `-c`, then the positional parameters.
+    args: Vec<String>,
+    /// Hidden `--startup-profile`: time each startup phase.
+    startup_profile: bool,
+    /// `-n`: only check the syntax of a script or `-c` command.
+    noexec: bool,
+    /// `-l`/`--login`: act as a login shell.
+    login: bool,
+    /// `-i`/`--interactive`: interactive even when stdin is not a terminal.
+    interactive: bool,
+    /// `-s`: read commands from stdin; operands are positional parameters.
+    stdin: bool,
+    /// `--noprofile`: skip the login profiles.
+    noprofile: bool,
+    /// `--norc`: skip the interactive startup file.
+    norc: bool,
+    /// `--rcfile FILE`: the interactive startup file to read instead.
+    rcfile: Option<String>,
+}
+
+impl Invocation {
+    fn parse(mut args: impl Iterator<Item = String>) -> Self {
+        let mut invocation = Self::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--startup-profile" => invocation.startup_profile = true,
+                "--login" => invocation.login = true,
+                "--interactive" => invocation.interactive = true,
+                "--noprofile" => invocation.noprofile = true,
+                "--norc" => invocation.norc = true,
+                "--rcfile" => {
+                    let Some(file) = args.next() else {
+                        eprintln!("--rcfile: option requires an argument");
+                        std::process::exit(2);
+                    };
+                    invocation.rcfile = Some(file);
+                }
+                "--version" => {
+                    println!("codecrafters-shell {}", env!("CARGO_PKG_VERSION"));
+                    std::process::exit(0);
+                }
+                "--help" => {
+                    print!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "--" => {
+                    if let Some(arg) = args.next() {
+                        invocation.operands(arg, args);
+                    }
+                    break;
+                }
+                // Commands from stdin, as without a script
+                "-" => break,
+                flags if flags.starts_with('-') && !flags.starts_with("--") => {
+                    // Single-letter flags combine, as in `-il`
+                    let mut command = false;
+                    for flag in flags[1..].chars() {
+                        match flag {
+                            'c' => command = true,
+                            'i' => invocation.interactive = true,
+                            'l' => invocation.login = true,
+                            'n' => invocation.noexec = true,
+                            's' => invocation.stdin = true,
+                            _ => invalid_option(&format!("-{}", flag)),
+                        }
+                    }
+                    if command {
+                        let Some(command) = args.next() else {
+                            eprintln!("-c: option requires an argument");
+                            std::process::exit(2);
+                        };
+                        invocation.command = Some(command);
+                        invocation.args = args.collect();
+                        break;
+                    }
+                }
+                flag if flag.starts_with('-') => invalid_option(flag),
+                _ => {
+                    invocation.operands(arg, args);
+                    break;
+                }
+            }
+        }
+        invocation
+    }
+
+    /// Takes the first operand as the script, or with `-s` as the first
+    /// positional parameter, and the rest as positional parameters.
+    fn operands(&mut self, first: String, rest: impl Iterator<Item = String>) {
+        if self.stdin {
+            self.args = std::iter::once(first).chain(rest).collect();
+        } else {
+            self.script = Some(first);
+            self.args = rest.collect();
+        }
+    }
+}
+
+fn invalid_option(flag: &str) -> ! {
+    eprintln!("{}: invalid option", flag);
+    eprint!("{}", USAGE);
+    std::process::exit(2);
+}
+
+/// Startup file of login shells, in the home directory.
+const PROFILE_FILE: &str = ".codecrafters_shell_profile";
+
+/// Startup file of interactive shells, in the home directory.
+const RC_FILE: &str = ".codecrafters_shellrc";
+
+/// Sources the startup files: `PROFILE_FILE` for a login shell, then
+/// `RC_FILE` or the `--rcfile` file for an interactive one. They are the
+/// shell's own rather than `~/.profile` and `~/.bashrc`, which are full of
+/// syntax it does not understand. Missing default files are skipped
+/// silently; a missing `--rcfile` is reported.
+fn read_startup_files(state: &mut ShellState, invocation: &Invocation) {
+    let home = home_dir(state).map(std::path::PathBuf::from);
+    let mut files = Vec::new();
+    if state.shopt("login_shell") && !invocation.noprofile {
+        if let Some(home) = &home {
+            files.push((home.join(PROFILE_FILE), false));
+        }
+    }
+    if state.interactive && !invocation.norc {
+        match (&invocation.rcfile, &home) {
+            (Some(file), _) => files.push((file.into(), true)),
+            (None, Some(home)) => files.push((home.join(RC_FILE), false)),
+            (None, None) => {}
+        }
+    }
+    for (path, explicit) in files {
+        if !path.is_file() {
+            if explicit {
+                println_err!(
+                    state,
+                    "{}: No such file or directory",
+                    prompt::sanitize(&path.display().to_string())
+                );
+            }
+            continue;
+        }
+        let mut output = Output::new();
+        source_fn(&[&path.display().to_string()], state, &mut output);
+        let _ = write_output(
+            &output,
+            &mut state.sinks.stdout(),
+            &mut state.sinks.stderr(),
+        );
+    }
+}
+
+/// Reports how long each startup phase took, to stderr, when enabled.
+struct StartupProfile {
+    enabled: bool,
+    start: std::time::Instant,
+    last: std::time::Instant,
+}
+
+impl StartupProfile {
+    fn new(enabled: bool) -> Self {
+        let now = std::time::Instant::now();
+        Self {
+            enabled,
+            start: now,
+            last: now,
+        }
+    }
+
+    fn mark(&mut self, phase: &str) {
+        let now = std::time::Instant::now();
+        if self.enabled {
+            eprintln!(
+                "startup: {:<12} {:>8.3}ms (total {:.3}ms)",
+                phase,
+                (now - self.last).as_secs_f64() * 1000.0,
+                (now - self.start).as_secs_f64() * 1000.0
+            );
+        }
+        self.last = now;
+    }
+}
+
+/// Runs the shell as a program: parses the command line, then executes a
+/// `-c` command or script, or reads commands from stdin. Never returns.
+pub fn run() -> ! {
+    let invocation = Invocation::parse(std::env::args().skip(1));
+    let mut profile = StartupProfile::new(invocation.startup_profile);
+    let mut state = ShellState::new();
+    state.json_output = state
+        .variable(json::VARIABLE)
+        .is_some_and(|value| value == "1");
+    state.interactive = invocation.interactive
+        || invocation.command.is_none()
+            && invocation.script.is_none()
+            && unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    // Like bash, only interactive shells expand aliases unless told to
+    state.shopts.insert("expand_aliases", state.interactive);
+    // `-bash`-style argv[0] is how login(1) starts a login shell
+    let login_argv0 = std::env::args()
+        .next()
+        .is_some_and(|arg0| arg0.starts_with('-'));
+    if invocation.login || login_argv0 {
+        state.shopts.insert("login_shell", true);
+    }
+    // Like bash, behave as a POSIX shell when invoked as `sh` or when
+    // POSIXLY_CORRECT is in the environment
+    let invoked_as_sh = std::env::args()
+        .next()
+        .is_some_and(|arg0| arg0.rsplit('/').next() == Some("sh"));
+    if invoked_as_sh || state.variable("POSIXLY_CORRECT").is_some() {
+        state.options.insert("posix", true);
+    }
+    if invocation.noexec {
+        state.options.insert("noexec", true);
+    }
+    profile.mark("options");
+    if let Err(e) = signals::init() {
+        println_err!(state, "signal pipe: {}", e);
+    }
+    signals::install(libc::SIGWINCH, handle_sigwinch);
+    if state.interactive {
+        signals::install(libc::SIGCHLD, handle_sigchld);
+        signals::install(libc::SIGHUP, handle_sighup);
+        let owner = unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) };
+        state.terminal_owner = (owner > 0).then_some(owner);
+        // Take our own process group and the terminal, and leave stop
+        // signals to the foreground job
+        unsafe {
+            for signal in [libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU] {
+                libc::signal(signal, libc::SIG_IGN);
+            }
+            libc::setpgid(0, 0);
+        }
+        jobs::give_terminal(unsafe { libc::getpgrp() });
+    }
+    profile.mark("signals");
+    read_startup_files(&mut state, &invocation);
+    profile.mark("rc files");
+
+    if let Some(command) = invocation.command {
+        let mut args = invocation.args.into_iter();
+        state.script = args.next();
+        state.positional = args.collect();
+        let status = execute_script(&mut state, &command, true);
+        shutdown(&mut state, status);
+    }
+    if let Some(script) = invocation.script {
+        let contents = std::fs::read_to_string(&script).unwrap_or_else(|e| {
+            println_err!(state, "{}: {}", prompt::sanitize(&script), e);
+            std::process::exit(127);
+        });
+        state.script = Some(script);
+        state.positional = invocation.args;
+        let status = execute_script(&mut state, &contents, true);
+        shutdown(&mut state, status);
+    }
+    state.positional = invocation.args;
+    if state.interactive {
+        // Read on the first `history` or Up arrow rather than here
+        if let Some(path) = state.variable("HISTFILE") {
+            state.history.defer_load(path);
+        }
+    }
+    sync_window_size(&mut state);
+    profile.mark("terminal");
+    loop {
+        if HUNG_UP.load(Ordering::Relaxed) {
+            shutdown(&mut state, 128 + libc::SIGHUP);
+        }
+        if WINDOW_RESIZED.swap(false, Ordering::Relaxed) {
+            sync_window_size(&mut state);
+        }
+        run_signal_traps(&mut state);
+        let notices = job_notices(&mut state);
+        if state.interactive {
+            for line in notices {
+                println_out!(state, "{}", line);
+            }
+        }
+
+        // Wait for user input
+        let input = match read_input(&mut state) {
+            Ok(Some(input)) => input,
+            Ok(None) => {
+                // EOF at the top level ends the shell with the last status
+                let status = state.last_status;
+                shutdown(&mut state, status);
+            }
+            Err(_) if HUNG_UP.load(Ordering::Relaxed) => {
+                shutdown(&mut state, 128 + libc::SIGHUP);
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                println_err!(state, "\ntimed out waiting for input: auto-logout");
+                let status = state.last_status;
+                shutdown(&mut state, status);
+            }
+            Err(e) => {
+                println_err!(state, "Error reading input: {}", e);
+                continue;
+            }
+        };
+        // `!` references and `^old^new` are expanded before anything else,
+        // and the expanded line is echoed and recorded in their place
+        let references = state.interactive && (input.contains('!') || input.starts_with('^'));
+        if references {
+            load_history(&mut state);
+        }
+        let expansion = if references {
+            state.history.expand(&input)
+        } else {
+            Ok(None)
+        };
+        let input = match expansion {
+            Ok(Some(expanded)) => {
+                println_out!(state, "{}", expanded.trim_end_matches('\n'));
+                expanded
+            }
+            Ok(None) => input,
+            Err(message) => {
+                println_err!(state, "{}", message);
+                state.last_status = 1;
+                continue;
+            }
+        };
+        // Recorded first, so the command can see itself in `history`
+        let number = state.history.next_number();
+        let ignore = state.variable("HISTIGNORE").unwrap_or_default();
+        state.history.record(&input, &ignore);
+        state.commands_read += 1;
+        let status = execute_audited(&mut state, &input);
+        if status != 0 && state.option("histignorefailure") && state.history.next_number() > number
+        {
+            state.history.delete(number);
+        }
+        sync_window_size(&mut state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn splits_outside_groups() {
+        assert_eq!(split_top_level("(a | b) | c", &['|']), ["(a | b) ", " c"]);
+        assert_eq!(split_top_level("a; { b; c; }", &[';']), ["a", " { b; c; }"]);
+        assert_eq!(split_list("a;\n;b"), ["a", "b"]);
+        assert_eq!(
+            split_list("a &&\n b ||  \n c\nd"),
+            ["a &&\n b ||  \n c", "d"]
+        );
+        assert_eq!(split_list("a & b"), ["a &", " b"]);
+        assert_eq!(split_list("a&b&"), ["a&", "b&"]);
+        assert_eq!(
+            split_list("a >&2 & b &>/dev/null"),
+            ["a >&2 &", " b &>/dev/null"]
+        );
+        assert_eq!(split_list("a && b |& c"), ["a && b |& c"]);
+    }
+
+    #[test]
+    fn pure_builtins() {
+        let table = [
+            ("echo", true),
+            ("type", true),
+            ("pwd", true),
+            ("caller", true),
+            ("wc", true),
+            ("basename", true),
+            ("dirname", true),
+            ("realpath", true),
+            // They change the shell or wait on something else
+            ("cd", false),
+            ("read", false),
+            ("set", false),
+            ("export", false),
+            ("exit", false),
+            ("history", false),
+            ("jobs", false),
+            ("wait", false),
+            ("sleep", false),
+            ("watch", false),
+            ("source", false),
+        ];
+        for (name, pure) in table {
+            let builtin = BuiltinCommand::from_str(name).unwrap();
+            assert_eq!(builtin.is_pure(), pure, "{}", name);
+        }
+        let pure = BuiltinCommand::NAMES
+            .iter()
+            .filter(|name| BuiltinCommand::from_str(name).unwrap().is_pure())
+            .count();
+        assert_eq!(pure, table.iter().filter(|(_, pure)| *pure).count());
+    }
+
+    #[test]
+    fn device_confirmation() {
+        use std::os::unix::fs::MetadataExt;
+
+        let zero = std::fs::metadata("/dev/zero").unwrap();
+        let null = std::fs::metadata("/dev/null").unwrap();
+        let file = std::fs::metadata(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")).unwrap();
+        let table: [(&std::fs::Metadata, &str, &[u64], bool); 6] = [
+            (&zero, "/dev/zero", &[], true),
+            (&zero, "/dev/./zero", &[], true),
+            // The shell's own terminal and the harmless devices need not ask
+            (&zero, "/dev/zero", &[zero.rdev()], false),
+            (&zero, "/dev/zero", &[null.rdev()], true),
+            (&null, "/dev/null", &[], false),
+            (&file, "Cargo.toml", &[], false),
+        ];
+        for (meta, path, owned_ttys, confirm) in table {
+            assert_eq!(
+                should_confirm(meta, path, owned_ttys),
+                confirm,
+                "{} {:?}",
+                path,
+                owned_ttys
+            );
+        }
+    }
+
+    #[test]
+    fn and_or_lists() {
+        use Connector::{And, Or};
+        assert_eq!(
+            split_and_or("a && b | c || { d && e; } || 'f||g'"),
+            [
+                (None, "a "),
+                (Some(And), " b | c "),
+                (Some(Or), " { d && e; } "),
+                (Some(Or), " 'f||g'")
+            ]
+        );
+        assert_eq!(split_and_or("a |& b & c"), [(None, "a |& b & c")]);
+        assert_eq!(split_and_or("&& a"), [(None, ""), (Some(And), " a")]);
+        assert!(ends_with_connector("a ||  "));
+        assert!(!ends_with_connector("a | |"));
+        assert!(!ends_with_connector("echo '&&'"));
+    }
+
+    #[test]
+    fn subshell_groups() {
+        let subshell = |body: &str| Group::Subshell(body.to_string());
+        assert_eq!(
+            parse_group(" (echo a) > f"),
+            Some(Ok((subshell("echo a"), " > f")))
+        );
+        assert_eq!(
+            parse_group("(echo ')'; (b))"),
+            Some(Ok((subshell("echo ')'; (b)"), "")))
+        );
+        assert_eq!(
+            parse_group("(case x in x) a;; esac)"),
+            Some(Ok((subshell("case x in x) a;; esac"), "")))
+        );
+        assert!(matches!(parse_group("(echo a"), Some(Err(_))));
+        assert_eq!(parse_group("echo (a)"), None);
+    }
+
+    #[test]
+    fn brace_groups() {
+        let braces = |body: &str| Group::Braces(body.to_string());
+        assert_eq!(
+            parse_group("{ echo a; } > f"),
+            Some(Ok((braces("echo a;"), " > f")))
+        );
+        assert_eq!(
+            parse_group("{ echo {a,b} ${x} }; }"),
+            Some(Ok((braces("echo {a,b} ${x} };"), "")))
+        );
+        assert_eq!(
+            parse_group("{ { echo a; }; echo b; }"),
+            Some(Ok((braces("{ echo a; }; echo b;"), "")))
+        );
+        assert_eq!(parse_group("{ }"), Some(Ok((braces(""), ""))));
+        assert!(matches!(parse_group("{ echo a }"), Some(Err(_))));
+        assert_eq!(parse_group("{a,b}"), None);
+    }
+
+    #[test]
+    fn sleep_total_sums_units() {
+        assert_eq!(
+            sleep_total(&["1", "2m", "0.5s"]),
+            Ok(Duration::from_millis(121_500))
+        );
+        assert_eq!(sleep_total(&["1h", "1d"]), Ok(Duration::from_secs(90_000)));
+        assert_eq!(sleep_total(&["1", "-1"]), Err("-1"));
+        assert_eq!(sleep_total(&["nan"]), Err("nan"));
+        assert_eq!(sleep_total(&["1x"]), Err("1x"));
+    }
+
+    #[test]
+    fn sleep_total_saturates() {
+        assert_eq!(sleep_total(&["1e300"]), Ok(Duration::MAX));
+        assert_eq!(sleep_total(&["infinity"]), Ok(Duration::MAX));
+        assert_eq!(sleep_total(&["1e19", "1e19"]), Ok(Duration::MAX));
+        assert_eq!(sleep_total(&["1e300d"]), Ok(Duration::MAX));
+    }
+
+    #[test]
+    fn watch_interval_saturates() {
+        assert_eq!(seconds_to_duration(1e300), Duration::MAX);
+        assert_eq!(seconds_to_duration(f64::INFINITY), Duration::MAX);
+        assert_eq!(seconds_to_duration(0.25), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn sleep_deadline_past_the_clock() {
+        assert_eq!(
+            sleep_deadline(Duration::from_secs(10_000_000_000_000_000_000)),
+            None
+        );
+        assert_eq!(sleep_deadline(Duration::MAX), None);
+        assert!(sleep_deadline(Duration::from_secs(1)).is_some());
+    }
+
+    #[test]
+    fn read_splits_replies() {
+        let split = |line: &str, ifs: &str, count: usize| {
+            split_reply(&reply_chars(line, false), ifs, count)
+        };
+        assert_eq!(split("  a  b c  ", " \t\n", 2), ["a", "b c"]);
+        assert_eq!(split("a", " ", 3), ["a", "", ""]);
+        assert_eq!(split("a\\ b c", " ", 2), ["a b", "c"]);
+        assert_eq!(split("x: y:z", ": ", 2), ["x", "y:z"]);
+        assert_eq!(split("x::z", ":", 3), ["x", "", "z"]);
+        assert_eq!(split("a b", "", 2), ["a b", ""]);
+        assert_eq!(
+            split_reply(&reply_chars("a\\ b", true), " ", 2),
+            ["a\\", "b"]
+        );
+        assert!(is_escaped("a\\"));
+        assert!(!is_escaped("a\\\\"));
+    }
+
+    #[test]
+    fn basename_and_dirname() {
+        for (path, base, dir) in [
+            ("/", "/", "/"),
+            ("//", "/", "/"),
+            ("", "", "."),
+            ("a", "a", "."),
+            ("a/", "a", "."),
+            ("/a", "a", "/"),
+            ("a/b//", "b", "a"),
+            ("//a//b", "b", "//a"),
+        ] {
+            assert_eq!(basename(path), base, "basename {:?}", path);
+            assert_eq!(dirname(path), dir, "dirname {:?}", path);
+        }
+    }
+}
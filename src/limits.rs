@@ -0,0 +1,153 @@
+//! Resource limits shown and changed by `ulimit`, for the shell itself or
+//! for the commands one command starts.
+
+use std::io;
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type Resource = libc::c_int;
+
+/// A limit `ulimit` knows, by its option letter.
+#[derive(Debug)]
+pub struct Limit {
+    pub flag: char,
+    resource: Resource,
+    pub name: &'static str,
+    pub unit: &'static str,
+    /// Bytes, or whatever the resource counts, in one `unit`.
+    scale: libc::rlim_t,
+}
+
+pub const LIMITS: &[Limit] = &[
+    Limit {
+        flag: 'c',
+        resource: libc::RLIMIT_CORE,
+        name: "core file size",
+        unit: "blocks",
+        scale: 512,
+    },
+    Limit {
+        flag: 'd',
+        resource: libc::RLIMIT_DATA,
+        name: "data seg size",
+        unit: "kbytes",
+        scale: 1024,
+    },
+    Limit {
+        flag: 'f',
+        resource: libc::RLIMIT_FSIZE,
+        name: "file size",
+        unit: "blocks",
+        scale: 512,
+    },
+    Limit {
+        flag: 'n',
+        resource: libc::RLIMIT_NOFILE,
+        name: "open files",
+        unit: "files",
+        scale: 1,
+    },
+    Limit {
+        flag: 's',
+        resource: libc::RLIMIT_STACK,
+        name: "stack size",
+        unit: "kbytes",
+        scale: 1024,
+    },
+    Limit {
+        flag: 't',
+        resource: libc::RLIMIT_CPU,
+        name: "cpu time",
+        unit: "seconds",
+        scale: 1,
+    },
+    Limit {
+        flag: 'u',
+        resource: libc::RLIMIT_NPROC,
+        name: "max user processes",
+        unit: "processes",
+        scale: 1,
+    },
+    Limit {
+        flag: 'v',
+        resource: libc::RLIMIT_AS,
+        name: "virtual memory",
+        unit: "kbytes",
+        scale: 1024,
+    },
+];
+
+pub fn find(flag: char) -> Option<&'static Limit> {
+    LIMITS.iter().find(|limit| limit.flag == flag)
+}
+
+impl Limit {
+    /// The soft or hard limit in `unit`s, as `ulimit` prints it.
+    pub fn show(&self, hard: bool) -> io::Result<String> {
+        let (soft_value, hard_value) = get(self.resource)?;
+        let value = if hard { hard_value } else { soft_value };
+        Ok(if value == libc::RLIM_INFINITY {
+            "unlimited".to_string()
+        } else {
+            (value / self.scale).to_string()
+        })
+    }
+
+    /// Reads `text`, a number of `unit`s or `unlimited`, as a new value for
+    /// the soft limit, the hard one, or both.
+    pub fn setting(&'static self, text: &str, soft: bool, hard: bool) -> Option<Setting> {
+        let value = match text {
+            "unlimited" => libc::RLIM_INFINITY,
+            text => text.parse::<libc::rlim_t>().ok()?.checked_mul(self.scale)?,
+        };
+        Some(Setting {
+            limit: self,
+            value,
+            soft,
+            hard,
+        })
+    }
+}
+
+/// A new value for a limit.
+#[derive(Debug, Clone, Copy)]
+pub struct Setting {
+    pub limit: &'static Limit,
+    value: libc::rlim_t,
+    soft: bool,
+    hard: bool,
+}
+
+impl Setting {
+    /// Changes the limit for this process and the ones it starts. Only
+    /// makes system calls, so it is safe between `fork` and `exec`.
+    pub fn apply(&self) -> io::Result<()> {
+        let (mut soft, mut hard) = get(self.limit.resource)?;
+        if self.soft {
+            soft = self.value;
+        }
+        if self.hard {
+            hard = self.value;
+        }
+        let limit = libc::rlimit {
+            rlim_cur: soft,
+            rlim_max: hard,
+        };
+        if unsafe { libc::setrlimit(self.limit.resource, &limit) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+fn get(resource: Resource) -> io::Result<(libc::rlim_t, libc::rlim_t)> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(resource, &mut limit) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((limit.rlim_cur, limit.rlim_max))
+}
@@ -0,0 +1,159 @@
+//! Executables found in `$PATH`, scanned on first use and rescanned when
+//! `PATH` changes.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::{paths, ShellState};
+
+#[derive(Debug, Default)]
+pub struct PathCache {
+    /// `PATH` value the cache was built from.
+    path: String,
+    /// Command name to every full path providing it, in `PATH` order.
+    commands: BTreeMap<String, Vec<String>>,
+}
+
+impl PathCache {
+    fn scan(path: &str) -> Self {
+        let mut commands: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for dir in directories(path) {
+            let Ok(entries) = std::fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                if !is_executable(&entry.path()) {
+                    continue;
+                }
+                let name = entry.file_name().to_string_lossy().to_string();
+                commands
+                    .entry(name)
+                    .or_default()
+                    .push(entry.path().display().to_string());
+            }
+        }
+        Self {
+            path: path.to_string(),
+            commands,
+        }
+    }
+
+    /// Makes sure the cache reflects `path`, the current `PATH`.
+    pub fn refresh(&mut self, path: &str) {
+        if self.commands.is_empty() || self.path != path {
+            *self = Self::scan(path);
+        }
+    }
+
+    /// Command names starting with `prefix`.
+    pub fn names_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a String> {
+        self.commands
+            .range(prefix.to_string()..)
+            .take_while(move |(name, _)| name.starts_with(prefix))
+            .map(|(name, _)| name)
+    }
+
+    /// Every path providing `name`, in `PATH` order.
+    pub fn paths(&self, name: &str) -> &[String] {
+        self.commands.get(name).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// What looking for a command in one `PATH` directory found.
+#[derive(Debug, PartialEq)]
+pub enum Probe {
+    Found(String),
+    NotFound,
+    /// A file with the command's name that is a directory or a device.
+    NotRegularFile(String),
+    NotExecutable(String),
+    PermissionDenied,
+    MissingDirectory,
+    NotADirectory,
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Probe::Found(path) => write!(f, "found {}", path),
+            Probe::NotFound => write!(f, "not found"),
+            Probe::NotRegularFile(path) => write!(f, "{} is not a regular file", path),
+            Probe::NotExecutable(path) => write!(f, "{} is not executable", path),
+            Probe::PermissionDenied => write!(f, "permission denied"),
+            Probe::MissingDirectory => write!(f, "no such directory"),
+            Probe::NotADirectory => write!(f, "not a directory"),
+        }
+    }
+}
+
+/// A `PATH` directory and what looking for a command there found.
+#[derive(Debug)]
+pub struct DirectoryProbe {
+    pub dir: String,
+    pub probe: Probe,
+}
+
+/// Finds the first executable called `name` in the search path.
+pub fn find_executable(state: &ShellState, name: &str) -> Option<String> {
+    let path = crate::search_path(state);
+    let found = directories(&path).find_map(|dir| match probe_directory(dir, name) {
+        Probe::Found(path) => Some(path),
+        _ => None,
+    });
+    found
+}
+
+/// Looks for `name` in every directory of the search path, saying for each
+/// why it does not provide the command, for diagnostics.
+pub fn probe_path(state: &ShellState, name: &str) -> Vec<DirectoryProbe> {
+    let path = crate::search_path(state);
+    directories(&path)
+        .map(|dir| {
+            let probe = match probe_directory(dir, name) {
+                Probe::NotFound => match std::fs::metadata(dir) {
+                    Ok(meta) if meta.is_dir() => Probe::NotFound,
+                    Ok(_) => Probe::NotADirectory,
+                    Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                        Probe::PermissionDenied
+                    }
+                    Err(_) => Probe::MissingDirectory,
+                },
+                probe => probe,
+            };
+            DirectoryProbe {
+                dir: dir.to_string(),
+                probe,
+            }
+        })
+        .collect()
+}
+
+/// Looks for the command `name` in `dir`. Telling a missing directory
+/// from a missing file is left to `probe_path`, so that normal lookups
+/// cost no extra `stat`.
+fn probe_directory(dir: &str, name: &str) -> Probe {
+    let candidate = Path::new(dir).join(name);
+    let display = candidate.display().to_string();
+    match std::fs::metadata(&candidate) {
+        Ok(meta) if !meta.is_file() => Probe::NotRegularFile(display),
+        Ok(_) if is_executable(&candidate) => Probe::Found(display),
+        Ok(_) => Probe::NotExecutable(display),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Probe::PermissionDenied,
+        Err(_) => Probe::NotFound,
+    }
+}
+
+/// Directories of a search path. Empty entries stand for the current
+/// directory, as in bash.
+fn directories(path: &str) -> impl Iterator<Item = &str> {
+    paths::split_list(path).map(|dir| if dir.is_empty() { "." } else { dir })
+}
+
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::metadata(path)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
@@ -0,0 +1,77 @@
+//! How paths and search lists such as `PATH` and `CDPATH` are written.
+//! The shell needs a Unix platform throughout, for `fork`, termios and
+//! file modes, so these are the Unix rules.
+
+/// Separator between the entries of `PATH` and `CDPATH`.
+const LIST_SEPARATOR: char = ':';
+
+/// Search path for commands when `PATH` is unset, as in bash.
+pub const DEFAULT_PATH: &str = "/usr/bin:/bin";
+
+/// Entries of a search list such as `PATH` or `CDPATH`, empty ones
+/// included.
+pub fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split(LIST_SEPARATOR)
+}
+
+/// Whether `path` is absolute.
+pub fn is_absolute(path: &str) -> bool {
+    path.starts_with('/')
+}
+
+/// `name` inside the directory `base`.
+pub fn join(base: &str, name: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), name)
+}
+
+/// Lexically resolves `.` and `..` components of `path` against `base`
+/// without following symlinks. `base` is absolute.
+pub fn normalize(base: &str, path: &str) -> String {
+    let joined = if is_absolute(path) {
+        path.to_string()
+    } else {
+        join(base, path)
+    };
+    let mut components: Vec<&str> = Vec::new();
+    for component in joined.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+    format!("/{}", components.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists() {
+        let entries: Vec<_> = split_list("/bin::/usr/bin").collect();
+        assert_eq!(entries, ["/bin", "", "/usr/bin"]);
+    }
+
+    #[test]
+    fn absolute_paths() {
+        assert!(is_absolute("/usr"));
+        assert!(!is_absolute("usr"));
+        assert!(!is_absolute(r"C:\Windows"));
+    }
+
+    #[test]
+    fn joining() {
+        assert_eq!(join("/usr/", "bin"), "/usr/bin");
+        assert_eq!(join("/", "etc"), "/etc");
+    }
+
+    #[test]
+    fn normalizing() {
+        assert_eq!(normalize("/home/me", "../you/./x"), "/home/you/x");
+        assert_eq!(normalize("/home/me", "/etc//"), "/etc");
+        assert_eq!(normalize("/", "../.."), "/");
+    }
+}
@@ -0,0 +1,407 @@
+//! `PS1` rendering for the interactive prompt.
+
+/// Prompt used when `PS1` is unset.
+const DEFAULT_PS1: &str = "$ ";
+
+/// Start and end of a zero-width region, as written by `\[` and `\]`.
+pub const ZERO_WIDTH_START: char = '\x01';
+pub const ZERO_WIDTH_END: char = '\x02';
+
+/// Shell state shown by prompt escapes, gathered when the prompt is drawn.
+pub struct PromptInfo {
+    /// Jobs in the job table, for `\j`.
+    pub jobs: usize,
+    /// History number the next command will get, for `\!`.
+    pub history_number: usize,
+    /// Number of the next command this session, for `\#`.
+    pub command_number: usize,
+    /// Time shown by `\t`, `\T`, `\@`, `\A`, `\d` and `\D{format}`.
+    pub now: std::time::SystemTime,
+    /// `now` in the local time zone, worked out by the first time escape
+    /// and reused by the others.
+    pub local_now: std::cell::OnceCell<Option<libc::tm>>,
+    /// `$USER`, for `\u`.
+    pub user: String,
+    /// `$PWD`, or the physical working directory when it is unset.
+    pub pwd: String,
+    /// `$HOME`, abbreviated to `~` in `\w` and `\W`.
+    pub home: Option<String>,
+}
+
+impl PromptInfo {
+    /// `now` formatted with an `strftime` format, `None` if it cannot be.
+    fn format_now(&self, format: &str) -> Option<String> {
+        let tm = self.local_now.get_or_init(|| local_time(self.now));
+        strftime(tm.as_ref()?, format)
+    }
+}
+
+/// Expands the backslash escapes of `ps1`, the value of `$PS1`. The
+/// working directory is shortened from the left so the prompt takes at
+/// most half of `columns`.
+pub fn render(ps1: Option<&str>, columns: usize, info: &PromptInfo) -> String {
+    let ps1 = ps1.unwrap_or(DEFAULT_PS1);
+    let cwd = working_directory(info);
+    // Render once without the directory to learn how much room is left
+    let rest_width = display_width(&expand(ps1, "", info));
+    let budget = (columns / 2).saturating_sub(rest_width).max(2);
+    expand(ps1, &truncate_left(&cwd, budget), info)
+}
+
+/// Expands `rps1`, the value of `$RPS1`, like `PS1`, for the right edge
+/// of the input line. `None` when it is unset or empty, or would take more
+/// than one line.
+pub fn render_right(rps1: Option<&str>, info: &PromptInfo) -> Option<String> {
+    let rps1 = rps1.filter(|rps1| !rps1.is_empty())?;
+    let right = expand(rps1, &working_directory(info), info);
+    (!right.contains('\n')).then_some(right)
+}
+
+fn expand(ps1: &str, cwd: &str, info: &PromptInfo) -> String {
+    let mut result = String::new();
+    let mut chars = ps1.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('w') => result.push_str(cwd),
+            Some('W') => result.push_str(match cwd.rsplit_once('/') {
+                Some((_, "")) | None => cwd,
+                Some((_, base)) => base,
+            }),
+            Some('u') => result.push_str(&info.user),
+            Some('h') => {
+                let host = hostname();
+                result.push_str(host.split('.').next().unwrap_or_default());
+            }
+            Some('H') => result.push_str(&hostname()),
+            Some('$') => result.push(if unsafe { libc::geteuid() } == 0 {
+                '#'
+            } else {
+                '$'
+            }),
+            Some('t') => result.push_str(&info.format_now("%H:%M:%S").unwrap_or_default()),
+            Some('T') => result.push_str(&info.format_now("%I:%M:%S").unwrap_or_default()),
+            Some('@') => result.push_str(&info.format_now("%I:%M %p").unwrap_or_default()),
+            Some('A') => result.push_str(&info.format_now("%H:%M").unwrap_or_default()),
+            Some('d') => result.push_str(&info.format_now("%a %b %d").unwrap_or_default()),
+            // `\D{format}`, the locale's time for an empty format. One that
+            // is unterminated or cannot be formatted is shown as written.
+            Some('D') => {
+                let rest = chars.as_str();
+                let formatted = rest
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.split_once('}'))
+                    .and_then(|(format, after)| {
+                        let format = if format.is_empty() { "%X" } else { format };
+                        Some((info.format_now(format)?, after))
+                    });
+                match formatted {
+                    Some((text, after)) => {
+                        result.push_str(&text);
+                        chars = after.chars();
+                    }
+                    None => result.push_str("\\D"),
+                }
+            }
+            Some('j') => result.push_str(&info.jobs.to_string()),
+            Some('!') => result.push_str(&info.history_number.to_string()),
+            Some('#') => result.push_str(&info.command_number.to_string()),
+            Some('n') => result.push('\n'),
+            Some('e') => result.push('\x1b'),
+            Some('[') => result.push(ZERO_WIDTH_START),
+            Some(']') => result.push(ZERO_WIDTH_END),
+            Some('\\') => result.push('\\'),
+            Some(other) => {
+                result.push('\\');
+                result.push(other);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+/// `$PWD` with the home directory abbreviated to `~`.
+fn working_directory(info: &PromptInfo) -> String {
+    let cwd = info.pwd.clone();
+    match info.home.as_deref() {
+        Some(home) if !home.is_empty() && home != "/" => match cwd.strip_prefix(home) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("~{}", rest),
+            _ => cwd,
+        },
+        _ => cwd,
+    }
+}
+
+/// Formats `time` in the local time zone with an `strftime` format.
+pub fn format_time(time: std::time::SystemTime, format: &str) -> String {
+    local_time(time)
+        .and_then(|tm| strftime(&tm, format))
+        .unwrap_or_default()
+}
+
+extern "C" {
+    fn tzset();
+}
+
+/// Breaks `time` down in the local time zone, following `$TZ` as it is
+/// now rather than when the shell started.
+fn local_time(time: std::time::SystemTime) -> Option<libc::tm> {
+    let seconds = match time.duration_since(std::time::UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs() as libc::time_t,
+        Err(_) => 0,
+    };
+    unsafe {
+        tzset();
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&seconds, &mut tm).is_null() {
+            return None;
+        }
+        Some(tm)
+    }
+}
+
+/// Formats `tm` with an `strftime` format. `None` if the format has a NUL
+/// or its result is empty or too long.
+fn strftime(tm: &libc::tm, format: &str) -> Option<String> {
+    let format = std::ffi::CString::new(format).ok()?;
+    let mut buffer = [0u8; 256];
+    let len = unsafe {
+        libc::strftime(
+            buffer.as_mut_ptr().cast(),
+            buffer.len(),
+            format.as_ptr(),
+            tm,
+        )
+    };
+    (len > 0).then(|| String::from_utf8_lossy(&buffer[..len]).to_string())
+}
+
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } == -1 {
+        return String::new();
+    }
+    let len = buffer
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).to_string()
+}
+
+/// Keeps the tail of `text` that fits in `max_width` columns behind a
+/// leading `…`.
+pub fn truncate_left(text: &str, max_width: usize) -> String {
+    if display_width(text) <= max_width {
+        return text.to_string();
+    }
+    let mut width = 1;
+    let mut start = text.len();
+    for (index, c) in text.char_indices().rev() {
+        width += char_width(c);
+        if width > max_width {
+            break;
+        }
+        start = index;
+    }
+    format!("…{}", &text[start..])
+}
+
+/// Terminal columns taken by `text`, skipping `\[`…`\]` regions.
+pub fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut hidden = false;
+    for c in text.chars() {
+        match c {
+            ZERO_WIDTH_START => hidden = true,
+            ZERO_WIDTH_END => hidden = false,
+            c if !hidden => width += char_width(c),
+            _ => {}
+        }
+    }
+    width
+}
+
+/// Removes the zero-width markers before the prompt is written out.
+pub fn strip_markers(text: &str) -> String {
+    text.chars()
+        .filter(|c| *c != ZERO_WIDTH_START && *c != ZERO_WIDTH_END)
+        .collect()
+}
+
+/// Columns a character occupies: none for controls and combining marks,
+/// two for East Asian wide and fullwidth characters.
+pub fn char_width(c: char) -> usize {
+    let code = c as u32;
+    match code {
+        0..=0x1f | 0x7f..=0x9f => 0,
+        0x0300..=0x036f | 0x200b..=0x200f | 0xfe00..=0xfe0f => 0,
+        0x1100..=0x115f
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f
+        | 0x1f900..=0x1f9ff
+        | 0x20000..=0x2fffd
+        | 0x30000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
+
+/// Makes text the shell prints on behalf of others, such as command lines
+/// and file names, safe for the terminal: C0 and C1 control characters
+/// and DEL are shown in caret notation (`^[`, `^?`, and `M-^[` for C1)
+/// instead of being acted on.
+pub fn sanitize(text: &str) -> String {
+    let mut sanitized = String::with_capacity(text.len());
+    for c in text.chars() {
+        let code = c as u32;
+        match code {
+            0..=0x1f => {
+                sanitized.push('^');
+                sanitized.push(char::from(code as u8 + 0x40));
+            }
+            0x7f => sanitized.push_str("^?"),
+            0x80..=0x9f => {
+                sanitized.push_str("M-^");
+                sanitized.push(char::from(code as u8 - 0x40));
+            }
+            _ => sanitized.push(c),
+        }
+    }
+    sanitized
+}
+
+/// Breaks `text` into lines of at most `columns` display columns, so long
+/// notices wrap cleanly instead of running over what follows.
+pub fn wrap(text: &str, columns: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    let mut width = 0;
+    for c in text.chars() {
+        let c_width = char_width(c);
+        if width + c_width > columns.max(1) && !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+            width = 0;
+        }
+        line.push(c);
+        width += c_width;
+    }
+    lines.push(line);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Prompt state whose local time is fixed to 2024-03-09 14:05:06, so
+    /// time escapes come out the same in every time zone.
+    fn info() -> PromptInfo {
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = 124;
+        tm.tm_mon = 2;
+        tm.tm_mday = 9;
+        tm.tm_wday = 6;
+        tm.tm_hour = 14;
+        tm.tm_min = 5;
+        tm.tm_sec = 6;
+        PromptInfo {
+            jobs: 0,
+            history_number: 1,
+            command_number: 1,
+            now: std::time::UNIX_EPOCH,
+            local_now: std::cell::OnceCell::from(Some(tm)),
+            user: "user".to_string(),
+            pwd: "/home/user/src".to_string(),
+            home: Some("/home/user".to_string()),
+        }
+    }
+
+    #[test]
+    fn time_escapes() {
+        let info = info();
+        assert_eq!(
+            expand(r"\D{%Y-%m-%d %H:%M}|\A|\t|\d|\D{%%}", "", &info),
+            "2024-03-09 14:05|14:05|14:05:06|Sat Mar 09|%"
+        );
+        assert_eq!(expand(r"\D{%H", "", &info), r"\D{%H");
+        assert_eq!(expand(r"a\D b", "", &info), r"a\D b");
+        // An empty format is the locale's time
+        assert_eq!(expand(r"\D{}", "", &info), "14:05:06");
+    }
+
+    #[test]
+    fn times_follow_tz() {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_secs(86_400 + 3_600);
+        std::env::set_var("TZ", "UTC0");
+        assert_eq!(format_time(time, "%Y-%m-%d %H:%M"), "1970-01-02 01:00");
+        std::env::set_var("TZ", "EST5");
+        assert_eq!(format_time(time, "%Y-%m-%d %H:%M"), "1970-01-01 20:00");
+        std::env::remove_var("TZ");
+    }
+
+    #[test]
+    fn control_characters_in_caret_notation() {
+        assert_eq!(sanitize("ls\x1b[2J\tx"), "ls^[[2J^Ix");
+        assert_eq!(sanitize("\0\x7f\u{9b}"), "^@^?M-^[");
+        assert_eq!(sanitize("café 日本"), "café 日本");
+    }
+
+    #[test]
+    fn wide_characters() {
+        assert_eq!(display_width("日本語"), 6);
+        assert_eq!(display_width("🦀 crab"), 7);
+        assert_eq!(display_width("cafe\u{301}"), 4);
+        assert_eq!(truncate_left("~/src/日本語", 7), "…日本語");
+        // A wide character that does not fit leaves a column unused
+        assert_eq!(truncate_left("~/src/日本語", 6), "…本語");
+        assert_eq!(wrap("ab日本😀c", 4), ["ab日", "本😀", "c"]);
+        assert_eq!(wrap("日本", 3), ["日", "本"]);
+
+        for text in [
+            "~/プロジェクト/日本語",
+            "🦀🦀/a😀b/カニ",
+            "e\u{301}テ\u{301}スト",
+        ] {
+            let width = display_width(text);
+            for max_width in 1..=width + 1 {
+                let truncated = truncate_left(text, max_width);
+                let truncated_width = display_width(&truncated);
+                assert!(truncated_width <= max_width, "{} {}", truncated, max_width);
+                if max_width >= width {
+                    assert_eq!(truncated, text);
+                } else {
+                    assert!(
+                        truncated_width + 1 >= max_width,
+                        "{} {}",
+                        truncated,
+                        max_width
+                    );
+                    assert!(text.ends_with(truncated.strip_prefix('…').unwrap()));
+                }
+            }
+            for columns in 2..=width + 1 {
+                let lines = wrap(text, columns);
+                assert_eq!(lines.concat(), text);
+                for line in &lines {
+                    assert!(display_width(line) <= columns, "{:?} {}", lines, columns);
+                }
+                assert!(lines.windows(2).all(|pair| {
+                    let next = pair[1].chars().next().unwrap();
+                    display_width(&pair[0]) + char_width(next) > columns
+                }));
+            }
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Running the shell from another program without a terminal: source text
+//! goes in, and every command comes back as a `CommandResult`. External
+//! commands still really run; only where their output goes changes.
+
+use std::io::{self, BufRead, Read, Write};
+use std::os::unix::io::AsRawFd;
+
+use crate::{ShellState, Sinks};
+
+/// Output of one command, in the order it arrived: chunks written to
+/// stderr are marked `true`.
+type Chunks = Vec<(bool, Vec<u8>)>;
+
+/// What running one command of a list produced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandResult {
+    /// The command as written, without the separator.
+    pub command: String,
+    pub status: i32,
+    /// Standard output of the command, when capture mode is on. Bytes that
+    /// are not valid UTF-8 are replaced; the session's writer gets them
+    /// unchanged.
+    pub stdout: Option<String>,
+    /// Standard error of the command, when capture mode is on, converted
+    /// like `stdout`.
+    pub stderr: Option<String>,
+}
+
+/// A non-interactive shell reading commands from `input` and writing what
+/// they print, builtins and external commands alike, to `output`.
+///
+/// Each session hands its commands pipes of its own for stdout and stderr,
+/// so the process's descriptors are left alone, and keeps its own
+/// variables, passing the exported ones to the commands it runs. Sessions
+/// may run side by side on different threads, but what a process has only
+/// one of stays shared: the working directory, which a `cd` in any session
+/// changes for all of them, the umask, resource limits and signal
+/// dispositions. An exported `TZ` is also written to the process
+/// environment, where the C library reads it. `exit` stops the session
+/// instead of the process; see `exited`. A background job started by a command keeps
+/// that command's pipes open, so collecting its output waits for the job.
+pub struct ShellSession<R, W> {
+    state: ShellState,
+    input: R,
+    output: W,
+    capture: bool,
+}
+
+impl<R: BufRead, W: Write> ShellSession<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        let mut state = ShellState::new();
+        state.embedded = true;
+        Self {
+            state,
+            input,
+            output,
+            capture: false,
+        }
+    }
+
+    /// With capture mode on, results also carry each command's output.
+    pub fn set_capture(&mut self, capture: bool) {
+        self.capture = capture;
+    }
+
+    /// Runs every command in `source`, line by line, and returns one result
+    /// per command. Nothing more runs once the shell has exited.
+    pub fn run_source(&mut self, source: &str) -> Vec<CommandResult> {
+        let mut results = Vec::new();
+        for (index, line) in source.lines().enumerate() {
+            self.state.line = index + 1;
+            for command in crate::split_list(line) {
+                if self.state.exited.is_some() {
+                    break;
+                }
+                match self.run_command(command) {
+                    Ok(result) => results.push(result),
+                    Err(e) => {
+                        let _ = writeln!(self.output, "{}: {}", command.trim(), e);
+                    }
+                }
+            }
+        }
+        self.state.line = 0;
+        results
+    }
+
+    /// Reads the rest of the input and runs it with `run_source`.
+    pub fn run_input(&mut self) -> io::Result<Vec<CommandResult>> {
+        let mut source = String::new();
+        self.input.read_to_string(&mut source)?;
+        Ok(self.run_source(&source))
+    }
+
+    /// Status of the last command run.
+    pub fn last_status(&self) -> i32 {
+        self.state.last_status
+    }
+
+    /// Status passed to `exit`, or the one an error that ends a
+    /// non-interactive shell left, once the shell has exited.
+    pub fn exited(&self) -> Option<i32> {
+        self.state.exited
+    }
+
+    /// Working directory of the session, which is the process's and so
+    /// the same for every session.
+    pub fn cwd(&self) -> io::Result<std::path::PathBuf> {
+        std::env::current_dir()
+    }
+
+    /// Looks up a shell variable or special parameter such as `?`.
+    pub fn variable(&self, name: &str) -> Option<String> {
+        self.state.variable(name)
+    }
+
+    /// Whether the `set -o` option `name` is on.
+    pub fn option(&self, name: &str) -> bool {
+        self.state.option(name)
+    }
+
+    /// Aliases defined so far, with their values. Like any
+    /// non-interactive shell, a session only expands them after
+    /// `shopt -s expand_aliases`.
+    pub fn aliases(&self) -> Vec<(String, String)> {
+        self.state
+            .aliases
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Names of the functions defined so far.
+    pub fn functions(&self) -> Vec<String> {
+        self.state.functions.keys().cloned().collect()
+    }
+
+    /// What tab completion would offer for the last word of `line`, as the
+    /// line editor lists it: each candidate with the hint shown beside it,
+    /// such as a job's command.
+    pub fn complete(&self, line: &str) -> Vec<(String, Option<String>)> {
+        let completion = crate::completion::complete(&self.state, line);
+        completion
+            .candidates
+            .into_iter()
+            .map(|candidate| {
+                let hint = completion.hints.get(&candidate).cloned();
+                (candidate, hint)
+            })
+            .collect()
+    }
+
+    /// Runs one line, which may hold several commands, and returns what it
+    /// printed to stdout and to stderr, converted like `CommandResult`'s,
+    /// and its status. Unlike `run_source`, nothing is written to the
+    /// session's writer.
+    pub fn execute_line(&mut self, line: &str) -> io::Result<(String, String, i32)> {
+        let (status, chunks) = self.captured(|state| crate::execute_line(state, line))?;
+        let (stdout, stderr) = split_chunks(&chunks);
+        Ok((
+            String::from_utf8_lossy(&stdout).into_owned(),
+            String::from_utf8_lossy(&stderr).into_owned(),
+            status,
+        ))
+    }
+
+    /// Runs `run` with the shell's stdout and stderr pointed at fresh
+    /// pipes, returning its status and everything written to either.
+    fn captured(&mut self, run: impl FnOnce(&mut ShellState) -> i32) -> io::Result<(i32, Chunks)> {
+        let (stdout_reader, stdout_writer) = crate::pipe()?;
+        let (stderr_reader, stderr_writer) = crate::pipe()?;
+        let reader = std::thread::spawn(move || collect(stdout_reader, stderr_reader));
+
+        self.state.sinks = Sinks {
+            stdin: None,
+            stdout: Some(stdout_writer),
+            stderr: Some(stderr_writer),
+        };
+        let status = run(&mut self.state);
+        // Dropping the write ends lets the reader see end of file
+        self.state.sinks = Sinks::default();
+
+        let chunks = reader
+            .join()
+            .map_err(|_| io::Error::other("output reader panicked"))??;
+        Ok((status, chunks))
+    }
+
+    fn run_command(&mut self, command: &str) -> io::Result<CommandResult> {
+        let (status, chunks) = self.captured(|state| crate::execute_list_item(state, command))?;
+
+        for (_, bytes) in &chunks {
+            self.output.write_all(bytes)?;
+        }
+        let (stdout, stderr) = if self.capture {
+            let (stdout, stderr) = split_chunks(&chunks);
+            (
+                Some(String::from_utf8_lossy(&stdout).into_owned()),
+                Some(String::from_utf8_lossy(&stderr).into_owned()),
+            )
+        } else {
+            (None, None)
+        };
+        Ok(CommandResult {
+            command: command.trim().to_string(),
+            status,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// Reads both pipes until every writer has closed them, keeping chunks in
+/// the order they became readable.
+fn collect(mut stdout: std::fs::File, mut stderr: std::fs::File) -> io::Result<Chunks> {
+    let mut chunks = Chunks::new();
+    let mut fds = [
+        libc::pollfd {
+            fd: stdout.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: stderr.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+    let mut buffer = [0; 4096];
+    while fds.iter().any(|pollfd| pollfd.fd != -1) {
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } == -1 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+        for (index, pollfd) in fds.iter_mut().enumerate() {
+            if pollfd.fd == -1 || pollfd.revents == 0 {
+                continue;
+            }
+            let file = if index == 0 { &mut stdout } else { &mut stderr };
+            match file.read(&mut buffer) {
+                Ok(0) => pollfd.fd = -1,
+                Ok(count) => {
+                    let is_err = index == 1;
+                    match chunks.last_mut() {
+                        Some((last_is_err, bytes)) if *last_is_err == is_err => {
+                            bytes.extend_from_slice(&buffer[..count]);
+                        }
+                        _ => chunks.push((is_err, buffer[..count].to_vec())),
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(chunks)
+}
+
+/// Everything written to stdout, and everything written to stderr.
+fn split_chunks(chunks: &Chunks) -> (Vec<u8>, Vec<u8>) {
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    for (is_err, bytes) in chunks {
+        if *is_err {
+            stderr.extend_from_slice(bytes);
+        } else {
+            stdout.extend_from_slice(bytes);
+        }
+    }
+    (stdout, stderr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> ShellSession<io::Empty, Vec<u8>> {
+        ShellSession::new(io::empty(), Vec::new())
+    }
+
+    #[test]
+    fn exit_stops_the_session_not_the_process() {
+        let mut shell = session();
+        let results = shell.run_source("echo before\nexit 4\necho after\n");
+        assert_eq!(results.len(), 2);
+        assert_eq!(shell.exited(), Some(4));
+        assert_eq!(shell.output, b"before\n");
+    }
+
+    #[test]
+    fn keeps_stdout_and_stderr_in_order() {
+        let mut shell = session();
+        shell.run_source("echo one; echo two >&2; sh -c 'echo three'\n");
+        assert_eq!(String::from_utf8_lossy(&shell.output), "one\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn sessions_run_side_by_side() {
+        let threads: Vec<_> = (0..4)
+            .map(|index| {
+                std::thread::spawn(move || {
+                    let mut shell = session();
+                    // Every session sets the same variable, and its
+                    // commands see only its own value
+                    let line = format!(
+                        "export in_session={0}; echo $in_session; sh -c 'echo $in_session; exit {0}'",
+                        index
+                    );
+                    let result = shell.execute_line(&line).unwrap();
+                    (result, shell.variable("in_session"))
+                })
+            })
+            .collect();
+        for (index, thread) in threads.into_iter().enumerate() {
+            let ((stdout, stderr, status), variable) = thread.join().unwrap();
+            assert_eq!(stdout, format!("{0}\n{0}\n", index));
+            assert_eq!(stderr, "");
+            assert_eq!(status, index as i32);
+            assert_eq!(variable, Some(index.to_string()));
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Self-pipe through which signal handlers wake the shell. Handlers only
+//! set flags and write a byte here; the work they call for, such as
+//! reporting finished jobs or running traps, is done by whoever polls the
+//! read end once it is safe to.
+
+use std::io;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+
+/// Read and write ends of the pipe, -1 until `init` has run.
+static PIPE: [AtomicI32; 2] = [AtomicI32::new(-1), AtomicI32::new(-1)];
+
+/// Signals caught for traps and not yet taken, one bit per signal number.
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Creates the pipe, non-blocking and closed on exec. Later calls do
+/// nothing.
+pub fn init() -> io::Result<()> {
+    if wake_fd().is_some() {
+        return Ok(());
+    }
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    for fd in fds {
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK);
+        }
+    }
+    PIPE[1].store(fds[1], Ordering::Relaxed);
+    PIPE[0].store(fds[0], Ordering::Relaxed);
+    Ok(())
+}
+
+/// Installs `handler` for `signal`. System calls it interrupts are
+/// restarted, except waits such as `poll` that are meant to be woken.
+pub fn install(signal: libc::c_int, handler: extern "C" fn(libc::c_int)) {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+}
+
+/// Catches `signal` for a trap: each arrival is recorded for
+/// `take_pending` and wakes the pipe.
+pub fn catch(signal: libc::c_int) {
+    install(signal, record);
+}
+
+extern "C" fn record(signal: libc::c_int) {
+    if (1..64).contains(&signal) {
+        PENDING.fetch_or(1 << signal, Ordering::Relaxed);
+    }
+    wake();
+}
+
+/// Makes `signal` ignored, as for a trap with an empty handler.
+pub fn ignore(signal: libc::c_int) {
+    unsafe { libc::signal(signal, libc::SIG_IGN) };
+}
+
+/// Gives `signal` back its default action.
+pub fn set_default(signal: libc::c_int) {
+    unsafe { libc::signal(signal, libc::SIG_DFL) };
+}
+
+/// Whether a signal caught for a trap is waiting for `take_pending`.
+pub fn has_pending() -> bool {
+    PENDING.load(Ordering::Relaxed) != 0
+}
+
+/// Takes the signals caught since the last call, lowest number first. One
+/// that arrived several times in between is taken once.
+pub fn take_pending() -> Vec<libc::c_int> {
+    let pending = PENDING.swap(0, Ordering::Relaxed);
+    (1..64)
+        .filter(|signal| pending & (1 << signal) != 0)
+        .collect()
+}
+
+/// Wakes whoever polls `wake_fd`. Async-signal-safe: a full pipe already
+/// holds a wakeup, so a failed write is ignored.
+pub fn wake() {
+    let fd = PIPE[1].load(Ordering::Relaxed);
+    if fd != -1 {
+        let byte = 0u8;
+        unsafe { libc::write(fd, (&byte as *const u8).cast(), 1) };
+    }
+}
+
+/// Read end of the pipe to poll alongside input, once `init` has run.
+pub fn wake_fd() -> Option<libc::c_int> {
+    let fd = PIPE[0].load(Ordering::Relaxed);
+    (fd != -1).then_some(fd)
+}
+
+/// Empties the pipe after a wakeup.
+pub fn drain() {
+    let Some(fd) = wake_fd() else {
+        return;
+    };
+    let mut buffer = [0u8; 64];
+    while unsafe { libc::read(fd, buffer.as_mut_ptr().cast(), buffer.len()) } > 0 {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readable(fd: libc::c_int) -> bool {
+        let mut poll = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut poll, 1, 0) == 1 }
+    }
+
+    extern "C" fn handle_usr2(_signal: libc::c_int) {
+        wake();
+    }
+
+    /// The pipe is process-wide, so everything runs in one test.
+    #[test]
+    fn signals_wake_the_pipe_without_blocking() {
+        init().unwrap();
+        let fd = wake_fd().unwrap();
+        init().unwrap();
+        assert_eq!(wake_fd(), Some(fd));
+        drain();
+        assert!(!readable(fd));
+
+        install(libc::SIGUSR2, handle_usr2);
+        for _ in 0..1000 {
+            unsafe { libc::raise(libc::SIGUSR2) };
+        }
+        assert!(readable(fd));
+        drain();
+        assert!(!readable(fd));
+
+        // More wakeups than the pipe holds must not block the handler
+        for _ in 0..200_000 {
+            wake();
+        }
+        assert!(readable(fd));
+        drain();
+        assert!(!readable(fd));
+
+        catch(libc::SIGUSR1);
+        for _ in 0..3 {
+            unsafe { libc::raise(libc::SIGUSR1) };
+        }
+        assert!(readable(fd));
+        assert_eq!(take_pending(), [libc::SIGUSR1]);
+        assert!(take_pending().is_empty());
+        drain();
+    }
+}
@@ -0,0 +1,433 @@
+//! Runs the shell binary in environments a session inside the test
+//! process cannot set up without disturbing the other tests.
+
+use std::process::Command;
+
+fn shell() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_codecrafters-shell"))
+}
+
+#[test]
+fn default_search_path_without_path() {
+    let output = shell()
+        .env_remove("PATH")
+        .args(["-c", "ls -d /; echo \"[$PATH]\""])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "/\n[]\n");
+    assert!(output.status.success());
+
+    let output = shell()
+        .env("PATH", "")
+        .args(["-c", "ls -d /"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(127));
+}
+
+#[test]
+fn autocd() {
+    let dir = std::env::temp_dir().join(format!("invocation-autocd.{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    // A directory named like a builtin must not shadow it
+    std::fs::create_dir_all(dir.join("true")).unwrap();
+    let output = shell()
+        .current_dir(&dir)
+        .args([
+            "-c",
+            "sub; echo $?; shopt -s autocd; sub; pwd; ..; pwd; \
+             true; pwd; shopt -u autocd; sub; echo $?",
+        ])
+        .output()
+        .unwrap();
+    let dir = dir.canonicalize().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("127\n{0}/sub\n{0}\n{0}\n127\n", dir.display())
+    );
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn working_directory_tildes() {
+    let dir = std::env::temp_dir().join(format!("invocation-tildes.{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    let output = shell()
+        .current_dir(&dir)
+        .env_remove("OLDPWD")
+        .args([
+            "-c",
+            "echo ~- ~-/x; cd sub; echo ~+ ~- a~+ \"~-\" ~-/y; \
+             pushd / > /dev/null; echo ~1 ~+1 ~-1 ~0 ~3",
+        ])
+        .output()
+        .unwrap();
+    let dir = dir.canonicalize().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "~- ~-/x\n{0}/sub {0} a~+ ~- {0}/y\n{0}/sub {0}/sub / / ~3\n",
+            dir.display()
+        )
+    );
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+#[test]
+fn coprocess_round_trip() {
+    let output = shell()
+        .args([
+            "-c",
+            "coproc ECHO { cat; }; echo hello >&${ECHO[1]}; \
+             read line <&${ECHO[0]}; echo \"got $line\"; fd=${ECHO[1]}; echo again >&$fd; \
+             read line <&${ECHO[0]}; echo \"got $line\"",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "got hello\ngot again\n"
+    );
+    assert!(output.status.success());
+}
+
+/// Traps on real signals change the dispositions of the whole process.
+#[test]
+fn signal_traps() {
+    let output = shell()
+        .args([
+            "-c",
+            "trap 'echo usr1; false' USR1; kill -USR1 $$; echo after $?; trap -p 10; \
+             trap '' TERM; kill $$; echo ignored; \
+             (sh -c 'kill -USR1 $PPID'; echo subshell); echo $?; \
+             trap - USR1; trap 'echo bye; exit 3' 15 INT; kill -INT $$; echo not reached",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "usr1\nafter 0\ntrap -- 'echo usr1; false' SIGUSR1\nignored\n138\nbye\n"
+    );
+    assert_eq!(output.status.code(), Some(3));
+
+    let output = shell()
+        .args(["-c", "trap 'echo x' BOGUS 64"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "trap: BOGUS: invalid signal specification\ntrap: 64: invalid signal specification\n"
+    );
+}
+
+/// A burst of signals while a trap's handler runs neither interrupts it nor
+/// kills the shell: they run the trap once more after it.
+#[test]
+fn signals_during_a_trap_handler() {
+    use std::io::{BufRead, BufReader};
+
+    let mut child = shell()
+        .args([
+            "-c",
+            "trap 'echo trap; sleep 0.3; echo handled' USR1; echo ready; sleep 1; echo done",
+        ])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = child.id() as libc::pid_t;
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut next = || lines.next().unwrap().unwrap();
+    assert_eq!(next(), "ready");
+    unsafe { libc::kill(pid, libc::SIGUSR1) };
+    assert_eq!(next(), "trap");
+    for _ in 0..500 {
+        unsafe { libc::kill(pid, libc::SIGUSR1) };
+    }
+    let rest: Vec<_> = std::iter::from_fn(|| Some(next()))
+        .take_while(|line| line != "done")
+        .collect();
+    assert_eq!(rest, ["handled", "trap", "handled"]);
+    assert!(child.wait().unwrap().success());
+}
+
+/// `&` ends a list item, so `wait -n` after it is a command of its own and
+/// returns as soon as the quicker of the two jobs ends.
+#[test]
+fn wait_for_the_first_of_two_jobs() {
+    let output = shell()
+        .args([
+            "-c",
+            "sleep 2 & (sleep 0.1; exit 3) & wait -n; echo first $?; jobs; \
+             kill %1; true && echo and-or & wait",
+        ])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), Some("first 3"));
+    assert!(lines.next().unwrap().contains("sleep 2"), "{stdout}");
+    assert_eq!(lines.next(), Some("and-or"));
+    assert_eq!(lines.next(), None);
+}
+
+/// `-c` neither loads `HISTFILE` nor appends to it; `history -r` shows
+/// that the sentinel entry was there to be read.
+#[test]
+fn command_string_leaves_history_file_alone() {
+    let histfile = std::env::temp_dir().join(format!("invocation-histfile.{}", std::process::id()));
+    std::fs::write(&histfile, "sentinel-entry\n").unwrap();
+    let output = shell()
+        .env("HISTFILE", &histfile)
+        .args(["-c", "history; echo \"[$?]\"; echo second"])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "[0]\nsecond\n");
+    assert_eq!(
+        std::fs::read_to_string(&histfile).unwrap(),
+        "sentinel-entry\n"
+    );
+
+    let output = shell()
+        .env("HISTFILE", &histfile)
+        .args(["-c", "history -r; history"])
+        .output()
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "    1  sentinel-entry\n"
+    );
+    let _ = std::fs::remove_file(histfile);
+}
+
+/// `cd` and `pwd` through a farm of symlinks, `lla -> la -> real/a` and
+/// `real/a/up -> ../../other`: logically `..` drops the last component
+/// of `$PWD`, physically it leaves the directory the links lead to. Each
+/// script ends with `/bin/pwd -P`, the working directory the shell is
+/// really in.
+#[test]
+fn logical_and_physical_paths() {
+    let dir = std::env::temp_dir().join(format!("invocation-symlinks.{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("real/a/b")).unwrap();
+    std::fs::create_dir_all(dir.join("other")).unwrap();
+    let dir = dir.canonicalize().unwrap();
+    let link = |target: &str, name: &str| std::os::unix::fs::symlink(target, dir.join(name));
+    link("real/a", "la").unwrap();
+    link("la", "lla").unwrap();
+    link("../../other", "real/a/up").unwrap();
+
+    let table = [
+        (
+            "cd lla/b; pwd; pwd -P; echo $PWD",
+            "lla/b real/a/b lla/b real/a/b",
+        ),
+        ("cd lla/b; cd ..; pwd", "lla real/a"),
+        ("cd lla/b; cd -P ..; pwd", "real/a real/a"),
+        (
+            "cd lla/b; cd ..; cd -P up; pwd; echo $PWD",
+            "other other other",
+        ),
+        ("cd -P lla; pwd; pwd -L", "real/a real/a real/a"),
+        ("cd la/up; pwd; pwd -P; cd ..; pwd", "la/up other la real/a"),
+        ("cd lla/up/..; pwd", "lla real/a"),
+        ("cd -P lla/up/..; pwd", " "),
+        // With a link on `$PWD` gone, `..` is the physical parent
+        (
+            "cd lla/b; rm \"$OLDPWD/la\"; cd ..; echo $?; pwd",
+            "0 real/a real/a",
+        ),
+    ];
+    for (script, expected) in table {
+        let _ = std::fs::remove_file(dir.join("la"));
+        link("real/a", "la").unwrap();
+        let output = shell()
+            .current_dir(&dir)
+            .env("PWD", &dir)
+            .args(["-c", &format!("{}; /bin/pwd -P", script)])
+            .output()
+            .unwrap();
+        let expected: String = expected
+            .split(' ')
+            .map(|path| match path {
+                "" => format!("{}\n", dir.display()),
+                "0" => "0\n".to_string(),
+                path => format!("{}/{}\n", dir.display(), path),
+            })
+            .collect();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            expected,
+            "{}",
+            script
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if script.contains("rm ") {
+            assert_eq!(
+                stderr,
+                format!(
+                    "cd: warning: {}/lla/b: no such directory, using the physical path\n",
+                    dir.display()
+                )
+            );
+        } else {
+            assert_eq!(stderr, "", "{}", script);
+        }
+    }
+
+    // An inherited `$PWD` counts only when it names the working directory
+    link("real/a", "la").unwrap();
+    for (pwd, expected) in [("lla/b", "lla/b"), ("real", "real/a/b")] {
+        let output = shell()
+            .current_dir(dir.join("real/a/b"))
+            .env("PWD", dir.join(pwd))
+            .args(["-c", "pwd; echo $PWD"])
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("{0}/{1}\n{0}/{1}\n", dir.display(), expected)
+        );
+    }
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+/// Every way out of an interactive login shell tears down in the same
+/// order: the `EXIT` trap runs before the history file is appended to, and
+/// jobs get SIGHUP under `huponexit` only after that. The trap and the
+/// job's `HUP` handler each log how many `echo teardown` lines the history
+/// file holds when they run.
+#[test]
+fn exit_paths_tear_down_in_order() {
+    let dir = std::env::temp_dir().join(format!("invocation-teardown.{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let log = dir.join("log");
+    let histfile = dir.join("history");
+    for (last, status) in [("logout", 0), ("exit 3", 3), ("", 0)] {
+        let _ = std::fs::remove_file(&log);
+        std::fs::write(&histfile, "").unwrap();
+        let count = "$(grep -c ^echo.teardown $HISTFILE)";
+        let script = format!(
+            "trap 'echo trap {count} >> {log}' EXIT\n\
+             shopt -s huponexit\n\
+             sh -c 'trap \"echo hup \\{count} >> {log}; exit\" HUP; \
+             while :; do sleep 0.05; done' &\n\
+             sleep 0.2\n\
+             echo teardown\n\
+             {last}\n",
+            log = log.display(),
+        );
+        let mut child = shell()
+            .args(["-i", "-l"])
+            .env("HISTFILE", &histfile)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        use std::io::Write;
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(script.as_bytes())
+            .unwrap();
+        assert_eq!(child.wait().unwrap().code(), Some(status), "{:?}", last);
+        // The job logs once it has handled the hangup
+        let mut logged = String::new();
+        for _ in 0..100 {
+            logged = std::fs::read_to_string(&log).unwrap_or_default();
+            if logged.contains("hup") {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        assert_eq!(logged, "trap 0\nhup 1\n", "{:?}", last);
+    }
+    let _ = std::fs::remove_dir_all(dir);
+}
+
+/// History expansion comes first, on the line as typed: the words it
+/// brings back are then alias-expanded, quoted and expanded like typed
+/// ones, while an alias's value is never history-expanded.
+#[test]
+fn history_expansion_comes_before_aliases() {
+    let mut child = shell()
+        .arg("-i")
+        .env("PS1", "")
+        .env("HISTFILE", "/dev/null")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    use std::io::Write;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(
+            b"alias ll='echo listed' bang='echo !!'\n\
+              x=value\n\
+              echo '$x' \"$x\"\n\
+              ll !*\n\
+              !!\n\
+              bang\n",
+        )
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "$x value\n\
+         ll '$x' \"$x\"\nlisted $x value\n\
+         ll '$x' \"$x\"\nlisted $x value\n\
+         !!\n"
+    );
+}
+
+/// `caller` names the line and file each call came from, with `$0` as the
+/// file of `-c` commands, and `caller N` adds the calling function.
+#[test]
+fn caller_frames() {
+    let dir = std::env::temp_dir().join(format!("invocation-caller.{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let sourced = dir.join("sourced.sh");
+    std::fs::write(
+        &sourced,
+        "caller; caller 0\nh() { caller 0; caller 1; }\nh\n",
+    )
+    .unwrap();
+    let script = dir.join("script.sh");
+    std::fs::write(
+        &script,
+        format!(
+            "f() {{ caller; caller 0; }}\nf\nsource {}\n",
+            sourced.display()
+        ),
+    )
+    .unwrap();
+    let nested = "f() { caller; caller 0; caller 1; caller 2; echo \"[$?]\"; }\n\
+                  g() { f; }\ng; caller; echo \"[$?]\"; caller x; echo \"[$?]\"";
+
+    let run = |args: &[&str]| {
+        let output = shell().args(args).output().unwrap();
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let program = env!("CARGO_BIN_EXE_codecrafters-shell");
+    assert_eq!(
+        run(&["-c", "f() { caller; caller 0; }; f"]),
+        format!("1 {0}\n1 main {0}\n", program)
+    );
+    assert_eq!(
+        run(&["-c", nested, "named"]),
+        "2 named\n2 g named\n3 main named\n[1]\n[1]\n[2]\n"
+    );
+    let sourced = sourced.display();
+    let script = script.display().to_string();
+    assert_eq!(
+        run(&[&script]),
+        format!(
+            "2 {0}\n2 main {0}\n3 {0}\n3 main {0}\n3 source {1}\n3 main {0}\n",
+            script, sourced
+        )
+    );
+    let _ = std::fs::remove_dir_all(dir);
+}
@@ -0,0 +1,993 @@
+//! Scripts run through `ShellSession::execute_line`, checking what they
+//! print and their status. Sessions share neither output nor variables, so
+//! these run in parallel; the working directory is still the process's,
+//! so tests that change it run the shell binary in `invocation.rs`.
+
+use std::io;
+
+use codecrafters_shell::session::ShellSession;
+
+fn session() -> ShellSession<io::Empty, Vec<u8>> {
+    ShellSession::new(io::empty(), Vec::new())
+}
+
+fn run(line: &str) -> (String, String, i32) {
+    session().execute_line(line).unwrap()
+}
+
+fn scratch_file(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("session-test.{}.{}", std::process::id(), name))
+}
+
+#[test]
+fn echo_and_status() {
+    assert_eq!(
+        run("echo hello world"),
+        ("hello world\n".into(), "".into(), 0)
+    );
+    assert_eq!(run("false; echo $?"), ("1\n".into(), "".into(), 0));
+}
+
+#[test]
+fn command_not_found() {
+    let (stdout, stderr, status) = run("no_such_command_in_session_test");
+    assert_eq!(stdout, "");
+    assert_eq!(
+        stderr,
+        "no_such_command_in_session_test: command not found\n"
+    );
+    assert_eq!(status, 127);
+}
+
+#[test]
+fn variables_persist_between_lines() {
+    let mut shell = session();
+    shell.execute_line("persist_test_var=abc").unwrap();
+    let (stdout, _, _) = shell
+        .execute_line("echo $persist_test_var ${persist_test_var}d \"$persist_test_var\"")
+        .unwrap();
+    assert_eq!(stdout, "abc abcd abc\n");
+    assert_eq!(shell.variable("persist_test_var").as_deref(), Some("abc"));
+}
+
+#[test]
+fn functions() {
+    let mut shell = session();
+    shell
+        .execute_line("session_greet() { echo \"hi $1\"; }")
+        .unwrap();
+    assert!(shell.functions().contains(&"session_greet".to_string()));
+    let (stdout, _, status) = shell.execute_line("session_greet bob").unwrap();
+    assert_eq!((stdout.as_str(), status), ("hi bob\n", 0));
+}
+
+#[test]
+fn aliases() {
+    let mut shell = session();
+    shell
+        .execute_line("alias greet='echo hello ' who=world echo='echo said'")
+        .unwrap();
+    assert_eq!(
+        shell.aliases(),
+        [
+            ("echo".to_string(), "echo said".to_string()),
+            ("greet".to_string(), "echo hello ".to_string()),
+            ("who".to_string(), "world".to_string()),
+        ]
+    );
+    // Off until `expand_aliases`, as in any non-interactive shell
+    assert_eq!(shell.execute_line("greet").unwrap().2, 127);
+    shell.execute_line("shopt -s expand_aliases").unwrap();
+    let (stdout, _, _) = shell.execute_line("greet who; 'greet'; echo who").unwrap();
+    assert_eq!(stdout, "said hello world\nsaid who\n");
+    let (_, stderr, _) = shell.execute_line("'greet'").unwrap();
+    assert_eq!(stderr, "greet: command not found\n");
+    assert_eq!(
+        shell.execute_line("type who; alias who").unwrap().0,
+        "who is aliased to `world'\nalias who='world'\n"
+    );
+    let (_, stderr, status) = shell.execute_line("unalias who greet missing").unwrap();
+    assert_eq!(
+        (stderr.as_str(), status),
+        ("unalias: missing: not found\n", 1)
+    );
+    assert_eq!(shell.aliases().len(), 1);
+}
+
+#[test]
+fn exported_variables() {
+    let mut shell = session();
+    let (stdout, _, _) = shell
+        .execute_line("x=1; sh -c 'echo [$x]'; export x; sh -c 'echo [$x]'")
+        .unwrap();
+    assert_eq!(stdout, "[]\n[1]\n");
+    let (stdout, _, _) = shell
+        .execute_line("z=3 sh -c 'echo [$z]'; echo [$z]; export y=2 w; sh -c 'echo [$y$w]'")
+        .unwrap();
+    assert_eq!(stdout, "[3]\n[]\n[2]\n");
+    assert_eq!(
+        shell
+            .execute_line("export -p")
+            .unwrap()
+            .0
+            .lines()
+            .find(|line| line.starts_with("export y=")),
+        Some("export y=2")
+    );
+    let (stdout, _, _) = shell
+        .execute_line("export -n y; sh -c 'echo [$y]'; echo $y; unset x y; echo [$x$y]")
+        .unwrap();
+    assert_eq!(stdout, "[]\n2\n[]\n");
+    let (_, stderr, status) = shell.execute_line("export 1x=2").unwrap();
+    assert_eq!(
+        (stderr.as_str(), status),
+        ("export: `1x=2': not a valid identifier\n", 1)
+    );
+    shell.execute_line("f() { echo f; }; unset -f f").unwrap();
+    assert_eq!(shell.execute_line("f").unwrap().2, 127);
+}
+
+#[test]
+fn expanded_redirection_targets() {
+    let home = scratch_file("home");
+    std::fs::create_dir_all(&home).unwrap();
+    let mut shell = session();
+    let (stdout, stderr, _) = shell
+        .execute_line(&format!(
+            "HOME={0}; echo tilde > ~/tilde; target={0}/variable; echo variable > $target; \
+             cat {0}/tilde {0}/variable",
+            home.display()
+        ))
+        .unwrap();
+    std::fs::remove_dir_all(&home).unwrap();
+    assert_eq!(
+        (stdout.as_str(), stderr.as_str()),
+        ("tilde\nvariable\n", "")
+    );
+}
+
+#[test]
+fn control_characters_in_error_messages() {
+    let (_, stderr, _) = run(
+        "cat < $'/nonexistent\\e[2J'; echo hi > $'/nonexistent/\\e[2J'; cd $'/nonexistent\\e]0;x\\a'",
+    );
+    assert!(!stderr.contains('\x1b'), "{:?}", stderr);
+    let lines: Vec<_> = stderr.lines().collect();
+    assert_eq!(lines.len(), 3, "{:?}", stderr);
+    assert!(lines[0].starts_with("/nonexistent^[[2J: "));
+    assert!(lines[1].contains("/nonexistent/^[[2J: "));
+    assert!(lines[2].starts_with("cd: /nonexistent^[]0;x^G: "));
+}
+
+#[test]
+fn realpath_like_coreutils() {
+    let dir = scratch_file("realpath");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("d")).unwrap();
+    std::fs::create_dir_all(dir.join("t")).unwrap();
+    std::fs::write(dir.join("f"), "").unwrap();
+    std::os::unix::fs::symlink(dir.join("t"), dir.join("d/link")).unwrap();
+    std::os::unix::fs::symlink(dir.join("nowhere"), dir.join("dangling")).unwrap();
+    std::os::unix::fs::symlink("loop", dir.join("loop")).unwrap();
+    let dir = dir.canonicalize().unwrap();
+    let d = dir.display();
+    let cases = [
+        // A missing last component is fine unless with -e
+        (format!("{d}/d/missing"), format!("{d}/d/missing\n"), ""),
+        (
+            format!("-e {d}/d/missing"),
+            String::new(),
+            "No such file or directory",
+        ),
+        (
+            format!("{d}/nodir/x"),
+            String::new(),
+            "No such file or directory",
+        ),
+        (format!("-m {d}/nodir/../x"), format!("{d}/x\n"), ""),
+        // Symlinks come before `..` unless with -L or -s
+        (format!("{d}/d/link/../x"), format!("{d}/x\n"), ""),
+        (format!("-L {d}/d/link/../x"), format!("{d}/d/x\n"), ""),
+        (format!("-s {d}/d/link/../x"), format!("{d}/d/x\n"), ""),
+        (format!("-s {d}/d/link"), format!("{d}/d/link\n"), ""),
+        (format!("{d}/dangling"), format!("{d}/nowhere\n"), ""),
+        (
+            format!("-e {d}/dangling"),
+            String::new(),
+            "No such file or directory",
+        ),
+        (format!("{d}/f/x"), String::new(), "Not a directory"),
+        (format!("-m {d}/f/x"), format!("{d}/f/x\n"), ""),
+        (
+            format!("{d}/loop"),
+            String::new(),
+            "Too many levels of symbolic links",
+        ),
+        (format!("-q {d}/nodir/x"), String::new(), ""),
+    ];
+    for (args, stdout, error) in cases {
+        let (out, err, status) = run(&format!("realpath {args}"));
+        assert_eq!(out, stdout, "{}", args);
+        assert_eq!(status, i32::from(stdout.is_empty()), "{}", args);
+        match error {
+            "" => assert_eq!(err, "", "{}", args),
+            error => assert!(
+                err.starts_with("realpath: ") && err.ends_with(&format!(": {error}\n")),
+                "{}: {}",
+                args,
+                err
+            ),
+        }
+    }
+    assert_eq!(
+        run("realpath -x /"),
+        ("".into(), "realpath: invalid option -- 'x'\n".into(), 1)
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn case_statement() {
+    let (stdout, _, _) = run("case foo in f*) echo matched;; *) echo other;; esac");
+    assert_eq!(stdout, "matched\n");
+}
+
+#[test]
+fn redirections() {
+    let path = scratch_file("redirections");
+    let path = path.display();
+    let (stdout, _, status) = run(&format!(
+        "echo one > {path}; echo two >> {path}; cat < {path}"
+    ));
+    assert_eq!((stdout.as_str(), status), ("one\ntwo\n", 0));
+
+    let (stdout, stderr, _) = run(&format!("ls {path}.missing 2> {path}; cat {path}"));
+    assert!(stdout.contains("No such file or directory"), "{}", stdout);
+    assert_eq!(stderr, "");
+    let _ = std::fs::remove_file(path.to_string());
+}
+
+#[test]
+fn builtin_output_keeps_nuls_and_raw_bytes() {
+    let path = scratch_file("raw-bytes");
+    let path = path.display();
+    let (_, _, status) = run(&format!(
+        r"echo -e 'a\0b\xffc' > {path}; echo -n -e '\0' >> {path}"
+    ));
+    assert_eq!(status, 0);
+    assert_eq!(std::fs::read(path.to_string()).unwrap(), b"a\0b\xffc\n\0");
+    let _ = std::fs::remove_file(path.to_string());
+}
+
+#[test]
+fn comments_and_blank_lines() {
+    let path = scratch_file("comments");
+    std::fs::write(
+        &path,
+        "echo one # trailing\n\n# only a comment\n   \nfalse\n# after false\n\n",
+    )
+    .unwrap();
+    let (stdout, _, status) = run(&format!("source {}", path.display()));
+    assert_eq!((stdout.as_str(), status), ("one\n", 1));
+
+    let mut shell = session();
+    shell.execute_line("false").unwrap();
+    assert_eq!(shell.execute_line("# nothing to run").unwrap().2, 1);
+    assert_eq!(shell.execute_line("   ").unwrap().2, 1);
+    let (stdout, _, _) = shell
+        .execute_line("echo \"#kept\" a#b # dropped; echo $?")
+        .unwrap();
+    assert_eq!(stdout, "#kept a#b\n");
+    let (stdout, _, _) = shell
+        .execute_line("case x in # which\n x) echo hit # here\n ;; # done\n esac")
+        .unwrap();
+    assert_eq!(stdout, "hit\n");
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn status_of_prefixes_assignments_and_redirections() {
+    let cases = [
+        ("! true", 1),
+        ("! false | true", 1),
+        ("time false", 1),
+        ("time true", 0),
+        ("! time false", 0),
+        ("session_status_var=$(false)", 1),
+        ("session_status_var=$(exit 3) session_status_other=1", 3),
+        ("false; session_status_var=1", 0),
+        ("echo hi > /nonexistent/session-status", 1),
+        ("cat < /nonexistent/session-status", 1),
+    ];
+    for (line, expected) in cases {
+        let (stdout, _, _) = run(&format!("{}; echo $?", line));
+        assert_eq!(stdout, format!("{}\n", expected), "{}", line);
+    }
+
+    let path = scratch_file("failed-redirection");
+    let (_, _, status) = run(&format!(
+        "echo ran < /nonexistent/session-status > {}",
+        path.display()
+    ));
+    assert_eq!(status, 1);
+    assert_eq!(std::fs::read_to_string(&path).unwrap_or_default(), "");
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn quoted_at_and_star() {
+    let mut shell = session();
+    shell
+        .execute_line(
+            "session_argc() { echo \"$#[$1][$2]\"; }\n\
+             session_forward() { session_argc \"$@\"; session_argc \"pre$@post\"; \
+             session_argc \"$*\"; session_argc $@; }",
+        )
+        .unwrap();
+    let (stdout, _, _) = shell.execute_line("session_forward").unwrap();
+    assert_eq!(stdout, "0[][]\n1[prepost][]\n1[][]\n0[][]\n");
+    let (stdout, _, _) = shell.execute_line("session_forward a 'b c'").unwrap();
+    assert_eq!(stdout, "2[a][b c]\n2[prea][b cpost]\n1[a b c][]\n3[a][b]\n");
+}
+
+#[test]
+fn tildes_after_colons_in_assignments() {
+    let home = std::env::var("HOME").unwrap();
+    let (stdout, _, _) = run(
+        "session_tilde_path=~/bin:~/local/bin:/x~:~; echo $session_tilde_path; \
+         echo a:~/b; session_tilde_quoted=/a:\"~\"/b:\\~/c; echo $session_tilde_quoted",
+    );
+    assert_eq!(
+        stdout,
+        format!("{0}/bin:{0}/local/bin:/x~:{0}\na:~/b\n/a:~/b:~/c\n", home)
+    );
+}
+
+#[test]
+fn ansi_c_strings_with_nuls_and_raw_bytes() {
+    let path = scratch_file("ansi-c");
+    let (_, _, status) = run(&format!(
+        r"session_ansi_nul=$'a\0b'; session_ansi_hex=$'c\x00d'; echo $session_ansi_nul$session_ansi_hex $'\xff' > {}",
+        path.display()
+    ));
+    assert_eq!(status, 0);
+    assert_eq!(std::fs::read(&path).unwrap(), b"ac \xff\n");
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn stderr_duplicate_goes_to_the_session() {
+    let (stdout, stderr, _) = run("echo to-err >&2; echo to-out");
+    assert_eq!((stdout.as_str(), stderr.as_str()), ("to-out\n", "to-err\n"));
+}
+
+#[test]
+fn descriptor_duplication() {
+    let (stdout, stderr, _) = run("echo one 1>&2; session_dup_fd=2; echo two >&$session_dup_fd");
+    assert_eq!((stdout.as_str(), stderr.as_str()), ("", "one\ntwo\n"));
+
+    // Stderr follows stdout, into a pipe or out of the shell
+    assert_eq!(
+        run("ls /nonexistent-session-dup 2>&1 | wc -l").0.trim(),
+        "1"
+    );
+    let (stdout, stderr, _) = run("ls /nonexistent-session-dup 2>&1");
+    assert!(stdout.contains("nonexistent-session-dup"), "{}", stdout);
+    assert_eq!(stderr, "");
+    let (stdout, stderr, _) = run("type session_dup_missing 2>&1");
+    assert_eq!(
+        (stdout.as_str(), stderr.as_str()),
+        ("session_dup_missing: not found\n", "")
+    );
+
+    // Descriptors above 2 reach external commands
+    assert_eq!(run("echo hi | sh -c 'cat <&3' 3<&0").0, "hi\n");
+    assert_eq!(run("sh -c 'echo up >&4' 4>&1").0, "up\n");
+
+    // `-` closes, and anything else is not a descriptor
+    assert_eq!(run("echo closed >&-").2, 1);
+    for line in [
+        "session_dup_word=foo; cat <&$session_dup_word",
+        "echo a 1>&foo",
+    ] {
+        let (_, stderr, status) = run(line);
+        assert!(
+            stderr.contains("ambiguous redirect"),
+            "{}: {}",
+            line,
+            stderr
+        );
+        assert_eq!(status, 1, "{}", line);
+    }
+
+    // Without a number in front, a file name gets stdout and stderr
+    let path = scratch_file("dup-file");
+    let (_, stderr, _) = run(&format!(
+        "ls /nonexistent-session-dup >& {}",
+        path.display()
+    ));
+    assert_eq!(stderr, "");
+    let written = std::fs::read_to_string(&path).unwrap();
+    assert!(written.contains("nonexistent-session-dup"), "{}", written);
+    let _ = std::fs::remove_file(path);
+    assert!(!std::path::Path::new("2").exists());
+}
+
+#[test]
+fn pipelines() {
+    assert_eq!(run("echo one two | wc -w").0.trim(), "2");
+    assert_eq!(run("printf 'b\\na\\n' | sort | head -n 1").0, "a\n");
+    assert_eq!(run("false | true; echo $?").0, "0\n");
+    assert_eq!(run("true | false; echo $?").0, "1\n");
+
+    let path = scratch_file("pipelines");
+    let (stdout, _, status) = run(&format!(
+        "printf 'x\\ny\\n' | cat | wc -l > {0}; tr -d ' ' < {0}",
+        path.display()
+    ));
+    assert_eq!((stdout.as_str(), status), ("2\n", 0));
+    let _ = std::fs::remove_file(path);
+
+    for line in ["echo a |", "echo a | | cat", "| cat"] {
+        let (stdout, stderr, status) = run(line);
+        assert_eq!(stdout, "", "{}", line);
+        assert!(stderr.contains("syntax error"), "{}: {}", line, stderr);
+        assert_eq!(status, 2, "{}", line);
+    }
+}
+
+#[test]
+fn builtins_in_pipelines() {
+    assert_eq!(run("echo hello | tr a-z A-Z").0, "HELLO\n");
+    assert_eq!(run("type echo | cat").0, "echo is a shell builtin\n");
+    assert_eq!(run("pwd | cat").0, run("pwd").0);
+    assert_eq!(run("echo one | echo two").0, "two\n");
+    // More than pipes hold, fed through a forked stage to another one
+    assert_eq!(
+        run("x=$(printf %0200000d 0); echo $x | { cat; } | (cat) | wc -c").0,
+        "200001\n"
+    );
+    assert_eq!(
+        run("printf 'a b\\n' | { read first second; echo \"[$second$first]\"; }").0,
+        "[ba]\n"
+    );
+    // Each stage runs apart from the shell, so its assignments are lost
+    assert_eq!(
+        run("echo x | read session_pipe_lost; echo \"[$session_pipe_lost]\"").0,
+        "[]\n"
+    );
+}
+
+#[test]
+fn external_output_is_captured() {
+    let (stdout, stderr, status) = run("sh -c 'echo out; echo err >&2; exit 5'");
+    assert_eq!(
+        (stdout.as_str(), stderr.as_str(), status),
+        ("out\n", "err\n", 5)
+    );
+}
+
+#[test]
+fn exit_ends_only_the_session() {
+    let mut shell = session();
+    let (stdout, _, status) = shell
+        .execute_line("echo before; exit 7; echo after")
+        .unwrap();
+    assert_eq!((stdout.as_str(), status), ("before\n", 7));
+    assert_eq!(shell.exited(), Some(7));
+}
+
+#[test]
+fn many_sessions_in_parallel() {
+    let threads: Vec<_> = (0..8)
+        .map(|index| {
+            std::thread::spawn(move || {
+                run(&format!(
+                    "echo start {index}; sh -c 'echo ext {index}'; echo end {index} >&2"
+                ))
+            })
+        })
+        .collect();
+    for (index, thread) in threads.into_iter().enumerate() {
+        let (stdout, stderr, status) = thread.join().unwrap();
+        assert_eq!(stdout, format!("start {index}\next {index}\n"));
+        assert_eq!(stderr, format!("end {index}\n"));
+        assert_eq!(status, 0);
+    }
+}
+
+#[test]
+fn subshells() {
+    assert_eq!(run("( exit 3 ); echo $?").0, "3\n");
+    assert_eq!(run("(echo a) | (cat)").0, "a\n");
+    assert_eq!(run("( echo a ) | cat").0, "a\n");
+    assert_eq!(run("(echo a; echo b) | wc -l").0.trim(), "2");
+    assert_eq!(run("echo x | (cat) | (cat)").0, "x\n");
+    assert_eq!(run("(echo outer; (echo inner)) | cat").0, "outer\ninner\n");
+    assert_eq!(run("(cd /; pwd)").0, "/\n");
+
+    let path = scratch_file("subshells");
+    let (stdout, _, status) = run(&format!(
+        "( echo a; sh -c 'echo b' ) > {0}; cat {0}",
+        path.display()
+    ));
+    assert_eq!((stdout.as_str(), status), ("a\nb\n", 0));
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn subshell_syntax_errors() {
+    assert_eq!(run("(echo a) b").2, 2);
+    assert_eq!(run("()").2, 2);
+    assert_eq!(run("(echo a").2, 2);
+}
+
+#[test]
+fn brace_groups() {
+    let mut shell = session();
+    let (stdout, _, _) = shell
+        .execute_line("{ brace_test_var=set; }; echo $brace_test_var")
+        .unwrap();
+    assert_eq!(stdout, "set\n");
+    assert_eq!(run("{ echo a; echo b; } | wc -l").0.trim(), "2");
+    assert_eq!(run("echo x | { cat; }").0, "x\n");
+    assert_eq!(run("{ echo a; }; echo $?").0, "a\n0\n");
+    assert_eq!(run("{ echo a }").2, 2);
+    // Commands that cannot start a group are not mistaken for one
+    assert_eq!(run("éclair_session_test; echo ok").0, "ok\n");
+    assert_eq!(run("echo a |").2, 2);
+}
+
+#[test]
+fn brace_group_output_keeps_its_order() {
+    let input = scratch_file("brace-input");
+    let output = scratch_file("brace-output");
+    std::fs::write(&input, "from cat\n").unwrap();
+    let (stdout, _, status) = run(&format!(
+        "{{ echo first; cat {}; echo last; }} > {1}; cat {1}",
+        input.display(),
+        output.display()
+    ));
+    assert_eq!((stdout.as_str(), status), ("first\nfrom cat\nlast\n", 0));
+    let _ = std::fs::remove_file(input);
+    let _ = std::fs::remove_file(output);
+}
+
+#[test]
+fn command_substitution() {
+    assert_eq!(run("echo \"$(echo \"a  b\")\"").0, "a  b\n");
+    assert_eq!(run("echo $(echo \"a  b\")").0, "a b\n");
+    assert_eq!(run("echo $(echo $(echo nested))").0, "nested\n");
+    assert_eq!(run("echo `echo back` $(printf 'x\\n\\n')y").0, "back xy\n");
+    assert_eq!(run("echo $(exit 3) $?").0, "3\n");
+
+    let mut shell = session();
+    let (stdout, _, _) = shell
+        .execute_line("substitution_test_var=$(echo hi); echo $substitution_test_var")
+        .unwrap();
+    assert_eq!(stdout, "hi\n");
+    assert_eq!(
+        shell
+            .execute_line("substitution_test_var=$(false)")
+            .unwrap()
+            .2,
+        1
+    );
+
+    // NULs cannot be stored, so they are dropped with a warning
+    let (stdout, stderr, status) = shell
+        .execute_line("substitution_test_var=$(printf 'a\\0b'); echo \"[$substitution_test_var]\"")
+        .unwrap();
+    assert_eq!((stdout.as_str(), status), ("[ab]\n", 0));
+    assert!(stderr.contains("ignored null byte in input"), "{}", stderr);
+}
+
+#[test]
+fn arithmetic_expansion() {
+    assert_eq!(run("echo $(( 1 + 2 * 3 ))").0, "7\n");
+    assert_eq!(
+        run("arith_test_var=6; echo $((arith_test_var / 4)) $(( $arith_test_var % 4 ))").0,
+        "1 2\n"
+    );
+    let (stdout, stderr, _) = run("echo $((1/0)); echo $?");
+    assert_eq!(stdout, "1\n");
+    assert!(stderr.contains("division by 0"), "{}", stderr);
+}
+
+#[test]
+fn redirection_patterns_must_match_one_file() {
+    let dir = scratch_file("redirect-glob");
+    std::fs::create_dir_all(&dir).unwrap();
+    let dir = dir.display();
+    let (_, stderr, status) = run(&format!("echo hi > {dir}/zz*"));
+    assert_eq!(status, 1);
+    assert!(stderr.ends_with("/zz*: no match\n"), "{}", stderr);
+    assert!(!std::path::Path::new(&format!("{dir}/zz*")).exists());
+
+    let (_, stderr, status) = run(&format!("touch {dir}/a1 {dir}/a2; echo hi > {dir}/a*"));
+    assert_eq!(status, 1);
+    assert!(stderr.ends_with("/a*: ambiguous redirect\n"), "{}", stderr);
+
+    let (stdout, _, status) = run(&format!(
+        "echo one > {dir}/a1; echo two > {dir}/a1*; cat {dir}/a1"
+    ));
+    assert_eq!((stdout.as_str(), status), ("two\n", 0));
+    let _ = std::fs::remove_dir_all(dir.to_string());
+}
+
+#[test]
+fn read_builtin() {
+    let path = scratch_file("read");
+    std::fs::write(&path, "  one two  three \nnext\\\n line\n").unwrap();
+    let mut shell = session();
+    let (stdout, _, status) = shell
+        .execute_line(&format!(
+            "{{ read read_test_a read_test_b; read; }} < {}; echo \"[$read_test_a][$read_test_b][$REPLY]\"",
+            path.display()
+        ))
+        .unwrap();
+    assert_eq!(
+        (stdout.as_str(), status),
+        ("[one][two  three][next line]\n", 0)
+    );
+    let _ = std::fs::remove_file(path);
+
+    assert_eq!(
+        run("printf 'a\\\\b' | { read -r v; echo $? $v; }").0,
+        "1 a\\b\n"
+    );
+    assert_eq!(
+        run("printf 'a\\0b\\n' | { read v; echo \"[$v]\"; }").0,
+        "[ab]\n"
+    );
+    assert_eq!(run("read 1x < /dev/null").2, 1);
+    assert_eq!(run("read -z").2, 2);
+}
+
+#[test]
+fn read_times_out() {
+    assert_eq!(run("sleep 1 | read -t 0.1 read_timeout_var").2, 142);
+    // TMOUT bounds the wait when `-t` is not given
+    assert_eq!(run("sleep 2 | { TMOUT=1; read v; echo $?; }").0, "142\n");
+    assert_eq!(run("read -t x").2, 1);
+}
+
+#[test]
+fn errexit() {
+    assert_eq!(
+        run("set -e; echo a; false; echo b"),
+        ("a\n".into(), "".into(), 1)
+    );
+    assert_eq!(run("set -e; false | true; echo piped").0, "piped\n");
+    assert_eq!(
+        run("set -e; set -o pipefail; false | true; echo piped").2,
+        1
+    );
+    assert_eq!(
+        run("set -e; errexit_test_fn() { false; echo in; }; errexit_test_fn; echo out").0,
+        ""
+    );
+    assert_eq!(
+        run("set -e; ! false; ! true; ! errexit_test_missing 2> /dev/null; echo negated").0,
+        "negated\n"
+    );
+    assert_eq!(run("set -e; (false); echo after").0, "");
+}
+
+#[test]
+fn and_or_lists() {
+    assert_eq!(run("true && echo yes || echo no").0, "yes\n");
+    assert_eq!(run("false && echo yes || echo no").0, "no\n");
+    assert_eq!(run("false || false && echo yes; echo $?").0, "1\n");
+    assert_eq!(run("echo '&&' && echo \"||\"").0, "&&\n||\n");
+    assert_eq!(
+        run("{ false || echo group; } && (true && echo sub)").0,
+        "group\nsub\n"
+    );
+    // Only the last pipeline of the list can make `set -e` exit
+    assert_eq!(
+        run("set -e; false && true; false || true; echo survived; true && false; echo not"),
+        ("survived\n".into(), "".into(), 1)
+    );
+    let (_, stderr, status) = run("&& echo x");
+    assert_eq!(
+        (stderr.as_str(), status),
+        ("syntax error near unexpected token `&&'\n", 2)
+    );
+}
+
+#[test]
+fn errexit_and_command_substitution() {
+    assert_eq!(run("set -e; errexit_test_var=$(false); echo after").2, 1);
+    assert_eq!(
+        run("set -e; echo $(false; echo inner) outer; echo after").0,
+        "inner outer\nafter\n"
+    );
+}
+
+#[test]
+fn nounset_and_defaults() {
+    assert_eq!(
+        run("set -u; echo ${nounset_test_a:-def} ${nounset_test_a-d2}").0,
+        "def d2\n"
+    );
+    assert_eq!(
+        run("nounset_test_b=; echo \"[${nounset_test_b:-x}][${nounset_test_b-y}][${nounset_test_b:+p}]\"").0,
+        "[x][][]\n"
+    );
+    assert_eq!(
+        run("echo ${nounset_test_c:=assigned}; echo $nounset_test_c").0,
+        "assigned\nassigned\n"
+    );
+
+    let (stdout, stderr, status) = run("set -u; echo $nounset_test_d; echo after");
+    assert_eq!((stdout.as_str(), status), ("", 1));
+    assert!(
+        stderr.ends_with("nounset_test_d: unbound variable\n"),
+        "{}",
+        stderr
+    );
+    let (_, stderr, status) = run("echo ${nounset_test_e?custom message}");
+    assert_eq!(status, 1);
+    assert!(
+        stderr.ends_with("nounset_test_e: custom message\n"),
+        "{}",
+        stderr
+    );
+    assert_eq!(run("set -u; echo \"$@\" $# $?").0, "0 0\n");
+}
+
+#[test]
+fn parameter_operators() {
+    let (stdout, stderr, status) = run(
+        "param_op_x=/usr/lib/file.tar.gz; echo ${#param_op_x} ${param_op_x#*/} ${param_op_x##*/} \
+         ${param_op_x%.*} ${param_op_x%%.*} ${param_op_x/l/L} ${param_op_x//l/L} \
+         ${param_op_x/#\\/usr/U} ${param_op_x/%gz/GZ} ${param_op_x:1:3} ${param_op_x: -2} \
+         ${param_op_x:1:-3} \"${param_op_x#\"*\"}\"",
+    );
+    assert_eq!(
+        (stdout.as_str(), stderr.as_str(), status),
+        (
+            "20 usr/lib/file.tar.gz file.tar.gz /usr/lib/file.tar /usr/lib/file \
+             /usr/Lib/file.tar.gz /usr/Lib/fiLe.tar.gz U/lib/file.tar.gz /usr/lib/file.tar.GZ \
+             usr gz usr/lib/file.tar /usr/lib/file.tar.gz\n",
+            "",
+            0
+        )
+    );
+    assert_eq!(
+        run("param_op_e=héllo; echo ${#param_op_e} ${param_op_e:1:2}").0,
+        "5 él\n"
+    );
+    assert_eq!(
+        run("param_op_n=abc; echo $(( ${#param_op_n} + 1 ))").0,
+        "4\n"
+    );
+
+    let (stdout, stderr, status) = run("echo ${param_op_x!}; echo after");
+    assert_eq!((stdout.as_str(), status), ("", 1));
+    assert!(
+        stderr.ends_with("${param_op_x!}: bad substitution\n"),
+        "{stderr}"
+    );
+    let (_, stderr, status) = run("set -u; echo ${#param_op_unset}");
+    assert_eq!(status, 1);
+    assert!(
+        stderr.ends_with("param_op_unset: unbound variable\n"),
+        "{stderr}"
+    );
+    let (_, stderr, status) = run("echo ${1=x}");
+    assert_eq!(status, 1);
+    assert!(
+        stderr.ends_with("$1: cannot assign in this way\n"),
+        "{stderr}"
+    );
+
+    let (stdout, _, status) = run("echo ${param_op_nul=$(printf 'x\\0y')}; echo $param_op_nul");
+    assert_eq!((stdout.as_str(), status), ("xy\nxy\n", 0));
+}
+
+#[test]
+fn funcname_array() {
+    let (stdout, _, _) = run(
+        "funcname_outer() { funcname_inner; }; \
+         funcname_inner() { echo ${FUNCNAME[0]} ${FUNCNAME[1]} ${FUNCNAME}; echo ${FUNCNAME[@]}; }; \
+         funcname_outer; echo \"[${FUNCNAME[0]}]\"",
+    );
+    assert_eq!(
+        stdout,
+        "funcname_inner funcname_outer funcname_inner\nfuncname_inner funcname_outer main\n[]\n"
+    );
+}
+
+/// Each difference `set -o posix` makes, with what the script prints by
+/// default and in posix mode.
+#[test]
+fn posix_mode() {
+    let table = [
+        // Assignments before special builtins persist
+        ("v=1 set +b; echo \"[$v]\"", "[]\n", "[1]\n"),
+        ("v=1 true; echo \"[$v]\"", "[]\n", "[]\n"),
+        // echo takes no -e
+        ("echo -e 'a\\tb'", "a\tb\n", "-e a\\tb\n"),
+        // Special builtins are found before functions
+        (
+            "set() { echo fn; }; type set | head -1; set +b",
+            "set is a function\nfn\n",
+            "set is a special shell builtin\n",
+        ),
+        ("true() { echo fn; }; true", "fn\n", "fn\n"),
+        // Errors in special builtins end the shell
+        ("export 1x; echo after", "after\n", ""),
+        ("unset -x; echo after", "after\n", ""),
+        ("set -o nosuch; echo after", "after\n", ""),
+        ("trap '' NOSUCH; echo after", "after\n", ""),
+        ("cd /nonexistent; echo after", "after\n", "after\n"),
+    ];
+    for (script, default, posix) in table {
+        assert_eq!(run(script).0, default, "{}", script);
+        assert_eq!(
+            run(&format!("set -o posix; {}", script)).0,
+            posix,
+            "posix: {}",
+            script
+        );
+    }
+}
+
+/// `local -` puts the options back however the function is left: at its
+/// end, through `return`, or when an error unwinds through it and ends the
+/// shell.
+#[test]
+fn local_options_are_restored() {
+    let functions = "ends() { local -; set -u; }; \
+                     returns() { local -; set -o pipefail; return 3; echo not; }; \
+                     fails() { local -; set -u -o xtrace; echo ${nope:?gone}; echo not; }; \
+                     nested() { local -; set -u; returns; echo $?; set -o | grep pipefail; }";
+    for (call, stdout, status, exited) in [
+        ("ends", "", 0, None),
+        ("returns", "", 3, None),
+        ("nested", "3\npipefail       \toff\n", 0, None),
+        ("fails", "", 1, Some(1)),
+        ("nested; fails", "3\npipefail       \toff\n", 1, Some(1)),
+    ] {
+        let mut shell = session();
+        shell.execute_line(functions).unwrap();
+        let (out, _, code) = shell.execute_line(call).unwrap();
+        assert_eq!((out.as_str(), code), (stdout, status), "{}", call);
+        assert_eq!(shell.exited(), exited, "{}", call);
+        for option in ["nounset", "pipefail", "xtrace"] {
+            assert!(!shell.option(option), "{} after {}", option, call);
+        }
+    }
+}
+
+/// `kill -0` only asks whether its targets exist: the shell itself, a
+/// running job by pid or job spec, and not a reaped process or job.
+#[test]
+fn kill_zero_probes() {
+    let table = [
+        ("kill -0 $$", "", 0),
+        ("sleep 1 & kill -0 $!; s=$?; kill $!; exit $s", "", 0),
+        ("sleep 1 & kill -0 %1; s=$?; kill %1; exit $s", "", 0),
+        ("sleep 1 & kill -0 %sleep $$; s=$?; kill %1; exit $s", "", 0),
+        (
+            "(exit 0) & p=$!; wait; kill -0 $p",
+            "kill: (PID) - No such process\n",
+            1,
+        ),
+        ("(exit 0) & wait; kill -0 %1", "kill: %1: no such job\n", 1),
+        ("kill -0 %9 $$", "kill: %9: no such job\n", 1),
+    ];
+    for (script, stderr, status) in table {
+        let mut shell = session();
+        let (_, err, code) = shell.execute_line(script).unwrap();
+        let pid = shell.variable("p").unwrap_or_default();
+        let err = if pid.is_empty() {
+            err
+        } else {
+            err.replace(&pid, "PID")
+        };
+        assert_eq!((err.as_str(), code), (stderr, status), "{}", script);
+    }
+}
+
+/// Three `getopts` calls over the same arguments, each printing its
+/// status, the option, `OPTARG` and `OPTIND`.
+#[test]
+fn getopts_table() {
+    let table = [
+        // Clustered options, and an argument in the same word or the next
+        ("ab: -abc -ba", "0 a - 1|0 b c 2|0 b a 3", ""),
+        ("a:b -a-b", "0 a -b 2|1 ? - 2|1 ? - 2", ""),
+        ("ab -ab -b", "0 a - 1|0 b - 2|0 b - 3", ""),
+        // Options end at `--`, which is skipped, or the first operand
+        ("ab -a -- -b", "0 a - 2|1 ? - 3|0 b - 4", ""),
+        ("ab -a x -b", "0 a - 2|1 ? - 2|1 ? - 2", ""),
+        // A missing argument, reported or, after a leading `:`, given as `:`
+        (
+            "ab: -b",
+            "0 ? - 2|1 ? - 2|1 ? - 2",
+            "option requires an argument -- b\n",
+        ),
+        (":ab: -a -b", "0 a - 2|0 : b 3|1 ? - 3", ""),
+        (":ab: -ab", "0 a - 1|0 : b 2|1 ? - 2", ""),
+        // An unknown option, reported or, after a leading `:`, in `OPTARG`
+        ("ab: -x", "0 ? - 2|1 ? - 2|1 ? - 2", "illegal option -- x\n"),
+        (":ab: -x", "0 ? x 2|1 ? - 2|1 ? - 2", ""),
+    ];
+    for (spec_args, stdout, stderr) in table {
+        let (spec, args) = spec_args.split_once(' ').unwrap();
+        let call = format!(
+            "getopts '{}' o {}; echo \"$? $o ${{OPTARG--}} $OPTIND\"; unset OPTARG; ",
+            spec, args
+        );
+        let (out, err, _) = run(&call.repeat(3));
+        assert_eq!(out.trim_end().replace('\n', "|"), stdout, "{}", spec_args);
+        // Errors start with `$0`, like bash's
+        let err = err.split_once(": ").map_or("", |(_, message)| message);
+        assert_eq!(err, stderr, "{}", spec_args);
+    }
+}
+
+/// The `complete` specs the shell registers for its own builtins: signal
+/// names after `kill -`, job specs hinted with their commands after
+/// `kill`, `fg`, `bg` and `wait`, and variable names after `unset` and
+/// `export`.
+#[test]
+fn default_completion_specs() {
+    let mut shell = session();
+    // Jobs holding none of the session's pipes, which it would wait for
+    let (stdout, _, _) = shell
+        .execute_line(
+            "complete -p; /bin/sleep 5 > /dev/null 2> /dev/null & \
+             /bin/sleep 5 2> /dev/null | cat > /dev/null 2> /dev/null & \
+             spec_test_one=1; spec_test_two=2",
+        )
+        .unwrap();
+    assert_eq!(
+        stdout,
+        "complete -A job bg\ncomplete -A variable export\ncomplete -A job fg\n\
+         complete -A signal -A job kill\ncomplete -A variable unset\n\
+         complete -A job wait\n"
+    );
+    let candidates = |shell: &ShellSession<_, _>, line: &str| -> Vec<String> {
+        shell
+            .complete(line)
+            .into_iter()
+            .map(|(candidate, hint)| match hint {
+                Some(hint) => format!("{} ({})", candidate, hint),
+                None => candidate,
+            })
+            .collect()
+    };
+    let jobs = [
+        "%1 (/bin/sleep 5 > /dev/null 2> /dev/null)",
+        "%2 (/bin/sleep 5 2> /dev/null | cat > /dev/null 2> /dev/null)",
+    ];
+    assert_eq!(candidates(&shell, "kill -US"), ["-USR1", "-USR2"]);
+    assert_eq!(candidates(&shell, "kill -9 -te"), ["-TERM"]);
+    assert_eq!(candidates(&shell, "kill "), jobs);
+    assert_eq!(candidates(&shell, "kill -TERM %2"), [jobs[1]]);
+    for command in ["fg", "bg", "wait"] {
+        assert_eq!(candidates(&shell, &format!("{} %", command)), jobs);
+        assert!(candidates(&shell, &format!("{} -", command)).is_empty());
+    }
+    for command in ["unset", "export"] {
+        assert_eq!(
+            candidates(&shell, &format!("{} spec_test_", command)),
+            ["spec_test_one", "spec_test_two"]
+        );
+    }
+    // Removing a spec goes back to completing paths
+    shell.execute_line("complete -r kill; kill %1 %2").unwrap();
+    assert!(candidates(&shell, "kill %").is_empty());
+}
+
+#[test]
+fn star_joins_with_the_first_ifs_character() {
+    assert_eq!(
+        run("join() { echo \"$*\"; }; IFS=:,; join a 'b c'; IFS=; join a b").0,
+        "a:b c\nab\n"
+    );
+}